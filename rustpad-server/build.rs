@@ -0,0 +1,30 @@
+//! Build script that bakes the git commit into the server binary.
+
+use std::process::Command;
+
+fn main() {
+    // Builds without a git checkout, such as in Docker, can pass the commit in.
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string("../.git/HEAD") {
+        if let Some(reference) = head.strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=../.git/{}", reference.trim());
+        }
+    }
+
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .map(String::into_bytes)
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()?;
+            output.status.success().then_some(output.stdout)
+        })
+        .and_then(|stdout| String::from_utf8(stdout).ok())
+        .map(|commit| commit.trim().to_owned())
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=RUSTPAD_GIT_COMMIT={}", commit);
+}
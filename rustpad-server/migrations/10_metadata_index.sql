@@ -0,0 +1,30 @@
+CREATE TABLE document_metadata(
+    document_id TEXT NOT NULL,
+    path TEXT NOT NULL,
+    value TEXT
+);
+
+CREATE INDEX idx_document_metadata_path ON document_metadata(path, value);
+CREATE INDEX idx_document_metadata_document_id ON document_metadata(document_id);
+
+INSERT INTO document_metadata (document_id, path, value)
+SELECT document.id, meta.fullkey, CAST(meta.value AS TEXT)
+FROM document, json_tree(document.metadata) AS meta
+WHERE meta.type NOT IN ('object', 'array');
+
+CREATE TRIGGER document_metadata_insert AFTER INSERT ON document BEGIN
+    INSERT INTO document_metadata (document_id, path, value)
+    SELECT new.id, fullkey, CAST(value AS TEXT) FROM json_tree(new.metadata)
+    WHERE type NOT IN ('object', 'array');
+END;
+
+CREATE TRIGGER document_metadata_update AFTER UPDATE OF metadata ON document BEGIN
+    DELETE FROM document_metadata WHERE document_id = old.id;
+    INSERT INTO document_metadata (document_id, path, value)
+    SELECT new.id, fullkey, CAST(value AS TEXT) FROM json_tree(new.metadata)
+    WHERE type NOT IN ('object', 'array');
+END;
+
+CREATE TRIGGER document_metadata_delete AFTER DELETE ON document BEGIN
+    DELETE FROM document_metadata WHERE document_id = old.id;
+END;
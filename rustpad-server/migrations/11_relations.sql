@@ -0,0 +1,13 @@
+CREATE TABLE document_relation(
+    source_id TEXT NOT NULL,
+    target_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (source_id, target_id, kind)
+);
+
+CREATE INDEX idx_document_relation_target_id ON document_relation(target_id);
+
+CREATE TRIGGER document_relation_delete AFTER DELETE ON document BEGIN
+    DELETE FROM document_relation WHERE source_id = old.id OR target_id = old.id;
+END;
@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN derived_name TEXT;
@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN detected_language TEXT;
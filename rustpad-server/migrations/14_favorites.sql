@@ -0,0 +1,12 @@
+CREATE TABLE favorite(
+    email TEXT NOT NULL,
+    document_id TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (email, document_id)
+);
+
+CREATE INDEX idx_favorite_document_id ON favorite(document_id);
+
+CREATE TRIGGER favorite_delete AFTER DELETE ON document BEGIN
+    DELETE FROM favorite WHERE document_id = old.id;
+END;
@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN settings TEXT NOT NULL DEFAULT '{}';
@@ -0,0 +1,17 @@
+CREATE TABLE document_stats(
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    documents INTEGER NOT NULL
+);
+
+INSERT INTO document_stats (id, documents) SELECT 1, count(*) FROM document;
+
+CREATE TRIGGER document_stats_insert AFTER INSERT ON document BEGIN
+    UPDATE document_stats SET documents = documents + 1 WHERE id = 1;
+END;
+
+CREATE TRIGGER document_stats_delete AFTER DELETE ON document BEGIN
+    UPDATE document_stats SET documents = documents - 1 WHERE id = 1;
+END;
+
+CREATE INDEX idx_document_deleted_at_updated_at ON document(deleted_at, updated_at);
+CREATE INDEX idx_document_deleted_at_created_at ON document(deleted_at, created_at);
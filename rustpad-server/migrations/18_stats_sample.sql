@@ -0,0 +1,6 @@
+CREATE TABLE stats_sample(
+    created_at INTEGER PRIMARY KEY,
+    documents INTEGER NOT NULL,
+    connections INTEGER NOT NULL,
+    ops_per_sec REAL NOT NULL
+);
@@ -0,0 +1,12 @@
+CREATE TABLE activity(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    document_id TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    revisions INTEGER NOT NULL
+);
+
+CREATE INDEX idx_activity_created_at ON activity(created_at);
+
+CREATE TRIGGER activity_delete AFTER DELETE ON document BEGIN
+    DELETE FROM activity WHERE document_id = old.id;
+END;
@@ -0,0 +1,12 @@
+CREATE TABLE operation(
+    document_id TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    email TEXT,
+    operation TEXT NOT NULL,
+    PRIMARY KEY (document_id, revision)
+);
+
+CREATE TRIGGER operation_delete AFTER DELETE ON document BEGIN
+    DELETE FROM operation WHERE document_id = old.id;
+END;
@@ -0,0 +1,16 @@
+ALTER TABLE document ADD COLUMN quarantined_at INTEGER;
+
+CREATE TABLE report(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    document_id TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    reporter TEXT,
+    created_at INTEGER NOT NULL,
+    resolved_at INTEGER
+);
+
+CREATE INDEX idx_report_document_id ON report(document_id);
+
+CREATE TRIGGER report_delete AFTER DELETE ON document BEGIN
+    DELETE FROM report WHERE document_id = old.id;
+END;
@@ -0,0 +1,11 @@
+CREATE TABLE published(
+    document_id TEXT PRIMARY KEY,
+    text TEXT NOT NULL,
+    language TEXT,
+    revision INTEGER NOT NULL,
+    published_at INTEGER NOT NULL
+);
+
+CREATE TRIGGER published_delete AFTER DELETE ON document BEGIN
+    DELETE FROM published WHERE document_id = old.id;
+END;
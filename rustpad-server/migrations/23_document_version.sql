@@ -0,0 +1,14 @@
+CREATE TABLE document_version(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    document_id TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    language TEXT,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX idx_document_version_document_id ON document_version(document_id);
+
+CREATE TRIGGER document_version_delete AFTER DELETE ON document BEGIN
+    DELETE FROM document_version WHERE document_id = old.id;
+END;
@@ -0,0 +1,2 @@
+ALTER TABLE document ADD COLUMN publish_at INTEGER;
+ALTER TABLE document ADD COLUMN unpublish_at INTEGER;
@@ -0,0 +1,15 @@
+ALTER TABLE document ADD COLUMN search_text TEXT;
+
+DROP TRIGGER document_fts_insert;
+DROP TRIGGER document_fts_update;
+
+CREATE TRIGGER document_fts_insert AFTER INSERT ON document BEGIN
+    INSERT INTO document_fts (id, name, text)
+    VALUES (new.id, coalesce(new.name, ''), coalesce(new.search_text, new.text));
+END;
+
+CREATE TRIGGER document_fts_update AFTER UPDATE OF name, text, search_text ON document BEGIN
+    DELETE FROM document_fts WHERE id = old.id;
+    INSERT INTO document_fts (id, name, text)
+    VALUES (new.id, coalesce(new.name, ''), coalesce(new.search_text, new.text));
+END;
@@ -0,0 +1,5 @@
+CREATE TABLE language_extension(
+    position INTEGER PRIMARY KEY,
+    extension TEXT NOT NULL,
+    language TEXT NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE operation ADD COLUMN created_at INTEGER;
@@ -0,0 +1,11 @@
+CREATE TABLE document_acl(
+    document_id TEXT NOT NULL,
+    email TEXT NOT NULL,
+    role TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (document_id, email)
+);
+
+CREATE TRIGGER document_acl_delete AFTER DELETE ON document BEGIN
+    DELETE FROM document_acl WHERE document_id = old.id;
+END;
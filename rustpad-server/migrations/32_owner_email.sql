@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN owner_email TEXT;
@@ -0,0 +1,6 @@
+-- Durable feed consumers, with the last outbox event each has acknowledged
+CREATE TABLE outbox_consumer (
+    name TEXT PRIMARY KEY,
+    last_event_id INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE operation ADD COLUMN signature TEXT;
+ALTER TABLE operation ADD COLUMN key_id TEXT;
@@ -0,0 +1,10 @@
+-- Document events awaiting delivery, written in the same transaction as the change
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    document_id TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    delivered_at INTEGER
+);
+
+CREATE INDEX idx_outbox_delivered_at ON outbox(delivered_at);
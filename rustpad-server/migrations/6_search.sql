@@ -0,0 +1,19 @@
+CREATE VIRTUAL TABLE document_fts USING fts5(id UNINDEXED, name, text);
+
+INSERT INTO document_fts (id, name, text)
+SELECT id, coalesce(name, ''), text FROM document;
+
+CREATE TRIGGER document_fts_insert AFTER INSERT ON document BEGIN
+    INSERT INTO document_fts (id, name, text)
+    VALUES (new.id, coalesce(new.name, ''), new.text);
+END;
+
+CREATE TRIGGER document_fts_update AFTER UPDATE OF name, text ON document BEGIN
+    DELETE FROM document_fts WHERE id = old.id;
+    INSERT INTO document_fts (id, name, text)
+    VALUES (new.id, coalesce(new.name, ''), new.text);
+END;
+
+CREATE TRIGGER document_fts_delete AFTER DELETE ON document BEGIN
+    DELETE FROM document_fts WHERE id = old.id;
+END;
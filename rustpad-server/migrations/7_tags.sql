@@ -0,0 +1,7 @@
+CREATE TABLE tag(
+    document_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (document_id, name)
+);
+
+CREATE INDEX idx_tag_name ON tag(name);
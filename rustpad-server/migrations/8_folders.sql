@@ -0,0 +1,13 @@
+CREATE TABLE folder(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    parent_id INTEGER REFERENCES folder(id),
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE INDEX idx_folder_parent_id ON folder(parent_id);
+
+ALTER TABLE document ADD COLUMN folder_id INTEGER REFERENCES folder(id);
+
+CREATE INDEX idx_document_folder_id ON document(folder_id);
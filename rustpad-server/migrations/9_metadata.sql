@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';
@@ -0,0 +1,267 @@
+//! Verification of Cloudflare Access identity tokens.
+//!
+//! Behind Cloudflare Access, requests carry the email of the signed-in user
+//! in a plain header, which anyone reaching the server without going through
+//! Access could forge. Access also signs a JWT for each request, sent in the
+//! `Cf-Access-Jwt-Assertion` header. When verification is configured, the
+//! email is only trusted if it comes from a token whose signature, expiry,
+//! issuer and audience check out against the team's published keys.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::info;
+use parking_lot::RwLock;
+use ring::signature::{RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256};
+use serde::Deserialize;
+
+/// Clock skew allowed when checking the expiry of a token, in seconds.
+const LEEWAY_SECS: i64 = 60;
+
+/// Age after which the signing keys are fetched again, so that revoked keys
+/// stop being accepted.
+const KEYS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Minimum time between fetches of the signing keys, so that tokens with
+/// unknown key IDs don't cause a request each.
+const KEYS_MIN_AGE: Duration = Duration::from_secs(60);
+
+/// Settings for verifying Cloudflare Access tokens.
+#[derive(Clone, Debug)]
+pub struct AccessConfig {
+    /// Expected issuer of tokens, the URL of the team domain.
+    pub issuer: String,
+    /// Application Audience (AUD) tag of the Access application.
+    pub audience: String,
+    /// URL of the JSON Web Key Set with the keys that sign tokens.
+    pub certs_url: String,
+}
+
+impl AccessConfig {
+    /// Creates the settings for a team domain, such as
+    /// `myteam.cloudflareaccess.com`, and an application audience tag.
+    pub fn new(team_domain: &str, audience: &str) -> Self {
+        let issuer = format!("https://{}", team_domain.trim_end_matches('/'));
+        Self {
+            certs_url: format!("{}/cdn-cgi/access/certs", issuer),
+            issuer,
+            audience: audience.into(),
+        }
+    }
+}
+
+/// Verifies Cloudflare Access tokens, caching the keys that sign them.
+pub struct AccessVerifier {
+    config: AccessConfig,
+    client: reqwest::Client,
+    keys: RwLock<KeySet>,
+}
+
+/// RSA public keys that sign tokens, by key ID.
+type SigningKeys = HashMap<String, RsaPublicKeyComponents<Vec<u8>>>;
+
+/// Signing keys, and when they were fetched.
+#[derive(Default)]
+struct KeySet {
+    keys: SigningKeys,
+    fetched_at: Option<Instant>,
+}
+
+/// A JSON Web Key Set, as published by Cloudflare Access.
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// A public key in a JSON Web Key Set. Only RSA keys are used.
+#[derive(Deserialize)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    #[serde(default)]
+    n: String,
+    #[serde(default)]
+    e: String,
+}
+
+/// Header of a JSON Web Token.
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: String,
+}
+
+/// Claims of a token that are checked or used.
+#[derive(Deserialize)]
+struct Claims {
+    aud: Audience,
+    iss: String,
+    exp: i64,
+    #[serde(default)]
+    nbf: Option<i64>,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+/// Identity of an authenticated user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// Stable identifier of the user at the issuer.
+    pub subject: String,
+    /// Email of the user, if the issuer includes it.
+    pub email: Option<String>,
+}
+
+impl Identity {
+    /// Returns the identity of a user known only by email, such as from the
+    /// unverified Cloudflare Access header.
+    pub fn from_email(email: String) -> Self {
+        Self {
+            subject: email.clone(),
+            email: Some(email),
+        }
+    }
+}
+
+/// Audience claim, which may hold one value or several.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == audience,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+impl AccessVerifier {
+    /// Creates a verifier, which fetches the signing keys when first used.
+    pub fn new(config: AccessConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            keys: Default::default(),
+        }
+    }
+
+    /// Returns the verified identity of a request from its Access token and
+    /// its email header, failing if the token is missing or invalid or the
+    /// header names someone else.
+    pub async fn authenticate(&self, token: Option<&str>, email: Option<&str>) -> Result<Identity> {
+        let token = token.context("missing access token")?;
+        let identity = self.verify(token).await?;
+        let verified = identity.email.as_deref().context("token has no email")?;
+        match email {
+            Some(email) if !email.eq_ignore_ascii_case(verified) => {
+                bail!("email header {:?} does not match token", email)
+            }
+            _ => Ok(identity),
+        }
+    }
+
+    /// Verifies a token, returning the identity it was issued to.
+    pub async fn verify(&self, token: &str) -> Result<Identity> {
+        let (message, signature) = token.rsplit_once('.').context("malformed token")?;
+        let (header, payload) = message.split_once('.').context("malformed token")?;
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+        if header.alg != "RS256" {
+            bail!("unsupported algorithm {:?}", header.alg);
+        }
+        let key = self.key(&header.kid).await?;
+        key.verify(
+            &RSA_PKCS1_2048_8192_SHA256,
+            message.as_bytes(),
+            &URL_SAFE_NO_PAD.decode(signature)?,
+        )
+        .map_err(|_| anyhow!("invalid signature"))?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        if claims.exp + LEEWAY_SECS < now {
+            bail!("token expired");
+        }
+        if claims.nbf.map_or(false, |nbf| nbf - LEEWAY_SECS > now) {
+            bail!("token not yet valid");
+        }
+        if claims.iss != self.config.issuer {
+            bail!("unexpected issuer {:?}", claims.iss);
+        }
+        if !claims.aud.contains(&self.config.audience) {
+            bail!("token is for another audience");
+        }
+        let subject = match claims.sub.filter(|sub| !sub.is_empty()) {
+            Some(sub) => sub,
+            None => claims.email.clone().context("token has no subject")?,
+        };
+        Ok(Identity {
+            subject,
+            email: claims.email,
+        })
+    }
+
+    /// Returns the signing key with an ID, fetching the keys again if it is
+    /// unknown or they are old.
+    async fn key(&self, kid: &str) -> Result<RsaPublicKeyComponents<Vec<u8>>> {
+        let refresh = {
+            let keys = self.keys.read();
+            let age = keys.fetched_at.map(|fetched_at| fetched_at.elapsed());
+            match keys.keys.get(kid) {
+                Some(key) if age.map_or(false, |age| age < KEYS_MAX_AGE) => {
+                    return Ok(key.clone());
+                }
+                _ => age.map_or(true, |age| age >= KEYS_MIN_AGE),
+            }
+        };
+        if refresh {
+            let keys = self.fetch_keys().await?;
+            *self.keys.write() = KeySet {
+                keys,
+                fetched_at: Some(Instant::now()),
+            };
+        }
+        let keys = self.keys.read();
+        keys.keys
+            .get(kid)
+            .cloned()
+            .with_context(|| format!("unknown signing key {:?}", kid))
+    }
+
+    /// Fetches the signing keys from the JSON Web Key Set of the team.
+    async fn fetch_keys(&self) -> Result<SigningKeys> {
+        info!(
+            "fetching access signing keys from {}",
+            self.config.certs_url
+        );
+        let jwks: Jwks = self
+            .client
+            .get(&self.config.certs_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let mut keys = SigningKeys::new();
+        for jwk in jwks.keys.into_iter().filter(|jwk| jwk.kty == "RSA") {
+            let key = RsaPublicKeyComponents {
+                n: URL_SAFE_NO_PAD.decode(&jwk.n)?,
+                e: URL_SAFE_NO_PAD.decode(&jwk.e)?,
+            };
+            keys.insert(jwk.kid, key);
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,10 @@
+//! Prints test vectors of the WebSocket protocol as JSON, for checking other
+//! client implementations against the server.
+
+use rustpad_server::protocol_tests::protocol_vectors;
+
+fn main() {
+    let vectors = protocol_vectors();
+    let json = serde_json::to_string_pretty(&vectors).expect("failed serialize");
+    println!("{}", json);
+}
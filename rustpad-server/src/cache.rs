@@ -0,0 +1,158 @@
+//! Short-lived cache for the document metadata queries polled by clients.
+//!
+//! Entries expire after a fixed time, and every write to the database clears
+//! the whole cache. Queries that started before a write never fill the cache
+//! after it, since each write also bumps a generation counter.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::database::{DocumentMeta, DocumentPage};
+
+/// How long cached results are served before querying again.
+const TTL: Duration = Duration::from_secs(5);
+
+/// Maximum number of entries of each kind, to bound memory from many distinct
+/// queries. The entries are all dropped once this is reached.
+const MAX_ENTRIES: usize = 1024;
+
+/// Cached results of metadata queries, shared by all clones of a database.
+#[derive(Debug, Default)]
+pub struct MetaCache {
+    generation: AtomicU64,
+    meta: Entries<String, Option<DocumentMeta>>,
+    lists: Entries<String, DocumentPage>,
+    count: Entries<(), usize>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Snapshot of the cache counters, returned as part of the stats.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct CacheStats {
+    /// Number of queries answered from the cache.
+    pub hits: u64,
+    /// Number of queries that went to the database.
+    pub misses: u64,
+    /// Number of results currently cached.
+    pub entries: usize,
+}
+
+/// Timestamped results of one kind of query.
+#[derive(Debug)]
+struct Entries<K, V>(Mutex<HashMap<K, (Instant, V)>>);
+
+impl<K, V> Default for Entries<K, V> {
+    fn default() -> Self {
+        Self(Mutex::new(HashMap::new()))
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> Entries<K, V> {
+    fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let entries = self.0.lock();
+        let (inserted, value) = entries.get(key)?;
+        (inserted.elapsed() < TTL).then(|| value.clone())
+    }
+
+    /// Inserts a result, unless the generation moved on since `generation`.
+    ///
+    /// The generation is checked while holding the lock, and invalidation
+    /// bumps it before clearing, so a stale result can never outlive a clear.
+    fn insert(&self, current: &AtomicU64, generation: u64, key: K, value: V) {
+        let mut entries = self.0.lock();
+        if current.load(Ordering::Acquire) != generation {
+            return;
+        }
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    fn clear(&self) {
+        self.0.lock().clear();
+    }
+}
+
+impl MetaCache {
+    /// Returns the current generation, to be passed back when inserting the
+    /// result of a query started now.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Drops all cached results, after the database was written to.
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.meta.clear();
+        self.lists.clear();
+        self.count.clear();
+    }
+
+    /// Returns the cached metadata of a document, if fresh.
+    pub fn get_meta(&self, id: &str) -> Option<Option<DocumentMeta>> {
+        self.record(self.meta.get(id))
+    }
+
+    /// Caches the metadata of a document, read at `generation`.
+    pub fn insert_meta(&self, generation: u64, id: &str, meta: &Option<DocumentMeta>) {
+        self.meta
+            .insert(&self.generation, generation, id.to_owned(), meta.clone());
+    }
+
+    /// Returns a cached page of the document list, if fresh.
+    pub fn get_list(&self, key: &str) -> Option<DocumentPage> {
+        self.record(self.lists.get(key))
+    }
+
+    /// Caches a page of the document list, read at `generation`.
+    pub fn insert_list(&self, generation: u64, key: String, page: &DocumentPage) {
+        self.lists
+            .insert(&self.generation, generation, key, page.clone());
+    }
+
+    /// Returns the cached number of documents, if fresh.
+    pub fn get_count(&self) -> Option<usize> {
+        self.record(self.count.get(&()))
+    }
+
+    /// Caches the number of documents, read at `generation`.
+    pub fn insert_count(&self, generation: u64, count: usize) {
+        self.count.insert(&self.generation, generation, (), count);
+    }
+
+    /// Returns a snapshot of the cache counters.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.meta.len() + self.lists.len() + self.count.len(),
+        }
+    }
+
+    /// Counts a lookup as a hit or a miss.
+    fn record<T>(&self, value: Option<T>) -> Option<T> {
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+}
@@ -1,10 +1,19 @@
 //! Backend SQLite database handlers for persisting documents.
 
+use std::borrow::Cow;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::{bail, Result};
-use serde::Serialize;
-use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqlitePool};
+use operational_transform::OperationSeq;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
+use sqlx::{FromRow, QueryBuilder, Row, Sqlite, SqlitePool, Transaction};
+
+use crate::cache::{CacheStats, MetaCache};
+use crate::detect::detect_language;
+use crate::languages::LanguageExtension;
+use crate::normalize::Normalization;
 
 /// Represents a document persisted in database storage.
 #[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
@@ -16,42 +25,542 @@ pub struct PersistedDocument {
 }
 
 /// Lightweight document metadata for listing
-#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug)]
 pub struct DocumentMeta {
     /// Unique document identifier.
     pub id: String,
     /// Optional document name.
     pub name: Option<String>,
+    /// Name derived from the text when last persisted, if enabled.
+    pub derived_name: Option<String>,
     /// Language of the document for editor syntax highlighting.
     pub language: Option<String>,
+    /// Language detected from the text when last persisted without one.
+    pub detected_language: Option<String>,
     /// Timestamp when the document was created.
     pub created_at: i64,
     /// Timestamp when the document was last updated.
     pub updated_at: i64,
+    /// Tags attached to the document, in sorted order.
+    pub tags: Vec<String>,
+    /// Folder containing the document, or `None` at the top level.
+    pub folder_id: Option<i64>,
+    /// Custom JSON metadata attached to the document.
+    pub metadata: serde_json::Value,
+    /// Timestamp when the document was archived, if it is read-only.
+    pub archived_at: Option<i64>,
+    /// Timestamp when the document was quarantined pending review of abuse
+    /// reports, if it is.
+    pub quarantined_at: Option<i64>,
+    /// Email of the user who owns the document, by default the one who
+    /// created it or first edited it while authenticated.
+    pub owner_email: Option<String>,
+}
+
+/// Column expression selecting the tags of each `document` row.
+const TAGS_COLUMN: &str =
+    "(SELECT group_concat(name, char(31)) FROM tag WHERE document_id = document.id) AS tags";
+
+impl<'r> FromRow<'r, SqliteRow> for DocumentMeta {
+    fn from_row(row: &'r SqliteRow) -> sqlx::Result<Self> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            derived_name: row.try_get("derived_name")?,
+            language: row.try_get("language")?,
+            detected_language: row.try_get("detected_language")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+            tags: split_tags(row.try_get("tags")?),
+            folder_id: row.try_get("folder_id")?,
+            metadata: serde_json::from_str(row.try_get("metadata")?)
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            archived_at: row.try_get("archived_at")?,
+            quarantined_at: row.try_get("quarantined_at")?,
+            owner_email: row.try_get("owner_email")?,
+        })
+    }
+}
+
+/// Order in which documents are listed.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// Most recently updated first.
+    #[default]
+    UpdatedAt,
+    /// Most recently created first.
+    CreatedAt,
+    /// Alphabetically by name, with unnamed documents last.
+    Name,
+}
+
+/// Per-document settings, persisted as a JSON object.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct DocumentSettings {
+    /// Reject all edits, independently of archiving.
+    pub read_only: bool,
+    /// Maximum length of the text in characters, if lower than the server limit.
+    pub max_size: Option<usize>,
+    /// Ignore language changes from clients.
+    pub language_locked: bool,
+    /// Unix timestamp after which the document is moved to the trash.
+    pub expires_at: Option<i64>,
+    /// Convert CRLF line endings to LF in text written over the REST API.
+    pub normalize_newlines: bool,
+}
+
+impl DocumentSettings {
+    /// Returns the maximum length of the text, given the server limit.
+    pub fn max_size(&self, limit: usize) -> usize {
+        self.max_size.map_or(limit, |max_size| max_size.min(limit))
+    }
+}
+
+/// Which documents to list, by whether they are archived.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFilter {
+    /// Only documents that are not archived.
+    #[default]
+    Exclude,
+    /// Only archived documents.
+    Only,
+    /// Both archived and other documents.
+    Include,
+}
+
+/// Options for sorting and paginating the document list.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ListOptions {
+    /// Maximum number of documents to return.
+    pub limit: Option<i64>,
+    /// Number of documents to skip before the first one returned.
+    pub offset: Option<i64>,
+    /// Order in which documents are listed.
+    #[serde(default)]
+    pub sort: SortOrder,
+    /// Only list documents with this tag.
+    pub tag: Option<String>,
+    /// Only list documents directly inside this folder.
+    pub folder: Option<i64>,
+    /// Only list documents in this language, or detected to be in it if they
+    /// have none set.
+    pub language: Option<String>,
+    /// Only list documents last updated after this timestamp.
+    pub updated_after: Option<i64>,
+    /// Only list documents last updated before this timestamp.
+    pub updated_before: Option<i64>,
+    /// Whether to list archived documents.
+    #[serde(default)]
+    pub archived: ArchiveFilter,
+    /// Only list documents whose metadata has these values, by JSON path such
+    /// as `$.ticket`. Values are compared as text.
+    #[serde(skip)]
+    pub metadata: Vec<(String, String)>,
+    /// Only list documents starred by the user with this email.
+    #[serde(skip)]
+    pub starred_by: Option<String>,
+}
+
+/// An action applied to many documents at once.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum BulkAction {
+    /// Soft delete the documents.
+    Delete,
+    /// Restore soft-deleted documents.
+    Restore,
+    /// Prepend a prefix to the name of each document.
+    RenamePrefix {
+        /// Text prepended to each name.
+        prefix: String,
+    },
+    /// Set the language of each document.
+    SetLanguage {
+        /// Language for editor syntax highlighting.
+        language: String,
+    },
+}
+
+/// A soft-deleted document, as listed in the trash.
+#[derive(Serialize, Clone, Debug)]
+pub struct TrashedDocument {
+    /// Metadata of the deleted document.
+    #[serde(flatten)]
+    pub meta: DocumentMeta,
+    /// Timestamp when the document was deleted.
+    pub deleted_at: i64,
+}
+
+/// A folder for organizing documents, which may be nested in another folder.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct Folder {
+    /// Unique folder identifier.
+    pub id: i64,
+    /// Display name of the folder.
+    pub name: String,
+    /// Folder containing this one, or `None` at the top level.
+    pub parent_id: Option<i64>,
+    /// Timestamp when the folder was created.
+    pub created_at: i64,
+    /// Timestamp when the folder was last renamed or moved.
+    pub updated_at: i64,
+}
+
+/// Kind of a typed link from one document to another.
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(rename_all = "kebab-case")]
+pub enum RelationKind {
+    /// The documents are about the same topic.
+    Related,
+    /// The source document replaces the target document.
+    Supersedes,
+    /// The source document is a translation of the target document.
+    TranslationOf,
+}
+
+/// Whether a relation points away from or towards a document.
+#[derive(sqlx::Type, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum RelationDirection {
+    /// The document is the source of the relation.
+    Outgoing,
+    /// The document is the target of the relation.
+    Incoming,
+}
+
+/// A relation between a document and another non-deleted document.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Relation {
+    /// Kind of the relation, read from source to target.
+    pub kind: RelationKind,
+    /// Identifier of the other document.
+    pub document_id: String,
+    /// Whether the other document is the target or the source.
+    pub direction: RelationDirection,
+}
+
+/// Outcome of patching the custom metadata of a document.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetadataUpdate {
+    /// The metadata was updated to the given value.
+    Updated(serde_json::Value),
+    /// The document does not exist or is deleted.
+    NotFound,
+    /// The merged metadata would exceed the size limit, so nothing was changed.
+    TooLarge,
+}
+
+/// A page of the document list, along with the total number of matches.
+#[derive(Clone, Debug)]
+pub struct DocumentPage {
+    /// Documents on this page.
+    pub documents: Vec<DocumentMeta>,
+    /// Number of documents matching the query across all pages.
+    pub total: i64,
+}
+
+/// A document matching a full-text search.
+#[derive(Serialize, Clone, Debug)]
+pub struct SearchResult {
+    /// Metadata of the matching document.
+    #[serde(flatten)]
+    pub meta: DocumentMeta,
+    /// HTML-escaped excerpt of the text, with matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+/// A document to be created together with its initial contents.
+#[derive(Clone, Debug, Default)]
+pub struct NewDocument {
+    /// Unique document identifier.
+    pub id: String,
+    /// Optional document name.
+    pub name: Option<String>,
+    /// Initial text content of the document.
+    pub text: String,
+    /// Language of the document for editor syntax highlighting.
+    pub language: Option<String>,
+}
+
+/// An event about a document, recorded in the outbox for delivery.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct DocumentEvent {
+    /// Sequence number of the event, increasing in commit order.
+    pub id: i64,
+    /// What happened, such as `created`, `renamed`, `updated` or `deleted`.
+    pub kind: String,
+    /// Identifier of the document that the event concerns.
+    pub document_id: String,
+    /// Timestamp when the event was recorded.
+    pub created_at: i64,
+}
+
+/// A background job stored in the queue.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct Job {
+    /// Unique job identifier.
+    pub id: i64,
+    /// Kind of job, used to select its handler.
+    pub kind: String,
+    /// JSON-encoded arguments of the job.
+    pub payload: String,
+    /// One of `pending`, `running`, `done` or `failed`.
+    pub status: String,
+    /// Number of times the job has been attempted.
+    pub attempts: i64,
+    /// Number of attempts after which the job is marked failed.
+    pub max_attempts: i64,
+    /// Error message from the most recent failed attempt.
+    pub last_error: Option<String>,
+    /// Timestamp before which the job should not run.
+    pub run_at: i64,
+    /// Timestamp when the job was enqueued.
+    pub created_at: i64,
+    /// Timestamp when the job was last updated.
+    pub updated_at: i64,
+}
+
+/// Edits persisted for a document since a point in time.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct DocumentActivity {
+    /// Unique document identifier.
+    pub id: String,
+    /// Optional document name.
+    pub name: Option<String>,
+    /// Number of revisions persisted in the period.
+    pub revisions: i64,
+    /// Timestamp when edits to the document were last persisted.
+    pub edited_at: i64,
+}
+
+/// A report of abuse filed against a document.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct Report {
+    /// Unique report identifier.
+    pub id: i64,
+    /// Document that the report concerns.
+    pub document_id: String,
+    /// Reason given by the reporter.
+    pub reason: String,
+    /// Authenticated email of the reporter, if any.
+    pub reporter: Option<String>,
+    /// Timestamp when the report was filed.
+    pub created_at: i64,
+    /// Timestamp when the report was resolved by an admin, if it was.
+    pub resolved_at: Option<i64>,
+}
+
+/// The frozen public version of a document, served apart from its live text.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct PublishedDocument {
+    /// Text content when the document was published.
+    pub text: String,
+    /// Language of the document when it was published.
+    pub language: Option<String>,
+    /// Revision of the document that was published.
+    pub revision: i64,
+    /// Timestamp when the document was last published.
+    pub published_at: i64,
+}
+
+/// A numbered version of a document, stored periodically for recovery.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct DocumentVersion {
+    /// Unique version identifier.
+    pub id: i64,
+    /// Revision of the document that the version holds.
+    pub revision: i64,
+    /// Timestamp when the version was stored.
+    pub created_at: i64,
+}
+
+/// An edit in the stored operation history of a document.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StoredOperation {
+    /// Revision that the operation was applied to, counting from zero.
+    pub revision: usize,
+    /// ID of the user who made the edit.
+    pub user_id: u64,
+    /// Authenticated email of the user who made the edit.
+    pub email: Option<String>,
+    /// Time the edit was made, in seconds since Unix epoch, if known.
+    pub created_at: Option<i64>,
+    /// How the edit was made, unless it was stored before this was recorded.
+    pub source: Option<EditSource>,
+    /// Hex-encoded HMAC-SHA256 signature of the edit, if the client signed it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// ID of the session key that the edit was signed with, from which the
+    /// key can be derived again with the server's signing secret.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// The operation itself.
+    pub operation: OperationSeq,
+}
+
+/// How an edit was made, as hinted by the client or inferred by the server.
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum EditSource {
+    /// Typed in the editor.
+    Typing,
+    /// Pasted into the editor.
+    Paste,
+    /// Made by a program, such as a formatter or a REST API client.
+    Programmatic,
+}
+
+/// A periodic sample of server activity, kept for drawing graphs.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct StatsSample {
+    /// Time the sample was taken, in seconds since Unix epoch.
+    pub created_at: i64,
+    /// Number of documents loaded in memory.
+    pub documents: i64,
+    /// Number of open WebSocket connections.
+    pub connections: i64,
+    /// Average rate of edits from clients since the previous sample.
+    pub ops_per_sec: f64,
+}
+
+/// A key for authenticating automated clients of the REST API. Only a hash
+/// of the key itself is stored.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct ApiKey {
+    /// Unique key identifier.
+    pub id: i64,
+    /// Label describing what the key is used for.
+    pub name: String,
+    /// Timestamp when the key was created.
+    pub created_at: i64,
+    /// Timestamp when the key was last used, if ever.
+    pub last_used_at: Option<i64>,
+    /// Timestamp when the key was revoked, if it has been.
+    pub revoked_at: Option<i64>,
+}
+
+/// What a user on the access control list of a document may do with it.
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    /// May edit the document, rename or delete it, and change its list.
+    Owner,
+    /// May edit the document.
+    Editor,
+    /// May only read the document.
+    Viewer,
+}
+
+/// An entry in the access control list of a document.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+    /// Authenticated email of the user.
+    pub email: String,
+    /// Role of the user on the document.
+    pub role: Role,
+    /// Timestamp when the user was given the role.
+    pub created_at: i64,
+}
+
+/// A role of a user on a document, for exporting their data.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserRole {
+    /// ID of the document.
+    pub document_id: String,
+    /// Role of the user on the document.
+    pub role: Role,
+    /// Timestamp when the user was given the role.
+    pub created_at: i64,
+}
+
+/// Outcome of a database maintenance task, returned from admin endpoints.
+#[derive(Serialize, Clone, Debug)]
+pub struct MaintenanceReport {
+    /// Name of the maintenance task that was run.
+    pub task: &'static str,
+    /// Whether the task completed without finding problems.
+    pub ok: bool,
+    /// Status messages reported by SQLite while running the task.
+    pub messages: Vec<String>,
+    /// Wall-clock time taken by the task, in milliseconds.
+    pub duration_ms: u64,
 }
 
 /// A driver for database operations wrapping a pool connection.
 #[derive(Clone, Debug)]
 pub struct Database {
     pool: SqlitePool,
+    /// Whether to derive names for documents from their text when storing.
+    derive_names: bool,
+    /// Normalization applied to the text of documents indexed for search.
+    normalization: Normalization,
+    /// Recent results of metadata queries, cleared by every write.
+    cache: Arc<MetaCache>,
 }
 
 impl Database {
     /// Construct a new database from Postgres connection URI.
     pub async fn new(uri: &str) -> Result<Self> {
-        {
-            // Create database file if missing, and run migrations.
-            let mut conn = SqliteConnectOptions::from_str(uri)?
-                .create_if_missing(true)
-                .connect()
-                .await?;
-            sqlx::migrate!().run(&mut conn).await?;
-        }
+        // Create database file if missing, and run migrations on the pool
+        // itself, since every parse of an in-memory URI names a new database.
+        let options = SqliteConnectOptions::from_str(uri)?.create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        sqlx::migrate!().run(&pool).await?;
         Ok(Database {
-            pool: SqlitePool::connect(uri).await?,
+            pool,
+            derive_names: false,
+            normalization: Default::default(),
+            cache: Default::default(),
         })
     }
 
+    /// List the versions of the schema migrations that have been applied
+    pub async fn applied_migrations(&self) -> Result<Vec<i64>> {
+        let rows: Vec<(i64,)> = sqlx::query_as(
+            r#"SELECT version FROM _sqlx_migrations WHERE success ORDER BY version"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|(version,)| version).collect())
+    }
+
+    /// Get the hit counts of the metadata cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Enable or disable deriving document names from their text when storing.
+    pub fn with_derived_names(self, derive_names: bool) -> Self {
+        Self {
+            derive_names,
+            ..self
+        }
+    }
+
+    /// Set the normalization applied to the text of documents indexed for search.
+    pub fn with_normalization(self, normalization: Normalization) -> Self {
+        Self {
+            normalization,
+            ..self
+        }
+    }
+
+    /// Returns the text of a document to index for search, if it differs
+    /// from the text itself.
+    fn search_text(&self, text: &str) -> Option<String> {
+        match self.normalization.apply(text) {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(text) => Some(text),
+        }
+    }
+
     /// Load the text of a document from the database.
     pub async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
         sqlx::query_as(r#"SELECT text, language FROM document WHERE id = $1"#)
@@ -68,23 +577,43 @@ impl Database {
             .unwrap()
             .as_secs() as i64;
 
+        let detected_language = match document.language {
+            Some(_) => None,
+            None => detect_language(&document.text),
+        };
+        let language = document.language.as_deref().or(detected_language);
+
         let result = sqlx::query(
             r#"
 INSERT INTO
-    document (id, text, language, created_at, updated_at)
+    document (
+        id, text, language, derived_name, detected_language, search_text, created_at,
+        updated_at
+    )
 VALUES
-    ($1, $2, $3, $4, $4)
+    ($1, $2, $3, $5, $6, $7, $4, $4)
 ON CONFLICT(id) DO UPDATE SET
     text = excluded.text,
+    search_text = excluded.search_text,
     language = excluded.language,
+    derived_name = excluded.derived_name,
+    detected_language = excluded.detected_language,
     updated_at = excluded.updated_at"#,
         )
         .bind(document_id)
         .bind(&document.text)
         .bind(&document.language)
         .bind(now)
+        .bind(if self.derive_names {
+            derive_name(&document.text, language)
+        } else {
+            None
+        })
+        .bind(detected_language)
+        .bind(self.search_text(&document.text))
         .execute(&self.pool)
         .await?;
+        self.cache.invalidate();
         if result.rows_affected() != 1 {
             bail!(
                 "expected store() to receive 1 row affected, but it affected {} rows instead",
@@ -95,158 +624,2214 @@ ON CONFLICT(id) DO UPDATE SET
     }
 
     /// Count the number of documents in the database.
+    ///
+    /// The count is kept up to date by triggers, rather than scanning the table.
     pub async fn count(&self) -> Result<usize> {
-        let row: (i64,) = sqlx::query_as("SELECT count(*) FROM document")
+        if let Some(count) = self.cache.get_count() {
+            return Ok(count);
+        }
+        let generation = self.cache.generation();
+        let row: (i64,) = sqlx::query_as("SELECT documents FROM document_stats WHERE id = 1")
             .fetch_one(&self.pool)
             .await?;
+        self.cache.insert_count(generation, row.0 as usize);
         Ok(row.0 as usize)
     }
 
-    /// List all non-deleted documents
-    pub async fn list(&self) -> Result<Vec<DocumentMeta>> {
-        sqlx::query_as(
-            r#"SELECT id, name, language, created_at, updated_at
+    /// List a page of non-deleted documents
+    pub async fn list(&self, options: &ListOptions) -> Result<DocumentPage> {
+        let key = format!("{:?}", options);
+        if let Some(page) = self.cache.get_list(&key) {
+            return Ok(page);
+        }
+        let generation = self.cache.generation();
+        let mut count =
+            QueryBuilder::<Sqlite>::new("SELECT count(*) FROM document WHERE deleted_at IS NULL");
+        push_filters(&mut count, options);
+        let (total,): (i64,) = count.build_query_as().fetch_one(&self.pool).await?;
+
+        let mut query = QueryBuilder::<Sqlite>::new(format!(
+            r#"SELECT id, name, derived_name, language, detected_language, created_at, updated_at, folder_id, metadata, archived_at, quarantined_at, owner_email, {}
                FROM document
-               WHERE deleted_at IS NULL
-               ORDER BY updated_at DESC"#
-        )
-        .fetch_all(&self.pool)
-        .await
-        .map_err(|e| e.into())
+               WHERE deleted_at IS NULL"#,
+            TAGS_COLUMN
+        ));
+        push_filters(&mut query, options);
+        query.push(match options.sort {
+            SortOrder::UpdatedAt => " ORDER BY updated_at DESC, id",
+            SortOrder::CreatedAt => " ORDER BY created_at DESC, id",
+            SortOrder::Name => {
+                " ORDER BY coalesce(name, derived_name) IS NULL, \
+                 coalesce(name, derived_name) COLLATE NOCASE, id"
+            }
+        });
+        // A negative limit means no limit in SQLite.
+        query.push(" LIMIT ");
+        query.push_bind(options.limit.unwrap_or(-1));
+        query.push(" OFFSET ");
+        query.push_bind(options.offset.unwrap_or(0).max(0));
+        let documents = query.build_query_as().fetch_all(&self.pool).await?;
+
+        let page = DocumentPage { documents, total };
+        self.cache.insert_list(generation, key, &page);
+        Ok(page)
     }
 
-    /// Create a new document
-    pub async fn create(&self, id: &str, name: Option<&str>) -> Result<DocumentMeta> {
+    /// Create a new document owned by a user, or return `None` if the ID is
+    /// already taken
+    pub async fn create(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        owner_email: Option<&str>,
+    ) -> Result<Option<DocumentMeta>> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
-        sqlx::query(
-            r#"INSERT INTO document (id, text, name, created_at, updated_at)
-               VALUES ($1, '', $2, $3, $3)"#
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"INSERT INTO document (id, text, name, owner_email, created_at, updated_at)
+               VALUES ($1, '', $2, $3, $4, $4)
+               ON CONFLICT(id) DO NOTHING"#
         )
         .bind(id)
         .bind(name)
+        .bind(owner_email)
         .bind(now)
-        .execute(&self.pool)
+        .execute(&mut tx)
         .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        push_event(&mut tx, "created", id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
 
-        Ok(DocumentMeta {
+        Ok(Some(DocumentMeta {
             id: id.to_string(),
             name: name.map(String::from),
+            derived_name: None,
+            detected_language: None,
             language: None,
             created_at: now,
             updated_at: now,
-        })
+            tags: Vec::new(),
+            folder_id: None,
+            metadata: serde_json::json!({}),
+            archived_at: None,
+            quarantined_at: None,
+            owner_email: owner_email.map(String::from),
+        }))
+    }
+
+    /// Create several documents with their initial contents in one transaction
+    ///
+    /// Either every document is created, or none of them are.
+    pub async fn create_many(&self, documents: &[NewDocument]) -> Result<Vec<DocumentMeta>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        for document in documents {
+            sqlx::query(
+                r#"INSERT INTO document
+                       (id, text, name, language, search_text, created_at, updated_at)
+                   VALUES ($1, $2, $3, $4, $6, $5, $5)"#
+            )
+            .bind(&document.id)
+            .bind(&document.text)
+            .bind(&document.name)
+            .bind(&document.language)
+            .bind(now)
+            .bind(self.search_text(&document.text))
+            .execute(&mut tx)
+            .await?;
+            push_event(&mut tx, "created", &document.id, now).await?;
+        }
+        tx.commit().await?;
+        self.cache.invalidate();
+
+        Ok(documents
+            .iter()
+            .map(|document| DocumentMeta {
+                id: document.id.clone(),
+                name: document.name.clone(),
+                derived_name: None,
+                detected_language: None,
+                language: document.language.clone(),
+                created_at: now,
+                updated_at: now,
+                tags: Vec::new(),
+                folder_id: None,
+                metadata: serde_json::json!({}),
+                archived_at: None,
+                quarantined_at: None,
+                owner_email: None,
+            })
+            .collect())
+    }
+
+    /// Search non-deleted documents by name and text, best matches first
+    ///
+    /// Documents protected by a password are left out, as are documents with
+    /// an access control list that the user isn't on and doesn't own.
+    pub async fn search(
+        &self,
+        query: &str,
+        email: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<SearchResult>> {
+        let query = fts_query(query);
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let rows = sqlx::query(&format!(
+            r#"SELECT document.id, document.name, document.derived_name, document.language,
+                      document.detected_language, document.created_at, document.updated_at,
+                      document.folder_id, document.metadata, document.archived_at,
+                      document.quarantined_at, document.owner_email, {},
+                      snippet(document_fts, 2, char(1), char(2), '...', 16) AS snippet
+               FROM document_fts
+               JOIN document ON document.id = document_fts.id
+               WHERE document_fts MATCH $1 AND document.deleted_at IS NULL
+                 AND document.password_hash IS NULL
+                 AND (document.owner_email = $3
+                      OR NOT EXISTS (SELECT 1 FROM document_acl
+                                     WHERE document_id = document.id)
+                      OR EXISTS (SELECT 1 FROM document_acl
+                                 WHERE document_id = document.id AND email = $3))
+               ORDER BY rank
+               LIMIT $2"#,
+            TAGS_COLUMN
+        ))
+        .bind(&query)
+        .bind(limit)
+        .bind(email)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| -> Result<SearchResult> {
+                Ok(SearchResult {
+                    meta: DocumentMeta::from_row(row)?,
+                    snippet: highlight(row.try_get("snippet")?),
+                })
+            })
+            .collect()
     }
 
     /// Get document metadata by ID
     pub async fn get_meta(&self, id: &str) -> Result<Option<DocumentMeta>> {
-        sqlx::query_as(
-            r#"SELECT id, name, language, created_at, updated_at
-               FROM document WHERE id = $1 AND deleted_at IS NULL"#
+        if let Some(meta) = self.cache.get_meta(id) {
+            return Ok(meta);
+        }
+        let generation = self.cache.generation();
+        let meta = sqlx::query_as(&format!(
+            r#"SELECT id, name, derived_name, language, detected_language, created_at, updated_at, folder_id, metadata, archived_at, quarantined_at, owner_email, {}
+               FROM document WHERE id = $1 AND deleted_at IS NULL"#,
+            TAGS_COLUMN
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        self.cache.insert_meta(generation, id, &meta);
+        Ok(meta)
+    }
+
+    /// Get the custom metadata of a non-deleted document
+    pub async fn get_metadata(&self, id: &str) -> Result<Option<serde_json::Value>> {
+        let row: Option<(String,)> = sqlx::query_as(
+            r#"SELECT metadata FROM document WHERE id = $1 AND deleted_at IS NULL"#
         )
         .bind(id)
         .fetch_optional(&self.pool)
-        .await
-        .map_err(|e| e.into())
+        .await?;
+        match row {
+            Some((metadata,)) => Ok(Some(serde_json::from_str(&metadata)?)),
+            None => Ok(None),
+        }
     }
 
-    /// Rename a document
-    pub async fn rename(&self, id: &str, name: &str) -> Result<()> {
+    /// Merge a JSON merge patch (RFC 7396) into the metadata of a non-deleted document
+    ///
+    /// Nothing is changed if the merged metadata would exceed `max_size` bytes.
+    pub async fn patch_metadata(
+        &self,
+        id: &str,
+        patch: &serde_json::Value,
+        max_size: usize,
+    ) -> Result<MetadataUpdate> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
-        let result = sqlx::query(
-            r#"UPDATE document SET name = $2, updated_at = $3
-               WHERE id = $1 AND deleted_at IS NULL"#
+        let mut tx = self.pool.begin().await?;
+        let row: Option<(String,)> = sqlx::query_as(
+            r#"UPDATE document SET metadata = json_patch(metadata, $2), updated_at = $3
+               WHERE id = $1 AND deleted_at IS NULL
+               RETURNING metadata"#
         )
         .bind(id)
-        .bind(name)
+        .bind(patch.to_string())
         .bind(now)
-        .execute(&self.pool)
+        .fetch_optional(&mut tx)
         .await?;
 
-        if result.rows_affected() == 0 {
-            bail!("Document not found: {}", id);
-        }
-        Ok(())
+        let metadata = match row {
+            Some((metadata,)) if metadata.len() > max_size => {
+                return Ok(MetadataUpdate::TooLarge);
+            }
+            Some((metadata,)) => metadata,
+            None => return Ok(MetadataUpdate::NotFound),
+        };
+        push_event(&mut tx, "updated", id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(MetadataUpdate::Updated(serde_json::from_str(&metadata)?))
     }
 
-    /// Soft delete a document
-    pub async fn soft_delete(&self, id: &str) -> Result<()> {
+    /// Attach a tag to a non-deleted document, if not already attached
+    pub async fn add_tag(&self, id: &str, tag: &str) -> Result<()> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
+        let mut tx = self.pool.begin().await?;
         let result = sqlx::query(
-            r#"UPDATE document SET deleted_at = $2
-               WHERE id = $1 AND deleted_at IS NULL"#
+            r#"INSERT OR IGNORE INTO tag (document_id, name)
+               SELECT id, $2 FROM document WHERE id = $1 AND deleted_at IS NULL"#
         )
         .bind(id)
-        .bind(now)
-        .execute(&self.pool)
+        .bind(tag)
+        .execute(&mut tx)
         .await?;
+        if result.rows_affected() > 0 {
+            push_event(&mut tx, "updated", id, now).await?;
+        }
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(())
+    }
 
-        if result.rows_affected() == 0 {
-            bail!("Document not found or already deleted: {}", id);
+    /// Detach a tag from a document
+    pub async fn remove_tag(&self, id: &str, tag: &str) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(r#"DELETE FROM tag WHERE document_id = $1 AND name = $2"#)
+            .bind(id)
+            .bind(tag)
+            .execute(&mut tx)
+            .await?;
+        if result.rows_affected() > 0 {
+            push_event(&mut tx, "updated", id, now).await?;
         }
+        tx.commit().await?;
+        self.cache.invalidate();
         Ok(())
     }
 
-    /// Soft delete all non-deleted documents
-    pub async fn delete_all_documents(&self) -> Result<u64> {
+    /// Star a non-deleted document for a user, if not already starred
+    pub async fn star(&self, email: &str, id: &str) -> Result<()> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
-        let result = sqlx::query(
-            r#"UPDATE document SET deleted_at = $1
-               WHERE deleted_at IS NULL"#
+        sqlx::query(
+            r#"INSERT OR IGNORE INTO favorite (email, document_id, created_at)
+               SELECT $1, id, $3 FROM document WHERE id = $2 AND deleted_at IS NULL"#
         )
+        .bind(email)
+        .bind(id)
         .bind(now)
         .execute(&self.pool)
         .await?;
+        self.cache.invalidate();
+        Ok(())
+    }
 
-        Ok(result.rows_affected())
+    /// Unstar a document for a user
+    pub async fn unstar(&self, email: &str, id: &str) -> Result<()> {
+        sqlx::query(r#"DELETE FROM favorite WHERE email = $1 AND document_id = $2"#)
+            .bind(email)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        self.cache.invalidate();
+        Ok(())
     }
 
-    /// Load all user color preferences
-    pub async fn load_user_colors(&self) -> Result<Vec<(String, u32)>> {
-        let rows: Vec<(String, i64)> = sqlx::query_as(
-            r#"SELECT email, hue FROM user_color"#
+    /// List the IDs of the documents starred by a user, most recent first
+    pub async fn starred(&self, email: &str) -> Result<Vec<String>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"SELECT document_id FROM favorite WHERE email = $1
+               ORDER BY created_at DESC, document_id"#
         )
+        .bind(email)
         .fetch_all(&self.pool)
         .await?;
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
 
-        Ok(rows.into_iter().map(|(email, hue)| (email, hue as u32)).collect())
+    /// List the IDs of the documents owned by a user, including deleted ones
+    pub async fn owned_documents(&self, email: &str) -> Result<Vec<String>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"SELECT id FROM document WHERE owner_email = $1 ORDER BY id"#
+        )
+        .bind(email)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|(id,)| id).collect())
     }
 
-    /// Save a user's color preference
-    pub async fn save_user_color(&self, email: &str, hue: u32) -> Result<()> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+    /// List the roles of a user on documents
+    pub async fn user_roles(&self, email: &str) -> Result<Vec<UserRole>> {
+        let roles = sqlx::query_as(
+            r#"SELECT document_id, role, created_at FROM document_acl WHERE email = $1
+               ORDER BY document_id"#
+        )
+        .bind(email)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(roles)
+    }
 
-        sqlx::query(
-            r#"INSERT INTO user_color (email, hue, updated_at)
-               VALUES ($1, $2, $3)
-               ON CONFLICT(email) DO UPDATE SET
-                   hue = excluded.hue,
-                   updated_at = excluded.updated_at"#
+    /// Get the owner of a document, even if it is deleted
+    pub async fn owner_email(&self, id: &str) -> Result<Option<String>> {
+        let row: Option<(Option<String>,)> =
+            sqlx::query_as(r#"SELECT owner_email FROM document WHERE id = $1"#)
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.and_then(|(email,)| email))
+    }
+
+    /// Make a user the owner of a non-deleted document that has none yet
+    pub async fn claim_owner(&self, id: &str, email: &str) -> Result<()> {
+        let result = sqlx::query(
+            r#"UPDATE document SET owner_email = $2
+               WHERE id = $1 AND owner_email IS NULL AND deleted_at IS NULL"#
         )
+        .bind(id)
         .bind(email)
-        .bind(hue as i64)
-        .bind(now)
         .execute(&self.pool)
         .await?;
-
+        if result.rows_affected() > 0 {
+            self.cache.invalidate();
+        }
         Ok(())
     }
+
+    /// Transfer a non-deleted document to another owner, returning whether it
+    /// exists
+    pub async fn set_owner(&self, id: &str, email: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"UPDATE document SET owner_email = $2 WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(email)
+        .execute(&self.pool)
+        .await?;
+        self.cache.invalidate();
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// List the access control list of a document, owners first
+    pub async fn acl(&self, id: &str) -> Result<Vec<AclEntry>> {
+        let entries = sqlx::query_as(
+            r#"SELECT email, role, created_at FROM document_acl WHERE document_id = $1
+               ORDER BY role = 'owner' DESC, email"#
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(entries)
+    }
+
+    /// Give a user a role on a non-deleted document, replacing any role they
+    /// had, returning whether the document exists
+    pub async fn set_role(&self, id: &str, email: &str, role: Role) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let result = sqlx::query(
+            r#"INSERT INTO document_acl (document_id, email, role, created_at)
+               SELECT id, $2, $3, $4 FROM document WHERE id = $1 AND deleted_at IS NULL
+               ON CONFLICT(document_id, email) DO UPDATE SET role = excluded.role"#
+        )
+        .bind(id)
+        .bind(email)
+        .bind(role)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Remove a user from the access control list of a document, returning
+    /// whether they were on it
+    pub async fn remove_role(&self, id: &str, email: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"DELETE FROM document_acl WHERE document_id = $1 AND email = $2"#
+        )
+        .bind(id)
+        .bind(email)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Link a document to another, if not already linked with the same kind
+    pub async fn add_relation(&self, source: &str, target: &str, kind: RelationKind) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query(
+            r#"INSERT OR IGNORE INTO document_relation (source_id, target_id, kind, created_at)
+               VALUES ($1, $2, $3, $4)"#
+        )
+        .bind(source)
+        .bind(target)
+        .bind(kind)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Remove a link between two documents
+    pub async fn remove_relation(
+        &self,
+        source: &str,
+        target: &str,
+        kind: RelationKind,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"DELETE FROM document_relation
+               WHERE source_id = $1 AND target_id = $2 AND kind = $3"#
+        )
+        .bind(source)
+        .bind(target)
+        .bind(kind)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// List the relations of a document in both directions, skipping deleted documents
+    pub async fn relations(&self, id: &str) -> Result<Vec<Relation>> {
+        sqlx::query_as(
+            r#"SELECT kind, document_id, direction FROM (
+                   SELECT kind, target_id AS document_id, 'outgoing' AS direction
+                   FROM document_relation WHERE source_id = $1
+                   UNION ALL
+                   SELECT kind, source_id AS document_id, 'incoming' AS direction
+                   FROM document_relation WHERE target_id = $1
+               )
+               WHERE document_id IN (SELECT id FROM document WHERE deleted_at IS NULL)
+               ORDER BY direction DESC, kind, document_id"#
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Move a non-deleted document into a folder, or to the top level
+    ///
+    /// Returns whether the document was found.
+    pub async fn move_document(&self, id: &str, folder_id: Option<i64>) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"UPDATE document SET folder_id = $2, updated_at = $3
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(folder_id)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        let found = result.rows_affected() > 0;
+        if found {
+            push_event(&mut tx, "updated", id, now).await?;
+        }
+        tx.commit().await?;
+        self.cache.invalidate();
+
+        Ok(found)
+    }
+
+    /// List all folders, ordered by name
+    pub async fn list_folders(&self) -> Result<Vec<Folder>> {
+        sqlx::query_as(
+            r#"SELECT id, name, parent_id, created_at, updated_at
+               FROM folder
+               ORDER BY name COLLATE NOCASE, id"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Get a folder by ID
+    pub async fn get_folder(&self, id: i64) -> Result<Option<Folder>> {
+        sqlx::query_as(
+            r#"SELECT id, name, parent_id, created_at, updated_at
+               FROM folder WHERE id = $1"#
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Create a new folder
+    pub async fn create_folder(&self, name: &str, parent_id: Option<i64>) -> Result<Folder> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query_as(
+            r#"INSERT INTO folder (name, parent_id, created_at, updated_at)
+               VALUES ($1, $2, $3, $3)
+               RETURNING id, name, parent_id, created_at, updated_at"#
+        )
+        .bind(name)
+        .bind(parent_id)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Rename a folder and move it under a new parent
+    pub async fn update_folder(
+        &self,
+        id: i64,
+        name: &str,
+        parent_id: Option<i64>,
+    ) -> Result<Option<Folder>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query_as(
+            r#"UPDATE folder SET name = $2, parent_id = $3, updated_at = $4
+               WHERE id = $1
+               RETURNING id, name, parent_id, created_at, updated_at"#
+        )
+        .bind(id)
+        .bind(name)
+        .bind(parent_id)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Check whether a folder is the same as, or nested inside, another folder
+    pub async fn folder_within(&self, id: i64, ancestor_id: i64) -> Result<bool> {
+        let row: Option<(i64,)> = sqlx::query_as(
+            r#"WITH RECURSIVE ancestor(id) AS (
+                   SELECT $1
+                   UNION
+                   SELECT folder.parent_id FROM folder
+                   JOIN ancestor ON folder.id = ancestor.id
+                   WHERE folder.parent_id IS NOT NULL
+               )
+               SELECT 1 FROM ancestor WHERE id = $2"#
+        )
+        .bind(id)
+        .bind(ancestor_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Count the subfolders and non-deleted documents directly inside a folder
+    pub async fn count_folder_children(&self, id: i64) -> Result<i64> {
+        let (count,): (i64,) = sqlx::query_as(
+            r#"SELECT (SELECT count(*) FROM folder WHERE parent_id = $1)
+                    + (SELECT count(*) FROM document
+                       WHERE folder_id = $1 AND deleted_at IS NULL)"#
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Delete a folder, detaching any deleted documents still inside it
+    ///
+    /// Returns whether the folder existed.
+    pub async fn delete_folder(&self, id: i64) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"UPDATE document SET folder_id = NULL WHERE folder_id = $1"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let result = sqlx::query(r#"DELETE FROM folder WHERE id = $1"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Get the password hash of a non-deleted document, if it is protected
+    pub async fn password_hash(&self, id: &str) -> Result<Option<String>> {
+        let row: Option<(Option<String>,)> = sqlx::query_as(
+            r#"SELECT password_hash FROM document WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.and_then(|(hash,)| hash))
+    }
+
+    /// Set or clear the password hash of a non-deleted document, returning
+    /// whether it exists
+    pub async fn set_password_hash(&self, id: &str, hash: Option<&str>) -> Result<bool> {
+        let result = sqlx::query(
+            r#"UPDATE document SET password_hash = $2
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Rename a document
+    pub async fn rename(&self, id: &str, name: &str) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"UPDATE document SET name = $2, updated_at = $3
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(name)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            bail!("Document not found: {}", id);
+        }
+        push_event(&mut tx, "renamed", id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(())
+    }
+
+    /// Archive or unarchive a non-deleted document, returning whether it exists
+    pub async fn set_archived(&self, id: &str, archived: bool) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"UPDATE document
+               SET archived_at = CASE WHEN $2 THEN coalesce(archived_at, $3) END
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(archived)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        let kind = if archived { "archived" } else { "unarchived" };
+        push_event(&mut tx, kind, id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(true)
+    }
+
+    /// Check whether a document has been archived
+    pub async fn is_archived(&self, id: &str) -> Result<bool> {
+        let row: Option<(Option<i64>,)> = sqlx::query_as(
+            r#"SELECT archived_at FROM document WHERE id = $1"#
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(matches!(row, Some((Some(_),))))
+    }
+
+    /// Quarantine or release a non-deleted document, returning whether it exists
+    pub async fn set_quarantined(&self, id: &str, quarantined: bool) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"UPDATE document
+               SET quarantined_at = CASE WHEN $2 THEN coalesce(quarantined_at, $3) END
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(quarantined)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        let kind = if quarantined { "quarantined" } else { "released" };
+        push_event(&mut tx, kind, id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(true)
+    }
+
+    /// Check whether a document is archived or quarantined, so that it cannot be edited
+    pub async fn is_locked(&self, id: &str) -> Result<bool> {
+        let row: Option<(bool,)> = sqlx::query_as(
+            r#"SELECT archived_at IS NOT NULL OR quarantined_at IS NOT NULL
+               FROM document WHERE id = $1"#
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(matches!(row, Some((true,))))
+    }
+
+    /// File an abuse report against a non-deleted document
+    ///
+    /// Returns the ID of the new report, or `None` if the document does not exist.
+    pub async fn add_report(
+        &self,
+        id: &str,
+        reason: &str,
+        reporter: Option<&str>,
+    ) -> Result<Option<i64>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let row: Option<(i64,)> = sqlx::query_as(
+            r#"INSERT INTO report (document_id, reason, reporter, created_at)
+               SELECT id, $2, $3, $4 FROM document WHERE id = $1 AND deleted_at IS NULL
+               RETURNING id"#
+        )
+        .bind(id)
+        .bind(reason)
+        .bind(reporter)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(id,)| id))
+    }
+
+    /// List abuse reports against non-deleted documents, oldest first
+    pub async fn reports(&self, resolved: bool) -> Result<Vec<Report>> {
+        sqlx::query_as(
+            r#"SELECT report.id, report.document_id, report.reason, report.reporter,
+                   report.created_at, report.resolved_at
+               FROM report JOIN document ON document.id = report.document_id
+               WHERE (report.resolved_at IS NOT NULL) = $1 AND document.deleted_at IS NULL
+               ORDER BY report.created_at, report.id"#
+        )
+        .bind(resolved)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// List the abuse reports filed by a user, oldest first
+    pub async fn user_reports(&self, email: &str) -> Result<Vec<Report>> {
+        sqlx::query_as(
+            r#"SELECT id, document_id, reason, reporter, created_at, resolved_at
+               FROM report WHERE reporter = $1
+               ORDER BY created_at, id"#
+        )
+        .bind(email)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Mark an abuse report as resolved, returning whether it exists
+    pub async fn resolve_report(&self, report_id: i64) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let result = sqlx::query(
+            r#"UPDATE report SET resolved_at = coalesce(resolved_at, $2) WHERE id = $1"#
+        )
+        .bind(report_id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Publish a revision of a non-deleted document, replacing any earlier one
+    ///
+    /// Returns when it was published, or `None` if the document does not exist.
+    pub async fn publish(
+        &self,
+        id: &str,
+        revision: usize,
+        document: &PersistedDocument,
+    ) -> Result<Option<i64>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let row: Option<(i64,)> = sqlx::query_as(
+            r#"INSERT INTO published (document_id, text, language, revision, published_at)
+               SELECT id, $2, $3, $4, $5 FROM document WHERE id = $1 AND deleted_at IS NULL
+               ON CONFLICT(document_id) DO UPDATE SET
+                   text = excluded.text,
+                   language = excluded.language,
+                   revision = excluded.revision,
+                   published_at = excluded.published_at
+               RETURNING published_at"#
+        )
+        .bind(id)
+        .bind(&document.text)
+        .bind(&document.language)
+        .bind(revision as i64)
+        .bind(now)
+        .fetch_optional(&mut tx)
+        .await?;
+
+        if row.is_none() {
+            return Ok(None);
+        }
+        push_event(&mut tx, "published", id, now).await?;
+        tx.commit().await?;
+        Ok(row.map(|(published_at,)| published_at))
+    }
+
+    /// Schedule a non-deleted document to be published or withdrawn, returning whether it exists
+    pub async fn set_publish_schedule(
+        &self,
+        id: &str,
+        publish_at: Option<i64>,
+        unpublish_at: Option<i64>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"UPDATE document SET publish_at = $2, unpublish_at = $3
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(publish_at)
+        .bind(unpublish_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Take the IDs of non-deleted documents that are due to be published
+    pub async fn due_publications(&self, now: i64) -> Result<Vec<String>> {
+        let ids: Vec<(String,)> = sqlx::query_as(
+            r#"UPDATE document SET publish_at = NULL
+               WHERE deleted_at IS NULL AND publish_at <= $1
+               RETURNING id"#
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Withdraw the published versions of documents that are due to be unpublished
+    ///
+    /// Returns the IDs of the documents whose published version was withdrawn.
+    pub async fn unpublish_expired(&self, now: i64) -> Result<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+        let ids: Vec<(String,)> = sqlx::query_as(
+            r#"UPDATE document SET unpublish_at = NULL
+               WHERE unpublish_at <= $1
+               RETURNING id"#
+        )
+        .bind(now)
+        .fetch_all(&mut tx)
+        .await?;
+
+        let mut unpublished = Vec::new();
+        for (id,) in ids {
+            let result = sqlx::query(r#"DELETE FROM published WHERE document_id = $1"#)
+                .bind(&id)
+                .execute(&mut tx)
+                .await?;
+            if result.rows_affected() > 0 {
+                push_event(&mut tx, "unpublished", &id, now).await?;
+                unpublished.push(id);
+            }
+        }
+        tx.commit().await?;
+        Ok(unpublished)
+    }
+
+    /// Get the published version of a document that is not deleted or quarantined
+    pub async fn published(&self, id: &str) -> Result<Option<PublishedDocument>> {
+        sqlx::query_as(
+            r#"SELECT published.text, published.language, published.revision,
+                   published.published_at
+               FROM published JOIN document ON document.id = published.document_id
+               WHERE published.document_id = $1 AND document.deleted_at IS NULL
+                   AND document.quarantined_at IS NULL"#
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Store a version of a document, keeping only its newest `keep` versions
+    pub async fn store_version(
+        &self,
+        id: &str,
+        revision: usize,
+        document: &PersistedDocument,
+        keep: usize,
+    ) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO document_version (document_id, revision, text, language, created_at)
+               VALUES ($1, $2, $3, $4, $5)"#
+        )
+        .bind(id)
+        .bind(revision as i64)
+        .bind(&document.text)
+        .bind(&document.language)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            r#"DELETE FROM document_version WHERE document_id = $1 AND id NOT IN (
+                   SELECT id FROM document_version WHERE document_id = $1
+                   ORDER BY id DESC LIMIT $2
+               )"#
+        )
+        .bind(id)
+        .bind(keep as i64)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// List the stored versions of a non-deleted document, newest first
+    pub async fn versions(&self, id: &str) -> Result<Vec<DocumentVersion>> {
+        sqlx::query_as(
+            r#"SELECT document_version.id, document_version.revision,
+                   document_version.created_at
+               FROM document_version JOIN document ON document.id = document_version.document_id
+               WHERE document_version.document_id = $1 AND document.deleted_at IS NULL
+               ORDER BY document_version.id DESC"#
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Load a stored version of a non-deleted document
+    pub async fn load_version(
+        &self,
+        id: &str,
+        version_id: i64,
+    ) -> Result<Option<PersistedDocument>> {
+        sqlx::query_as(
+            r#"SELECT document_version.text, document_version.language
+               FROM document_version JOIN document ON document.id = document_version.document_id
+               WHERE document_version.document_id = $1 AND document_version.id = $2
+                   AND document.deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(version_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// List the custom mapping of file extensions to languages, in order
+    ///
+    /// Returns an empty list if the built-in mapping is in use.
+    pub async fn language_extensions(&self) -> Result<Vec<LanguageExtension>> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            r#"SELECT extension, language FROM language_extension ORDER BY position"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(extension, language)| LanguageExtension {
+                extension,
+                language,
+            })
+            .collect())
+    }
+
+    /// Replace the custom mapping of file extensions to languages
+    ///
+    /// An empty list restores the built-in mapping.
+    pub async fn set_language_extensions(&self, extensions: &[LanguageExtension]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM language_extension"#)
+            .execute(&mut tx)
+            .await?;
+        for (position, entry) in extensions.iter().enumerate() {
+            sqlx::query(
+                r#"INSERT INTO language_extension (position, extension, language)
+                   VALUES ($1, $2, $3)"#
+            )
+            .bind(position as i64)
+            .bind(&entry.extension)
+            .bind(&entry.language)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// List all API keys, including revoked ones
+    pub async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        sqlx::query_as(
+            r#"SELECT id, name, created_at, last_used_at, revoked_at
+               FROM api_key ORDER BY id"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Store a new API key by the hash of its value
+    pub async fn create_api_key(&self, name: &str, key_hash: &str) -> Result<ApiKey> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query_as(
+            r#"INSERT INTO api_key (name, key_hash, created_at)
+               VALUES ($1, $2, $3)
+               RETURNING id, name, created_at, last_used_at, revoked_at"#
+        )
+        .bind(name)
+        .bind(key_hash)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Revoke an API key, returning whether it existed and was not yet revoked
+    pub async fn revoke_api_key(&self, id: i64) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let result = sqlx::query(
+            r#"UPDATE api_key SET revoked_at = $2
+               WHERE id = $1 AND revoked_at IS NULL"#
+        )
+        .bind(id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Record a use of the unrevoked API key with a hash, returning its ID
+    pub async fn use_api_key(&self, key_hash: &str) -> Result<Option<i64>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let row: Option<(i64,)> = sqlx::query_as(
+            r#"UPDATE api_key SET last_used_at = $2
+               WHERE key_hash = $1 AND revoked_at IS NULL
+               RETURNING id"#
+        )
+        .bind(key_hash)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(id,)| id))
+    }
+
+    /// Get the settings of a non-deleted document
+    pub async fn settings(&self, id: &str) -> Result<Option<DocumentSettings>> {
+        let row: Option<(String,)> = sqlx::query_as(
+            r#"SELECT settings FROM document WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        match row {
+            Some((settings,)) => Ok(Some(serde_json::from_str(&settings)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the settings of a non-deleted document
+    pub async fn set_settings(&self, id: &str, settings: &DocumentSettings) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"UPDATE document SET settings = $2, updated_at = $3
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(serde_json::to_string(settings)?)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        push_event(&mut tx, "settings", id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(true)
+    }
+
+    /// Soft delete documents whose settings expire them at or before `now`,
+    /// returning their IDs
+    pub async fn trash_expired(&self, now: i64) -> Result<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+        let ids: Vec<(String,)> = sqlx::query_as(
+            r#"UPDATE document SET deleted_at = $1
+               WHERE deleted_at IS NULL
+                 AND json_extract(settings, '$.expires_at') <= $1
+               RETURNING id"#
+        )
+        .bind(now)
+        .fetch_all(&mut tx)
+        .await?;
+
+        for (id,) in &ids {
+            push_event(&mut tx, "deleted", id, now).await?;
+        }
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Check whether a document has been soft deleted
+    pub async fn is_deleted(&self, id: &str) -> Result<bool> {
+        let row: Option<(Option<i64>,)> = sqlx::query_as(
+            r#"SELECT deleted_at FROM document WHERE id = $1"#
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(matches!(row, Some((Some(_),))))
+    }
+
+    /// Soft delete a document
+    pub async fn soft_delete(&self, id: &str) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"UPDATE document SET deleted_at = $2
+               WHERE id = $1 AND deleted_at IS NULL"#
+        )
+        .bind(id)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            bail!("Document not found or already deleted: {}", id);
+        }
+        push_event(&mut tx, "deleted", id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(())
+    }
+
+    /// List soft-deleted documents, most recently deleted first
+    pub async fn list_deleted(&self) -> Result<Vec<TrashedDocument>> {
+        let rows = sqlx::query(&format!(
+            r#"SELECT id, name, derived_name, language, detected_language, created_at, updated_at, folder_id, deleted_at, metadata, archived_at, quarantined_at, owner_email, {}
+               FROM document
+               WHERE deleted_at IS NOT NULL
+               ORDER BY deleted_at DESC, id"#,
+            TAGS_COLUMN
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| -> Result<TrashedDocument> {
+                Ok(TrashedDocument {
+                    meta: DocumentMeta::from_row(row)?,
+                    deleted_at: row.try_get("deleted_at")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Restore a soft-deleted document
+    ///
+    /// Returns whether a deleted document was found.
+    pub async fn restore(&self, id: &str) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"UPDATE document SET deleted_at = NULL
+               WHERE id = $1 AND deleted_at IS NOT NULL"#
+        )
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        push_event(&mut tx, "restored", id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(true)
+    }
+
+    /// Permanently delete a document and its tags, whether or not it was soft deleted
+    ///
+    /// Returns whether the document existed.
+    pub async fn purge(&self, id: &str) -> Result<bool> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM tag WHERE document_id = $1"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let result = sqlx::query(r#"DELETE FROM document WHERE id = $1"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        push_event(&mut tx, "purged", id, now).await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(true)
+    }
+
+    /// Permanently delete documents that were soft deleted before a timestamp
+    pub async fn purge_deleted(&self, before: i64) -> Result<u64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO outbox (kind, document_id, created_at)
+               SELECT 'purged', id, $2 FROM document WHERE deleted_at < $1"#
+        )
+        .bind(before)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            r#"DELETE FROM tag WHERE document_id IN
+                   (SELECT id FROM document WHERE deleted_at < $1)"#
+        )
+        .bind(before)
+        .execute(&mut tx)
+        .await?;
+        let result = sqlx::query(r#"DELETE FROM document WHERE deleted_at < $1"#)
+            .bind(before)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+
+        Ok(result.rows_affected())
+    }
+
+    /// Apply an action to several documents in one transaction
+    ///
+    /// Documents that the action does not apply to, such as deleting an already
+    /// deleted document, are skipped. Returns the number of documents changed.
+    pub async fn bulk(&self, action: &BulkAction, ids: &[String]) -> Result<u64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let kind = match action {
+            BulkAction::Delete => "deleted",
+            BulkAction::Restore => "restored",
+            BulkAction::RenamePrefix { .. } => "renamed",
+            BulkAction::SetLanguage { .. } => "updated",
+        };
+
+        let mut tx = self.pool.begin().await?;
+        let mut changed = 0;
+        for id in ids {
+            let query = match action {
+                BulkAction::Delete => sqlx::query(
+                    r#"UPDATE document SET deleted_at = $2
+                       WHERE id = $1 AND deleted_at IS NULL"#,
+                )
+                .bind(id)
+                .bind(now),
+                BulkAction::Restore => sqlx::query(
+                    r#"UPDATE document SET deleted_at = NULL
+                       WHERE id = $1 AND deleted_at IS NOT NULL"#,
+                )
+                .bind(id),
+                BulkAction::RenamePrefix { prefix } => sqlx::query(
+                    r#"UPDATE document SET name = $2 || coalesce(name, ''), updated_at = $3
+                       WHERE id = $1 AND deleted_at IS NULL"#,
+                )
+                .bind(id)
+                .bind(prefix)
+                .bind(now),
+                BulkAction::SetLanguage { language } => sqlx::query(
+                    r#"UPDATE document SET language = $2, updated_at = $3
+                       WHERE id = $1 AND deleted_at IS NULL"#,
+                )
+                .bind(id)
+                .bind(language)
+                .bind(now),
+            };
+            if query.execute(&mut tx).await?.rows_affected() > 0 {
+                push_event(&mut tx, kind, id, now).await?;
+                changed += 1;
+            }
+        }
+        tx.commit().await?;
+        self.cache.invalidate();
+        Ok(changed)
+    }
+
+    /// Soft delete all non-deleted documents
+    pub async fn delete_all_documents(&self) -> Result<u64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO outbox (kind, document_id, created_at)
+               SELECT 'deleted', id, $1 FROM document WHERE deleted_at IS NULL"#
+        )
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        let result = sqlx::query(
+            r#"UPDATE document SET deleted_at = $1
+               WHERE deleted_at IS NULL"#
+        )
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+
+        Ok(result.rows_affected())
+    }
+
+    /// Load all user color preferences
+    pub async fn load_user_colors(&self) -> Result<Vec<(String, u32)>> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            r#"SELECT email, hue FROM user_color"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|(email, hue)| (email, hue as u32)).collect())
+    }
+
+    /// Save a user's color preference
+    pub async fn save_user_color(&self, email: &str, hue: u32) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query(
+            r#"INSERT INTO user_color (email, hue, updated_at)
+               VALUES ($1, $2, $3)
+               ON CONFLICT(email) DO UPDATE SET
+                   hue = excluded.hue,
+                   updated_at = excluded.updated_at"#
+        )
+        .bind(email)
+        .bind(hue as i64)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get a user's color preference
+    pub async fn get_user_color(&self, email: &str) -> Result<Option<u32>> {
+        let row: Option<(i64,)> = sqlx::query_as(
+            r#"SELECT hue FROM user_color WHERE email = $1"#
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|(hue,)| hue as u32))
+    }
+
+    /// Delete all stored data about a user, returning the number of rows removed
+    ///
+    /// This covers the user's color preference, starred documents, roles on
+    /// documents, and any queued jobs that carry their email in the payload.
+    /// Their email is also cleared from the stored edit history, from the
+    /// documents they own and from the abuse reports they filed.
+    pub async fn delete_user_data(&self, email: &str) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let colors = sqlx::query(r#"DELETE FROM user_color WHERE email = $1"#)
+            .bind(email)
+            .execute(&mut tx)
+            .await?;
+        let favorites = sqlx::query(r#"DELETE FROM favorite WHERE email = $1"#)
+            .bind(email)
+            .execute(&mut tx)
+            .await?;
+        let roles = sqlx::query(r#"DELETE FROM document_acl WHERE email = $1"#)
+            .bind(email)
+            .execute(&mut tx)
+            .await?;
+        let owned = sqlx::query(
+            r#"UPDATE document SET owner_email = NULL WHERE owner_email = $1"#
+        )
+        .bind(email)
+        .execute(&mut tx)
+        .await?;
+        let jobs = sqlx::query(
+            r#"DELETE FROM job WHERE json_valid(payload)
+                   AND json_extract(payload, '$.email') = $1"#
+        )
+        .bind(email)
+        .execute(&mut tx)
+        .await?;
+        let operations = sqlx::query(r#"UPDATE operation SET email = NULL WHERE email = $1"#)
+            .bind(email)
+            .execute(&mut tx)
+            .await?;
+        let reports = sqlx::query(r#"UPDATE report SET reporter = NULL WHERE reporter = $1"#)
+            .bind(email)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+
+        Ok(colors.rows_affected()
+            + favorites.rows_affected()
+            + roles.rows_affected()
+            + owned.rows_affected()
+            + jobs.rows_affected()
+            + operations.rows_affected()
+            + reports.rows_affected())
+    }
+
+    /// Replace a user's email with a pseudonym, returning the number of rows updated
+    ///
+    /// The color preference, starred documents, roles on documents and owned
+    /// documents are kept under the pseudonym, and queued jobs, stored edits
+    /// and abuse reports that carry the email are rewritten.
+    pub async fn anonymize_user(&self, email: &str, pseudonym: &str) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let colors = sqlx::query(r#"UPDATE user_color SET email = $2 WHERE email = $1"#)
+            .bind(email)
+            .bind(pseudonym)
+            .execute(&mut tx)
+            .await?;
+        let favorites = sqlx::query(
+            r#"UPDATE OR REPLACE favorite SET email = $2 WHERE email = $1"#
+        )
+        .bind(email)
+        .bind(pseudonym)
+        .execute(&mut tx)
+        .await?;
+        let roles = sqlx::query(
+            r#"UPDATE OR REPLACE document_acl SET email = $2 WHERE email = $1"#
+        )
+        .bind(email)
+        .bind(pseudonym)
+        .execute(&mut tx)
+        .await?;
+        let owned = sqlx::query(
+            r#"UPDATE document SET owner_email = $2 WHERE owner_email = $1"#
+        )
+        .bind(email)
+        .bind(pseudonym)
+        .execute(&mut tx)
+        .await?;
+        let jobs = sqlx::query(
+            r#"UPDATE job SET payload = json_set(payload, '$.email', $2)
+               WHERE json_valid(payload) AND json_extract(payload, '$.email') = $1"#
+        )
+        .bind(email)
+        .bind(pseudonym)
+        .execute(&mut tx)
+        .await?;
+        let operations = sqlx::query(r#"UPDATE operation SET email = $2 WHERE email = $1"#)
+            .bind(email)
+            .bind(pseudonym)
+            .execute(&mut tx)
+            .await?;
+        let reports = sqlx::query(r#"UPDATE report SET reporter = $2 WHERE reporter = $1"#)
+            .bind(email)
+            .bind(pseudonym)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        self.cache.invalidate();
+
+        Ok(colors.rows_affected()
+            + favorites.rows_affected()
+            + roles.rows_affected()
+            + owned.rows_affected()
+            + jobs.rows_affected()
+            + operations.rows_affected()
+            + reports.rows_affected())
+    }
+
+    /// Rebuild the database file, reclaiming space left by deleted rows
+    pub async fn vacuum(&self) -> Result<MaintenanceReport> {
+        let start = std::time::Instant::now();
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+
+        Ok(MaintenanceReport {
+            task: "vacuum",
+            ok: true,
+            messages: vec![String::from("ok")],
+            duration_ms: start.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Run SQLite's integrity check over the whole database file
+    pub async fn integrity_check(&self) -> Result<MaintenanceReport> {
+        let start = std::time::Instant::now();
+        let rows: Vec<(String,)> = sqlx::query_as("PRAGMA integrity_check")
+            .fetch_all(&self.pool)
+            .await?;
+        let messages: Vec<String> = rows.into_iter().map(|(message,)| message).collect();
+
+        Ok(MaintenanceReport {
+            task: "integrity_check",
+            ok: messages.len() == 1 && messages[0] == "ok",
+            messages,
+            duration_ms: start.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Add a job to the background queue, returning its ID
+    pub async fn enqueue_job(&self, kind: &str, payload: &str) -> Result<i64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let result = sqlx::query(
+            r#"INSERT INTO job (kind, payload, run_at, created_at, updated_at)
+               VALUES ($1, $2, $3, $3, $3)"#
+        )
+        .bind(kind)
+        .bind(payload)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Atomically mark the next due job as running and return it
+    pub async fn claim_job(&self) -> Result<Option<Job>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query_as(
+            r#"UPDATE job SET status = 'running', updated_at = $1
+               WHERE id = (
+                   SELECT id FROM job
+                   WHERE status = 'pending' AND run_at <= $1
+                   ORDER BY run_at, id
+                   LIMIT 1
+               )
+               RETURNING id, kind, payload, status, attempts, max_attempts,
+                         last_error, run_at, created_at, updated_at"#
+        )
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Mark a running job as successfully completed
+    pub async fn complete_job(&self, id: i64) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query(
+            r#"UPDATE job SET status = 'done', attempts = attempts + 1, updated_at = $2
+               WHERE id = $1"#
+        )
+        .bind(id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a failed attempt, rescheduling the job after `retry_secs` if given
+    pub async fn fail_job(&self, id: i64, error: &str, retry_secs: Option<i64>) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let (status, run_at) = match retry_secs {
+            Some(secs) => ("pending", now + secs),
+            None => ("failed", now),
+        };
+        sqlx::query(
+            r#"UPDATE job SET status = $2, attempts = attempts + 1, last_error = $3,
+                   run_at = $4, updated_at = $5
+               WHERE id = $1"#
+        )
+        .bind(id)
+        .bind(status)
+        .bind(error)
+        .bind(run_at)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Return jobs left running by a previous process to the queue
+    pub async fn requeue_running_jobs(&self) -> Result<u64> {
+        let result = sqlx::query(r#"UPDATE job SET status = 'pending' WHERE status = 'running'"#)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// List the most recently enqueued jobs
+    pub async fn list_jobs(&self, limit: i64) -> Result<Vec<Job>> {
+        sqlx::query_as(
+            r#"SELECT id, kind, payload, status, attempts, max_attempts,
+                      last_error, run_at, created_at, updated_at
+               FROM job
+               ORDER BY id DESC
+               LIMIT $1"#
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Count jobs in each status
+    pub async fn count_jobs_by_status(&self) -> Result<Vec<(String, i64)>> {
+        sqlx::query_as(r#"SELECT status, count(*) FROM job GROUP BY status"#)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    /// Return up to `limit` outbox events recorded after the event `after`
+    pub async fn events_after(&self, after: i64, limit: i64) -> Result<Vec<DocumentEvent>> {
+        sqlx::query_as(
+            r#"SELECT id, kind, document_id, created_at
+               FROM outbox
+               WHERE id > $1
+               ORDER BY id
+               LIMIT $2"#
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Return the ID of the last outbox event recorded before a timestamp, or zero
+    pub async fn last_event_before(&self, before: i64) -> Result<i64> {
+        let (id,): (i64,) =
+            sqlx::query_as(r#"SELECT coalesce(max(id), 0) FROM outbox WHERE created_at < $1"#)
+                .bind(before)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(id)
+    }
+
+    /// Return the last event acknowledged by a durable feed consumer,
+    /// registering it first if it is new.
+    ///
+    /// New consumers start after the last event delivered to every other
+    /// consumer, so they still receive any events that are pending.
+    pub async fn consumer_cursor(&self, name: &str) -> Result<i64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query(
+            r#"INSERT INTO outbox_consumer (name, last_event_id, updated_at)
+               SELECT $1, coalesce(max(id), 0), $2
+               FROM outbox WHERE delivered_at IS NOT NULL
+               ON CONFLICT(name) DO NOTHING"#
+        )
+        .bind(name)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        let (last_event_id,): (i64,) =
+            sqlx::query_as(r#"SELECT last_event_id FROM outbox_consumer WHERE name = $1"#)
+                .bind(name)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(last_event_id)
+    }
+
+    /// Acknowledge all outbox events up to and including `last_id` for a
+    /// durable feed consumer, marking events acknowledged by every consumer
+    /// as delivered
+    pub async fn ack_events(&self, name: &str, last_id: i64) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"UPDATE outbox_consumer
+               SET last_event_id = max(last_event_id, $2), updated_at = $3
+               WHERE name = $1"#
+        )
+        .bind(name)
+        .bind(last_id)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            r#"UPDATE outbox SET delivered_at = $1
+               WHERE delivered_at IS NULL
+                 AND id <= (SELECT min(last_event_id) FROM outbox_consumer)"#
+        )
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Remove delivered outbox events recorded before a timestamp
+    pub async fn prune_events(&self, before: i64) -> Result<u64> {
+        let result = sqlx::query(
+            r#"DELETE FROM outbox WHERE delivered_at IS NOT NULL AND created_at < $1"#
+        )
+        .bind(before)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Record a sample of server activity, dropping samples taken before `before`
+    pub async fn record_stats_sample(&self, sample: &StatsSample, before: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO stats_sample (created_at, documents, connections, ops_per_sec)
+               VALUES ($1, $2, $3, $4)"#
+        )
+        .bind(sample.created_at)
+        .bind(sample.documents)
+        .bind(sample.connections)
+        .bind(sample.ops_per_sec)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(r#"DELETE FROM stats_sample WHERE created_at < $1"#)
+            .bind(before)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Record a number of revisions persisted for a document at a timestamp
+    pub async fn record_activity(&self, id: &str, revisions: usize, now: i64) -> Result<()> {
+        sqlx::query(
+            r#"INSERT INTO activity (document_id, created_at, revisions)
+               VALUES ($1, $2, $3)"#
+        )
+        .bind(id)
+        .bind(now)
+        .bind(revisions as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// List non-deleted documents with edits persisted after a timestamp,
+    /// most recently edited first
+    pub async fn activity(&self, since: i64, limit: i64) -> Result<Vec<DocumentActivity>> {
+        sqlx::query_as(
+            r#"SELECT document.id, document.name, sum(activity.revisions) AS revisions,
+                   max(activity.created_at) AS edited_at
+               FROM activity JOIN document ON document.id = activity.document_id
+               WHERE activity.created_at > $1 AND document.deleted_at IS NULL
+               GROUP BY document.id
+               ORDER BY edited_at DESC, document.id
+               LIMIT $2"#
+        )
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    /// Delete records of edits persisted before a timestamp
+    pub async fn prune_activity(&self, before: i64) -> Result<u64> {
+        let result = sqlx::query(r#"DELETE FROM activity WHERE created_at < $1"#)
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Store operations of a document, replacing any stored from revision `start` on
+    pub async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[StoredOperation],
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM operation WHERE document_id = $1 AND revision >= $2"#)
+            .bind(document_id)
+            .bind(start as i64)
+            .execute(&mut tx)
+            .await?;
+        for op in operations {
+            sqlx::query(
+                r#"INSERT INTO operation
+                       (document_id, revision, user_id, email, created_at, source, signature,
+                        key_id, operation)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#
+            )
+            .bind(document_id)
+            .bind(op.revision as i64)
+            .bind(op.user_id as i64)
+            .bind(&op.email)
+            .bind(op.created_at)
+            .bind(op.source)
+            .bind(&op.signature)
+            .bind(&op.key_id)
+            .bind(serde_json::to_string(&op.operation)?)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Load the stored operations of a document in order of revision
+    ///
+    /// Returns nothing if the document has more than `limit` operations.
+    pub async fn load_operations(
+        &self,
+        document_id: &str,
+        limit: usize,
+    ) -> Result<Vec<StoredOperation>> {
+        let operations = self.operations_from(document_id, 0, limit + 1).await?;
+        if operations.len() > limit {
+            return Ok(Vec::new());
+        }
+        Ok(operations)
+    }
+
+    /// Load up to `limit` stored operations of a document, in order of
+    /// revision starting from `start`
+    pub async fn operations_from(
+        &self,
+        document_id: &str,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<StoredOperation>> {
+        let rows = sqlx::query(
+            r#"SELECT revision, user_id, email, created_at, source, signature, key_id, operation
+               FROM operation
+               WHERE document_id = $1 AND revision >= $2 ORDER BY revision LIMIT $3"#
+        )
+        .bind(document_id)
+        .bind(start as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(stored_operation).collect()
+    }
+
+    /// Load the stored operations made by a user, by document and revision
+    pub async fn user_operations(&self, email: &str) -> Result<Vec<(String, StoredOperation)>> {
+        let rows = sqlx::query(
+            r#"SELECT document_id, revision, user_id, email, created_at, source, signature,
+                      key_id, operation
+               FROM operation WHERE email = $1 ORDER BY document_id, revision"#
+        )
+        .bind(email)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| Ok((row.try_get("document_id")?, stored_operation(row)?)))
+            .collect()
+    }
+
+    /// List samples of server activity taken since a timestamp, oldest first
+    pub async fn stats_samples(&self, since: i64) -> Result<Vec<StatsSample>> {
+        sqlx::query_as(
+            r#"SELECT created_at, documents, connections, ops_per_sec FROM stats_sample
+               WHERE created_at >= $1 ORDER BY created_at"#
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+}
+
+/// Restrict a document query to the documents matching the list options.
+fn push_filters(query: &mut QueryBuilder<'_, Sqlite>, options: &ListOptions) {
+    match options.archived {
+        ArchiveFilter::Exclude => {
+            query.push(" AND archived_at IS NULL");
+        }
+        ArchiveFilter::Only => {
+            query.push(" AND archived_at IS NOT NULL");
+        }
+        ArchiveFilter::Include => (),
+    }
+    if let Some(tag) = &options.tag {
+        query.push(
+            " AND EXISTS (SELECT 1 FROM tag WHERE document_id = document.id AND name = ",
+        );
+        query.push_bind(tag.clone());
+        query.push(")");
+    }
+    if let Some(folder) = options.folder {
+        query.push(" AND folder_id = ");
+        query.push_bind(folder);
+    }
+    if let Some(language) = &options.language {
+        query.push(" AND coalesce(language, detected_language) = ");
+        query.push_bind(language.clone());
+    }
+    if let Some(after) = options.updated_after {
+        query.push(" AND updated_at > ");
+        query.push_bind(after);
+    }
+    if let Some(before) = options.updated_before {
+        query.push(" AND updated_at < ");
+        query.push_bind(before);
+    }
+    if let Some(email) = &options.starred_by {
+        query.push(
+            " AND EXISTS (SELECT 1 FROM favorite WHERE document_id = document.id AND email = ",
+        );
+        query.push_bind(email.clone());
+        query.push(")");
+    }
+    for (path, value) in &options.metadata {
+        query.push(
+            " AND EXISTS (SELECT 1 FROM document_metadata \
+             WHERE document_id = document.id AND path = ",
+        );
+        query.push_bind(path.clone());
+        query.push(" AND value = ");
+        query.push_bind(value.clone());
+        query.push(")");
+    }
+}
+
+/// Maximum length of a derived document name, in characters.
+const MAX_DERIVED_NAME_LENGTH: usize = 80;
+
+/// Derive a document name from its first non-empty line, or its first heading
+/// if it is written in Markdown.
+fn derive_name(text: &str, language: Option<&str>) -> Option<String> {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    let line = match language {
+        Some("markdown") => text
+            .lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .or_else(|| lines.next()),
+        _ => lines.next(),
+    }?;
+    let name: String = line
+        .trim_start_matches('#')
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_DERIVED_NAME_LENGTH)
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Read a row of the `operation` table.
+fn stored_operation(row: &SqliteRow) -> Result<StoredOperation> {
+    Ok(StoredOperation {
+        revision: row.try_get::<i64, _>("revision")? as usize,
+        user_id: row.try_get::<i64, _>("user_id")? as u64,
+        email: row.try_get("email")?,
+        created_at: row.try_get("created_at")?,
+        source: row.try_get("source")?,
+        signature: row.try_get("signature")?,
+        key_id: row.try_get("key_id")?,
+        operation: serde_json::from_str(row.try_get("operation")?)?,
+    })
+}
+
+/// Split the tags selected by [`TAGS_COLUMN`] into a sorted list.
+fn split_tags(tags: Option<String>) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .as_deref()
+        .unwrap_or_default()
+        .split('\u{1f}')
+        .filter(|tag| !tag.is_empty())
+        .map(String::from)
+        .collect();
+    tags.sort();
+    tags
+}
+
+/// Turn free-form user input into an FTS5 query matching all of its terms.
+///
+/// Each term is quoted so that punctuation is never parsed as query syntax.
+fn fts_query(input: &str) -> String {
+    input
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escape a raw snippet for HTML, replacing the match delimiters with `<mark>`.
+fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '\u{1}' => html.push_str("<mark>"),
+            '\u{2}' => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Record a document event in the outbox as part of an enclosing transaction.
+async fn push_event(
+    tx: &mut Transaction<'_, Sqlite>,
+    kind: &str,
+    document_id: &str,
+    now: i64,
+) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO outbox (kind, document_id, created_at)
+           VALUES ($1, $2, $3)"#
+    )
+    .bind(kind)
+    .bind(document_id)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
 }
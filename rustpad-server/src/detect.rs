@@ -0,0 +1,122 @@
+//! Lightweight detection of a document's language from its content.
+//!
+//! Detection scores a few characteristic patterns per language over the first
+//! lines of the text. It is only meant as a fallback for documents whose
+//! language was never set, so it prefers returning nothing over guessing.
+
+/// Number of leading lines examined.
+const MAX_LINES: usize = 200;
+
+/// Minimum score for a language to be reported.
+const MIN_SCORE: u32 = 3;
+
+/// Interpreters named in shebang lines, and their languages.
+const SHEBANGS: &[(&str, &str)] = &[
+    ("python", "python"),
+    ("bash", "shell"),
+    ("sh", "shell"),
+    ("zsh", "shell"),
+    ("node", "javascript"),
+    ("ruby", "ruby"),
+    ("perl", "perl"),
+    ("php", "php"),
+];
+
+/// Line prefixes that hint at a language, and how strongly.
+const LINE_PATTERNS: &[(&str, &str, u32)] = &[
+    ("fn ", "rust", 2),
+    ("pub fn ", "rust", 3),
+    ("use std::", "rust", 3),
+    ("impl ", "rust", 2),
+    ("let mut ", "rust", 2),
+    ("#[derive(", "rust", 3),
+    ("def ", "python", 2),
+    ("import ", "python", 1),
+    ("from ", "python", 1),
+    ("elif ", "python", 3),
+    ("if __name__", "python", 3),
+    ("package ", "go", 1),
+    ("func ", "go", 2),
+    ("#include", "cpp", 2),
+    ("std::", "cpp", 2),
+    ("template <", "cpp", 2),
+    ("public class ", "java", 3),
+    ("public static void ", "java", 3),
+    ("const ", "javascript", 1),
+    ("function ", "javascript", 2),
+    ("console.log(", "javascript", 2),
+    ("interface ", "typescript", 1),
+    ("export type ", "typescript", 3),
+    ("# ", "markdown", 1),
+    ("## ", "markdown", 2),
+    ("```", "markdown", 2),
+    ("- [ ] ", "markdown", 2),
+    ("select ", "sql", 2),
+    ("create table ", "sql", 3),
+    ("insert into ", "sql", 3),
+    ("echo ", "shell", 1),
+    ("export ", "shell", 1),
+];
+
+/// Detects the editor language of a text, if it is recognizable.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let first = text.lines().find(|line| !line.trim().is_empty())?.trim();
+    if let Some(language) = from_first_line(first) {
+        return Some(language);
+    }
+    if (first.starts_with('{') || first.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(text).is_ok()
+    {
+        return Some("json");
+    }
+
+    let mut scores: Vec<(&'static str, u32)> = Vec::new();
+    for line in text.lines().take(MAX_LINES) {
+        let line = line.trim_start();
+        let lower = line.to_ascii_lowercase();
+        for &(prefix, language, weight) in LINE_PATTERNS {
+            let matched = if prefix.chars().any(|c| c.is_ascii_uppercase()) {
+                line.starts_with(prefix)
+            } else {
+                lower.starts_with(prefix)
+            };
+            if matched {
+                match scores.iter_mut().find(|(lang, _)| *lang == language) {
+                    Some((_, score)) => *score += weight,
+                    None => scores.push((language, weight)),
+                }
+            }
+        }
+    }
+    scores
+        .into_iter()
+        .filter(|&(_, score)| score >= MIN_SCORE)
+        .max_by_key(|&(_, score)| score)
+        .map(|(language, _)| language)
+}
+
+/// Detects a language from an unambiguous first line, such as a shebang.
+fn from_first_line(line: &str) -> Option<&'static str> {
+    if let Some(command) = line.strip_prefix("#!") {
+        let mut words = command.split_whitespace();
+        let program = words.next()?.rsplit('/').next()?;
+        let interpreter = match program {
+            "env" => words.next()?,
+            program => program,
+        };
+        return SHEBANGS
+            .iter()
+            .find(|(name, _)| interpreter.starts_with(name))
+            .map(|&(_, language)| language);
+    }
+    let lower = line.to_ascii_lowercase();
+    if lower.starts_with("<?php") {
+        Some("php")
+    } else if lower.starts_with("<?xml") {
+        Some("xml")
+    } else if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+        Some("html")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,384 @@
+//! Computation and application of unified diffs to document text.
+//!
+//! Patches are checked against the text line by line, without fuzz: every
+//! hunk must match its context and removed lines exactly at its stated
+//! position. A patch is converted into a single operation, so that it is
+//! either applied as a whole or not at all.
+
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+use operational_transform::OperationSeq;
+
+/// Number of unchanged lines shown around each change in a rendered diff.
+const CONTEXT_LINES: usize = 3;
+
+/// Largest number of added and removed lines searched for when diffing two
+/// texts. Texts differing by more are diffed as a single change replacing
+/// everything between their common first and last lines.
+const MAX_EDIT_DISTANCE: usize = 2000;
+
+/// A parsed unified diff for a single file.
+#[derive(Clone, Debug)]
+pub struct Patch {
+    hunks: Vec<Hunk>,
+}
+
+/// A contiguous change, starting at a line of the original text.
+#[derive(Clone, Debug)]
+struct Hunk {
+    /// Line number of the first original line, counted from 1, or the line
+    /// after which to insert if the hunk has no original lines.
+    old_start: usize,
+    lines: Vec<HunkLine>,
+}
+
+/// A line of a hunk, without its line break.
+#[derive(Clone, Debug)]
+enum HunkLine {
+    /// A line kept unchanged.
+    Context(String),
+    /// A line of the original text that is removed.
+    Remove(String),
+    /// A line that is added, and whether it ends with a line break.
+    Add { text: String, newline: bool },
+}
+
+impl Patch {
+    /// Parses a unified diff, ignoring any file headers.
+    pub fn parse(diff: &str) -> Result<Self> {
+        let mut hunks: Vec<Hunk> = Vec::new();
+        let mut lines = diff.lines().peekable();
+        while let Some(line) = lines.next() {
+            if !line.starts_with("@@ ") {
+                continue;
+            }
+            let (old_start, mut old_count, mut new_count) =
+                parse_header(line).with_context(|| format!("invalid hunk header: {}", line))?;
+            let mut hunk = Hunk {
+                old_start,
+                lines: Vec::new(),
+            };
+            while old_count > 0 || new_count > 0 {
+                let line = match lines.next() {
+                    Some(line) => line,
+                    None => bail!("hunk {} ends early", hunks.len() + 1),
+                };
+                let text = line.get(1..).unwrap_or_default();
+                let hunk_line = match line.as_bytes().first() {
+                    Some(b' ') | None if old_count > 0 && new_count > 0 => {
+                        old_count -= 1;
+                        new_count -= 1;
+                        HunkLine::Context(text.to_owned())
+                    }
+                    Some(b'-') if old_count > 0 => {
+                        old_count -= 1;
+                        HunkLine::Remove(text.to_owned())
+                    }
+                    Some(b'+') if new_count > 0 => {
+                        new_count -= 1;
+                        HunkLine::Add {
+                            text: text.to_owned(),
+                            newline: true,
+                        }
+                    }
+                    Some(b'\\') => continue,
+                    _ => bail!("unexpected line in hunk {}: {}", hunks.len() + 1, line),
+                };
+                hunk.lines.push(hunk_line);
+                if lines.peek().map_or(false, |next| next.starts_with('\\')) {
+                    // "\ No newline at end of file" applies to the line before.
+                    if let Some(HunkLine::Add { newline, .. }) = hunk.lines.last_mut() {
+                        *newline = false;
+                    }
+                }
+            }
+            if let Some(previous) = hunks.last() {
+                if hunk.start() < previous.start() + previous.old_len() {
+                    bail!("hunk {} overlaps the hunk before it", hunks.len() + 1);
+                }
+            }
+            hunks.push(hunk);
+        }
+        if hunks.is_empty() {
+            bail!("patch contains no hunks");
+        }
+        Ok(Self { hunks })
+    }
+
+    /// Builds the operation applying this patch to a text.
+    ///
+    /// If any hunk does not match the text, returns the numbers of all such
+    /// hunks, counted from 1.
+    pub fn operation(&self, text: &str) -> Result<OperationSeq, Vec<usize>> {
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let mut operation = OperationSeq::default();
+        let mut failed = Vec::new();
+        let mut line = 0;
+        for (number, hunk) in self.hunks.iter().enumerate() {
+            let start = hunk.start();
+            if !hunk.matches(&lines, start) {
+                failed.push(number + 1);
+                continue;
+            }
+            for &kept in &lines[line..start] {
+                operation.retain(char_len(kept));
+            }
+            line = start;
+            for hunk_line in &hunk.lines {
+                match hunk_line {
+                    HunkLine::Context(_) => {
+                        operation.retain(char_len(lines[line]));
+                        line += 1;
+                    }
+                    HunkLine::Remove(_) => {
+                        operation.delete(char_len(lines[line]));
+                        line += 1;
+                    }
+                    HunkLine::Add { text, newline } => {
+                        operation.insert(text);
+                        if *newline {
+                            operation.insert("\n");
+                        }
+                    }
+                }
+            }
+        }
+        if !failed.is_empty() {
+            return Err(failed);
+        }
+        for &kept in &lines[line..] {
+            operation.retain(char_len(kept));
+        }
+        Ok(operation)
+    }
+}
+
+impl Hunk {
+    /// Returns the index of the first original line of the hunk.
+    fn start(&self) -> usize {
+        if self.old_len() == 0 {
+            self.old_start
+        } else {
+            self.old_start.saturating_sub(1)
+        }
+    }
+
+    /// Returns the number of original lines covered by the hunk.
+    fn old_len(&self) -> usize {
+        self.old_lines().count()
+    }
+
+    /// Returns the original lines covered by the hunk, in order.
+    fn old_lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|line| match line {
+            HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+            HunkLine::Add { .. } => None,
+        })
+    }
+
+    /// Checks whether the original lines of the hunk appear at a line index.
+    fn matches(&self, lines: &[&str], start: usize) -> bool {
+        let end = start + self.old_len();
+        end <= lines.len()
+            && lines[start..end]
+                .iter()
+                .zip(self.old_lines())
+                .all(|(line, expected)| line.strip_suffix('\n').unwrap_or(line) == expected)
+    }
+}
+
+/// Parses a hunk header like `@@ -1,3 +1,4 @@` into the original start line
+/// and the numbers of original and new lines.
+fn parse_header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ ")?.split(' ');
+    let (old_start, old_count) = parse_range(ranges.next()?.strip_prefix('-')?)?;
+    let (_, new_count) = parse_range(ranges.next()?.strip_prefix('+')?)?;
+    (ranges.next()? == "@@").then_some((old_start, old_count, new_count))
+}
+
+/// Parses a range like `3,4`, where the count defaults to 1.
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+/// Returns the length of a string in characters, as used by operations.
+fn char_len(text: &str) -> u64 {
+    text.chars().count() as u64
+}
+
+/// What happens to a line when going from one text to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edit {
+    Keep,
+    Remove,
+    Add,
+}
+
+/// Renders a unified diff from `old` to `new`, with file headers naming them
+/// `old_label` and `new_label`. Returns an empty string if the texts are equal.
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let edits = diff_lines(&old_lines, &new_lines);
+
+    // Indices of the old and new line that each edit is at.
+    let mut positions = Vec::with_capacity(edits.len());
+    let (mut old_index, mut new_index) = (0, 0);
+    for edit in &edits {
+        positions.push((old_index, new_index));
+        match edit {
+            Edit::Keep => {
+                old_index += 1;
+                new_index += 1;
+            }
+            Edit::Remove => old_index += 1,
+            Edit::Add => new_index += 1,
+        }
+    }
+
+    let changes: Vec<usize> = (0..edits.len())
+        .filter(|&i| edits[i] != Edit::Keep)
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+    let mut diff = format!("--- {}\n+++ {}\n", old_label, new_label);
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes separated by few enough unchanged lines share a hunk.
+        let start = changes[i].saturating_sub(CONTEXT_LINES);
+        let mut end = changes[i] + 1;
+        i += 1;
+        while i < changes.len() && changes[i] <= end + 2 * CONTEXT_LINES {
+            end = changes[i] + 1;
+            i += 1;
+        }
+        let end = (end + CONTEXT_LINES).min(edits.len());
+
+        let hunk = &edits[start..end];
+        let (old_start, new_start) = positions[start];
+        let old_count = hunk.iter().filter(|&&edit| edit != Edit::Add).count();
+        let new_count = hunk.iter().filter(|&&edit| edit != Edit::Remove).count();
+        writeln!(
+            diff,
+            "@@ -{} +{} @@",
+            format_range(old_start, old_count),
+            format_range(new_start, new_count)
+        )
+        .unwrap();
+        for (edit, &(old_index, new_index)) in hunk.iter().zip(&positions[start..end]) {
+            let (prefix, line) = match edit {
+                Edit::Keep => (' ', old_lines[old_index]),
+                Edit::Remove => ('-', old_lines[old_index]),
+                Edit::Add => ('+', new_lines[new_index]),
+            };
+            diff.push(prefix);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    diff
+}
+
+/// Formats the range of a hunk header from a line index and count.
+fn format_range(start: usize, count: usize) -> String {
+    match count {
+        // An empty range names the line before it.
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+/// Computes the edits turning one sequence of lines into another.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut edits = vec![Edit::Keep; prefix];
+    match shortest_edits(old_middle, new_middle) {
+        Some(middle) => edits.extend(middle),
+        None => {
+            edits.extend(std::iter::repeat(Edit::Remove).take(old_middle.len()));
+            edits.extend(std::iter::repeat(Edit::Add).take(new_middle.len()));
+        }
+    }
+    edits.extend(std::iter::repeat(Edit::Keep).take(suffix));
+    edits
+}
+
+/// Finds the fewest lines to remove and add with Myers' algorithm, or returns
+/// `None` if more than [`MAX_EDIT_DISTANCE`] are needed.
+fn shortest_edits(old: &[&str], new: &[&str]) -> Option<Vec<Edit>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (n + m).min(MAX_EDIT_DISTANCE as isize);
+    // Furthest x reached on each diagonal k = x - y, stored at k + offset.
+    let offset = n + m + 1;
+    let mut v = vec![0; 2 * offset as usize + 1];
+    // For each number of edits d, the furthest x on diagonals -d..=d before
+    // the round, kept for tracing the path back.
+    let mut trace = Vec::new();
+    for d in 0..=max {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                return Some(trace_back(&trace, n, m));
+            }
+        }
+    }
+    None
+}
+
+/// Follows the furthest paths recorded by [`shortest_edits`] back from the
+/// end of both sequences, returning the edits in order.
+fn trace_back(trace: &[Vec<isize>], mut x: isize, mut y: isize) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for (d, v) in trace.iter().enumerate().skip(1).rev() {
+        let d = d as isize;
+        let k = x - y;
+        let furthest = |k: isize| v[(k + d) as usize];
+        let prev_k = if k == -d || (k != d && furthest(k - 1) < furthest(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = furthest(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Keep);
+            x -= 1;
+            y -= 1;
+        }
+        edits.push(if x == prev_x { Edit::Add } else { Edit::Remove });
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.extend(std::iter::repeat(Edit::Keep).take(x as usize));
+    edits.reverse();
+    edits
+}
@@ -0,0 +1,77 @@
+//! Server-side rendering of documents for embedding in other pages.
+
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+/// URL schemes allowed in the links and images of rendered markdown.
+const SAFE_SCHEMES: &[&str] = &["http:", "https:", "mailto:"];
+
+/// Renders a document to an HTML fragment.
+///
+/// Markdown documents are rendered with raw HTML escaped and unsafe link
+/// targets removed, so the output can be embedded without further
+/// sanitization. Other documents are shown as a code block.
+pub fn render_html(text: &str, language: Option<&str>) -> String {
+    if language != Some("markdown") {
+        let class = language.unwrap_or("plaintext");
+        return format!(
+            "<pre><code class=\"language-{}\">{}</code></pre>\n",
+            escape(class),
+            escape(text)
+        );
+    }
+    let events = Parser::new_ext(text, markdown_options()).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, url, title)) => {
+            Event::Start(Tag::Link(kind, safe_url(url), title))
+        }
+        Event::Start(Tag::Image(kind, url, title)) => {
+            Event::Start(Tag::Image(kind, safe_url(url), title))
+        }
+        event => event,
+    });
+    let mut output = String::new();
+    html::push_html(&mut output, events);
+    output
+}
+
+/// Markdown extensions supported when rendering, following GitHub's dialect.
+fn markdown_options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+}
+
+/// Removes a URL whose scheme could run script when followed.
+///
+/// Relative URLs and fragments are kept.
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    let lower = url.trim_start().to_ascii_lowercase();
+    let scheme_end = lower.find(|c: char| matches!(c, ':' | '/' | '?' | '#'));
+    match scheme_end {
+        Some(index) if lower[index..].starts_with(':') => {
+            if SAFE_SCHEMES.iter().any(|scheme| lower.starts_with(scheme)) {
+                url
+            } else {
+                CowStr::Borrowed("")
+            }
+        }
+        _ => url,
+    }
+}
+
+/// Escapes text for inclusion in HTML content or attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,219 @@
+//! Live feed of document events over WebSocket, filtered by document ID,
+//! tag, folder or kind of event.
+
+use std::collections::{HashSet, VecDeque};
+
+use futures::prelude::*;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::ws::{Message, WebSocket};
+
+use crate::database::{Database, DocumentEvent, DocumentMeta};
+
+/// Documents that a feed subscriber is interested in.
+#[derive(Deserialize, Debug)]
+pub struct FeedFilter {
+    /// Only send events for documents with an ID matching this glob pattern,
+    /// where `*` matches any characters and `?` matches one.
+    pub id: Option<String>,
+    /// Only send events of this kind, such as `created` or `deleted`.
+    pub kind: Option<String>,
+    /// Only send events for documents with this tag.
+    pub tag: Option<String>,
+    /// Only send events for documents directly inside this folder.
+    pub folder: Option<i64>,
+    /// Name of a durable consumer. Durable consumers are first sent the events
+    /// recorded since their last acknowledgement, which they send back as
+    /// `{"ack": id}` once the event with that ID is handled.
+    pub consumer: Option<String>,
+}
+
+impl FeedFilter {
+    fn matches_event(&self, event: &DocumentEvent) -> bool {
+        let id_matches = self
+            .id
+            .as_ref()
+            .map_or(true, |pattern| glob_matches(pattern, &event.document_id));
+        let kind_matches = self.kind.as_ref().map_or(true, |kind| *kind == event.kind);
+        id_matches && kind_matches
+    }
+
+    fn matches(&self, meta: &DocumentMeta) -> bool {
+        let tag_matches = self
+            .tag
+            .as_ref()
+            .map_or(true, |tag| meta.tags.contains(tag));
+        let folder_matches = self.folder.map_or(true, |id| meta.folder_id == Some(id));
+        tag_matches && folder_matches
+    }
+}
+
+/// Acknowledgement of the events up to an ID, sent by durable consumers.
+#[derive(Deserialize)]
+struct FeedAck {
+    ack: i64,
+}
+
+/// Maximum number of events replayed from the outbox at once.
+const REPLAY_BATCH_SIZE: i64 = 100;
+
+/// An event sent to feed subscribers.
+#[derive(Serialize)]
+struct FeedEvent {
+    #[serde(flatten)]
+    event: DocumentEvent,
+    /// Metadata of the document when the event is delivered, or `None` once
+    /// it is deleted.
+    meta: Option<DocumentMeta>,
+}
+
+/// Sends the events matching `filter` to a WebSocket until it closes.
+///
+/// An event is sent if its document ID and kind match the filter, and its
+/// document matches the tag and folder of the filter or matched it at an
+/// earlier event on this feed, so that clients learn of documents leaving the
+/// filter too. Deleted documents have no metadata left to filter on, so their
+/// events are always sent.
+///
+/// Durable consumers are replayed the outbox from their last acknowledged
+/// event before following live events, and again after falling behind.
+pub async fn serve(
+    socket: WebSocket,
+    filter: FeedFilter,
+    database: Database,
+    mut events: broadcast::Receiver<DocumentEvent>,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let mut matching = HashSet::new();
+    // The last event handled by a durable consumer, and events to replay.
+    let mut cursor = match &filter.consumer {
+        Some(name) => match database.consumer_cursor(name).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!("when reading feed consumer cursor: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let mut replaying = cursor.is_some();
+    let mut backlog = VecDeque::new();
+    loop {
+        if let Some(last_id) = cursor.filter(|_| replaying && backlog.is_empty()) {
+            match database.events_after(last_id, REPLAY_BATCH_SIZE).await {
+                Ok(batch) => {
+                    replaying = !batch.is_empty();
+                    backlog.extend(batch);
+                }
+                Err(e) => {
+                    error!("when replaying outbox events for feed: {}", e);
+                    break;
+                }
+            }
+        }
+        let event = match backlog.pop_front() {
+            Some(event) => event,
+            None => tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("feed subscriber skipped {} events", skipped);
+                        replaying = cursor.is_some();
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                message = stream.next() => match message {
+                    Some(Ok(message)) => {
+                        if let (Some(name), Some(last_id)) = (&filter.consumer, cursor) {
+                            acknowledge(&database, name, &message, last_id).await;
+                        }
+                        continue;
+                    }
+                    _ => break,
+                },
+            },
+        };
+        if let Some(last_id) = cursor {
+            // Live events may repeat ones already replayed from the outbox.
+            if event.id <= last_id {
+                continue;
+            }
+            cursor = Some(event.id);
+        }
+        if !filter.matches_event(&event) {
+            continue;
+        }
+        let meta = match database.get_meta(&event.document_id).await {
+            Ok(meta) => meta,
+            Err(e) => {
+                error!("when reading metadata for feed: {}", e);
+                continue;
+            }
+        };
+        let send = match &meta {
+            Some(meta) if filter.matches(meta) => {
+                matching.insert(event.document_id.clone());
+                true
+            }
+            Some(_) => matching.remove(&event.document_id),
+            None => {
+                matching.remove(&event.document_id);
+                true
+            }
+        };
+        if !send {
+            continue;
+        }
+        let text = serde_json::to_string(&FeedEvent { event, meta })
+            .expect("serializing a feed event cannot fail");
+        if sink.send(Message::text(text)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Records an acknowledgement from a durable consumer, which cannot cover
+/// events after `last_id` that it has not been sent.
+async fn acknowledge(database: &Database, name: &str, message: &Message, last_id: i64) {
+    if !message.is_text() {
+        return;
+    }
+    let ack = match message.to_str().map(serde_json::from_str::<FeedAck>) {
+        Ok(Ok(ack)) => ack.ack.min(last_id),
+        _ => {
+            warn!("feed consumer {} sent an invalid acknowledgement", name);
+            return;
+        }
+    };
+    if let Err(e) = database.ack_events(name, ack).await {
+        error!("when acknowledging feed events: {}", e);
+    }
+}
+
+/// Returns whether `text` matches a glob `pattern`, where `*` matches any
+/// sequence of characters and `?` matches a single character.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` seen, and the text it has matched up to.
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
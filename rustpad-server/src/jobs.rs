@@ -0,0 +1,137 @@
+//! Persistent background jobs with retries and exponential backoff.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use log::{error, info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time;
+
+use crate::database::Database;
+
+/// Job kind for persisting an authenticated user's color preference.
+pub const SAVE_USER_COLOR: &str = "save_user_color";
+
+/// Payload of a [`SAVE_USER_COLOR`] job.
+#[derive(Serialize, Deserialize)]
+pub struct UserColorJob {
+    /// Authenticated email of the user.
+    pub email: String,
+    /// Preferred hue of the user's color.
+    pub hue: u32,
+}
+
+/// Job kind for adding revisions persisted for a document to its activity.
+pub const RECORD_ACTIVITY: &str = "record_activity";
+
+/// Payload of a [`RECORD_ACTIVITY`] job.
+#[derive(Serialize, Deserialize)]
+pub struct ActivityJob {
+    /// ID of the document that was persisted.
+    pub document_id: String,
+    /// Number of revisions newly persisted.
+    pub revisions: usize,
+    /// Time the revisions were persisted, in seconds since Unix epoch.
+    pub created_at: i64,
+}
+
+/// How long the worker waits before polling an empty queue again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before the first retry of a failed job, doubled on each attempt.
+const RETRY_BASE: Duration = Duration::from_secs(5);
+
+/// Upper bound on the delay between retries of a failed job.
+const RETRY_MAX: Duration = Duration::from_secs(3600);
+
+type Handler = Arc<dyn Fn(Value) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// A queue of background jobs stored in the database, with registered handlers.
+#[derive(Clone)]
+pub struct Jobs {
+    database: Database,
+    handlers: Arc<RwLock<HashMap<&'static str, Handler>>>,
+}
+
+impl Jobs {
+    /// Create a job queue backed by the given database.
+    pub fn new(database: Database) -> Self {
+        Self {
+            database,
+            handlers: Default::default(),
+        }
+    }
+
+    /// Register the handler that runs jobs of a given kind.
+    pub fn register<F, Fut>(&self, kind: &'static str, handler: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |payload| Box::pin(handler(payload)));
+        self.handlers.write().insert(kind, handler);
+    }
+
+    /// Run queued jobs until the server shuts down.
+    pub async fn worker(self) {
+        match self.database.requeue_running_jobs().await {
+            Ok(0) => {}
+            Ok(n) => info!("requeued {} jobs interrupted by a restart", n),
+            Err(e) => error!("when requeueing interrupted jobs: {}", e),
+        }
+        loop {
+            match self.run_next().await {
+                Ok(true) => {}
+                Ok(false) => time::sleep(POLL_INTERVAL).await,
+                Err(e) => {
+                    error!("when running background job: {}", e);
+                    time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Claim and run a single due job, returning false if none was ready.
+    async fn run_next(&self) -> Result<bool> {
+        let job = match self.database.claim_job().await? {
+            Some(job) => job,
+            None => return Ok(false),
+        };
+        let handler = self.handlers.read().get(job.kind.as_str()).cloned();
+        let result = match handler {
+            Some(handler) => match serde_json::from_str(&job.payload) {
+                Ok(payload) => handler(payload).await,
+                Err(e) => Err(e.into()),
+            },
+            None => Err(anyhow!("no handler registered for job kind {}", job.kind)),
+        };
+        match result {
+            Ok(()) => self.database.complete_job(job.id).await?,
+            Err(e) => {
+                let attempts = job.attempts + 1;
+                let retry = (attempts < job.max_attempts).then(|| backoff(attempts));
+                warn!(
+                    "job {} ({}) failed on attempt {}, retry in {:?}: {}",
+                    job.id, job.kind, attempts, retry, e
+                );
+                let retry_secs = retry.map(|delay| delay.as_secs() as i64);
+                self.database
+                    .fail_job(job.id, &e.to_string(), retry_secs)
+                    .await?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Delay before retrying a job that has failed `attempts` times.
+fn backoff(attempts: i64) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    std::cmp::min(RETRY_BASE * 2u32.pow(exponent), RETRY_MAX)
+}
@@ -0,0 +1,162 @@
+//! Mapping between file extensions and editor languages.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of an extension or language name in the mapping.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// File extensions and the editor language of files that have them.
+///
+/// The first extension listed for a language is its preferred one.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("bat", "bat"),
+    ("c", "c"),
+    ("h", "c"),
+    ("clj", "clojure"),
+    ("coffee", "coffeescript"),
+    ("cpp", "cpp"),
+    ("cc", "cpp"),
+    ("hpp", "cpp"),
+    ("cs", "csharp"),
+    ("css", "css"),
+    ("dart", "dart"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("fs", "fsharp"),
+    ("go", "go"),
+    ("graphql", "graphql"),
+    ("hcl", "hcl"),
+    ("tf", "hcl"),
+    ("html", "html"),
+    ("htm", "html"),
+    ("ini", "ini"),
+    ("java", "java"),
+    ("js", "javascript"),
+    ("mjs", "javascript"),
+    ("jsx", "javascript"),
+    ("json", "json"),
+    ("jl", "julia"),
+    ("kt", "kotlin"),
+    ("less", "less"),
+    ("lua", "lua"),
+    ("md", "markdown"),
+    ("markdown", "markdown"),
+    ("m", "objective-c"),
+    ("pas", "pascal"),
+    ("pl", "perl"),
+    ("php", "php"),
+    ("txt", "plaintext"),
+    ("ps1", "powershell"),
+    ("proto", "proto"),
+    ("py", "python"),
+    ("r", "r"),
+    ("rst", "restructuredtext"),
+    ("rb", "ruby"),
+    ("rs", "rust"),
+    ("scala", "scala"),
+    ("scm", "scheme"),
+    ("scss", "scss"),
+    ("sh", "shell"),
+    ("bash", "shell"),
+    ("sol", "sol"),
+    ("sql", "sql"),
+    ("swift", "swift"),
+    ("sv", "systemverilog"),
+    ("tcl", "tcl"),
+    ("ts", "typescript"),
+    ("tsx", "typescript"),
+    ("vb", "vb"),
+    ("v", "verilog"),
+    ("xml", "xml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+];
+
+/// A file extension and the editor language of files that have it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LanguageExtension {
+    /// File extension, without the leading dot.
+    pub extension: String,
+    /// Editor language of files with the extension.
+    pub language: String,
+}
+
+/// Mapping between file extensions and editor languages, which admins may
+/// replace to add languages of their own.
+///
+/// The first extension listed for a language is its preferred one.
+#[derive(Clone, Debug)]
+pub struct LanguageMap {
+    extensions: Vec<LanguageExtension>,
+}
+
+impl Default for LanguageMap {
+    fn default() -> Self {
+        let extensions = EXTENSIONS
+            .iter()
+            .map(|&(extension, language)| LanguageExtension {
+                extension: extension.into(),
+                language: language.into(),
+            })
+            .collect();
+        Self { extensions }
+    }
+}
+
+impl LanguageMap {
+    /// Builds a mapping from a list of extensions, or the built-in mapping if
+    /// the list is empty.
+    pub fn new(extensions: Vec<LanguageExtension>) -> Self {
+        if extensions.is_empty() {
+            return Self::default();
+        }
+        Self { extensions }
+    }
+
+    /// Returns the extensions in the mapping, in order.
+    pub fn extensions(&self) -> &[LanguageExtension] {
+        &self.extensions
+    }
+
+    /// Returns the editor language for a file name, based on its extension.
+    pub fn from_filename(&self, filename: &str) -> Option<&str> {
+        if filename.eq_ignore_ascii_case("dockerfile") {
+            return Some("dockerfile");
+        }
+        let (_, extension) = filename.rsplit_once('.')?;
+        self.extensions
+            .iter()
+            .find(|entry| entry.extension.eq_ignore_ascii_case(extension))
+            .map(|entry| entry.language.as_str())
+    }
+
+    /// Returns the preferred file extension for an editor language.
+    pub fn extension_for(&self, language: &str) -> Option<&str> {
+        self.extensions
+            .iter()
+            .find(|entry| entry.language == language)
+            .map(|entry| entry.extension.as_str())
+    }
+}
+
+/// Returns whether a list of extensions can be used as a mapping, with valid
+/// names and no extension listed twice.
+pub fn is_valid(extensions: &[LanguageExtension]) -> bool {
+    let mut seen = HashSet::new();
+    extensions.iter().all(|entry| {
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || "-_+".contains(c);
+        valid_name(&entry.extension)
+            && entry.extension.chars().all(valid_char)
+            && valid_name(&entry.language)
+            && !entry.language.contains(char::is_whitespace)
+            && seen.insert(entry.extension.to_ascii_lowercase())
+    })
+}
+
+/// Returns whether a name is non-empty, not too long, and has no control
+/// characters.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LENGTH && !name.contains(char::is_control)
+}
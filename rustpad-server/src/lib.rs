@@ -3,21 +3,87 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use dashmap::DashMap;
-use log::{error, info};
+use futures::TryStreamExt;
+use log::{error, info, warn};
+use operational_transform::OperationSeq;
+use parking_lot::RwLock;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 use tokio::time::{self, Instant};
-use warp::{filters::BoxedFilter, http::StatusCode, ws::Ws, Filter, Rejection, Reply};
+use warp::multipart::{FormData, Part};
+use warp::http::{HeaderMap, HeaderValue, StatusCode};
+use warp::{filters::BoxedFilter, ws::Ws, Buf, Filter, Rejection, Reply};
 
-use crate::{database::Database, rustpad::Rustpad};
+use crate::{
+    access::{AccessConfig, AccessVerifier, Identity},
+    cache::CacheStats,
+    database::{
+        AclEntry, ApiKey, ArchiveFilter, BulkAction, Database, DocumentEvent, DocumentMeta,
+        DocumentSettings, Job, ListOptions, MetadataUpdate, NewDocument, PersistedDocument,
+        PublishedDocument, Relation, RelationKind, Report, Role, SortOrder, StatsSample,
+        StoredOperation, UserRole,
+    },
+    diff::{self, Patch},
+    feed::FeedFilter,
+    jobs::{ActivityJob, Jobs, UserColorJob, RECORD_ACTIVITY, SAVE_USER_COLOR},
+    languages::{LanguageExtension, LanguageMap},
+    messages::Notice,
+    metrics::{RouteMetrics, RouteStats},
+    normalize::Normalization,
+    oidc::{OidcConfig, OidcVerifier},
+    pdf::PdfWriter,
+    rustpad::{
+        receive_password, refuse_password, AuthoredEdit, ClientAgent, LineEdit, RejectionStats,
+        Rustpad, Session, SocketConfig, SocketMetrics, SocketStats, TextSnapshot,
+        MAX_DOCUMENT_SIZE, PROTOCOL_VERSION,
+    },
+    share::{ShareClaims, ShareScope, ShareSigner},
+    signing::EditSigner,
+    standby::{Standby, StandbyConfig},
+    status::StatusPage,
+    timeline::Timeline,
+    zip::ZipWriter,
+};
 
+pub mod access;
+mod cache;
 pub mod database;
+mod detect;
+mod diff;
+mod export;
+mod feed;
+mod jobs;
+mod languages;
+pub mod messages;
+mod metrics;
+pub mod normalize;
+pub mod oidc;
 mod ot;
+mod outbox;
+mod password;
+mod pdf;
 mod rustpad;
+mod share;
+pub mod signing;
+pub mod standby;
+mod status;
+mod timeline;
+mod timestamps;
+mod zip;
+
+#[cfg(feature = "protocol-tests")]
+pub use rustpad::protocol_tests;
+#[cfg(feature = "simulation")]
+pub use rustpad::simulation;
 
 /// An entry stored in the global server map.
 ///
@@ -50,6 +116,19 @@ struct CustomReject(anyhow::Error);
 
 impl warp::reject::Reject for CustomReject {}
 
+/// Rejection for requests without a valid bearer token.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Rejection for requests by users whose role on a document does not allow
+/// them.
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
 /// The shared state of the server, accessible from within request handlers.
 #[derive(Clone)]
 struct ServerState {
@@ -57,6 +136,125 @@ struct ServerState {
     documents: Arc<DashMap<String, Document>>,
     /// Connection to the database pool.
     database: Database,
+    /// Held while a database maintenance task is running.
+    maintenance: Arc<tokio::sync::Mutex<()>>,
+    /// Counters updated by the background cleaner task.
+    cleaner_metrics: Arc<CleanerMetrics>,
+    /// Request counts and latencies for each API route.
+    route_metrics: Arc<RouteMetrics>,
+    /// Connection and traffic counters for WebSockets.
+    socket_metrics: Arc<SocketMetrics>,
+    /// Document events delivered from the outbox, for live feeds.
+    events: broadcast::Sender<DocumentEvent>,
+    /// Clients recently disconnected for sending oversized messages.
+    offenders: Arc<Offenders>,
+    /// Clients that recently filed abuse reports.
+    reporters: Arc<Reporters>,
+    /// When numbered versions of documents are stored.
+    versions: VersionPolicy,
+    /// Normalization applied to exported text.
+    normalization: Normalization,
+    /// Mapping between file extensions and languages, for imports and exports.
+    language_map: Arc<RwLock<LanguageMap>>,
+    /// Verifier of Cloudflare Access tokens, if the email header isn't trusted.
+    access: Option<Arc<AccessVerifier>>,
+    /// Verifier of bearer tokens from an OpenID Connect provider, if required.
+    oidc: Option<Arc<OidcVerifier>>,
+    /// Whether routes that create, delete, import or change documents other
+    /// than by editing their text need an API key.
+    require_api_key: bool,
+    /// Hash of the operator token accepted on admin routes, if configured.
+    admin_token: Option<String>,
+    /// Signer of share links to documents.
+    shares: Arc<ShareSigner>,
+    /// State of following the primary, if this server is a warm standby.
+    standby: Option<Arc<Standby>>,
+}
+
+/// Clients that sent messages over the size limit, refused new connections
+/// once they do so repeatedly.
+#[derive(Default)]
+struct Offenders {
+    /// Number of strikes and time of the first one, by client.
+    strikes: DashMap<String, (u32, Instant)>,
+}
+
+impl Offenders {
+    /// Records an oversized message from a client.
+    fn record(&self, client: &str) {
+        if self.strikes.len() >= MAX_OFFENDERS {
+            self.strikes.retain(|_, (_, first)| first.elapsed() < OFFENDER_WINDOW);
+        }
+        let mut entry = self
+            .strikes
+            .entry(client.to_owned())
+            .or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= OFFENDER_WINDOW {
+            *entry = (0, Instant::now());
+        }
+        entry.0 += 1;
+    }
+
+    /// Returns if a client has too many recent strikes to connect.
+    fn blocked(&self, client: &str) -> bool {
+        self.strikes.get(client).map_or(false, |entry| {
+            entry.0 >= OFFENDER_STRIKES && entry.1.elapsed() < OFFENDER_WINDOW
+        })
+    }
+}
+
+/// Clients that recently filed abuse reports, limited to a few each.
+#[derive(Default)]
+struct Reporters {
+    /// Number of reports and time of the first one, by client.
+    reports: DashMap<String, (u32, Instant)>,
+}
+
+impl Reporters {
+    /// Records a report from a client, or returns false if the client has
+    /// filed too many recently.
+    fn try_record(&self, client: &str) -> bool {
+        if self.reports.len() >= MAX_REPORTERS {
+            self.reports
+                .retain(|_, (_, first)| first.elapsed() < REPORT_WINDOW);
+        }
+        let mut entry = self
+            .reports
+            .entry(client.to_owned())
+            .or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= REPORT_WINDOW {
+            *entry = (0, Instant::now());
+        }
+        if entry.0 >= REPORT_LIMIT {
+            return false;
+        }
+        entry.0 += 1;
+        true
+    }
+}
+
+/// Counters describing the work done by the cleaner task.
+#[derive(Default)]
+struct CleanerMetrics {
+    /// Number of full passes started over the document map.
+    passes: AtomicU64,
+    /// Number of documents evicted from memory.
+    evicted: AtomicU64,
+    /// Duration of the most recent cleaner tick, in microseconds.
+    last_tick_micros: AtomicU64,
+    /// Longest cleaner tick observed, in microseconds.
+    max_tick_micros: AtomicU64,
+}
+
+impl CleanerMetrics {
+    fn snapshot(&self) -> CleanerStats {
+        CleanerStats {
+            passes: self.passes.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            last_tick_micros: self.last_tick_micros.load(Ordering::Relaxed),
+            max_tick_micros: self.max_tick_micros.load(Ordering::Relaxed),
+        }
+    }
 }
 
 /// Statistics about the server, returned from an API endpoint.
@@ -68,313 +266,5391 @@ struct Stats {
     num_documents: usize,
     /// Number of documents persisted in the database.
     database_size: usize,
+    /// Work done by the background cleaner task.
+    cleaner: CleanerStats,
+    /// Edits rejected by documents currently in memory, by category.
+    rejected_edits: RejectionStats,
+    /// Request counts, error counts and latencies, by method and route.
+    routes: BTreeMap<String, RouteStats>,
+    /// WebSocket connections and traffic.
+    sockets: SocketStats,
+    /// Hits and misses of the cache for document metadata queries.
+    cache: CacheStats,
 }
 
-/// Request body for creating a new document.
-#[derive(Deserialize)]
-struct CreateDocumentRequest {
-    name: Option<String>,
+/// Build and protocol information, returned from an API endpoint.
+#[derive(Serialize)]
+struct VersionInfo {
+    /// Version of the server crate.
+    version: &'static str,
+    /// Git commit the server was built from.
+    commit: &'static str,
+    /// Version of the WebSocket message protocol.
+    protocol: u32,
+    /// Optional capabilities supported by this server.
+    features: &'static [&'static str],
 }
 
-/// Request body for renaming a document.
-#[derive(Deserialize)]
-struct RenameDocumentRequest {
-    name: String,
+/// Optional subsystems and limits of this server, for clients to adapt to.
+#[derive(Serialize)]
+struct Capabilities {
+    /// How users are identified: `oidc` with bearer tokens from an OpenID
+    /// Connect provider, `cloudflare-access` with verified Cloudflare Access
+    /// tokens, or `none` if the identity headers are trusted as they are.
+    auth: &'static str,
+    /// Whether changing documents requires an API key.
+    api_keys: bool,
+    /// Whether documents can be end-to-end encrypted.
+    e2ee: bool,
+    /// Whether code in documents can be executed on the server.
+    exec: bool,
+    /// Whether documents have a chat channel.
+    chat: bool,
+    /// Whether comments can be attached to documents.
+    comments: bool,
+    /// Whether authenticated clients can sign their edits.
+    signing: bool,
+    /// Whether stored documents can be searched.
+    search: bool,
+    /// Size limits enforced by the server.
+    limits: Limits,
 }
 
-/// Response for user identity endpoint.
+/// Size limits enforced by the server, returned as part of [`Capabilities`].
 #[derive(Serialize)]
-struct UserIdentityResponse {
-    email: Option<String>,
+struct Limits {
+    /// Maximum length of a document, in characters.
+    document_size: usize,
+    /// Maximum number of documents in a bulk operation.
+    bulk_ids: usize,
+    /// Maximum length of a tag, in characters.
+    tag_length: usize,
+    /// Maximum size of the custom metadata of a document, in bytes.
+    metadata_size: usize,
 }
 
-/// Response for delete all documents endpoint.
+/// Optional capabilities that clients can check for in [`VersionInfo`].
+const FEATURES: &[&str] = &[
+    "checksums",
+    "signing",
+    "search",
+    "tags",
+    "folders",
+    "trash",
+    "metadata",
+    "export",
+    "relations",
+    "localized-errors",
+    "custom-ids",
+    "line-edits",
+    "stars",
+    "patches",
+    "archive",
+    "raw",
+    "document-stats",
+    "etags",
+    "settings",
+    "ranges",
+    "stats-history",
+    "sessions",
+    "feed",
+    "activity",
+    "revert",
+    "diff",
+    "reports",
+    "blame",
+    "publish",
+    "versions",
+];
+
+/// Snapshot of cleaner metrics, returned as part of [`Stats`].
 #[derive(Serialize)]
-struct DeleteAllResponse {
-    deleted: u64,
+struct CleanerStats {
+    passes: u64,
+    evicted: u64,
+    last_tick_micros: u64,
+    max_tick_micros: u64,
 }
 
-/// Server configuration.
-#[derive(Clone, Debug)]
-pub struct ServerConfig {
-    /// Number of days to clean up documents after inactivity.
-    pub expiry_days: u32,
-    /// Database object for persistence.
-    pub database: Database,
+/// Query parameters for the `/api/socket/{id}` endpoint.
+#[derive(Deserialize)]
+struct SocketQuery {
+    /// Version of the client application, for tracking protocol rollouts.
+    client_version: Option<String>,
+    /// Password of a protected document, unless sent as the first message.
+    password: Option<String>,
+    /// Share token for the document, which takes the place of its password.
+    token: Option<String>,
 }
 
-
-/// A combined filter handling all server routes.
-pub fn server(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
-    warp::path("api")
-        .and(backend(config))
-        .or(frontend())
-        .boxed()
+/// Query parameters carrying a bearer token, for clients such as browser
+/// WebSockets that cannot set an `Authorization` header.
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
 }
 
-/// Construct routes for static files from React.
-fn frontend() -> BoxedFilter<(impl Reply,)> {
-    warp::fs::dir("dist").boxed()
+/// Query parameters with the credentials for a document protected by a
+/// password, on its REST routes.
+#[derive(Deserialize)]
+struct DocumentKeyQuery {
+    /// Password of the document, unless sent in the `X-Document-Password`
+    /// header.
+    password: Option<String>,
+    /// Share token for the document, which takes the place of its password.
+    token: Option<String>,
 }
 
-/// Construct backend routes, including WebSocket handlers.
-fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
-    let state = ServerState {
-        documents: Default::default(),
-        database: config.database,
-    };
-    tokio::spawn(cleaner(state.clone(), config.expiry_days));
+/// Credentials that a request carries for a document protected by a password.
+struct DocumentKey {
+    password: Option<String>,
+    token: Option<String>,
+}
 
-    let state_filter = warp::any().map(move || state.clone());
+/// Maximum length of a user agent or client version kept for a connection.
+const MAX_CLIENT_AGENT_LENGTH: usize = 256;
 
-    let socket = warp::path!("socket" / String)
-        .and(warp::ws())
-        .and(warp::header::optional::<String>("cf-access-authenticated-user-email"))
-        .and(state_filter.clone())
-        .and_then(socket_handler);
+/// Query parameters for the `/api/text/{id}` endpoint.
+#[derive(Deserialize)]
+struct TextQuery {
+    /// Return JSON with the revision and language instead of plain text.
+    #[serde(default)]
+    with_revision: bool,
+    /// Return the text at this earlier revision, rebuilt from the operation
+    /// history, instead of the latest text.
+    revision: Option<usize>,
+}
 
-    let user_identity = warp::path!("user-identity")
-        .and(warp::get())
-        .and(warp::header::optional::<String>("cf-access-authenticated-user-email"))
-        .map(|email: Option<String>| {
-            warp::reply::json(&UserIdentityResponse { email })
-        });
+/// Query parameters for the `/api/published/{id}` endpoint.
+#[derive(Deserialize)]
+struct PublishedQuery {
+    /// Return JSON with the revision and language instead of plain text.
+    #[serde(default)]
+    with_revision: bool,
+}
 
-    let text = warp::path!("text" / String)
-        .and(state_filter.clone())
-        .and_then(text_handler);
+/// Query parameters for the `/api/stats/history` endpoint.
+#[derive(Deserialize)]
+struct StatsHistoryQuery {
+    /// Number of hours of samples to return, up to [`STATS_HISTORY_HOURS`].
+    #[serde(default = "default_history_hours")]
+    hours: u32,
+}
 
-    let start_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("SystemTime returned before UNIX_EPOCH")
-        .as_secs();
-    let stats = warp::path!("stats")
-        .and(warp::any().map(move || start_time))
-        .and(state_filter.clone())
-        .and_then(stats_handler);
+fn default_history_hours() -> u32 {
+    24
+}
 
-    let list_docs = warp::path!("documents")
-        .and(warp::get())
-        .and(state_filter.clone())
-        .and_then(list_documents_handler);
+/// Query parameters for the `/api/documents/{id}/diff` endpoint.
+#[derive(Deserialize)]
+struct DiffQuery {
+    /// Revision to diff from.
+    from: usize,
+    /// Revision to diff to, defaulting to the latest.
+    to: Option<usize>,
+}
 
-    let create_doc = warp::path!("documents")
-        .and(warp::post())
-        .and(warp::body::json())
-        .and(state_filter.clone())
-        .and_then(create_document_handler);
+/// Request body for filing an abuse report.
+#[derive(Deserialize)]
+struct ReportRequest {
+    /// Why the document is being reported.
+    reason: String,
+}
 
-    let get_doc = warp::path!("documents" / String)
-        .and(warp::get())
-        .and(state_filter.clone())
-        .and_then(get_document_handler);
+/// Query parameters for the `/api/admin/reports` endpoint.
+#[derive(Deserialize)]
+struct ReportsQuery {
+    /// List resolved reports instead of those awaiting review.
+    #[serde(default)]
+    resolved: bool,
+}
 
-    let rename_doc = warp::path!("documents" / String)
-        .and(warp::patch())
-        .and(warp::body::json())
-        .and(state_filter.clone())
-        .and_then(rename_document_handler);
+/// Query parameters for the `/api/activity` endpoint.
+#[derive(Deserialize)]
+struct ActivityQuery {
+    /// Only list edits persisted after this timestamp.
+    since: i64,
+    /// Maximum number of documents to return, up to [`MAX_ACTIVITY_LIMIT`].
+    #[serde(default = "default_activity_limit")]
+    limit: i64,
+}
 
-    let delete_doc = warp::path!("documents" / String)
-        .and(warp::delete())
-        .and(state_filter.clone())
-        .and_then(delete_document_handler);
+fn default_activity_limit() -> i64 {
+    100
+}
 
-    let delete_all_docs = warp::path!("documents" / "all")
-        .and(warp::delete())
-        .and(state_filter.clone())
-        .and_then(delete_all_documents_handler);
+/// Maximum number of documents returned by the activity endpoint.
+const MAX_ACTIVITY_LIMIT: i64 = 1000;
 
-    socket.or(text).or(stats).or(user_identity).or(list_docs).or(create_doc).or(delete_all_docs).or(get_doc).or(rename_doc).or(delete_doc).boxed()
+/// Query parameters for the `/api/search` endpoint.
+#[derive(Deserialize)]
+struct SearchQuery {
+    /// Search terms, all of which must match.
+    q: String,
+    /// Maximum number of results to return, up to [`MAX_SEARCH_LIMIT`].
+    #[serde(default = "default_search_limit")]
+    limit: i64,
 }
 
-/// Handler for the `/api/socket/{id}` endpoint.
-async fn socket_handler(
-    id: String,
-    ws: Ws,
-    cf_email: Option<String>,
-    state: ServerState,
-) -> Result<impl Reply, Rejection> {
-    use dashmap::mapref::entry::Entry;
+fn default_search_limit() -> i64 {
+    50
+}
 
-    let mut entry = match state.documents.entry(id.clone()) {
-        Entry::Occupied(e) => e.into_ref(),
-        Entry::Vacant(e) => {
-            let rustpad = Arc::new(match state.database.load(&id).await {
-                Ok(doc) => Rustpad::from_document(doc, state.database.clone()),
-                Err(_) => Rustpad::new(state.database.clone()),
-            });
-            // Load user colors from database
-            rustpad.load_colors().await;
-            tokio::spawn(persister(id.clone(), Arc::clone(&rustpad), state.database.clone()));
-            e.insert(Document::new(rustpad))
-        }
-    };
+/// Maximum number of results returned by the search endpoint.
+const MAX_SEARCH_LIMIT: i64 = 200;
 
-    let value = entry.value_mut();
-    value.last_accessed = Instant::now();
-    let rustpad = Arc::clone(&value.rustpad);
-    Ok(ws.on_upgrade(move |socket| async move { rustpad.on_connection(socket, cf_email).await }))
+/// Request body for creating a new document.
+#[derive(Deserialize)]
+struct CreateDocumentRequest {
+    /// Custom ID for the document, generated randomly if omitted.
+    id: Option<String>,
+    name: Option<String>,
 }
 
-/// Handler for the `/api/text/{id}` endpoint.
-async fn text_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    Ok(match state.documents.get(&id) {
-        Some(value) => value.rustpad.text(),
-        None => {
-            state.database.load(&id)
-                .await
-                .map(|document| document.text)
-                .unwrap_or_default()
-        }
-    })
-}
+/// Maximum length of a custom document ID.
+const MAX_DOCUMENT_ID_LENGTH: usize = 64;
 
-/// Handler for the `/api/stats` endpoint.
-async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
-    let num_documents = state.documents.len();
-    let database_size = match state.database.count().await {
-        Ok(size) => size,
-        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
-    };
-    Ok(warp::reply::json(&Stats {
-        start_time,
-        num_documents,
-        database_size,
-    }))
+/// Number of random IDs tried when creating a document before giving up.
+const MAX_CREATE_ATTEMPTS: usize = 8;
+
+/// Request body for renaming a document or updating its metadata.
+#[derive(Deserialize)]
+struct UpdateDocumentRequest {
+    name: Option<String>,
+    /// JSON merge patch applied to the custom metadata.
+    metadata: Option<serde_json::Value>,
+    /// Password required to connect to the document, or `null` to remove it.
+    #[serde(default, deserialize_with = "double_option")]
+    password: Option<Option<String>>,
 }
 
-/// Generate a random document ID.
-fn generate_document_id() -> String {
-    const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
-    let mut rng = rand::thread_rng();
-    (0..6)
-        .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
-        .collect()
+/// Request body for updating the settings of a document.
+///
+/// Omitted fields are left unchanged, and `null` clears optional ones.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateSettingsRequest {
+    read_only: Option<bool>,
+    #[serde(default, deserialize_with = "double_option")]
+    max_size: Option<Option<usize>>,
+    language_locked: Option<bool>,
+    #[serde(default, deserialize_with = "double_option")]
+    expires_at: Option<Option<i64>>,
+    normalize_newlines: Option<bool>,
 }
 
-/// Handler for the GET `/api/documents` endpoint.
-async fn list_documents_handler(state: ServerState) -> Result<impl Reply, Rejection> {
-    match state.database.list().await {
-        Ok(documents) => Ok(warp::reply::json(&documents)),
-        Err(e) => {
-            error!("Failed to list documents: {}", e);
-            Err(warp::reject::custom(CustomReject(e)))
+impl UpdateSettingsRequest {
+    /// Apply the given fields to a document's settings.
+    fn apply(self, settings: &mut DocumentSettings) {
+        if let Some(read_only) = self.read_only {
+            settings.read_only = read_only;
+        }
+        if let Some(max_size) = self.max_size {
+            settings.max_size = max_size;
+        }
+        if let Some(language_locked) = self.language_locked {
+            settings.language_locked = language_locked;
+        }
+        if let Some(expires_at) = self.expires_at {
+            settings.expires_at = expires_at;
+        }
+        if let Some(normalize_newlines) = self.normalize_newlines {
+            settings.normalize_newlines = normalize_newlines;
         }
     }
 }
 
-/// Handler for the POST `/api/documents` endpoint.
-async fn create_document_handler(
-    body: CreateDocumentRequest,
-    state: ServerState,
-) -> Result<impl Reply, Rejection> {
-    let id = generate_document_id();
-    match state.database.create(&id, body.name.as_deref()).await {
-        Ok(meta) => Ok(warp::reply::with_status(
-            warp::reply::json(&meta),
-            StatusCode::CREATED,
-        )),
-        Err(e) => {
-            error!("Failed to create document: {}", e);
-            Err(warp::reject::custom(CustomReject(e)))
-        }
-    }
+/// Deserialize a field that may be present but `null`, as `Some(None)`.
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
-/// Handler for the GET `/api/documents/{id}` endpoint.
-async fn get_document_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    match state.database.get_meta(&id).await {
-        Ok(Some(meta)) => Ok(warp::reply::json(&meta)),
-        Ok(None) => Err(warp::reject::not_found()),
-        Err(e) => {
-            error!("Failed to get document {}: {}", id, e);
-            Err(warp::reject::custom(CustomReject(e)))
-        }
-    }
+/// Request body for applying an action to many documents.
+#[derive(Deserialize)]
+struct BulkRequest {
+    #[serde(flatten)]
+    action: BulkAction,
+    ids: Vec<String>,
 }
 
-/// Handler for the PATCH `/api/documents/{id}` endpoint.
-async fn rename_document_handler(
-    id: String,
-    body: RenameDocumentRequest,
-    state: ServerState,
-) -> Result<impl Reply, Rejection> {
-    if let Err(e) = state.database.rename(&id, &body.name).await {
-        error!("Failed to rename document {}: {}", id, e);
-        return Err(warp::reject::custom(CustomReject(e)));
-    }
-    match state.database.get_meta(&id).await {
-        Ok(Some(meta)) => Ok(warp::reply::json(&meta)),
-        Ok(None) => Err(warp::reject::not_found()),
-        Err(e) => Err(warp::reject::custom(CustomReject(e)))
-    }
+/// Response for a bulk document operation.
+#[derive(Serialize)]
+struct BulkResponse {
+    /// Number of documents that were changed.
+    changed: u64,
 }
 
-/// Handler for the DELETE `/api/documents/{id}` endpoint.
-async fn delete_document_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    state.documents.remove(&id);
+/// Maximum number of documents in a single bulk operation.
+const MAX_BULK_IDS: usize = 1000;
 
-    match state.database.soft_delete(&id).await {
-        Ok(()) => Ok(StatusCode::NO_CONTENT),
-        Err(e) => {
-            error!("Failed to delete document {}: {}", id, e);
-            Err(warp::reject::custom(CustomReject(e)))
-        }
-    }
+/// Maximum total size of a multipart import request, in bytes.
+const MAX_IMPORT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Output formats of the document export endpoint.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    /// Rendered HTML fragment, safe to embed in another page.
+    #[default]
+    Html,
+    /// Raw markdown source.
+    Markdown,
+    /// Paginated PDF of the text in a monospace font.
+    Pdf,
 }
 
-/// Handler for the DELETE `/api/documents/all` endpoint.
-async fn delete_all_documents_handler(state: ServerState) -> Result<impl Reply, Rejection> {
-    // Clear all in-memory documents
-    state.documents.clear();
+/// Query parameters for the document export endpoint.
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
 
-    match state.database.delete_all_documents().await {
-        Ok(deleted) => Ok(warp::reply::json(&DeleteAllResponse { deleted })),
-        Err(e) => {
-            error!("Failed to delete all documents: {}", e);
-            Err(warp::reject::custom(CustomReject(e)))
-        }
-    }
+/// Hash algorithms of the raw document endpoint.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum HashAlgorithm {
+    /// SHA-256 of the UTF-8 text.
+    #[default]
+    Sha256,
 }
 
-const HOUR: Duration = Duration::from_secs(3600);
+/// Query parameters for the raw document endpoint.
+#[derive(Deserialize)]
+struct RawQuery {
+    #[serde(default)]
+    hash: HashAlgorithm,
+}
 
-/// Reclaims memory for documents.
-async fn cleaner(state: ServerState, expiry_days: u32) {
+/// Query parameters for deleting a document.
+#[derive(Deserialize)]
+struct DeleteQuery {
+    /// Permanently delete the document instead of moving it to the trash.
+    #[serde(default)]
+    purge: bool,
+}
+
+/// Request body for moving a document between folders.
+#[derive(Deserialize)]
+struct MoveDocumentRequest {
+    /// Destination folder, or `None` for the top level.
+    folder_id: Option<i64>,
+}
+
+/// Response body of the document statistics endpoint.
+#[derive(Serialize)]
+struct DocumentStats {
+    chars: usize,
+    bytes: usize,
+    words: usize,
+    lines: usize,
+    revision: usize,
+    /// Number of clients connected to the document.
+    users: usize,
+}
+
+/// Request body for creating or updating a folder.
+#[derive(Deserialize)]
+struct FolderRequest {
+    name: String,
+    /// Parent folder, or `None` for the top level.
+    parent_id: Option<i64>,
+}
+
+/// Request body for minting an API key.
+#[derive(Deserialize)]
+struct ApiKeyRequest {
+    /// Label describing what the key is used for.
+    name: String,
+}
+
+/// Response for minting an API key, the only time the key itself is shown.
+#[derive(Serialize)]
+struct NewApiKey {
+    #[serde(flatten)]
+    info: ApiKey,
+    key: String,
+}
+
+/// Request body for attaching or detaching a document tag.
+#[derive(Deserialize)]
+struct TagRequest {
+    tag: String,
+}
+
+/// Request body for transferring a document to another owner.
+#[derive(Deserialize)]
+struct TransferRequest {
+    email: String,
+}
+
+/// Request body for giving a user a role on a document.
+#[derive(Deserialize)]
+struct AclRequest {
+    email: String,
+    role: Role,
+}
+
+/// Request body for removing a user from the access control list of a
+/// document.
+#[derive(Deserialize)]
+struct AclRemoveRequest {
+    email: String,
+}
+
+/// Request body for linking or unlinking two documents.
+#[derive(Deserialize)]
+struct RelationRequest {
+    kind: RelationKind,
+    /// Document that the relation points to.
+    target: String,
+}
+
+/// A single document's metadata along with its relations.
+#[derive(Serialize)]
+struct DocumentDetails {
+    #[serde(flatten)]
+    meta: DocumentMeta,
+    relations: Vec<Relation>,
+}
+
+/// Maximum length of a document tag, in characters.
+const MAX_TAG_LENGTH: usize = 64;
+
+/// Maximum size of the custom metadata of a document, as serialized JSON.
+const MAX_METADATA_SIZE: usize = 16 * 1024;
+
+/// Response for user identity endpoint.
+#[derive(Serialize)]
+struct UserIdentityResponse {
+    email: Option<String>,
+}
+
+/// Response for replacing or appending to the text of a document.
+#[derive(Serialize)]
+struct ReplaceTextResponse {
+    /// Revision of the document after the replacement.
+    revision: usize,
+}
+
+/// Query parameters for the `/api/documents/{id}/publish` endpoint.
+#[derive(Deserialize)]
+struct PublishQuery {
+    /// Time to publish the document at, instead of right away.
+    publish_at: Option<i64>,
+    /// Time to withdraw the published version at.
+    unpublish_at: Option<i64>,
+}
+
+/// Request body for minting a share link to a document.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShareRequest {
+    /// What the link allows, `read` by default.
+    #[serde(default = "default_share_scope")]
+    scope: ShareScope,
+    /// Time the link expires at, a day from now by default.
+    expires_at: Option<i64>,
+}
+
+fn default_share_scope() -> ShareScope {
+    ShareScope::Read
+}
+
+/// Response for minting a share link to a document.
+#[derive(Serialize)]
+struct ShareResponse {
+    /// Token to pass in the `token` query parameter.
+    token: String,
+    /// What the link allows.
+    scope: ShareScope,
+    /// Time the link expires at.
+    expires_at: i64,
+}
+
+/// Default lifetime of a share link.
+const DEFAULT_SHARE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest lifetime of a share link.
+const MAX_SHARE_EXPIRY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Response for publishing a document.
+#[derive(Serialize)]
+struct PublishResponse {
+    /// Revision of the document that was published, unless it is scheduled.
+    revision: Option<usize>,
+    /// Timestamp when the document was published, unless it is scheduled.
+    published_at: Option<i64>,
+    /// Time the document is scheduled to be published at.
+    publish_at: Option<i64>,
+    /// Time the published version is scheduled to be withdrawn at.
+    unpublish_at: Option<i64>,
+}
+
+/// Request body for reverting a document to an earlier revision.
+#[derive(Deserialize)]
+struct RevertRequest {
+    /// Revision whose text to restore.
+    revision: usize,
+}
+
+/// Response for a patch whose hunks do not match the document.
+#[derive(Serialize)]
+struct PatchConflict {
+    /// Numbers of the hunks that failed to apply, counted from 1.
+    failed_hunks: Vec<usize>,
+}
+
+/// Response for the document pre-warm endpoint.
+#[derive(Serialize)]
+struct WarmResponse {
+    /// Whether the document was already loaded in memory.
+    already_loaded: bool,
+    /// Current revision of the in-memory document.
+    revision: usize,
+}
+
+/// Response for the document persist endpoint.
+#[derive(Serialize)]
+struct PersistResponse {
+    /// Revision of the in-memory document that was stored.
+    revision: usize,
+}
+
+/// Rejected edit counts for a single in-memory document.
+#[derive(Serialize)]
+struct DocumentRejections {
+    id: String,
+    rejections: RejectionStats,
+}
+
+/// Number of open connections from clients reporting one version.
+#[derive(Serialize)]
+struct ClientVersionCount {
+    /// Version of the client, or `None` for clients that reported none.
+    client_version: Option<String>,
+    connections: usize,
+}
+
+/// A document currently loaded in memory.
+#[derive(Serialize)]
+struct ActiveDocument {
+    id: String,
+    /// Seconds since the document was last accessed.
+    idle_secs: u64,
+    /// Seconds until the cleaner may evict the document, if not accessed again.
+    evict_in_secs: u64,
+    revision: usize,
+    /// Number of clients connected to the document.
+    users: usize,
+}
+
+/// Status of a warm standby.
+#[derive(Serialize)]
+struct StandbyStatus {
+    /// Base URL of the primary being followed.
+    primary: String,
+    /// Whether the standby has been promoted to serve on its own.
+    promoted: bool,
+    /// Number of documents being replicated from the primary.
+    followed: usize,
+}
+
+/// All data held about a single user, for subject access requests.
+#[derive(Serialize)]
+struct UserExport {
+    email: String,
+    /// Time at which the export was generated.
+    exported_at: i64,
+    /// Saved color preference, if any.
+    color: Option<u32>,
+    /// IDs of the documents starred by the user.
+    starred: Vec<String>,
+    /// IDs of the documents owned by the user.
+    owned: Vec<String>,
+    /// Roles of the user on documents with an access control list.
+    roles: Vec<UserRole>,
+    /// Abuse reports filed by the user.
+    reports: Vec<Report>,
+    /// Edits by the user, from the stored history of documents and from
+    /// in-memory documents.
+    edits: Vec<DocumentEdits>,
+}
+
+/// Edits by a single user to one document.
+#[derive(Serialize)]
+struct DocumentEdits {
+    id: String,
+    edits: Vec<AuthoredEdit>,
+}
+
+/// Response for deleting a user's data.
+#[derive(Serialize)]
+struct UserDeletion {
+    /// Number of database rows removed.
+    rows_deleted: u64,
+    /// Number of edits whose author was removed from in-memory history.
+    edits_anonymized: usize,
+}
+
+/// Response for anonymizing a user's data.
+#[derive(Serialize)]
+struct UserAnonymization {
+    /// Pseudonym that replaced the user's email.
+    pseudonym: String,
+    /// Number of database rows rewritten.
+    rows_updated: u64,
+    /// Number of edits attributed to the pseudonym in in-memory history.
+    edits_anonymized: usize,
+}
+
+/// Response for the background jobs status endpoint.
+#[derive(Serialize)]
+struct JobsResponse {
+    /// Number of jobs in each status.
+    counts: HashMap<String, i64>,
+    /// The most recently enqueued jobs.
+    recent: Vec<Job>,
+}
+
+/// Response for delete all documents endpoint.
+#[derive(Serialize)]
+struct DeleteAllResponse {
+    deleted: u64,
+}
+
+/// Server configuration.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Number of days to clean up documents after inactivity.
+    pub expiry_days: u32,
+    /// Time between incremental cleaner ticks.
+    pub cleaner_interval: Duration,
+    /// Maximum number of in-memory documents examined per cleaner tick.
+    pub cleaner_batch_size: usize,
+    /// Number of days deleted documents are kept before being purged.
+    pub purge_after_days: u32,
+    /// Whether JSON responses include RFC 3339 copies of Unix timestamps.
+    pub rfc3339_timestamps: bool,
+    /// Whether to derive names for documents from their text when persisting.
+    pub derive_names: bool,
+    /// Time between samples of server activity recorded for the stats history.
+    pub stats_sample_interval: Duration,
+    /// Maximum size of a WebSocket message from a client, in bytes.
+    pub max_message_size: usize,
+    /// Label of the region this server runs in, sent to clients on connect.
+    pub region: Option<String>,
+    /// URLs of other instances of a deployment spanning regions, sent to
+    /// clients on connect so they can pick a closer one.
+    pub alternates: Vec<String>,
+    /// Security headers sent with the frontend.
+    pub security_headers: SecurityHeaders,
+    /// When numbered versions of documents are stored, and how many are kept.
+    pub versions: VersionPolicy,
+    /// Normalization applied to text that is exported or indexed for search.
+    pub normalization: Normalization,
+    /// Settings for verifying Cloudflare Access tokens on WebSocket
+    /// connections. If `None`, the email header from Access is trusted as is.
+    pub access: Option<AccessConfig>,
+    /// Settings for requiring a bearer token from an OpenID Connect provider
+    /// on all API requests. The identity from the token takes precedence over
+    /// Cloudflare Access on WebSocket connections.
+    pub oidc: Option<OidcConfig>,
+    /// Whether routes that create, delete, import or change documents other
+    /// than by editing their text need an API key, or a token from the
+    /// OpenID Connect provider if configured.
+    pub require_api_key: bool,
+    /// Bearer token of the operator, accepted on admin routes. If set, or if
+    /// API keys are required, admin routes refuse requests without a token.
+    pub admin_token: Option<String>,
+    /// Secret that share links are signed with. If `None`, a random secret is
+    /// used, and links stop working when the server restarts.
+    pub share_secret: Option<String>,
+    /// Secret that the keys for signing edits are derived from. If `None`, a
+    /// random secret is used, and stored signatures can no longer be checked
+    /// when the server restarts.
+    pub signing_secret: Option<String>,
+    /// Primary to follow as a warm standby, keeping documents read-only until
+    /// promoted. If `None`, the server serves on its own.
+    pub standby: Option<StandbyConfig>,
+    /// Database object for persistence.
+    pub database: Database,
+}
+
+
+/// Default value of the Referrer-Policy header, which keeps document URLs
+/// from leaking to the sites that documents link to.
+pub const DEFAULT_REFERRER_POLICY: &str = "no-referrer";
+
+/// Security headers sent with the frontend, for servers exposed directly
+/// rather than behind a hardened proxy.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    /// Content Security Policy of the frontend, if any.
+    pub content_security_policy: Option<String>,
+    /// Origins allowed to embed the frontend in a frame. If empty, framing is
+    /// denied entirely.
+    pub frame_ancestors: Vec<String>,
+    /// Value of the Referrer-Policy header.
+    pub referrer_policy: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            content_security_policy: None,
+            frame_ancestors: Vec::new(),
+            referrer_policy: DEFAULT_REFERRER_POLICY.into(),
+        }
+    }
+}
+
+/// Default time after which a changed document gets a new version.
+pub const DEFAULT_VERSION_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Default number of revisions after which a document gets a new version.
+pub const DEFAULT_VERSION_REVISIONS: usize = 1000;
+
+/// Default number of versions kept for each document.
+pub const DEFAULT_VERSIONS_KEPT: usize = 48;
+
+/// When the persister stores a numbered version of a changed document, for
+/// point-in-time recovery, and how many versions of each document are kept.
+#[derive(Clone, Copy, Debug)]
+pub struct VersionPolicy {
+    /// Time after which a changed document gets a new version.
+    pub interval: Duration,
+    /// Number of revisions after which a document gets a new version, even
+    /// before the interval has passed.
+    pub revisions: usize,
+    /// Number of versions kept for each document, dropping the oldest first.
+    /// No versions are stored if this is zero.
+    pub keep: usize,
+}
+
+impl Default for VersionPolicy {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_VERSION_INTERVAL,
+            revisions: DEFAULT_VERSION_REVISIONS,
+            keep: DEFAULT_VERSIONS_KEPT,
+        }
+    }
+}
+
+impl VersionPolicy {
+    /// Returns whether a document at `revision` is due a new version, given
+    /// the revision and time of its last one.
+    fn is_due(&self, revision: usize, last_revision: usize, last_time: Instant) -> bool {
+        self.keep > 0
+            && revision > last_revision
+            && (revision - last_revision >= self.revisions || last_time.elapsed() >= self.interval)
+    }
+}
+
+impl SecurityHeaders {
+    /// Builds the headers, skipping any with an invalid value.
+    ///
+    /// X-Frame-Options cannot name allowed origins, so it is only sent when
+    /// framing is denied, and the allowlist goes into the `frame-ancestors`
+    /// directive of the Content Security Policy instead.
+    fn header_map(&self) -> HeaderMap {
+        let frame_ancestors = if self.frame_ancestors.is_empty() {
+            "frame-ancestors 'none'".to_owned()
+        } else {
+            format!("frame-ancestors {}", self.frame_ancestors.join(" "))
+        };
+        let policy = match &self.content_security_policy {
+            Some(policy) => format!("{}; {}", policy.trim_end_matches(';'), frame_ancestors),
+            None => frame_ancestors,
+        };
+        let mut values = vec![
+            ("content-security-policy", policy),
+            ("referrer-policy", self.referrer_policy.clone()),
+        ];
+        if self.frame_ancestors.is_empty() {
+            values.push(("x-frame-options", "DENY".to_owned()));
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            match HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    headers.insert(name, value);
+                }
+                Err(_) => warn!("ignoring invalid value for the {} header", name),
+            }
+        }
+        headers
+    }
+}
+
+/// A combined filter handling all server routes.
+pub fn server(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
+    let headers = config.security_headers.header_map();
+    warp::path("api")
+        .and(backend(config))
+        .or(frontend().with(warp::reply::with::headers(headers)))
+        .boxed()
+}
+
+/// Construct routes for static files from React.
+#[cfg(not(feature = "embed-frontend"))]
+fn frontend() -> BoxedFilter<(impl Reply,)> {
+    warp::fs::dir("dist").boxed()
+}
+
+/// Static files from React, built into the binary.
+#[cfg(feature = "embed-frontend")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "../dist"]
+struct Assets;
+
+/// Construct routes for static files from React, embedded in the binary.
+#[cfg(feature = "embed-frontend")]
+fn frontend() -> BoxedFilter<(impl Reply,)> {
+    warp::get()
+        .and(warp::path::tail())
+        .and_then(|tail: warp::path::Tail| async move {
+            let path = match tail.as_str() {
+                "" => "index.html".to_owned(),
+                path if path.ends_with('/') => format!("{}index.html", path),
+                path => path.to_owned(),
+            };
+            let file = Assets::get(&path).ok_or_else(warp::reject::not_found)?;
+            let content_type = file.metadata.mimetype().to_owned();
+            let reply =
+                warp::reply::with_header(file.data.into_owned(), "content-type", content_type);
+            Ok::<_, Rejection>(reply)
+        })
+        .boxed()
+}
+
+/// Construct backend routes, including WebSocket handlers.
+fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
+    let jobs = Jobs::new(config.database.clone());
+    register_jobs(&jobs, &config.database);
+    tokio::spawn(jobs.clone().worker());
+
+    let (events, _) = broadcast::channel(256);
+    let started_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime returned before UNIX_EPOCH")
+        .as_secs() as i64;
+    tokio::spawn(outbox::dispatcher(config.database.clone(), events.clone(), started_at));
+
+    let auth = match (&config.oidc, &config.access) {
+        (Some(_), _) => "oidc",
+        (None, Some(_)) => "cloudflare-access",
+        (None, None) => "none",
+    };
+    let api_keys = config.require_api_key;
+
+    let state = ServerState {
+        documents: Default::default(),
+        database: config
+            .database
+            .with_derived_names(config.derive_names)
+            .with_normalization(config.normalization),
+        maintenance: Default::default(),
+        cleaner_metrics: Default::default(),
+        route_metrics: Default::default(),
+        socket_metrics: Default::default(),
+        events,
+        offenders: Default::default(),
+        reporters: Default::default(),
+        versions: config.versions,
+        normalization: config.normalization,
+        language_map: Default::default(),
+        access: config.access.map(AccessVerifier::new).map(Arc::new),
+        oidc: config.oidc.map(OidcVerifier::new).map(Arc::new),
+        require_api_key: config.require_api_key,
+        admin_token: config.admin_token.as_deref().map(hash_api_key),
+        shares: Arc::new(match &config.share_secret {
+            Some(secret) => ShareSigner::new(secret.as_bytes()),
+            None => ShareSigner::random(),
+        }),
+        standby: config.standby.map(Standby::new).map(Arc::new),
+    };
+    tokio::spawn(cleaner(
+        state.clone(),
+        config.expiry_days,
+        config.cleaner_interval,
+        config.cleaner_batch_size,
+    ));
+    tokio::spawn(purger(state.database.clone(), config.purge_after_days));
+    tokio::spawn(expirer(state.clone()));
+    tokio::spawn(publish_scheduler(state.clone()));
+    tokio::spawn(load_language_map(state.clone()));
+    tokio::spawn(stats_sampler(state.clone(), config.stats_sample_interval));
+    if let Some(standby) = &state.standby {
+        tokio::spawn(follow_primary(state.clone(), Arc::clone(standby)));
+    }
+
+    let rfc3339_timestamps = config.rfc3339_timestamps;
+    let route_metrics = Arc::clone(&state.route_metrics);
+    let track = warp::log::custom(move |info| {
+        route_metrics.record(info.method(), info.path(), info.status(), info.elapsed());
+    });
+
+    let state_filter = warp::any().map(move || state.clone());
+
+    let authenticate = warp::header::optional::<String>("authorization")
+        .and(warp::query::<TokenQuery>())
+        .and(state_filter.clone())
+        .and_then(authenticate_handler);
+
+    let api_key = warp::header::optional::<String>("authorization")
+        .and(state_filter.clone())
+        .and_then(require_api_key_handler)
+        .untuple_one();
+
+    let operator = warp::header::optional::<String>("authorization")
+        .and(state_filter.clone())
+        .and_then(require_operator_handler)
+        .untuple_one();
+
+    let requester = authenticate
+        .clone()
+        .and(warp::header::optional::<String>("cf-access-authenticated-user-email"))
+        .and(warp::header::optional::<String>("cf-access-jwt-assertion"))
+        .and(state_filter.clone())
+        .and_then(requester_handler);
+
+    let authenticated = authenticate.clone().map(|_: Option<Identity>| ()).untuple_one();
+
+    let document_key = warp::header::optional::<String>("x-document-password")
+        .and(warp::query::<DocumentKeyQuery>())
+        .map(|header: Option<String>, query: DocumentKeyQuery| DocumentKey {
+            password: header.or(query.password),
+            token: query.token,
+        });
+
+    let socket_config = Arc::new(SocketConfig {
+        max_message_size: config.max_message_size,
+        region: config.region.clone(),
+        alternates: config.alternates.clone(),
+        read_only: false,
+        signer: Arc::new(match &config.signing_secret {
+            Some(secret) => EditSigner::new(secret.as_bytes()),
+            None => EditSigner::random(),
+        }),
+    });
+    let socket = warp::path!("socket" / String)
+        .and(warp::ws())
+        .and(authenticate)
+        .and(warp::query::<SocketQuery>())
+        .and(warp::header::optional::<String>("cf-access-authenticated-user-email"))
+        .and(warp::header::optional::<String>("cf-access-jwt-assertion"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::addr::remote())
+        .and(warp::any().map(move || Arc::clone(&socket_config)))
+        .and(state_filter.clone())
+        .and_then(socket_handler);
+
+    let feed = warp::path!("feed")
+        .and(warp::ws())
+        .and(warp::query::<FeedFilter>())
+        .and(state_filter.clone())
+        .and_then(feed_handler);
+
+    let activity = warp::path!("activity")
+        .and(warp::get())
+        .and(warp::query::<ActivityQuery>())
+        .and(state_filter.clone())
+        .and_then(activity_handler);
+
+    let user_identity = warp::path!("user-identity")
+        .and(warp::get())
+        .and(requester.clone())
+        .map(|email: Option<String>| {
+            warp::reply::json(&UserIdentityResponse { email })
+        });
+
+    let text = warp::path!("text" / String)
+        .and(warp::get())
+        .and(warp::query::<TextQuery>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("range"))
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(text_handler);
+
+    let replace_text = warp::path!("text" / String)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(4 * MAX_DOCUMENT_SIZE as u64))
+        .and(warp::body::bytes())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(replace_text_handler);
+
+    let append_text = warp::path!("text" / String / "append")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4 * MAX_DOCUMENT_SIZE as u64))
+        .and(warp::body::bytes())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(append_text_handler);
+
+    let edit_lines = warp::path!("documents" / String / "lines")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4 * MAX_DOCUMENT_SIZE as u64))
+        .and(warp::body::json())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(edit_lines_handler);
+
+    let apply_patch = warp::path!("documents" / String / "patch")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4 * MAX_DOCUMENT_SIZE as u64))
+        .and(warp::body::bytes())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(apply_patch_handler);
+
+    let revert_doc = warp::path!("documents" / String / "revert")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(revert_document_handler);
+
+    let start_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime returned before UNIX_EPOCH")
+        .as_secs();
+    let stats = warp::path!("stats")
+        .and(warp::any().map(move || start_time))
+        .and(state_filter.clone())
+        .and_then(stats_handler);
+
+    let status = warp::path!("status")
+        .and(warp::get())
+        .and(warp::any().map(move || start_time))
+        .and(state_filter.clone())
+        .and_then(status_handler);
+
+    let stats_history = warp::path!("stats" / "history")
+        .and(warp::get())
+        .and(warp::query::<StatsHistoryQuery>())
+        .and(state_filter.clone())
+        .and_then(stats_history_handler);
+
+    let search = warp::path!("search")
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+        .and(requester.clone())
+        .and(state_filter.clone())
+        .and_then(search_handler);
+
+    let version = warp::path!("version").and(warp::get()).map(|| {
+        warp::reply::json(&VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("RUSTPAD_GIT_COMMIT"),
+            protocol: PROTOCOL_VERSION,
+            features: FEATURES,
+        })
+    });
+
+    let capabilities = warp::path!("capabilities").and(warp::get()).map(move || {
+        warp::reply::json(&Capabilities {
+            auth,
+            api_keys,
+            e2ee: false,
+            exec: false,
+            chat: false,
+            comments: false,
+            signing: true,
+            search: true,
+            limits: Limits {
+                document_size: MAX_DOCUMENT_SIZE,
+                bulk_ids: MAX_BULK_IDS,
+                tag_length: MAX_TAG_LENGTH,
+                metadata_size: MAX_METADATA_SIZE,
+            },
+        })
+    });
+
+    let list_docs = warp::path!("documents")
+        .and(warp::get())
+        .and(warp::query::<ListOptions>())
+        .and(warp::query::<Vec<(String, String)>>())
+        .and(requester.clone())
+        .and(state_filter.clone())
+        .and_then(list_documents_handler);
+
+    let create_doc = warp::path!("documents")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(create_document_handler);
+
+    let get_doc = warp::path!("documents" / String)
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(get_document_handler);
+
+    let rename_doc = warp::path!("documents" / String)
+        .and(warp::patch())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(update_document_handler);
+
+    let delete_doc = warp::path!("documents" / String)
+        .and(warp::delete())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::query::<DeleteQuery>())
+        .and(state_filter.clone())
+        .and_then(delete_document_handler);
+
+    let warm_doc = warp::path!("documents" / String / "warm")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(warm_document_handler);
+
+    let persist_doc = warp::path!("documents" / String / "persist")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(persist_document_handler);
+
+    let star_doc = warp::path!("documents" / String / "star")
+        .and(warp::put())
+        .and(requester.clone())
+        .and(state_filter.clone())
+        .and_then(star_document_handler);
+
+    let unstar_doc = warp::path!("documents" / String / "star")
+        .and(warp::delete())
+        .and(requester.clone())
+        .and(state_filter.clone())
+        .and_then(unstar_document_handler);
+
+    let add_tag = warp::path!("documents" / String / "tags")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(add_tag_handler);
+
+    let remove_tag = warp::path!("documents" / String / "tags")
+        .and(warp::delete())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(remove_tag_handler);
+
+    let add_relation = warp::path!("documents" / String / "relations")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(add_relation_handler);
+
+    let remove_relation = warp::path!("documents" / String / "relations")
+        .and(warp::delete())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(remove_relation_handler);
+
+    let get_metadata = warp::path!("documents" / String / "metadata")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(get_metadata_handler);
+
+    let get_settings = warp::path!("documents" / String / "settings")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(get_settings_handler);
+
+    let patch_settings = warp::path!("documents" / String / "settings")
+        .and(warp::patch())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(patch_settings_handler);
+
+    let patch_metadata = warp::path!("documents" / String / "metadata")
+        .and(warp::patch())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::content_length_limit(MAX_METADATA_SIZE as u64))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(patch_metadata_handler);
+
+    let bulk_docs = warp::path!("documents" / "bulk")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(bulk_documents_handler);
+
+    let import_docs = warp::path!("documents" / "import")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(warp::multipart::form().max_length(MAX_IMPORT_SIZE))
+        .and(state_filter.clone())
+        .and_then(import_documents_handler);
+
+    let duplicate_doc = warp::path!("documents" / String / "duplicate")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(duplicate_document_handler);
+
+    let download_doc = warp::path!("documents" / String / "download")
+        .and(warp::get())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(download_document_handler);
+
+    let doc_stats = warp::path!("documents" / String / "stats")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(document_stats_handler);
+
+    let doc_session = warp::path!("documents" / String / "session")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(document_session_handler);
+
+    let raw_doc = warp::path!("documents" / String / "raw")
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::method())
+        .and(warp::query::<RawQuery>())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(raw_document_handler);
+
+    let diff_doc = warp::path!("documents" / String / "diff")
+        .and(warp::get())
+        .and(warp::query::<DiffQuery>())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(diff_document_handler);
+
+    let blame_doc = warp::path!("documents" / String / "blame")
+        .and(warp::get())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(blame_document_handler);
+
+    let history_doc = warp::path!("documents" / String / "history.ndjson")
+        .and(warp::get())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(history_document_handler);
+
+    let timeline_doc = warp::path!("documents" / String / "timeline")
+        .and(warp::get())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(timeline_document_handler);
+
+    let export_doc = warp::path!("documents" / String / "export")
+        .and(warp::get())
+        .and(warp::query::<ExportQuery>())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(export_document_handler);
+
+    let transfer_doc = warp::path!("documents" / String / "transfer")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(transfer_document_handler);
+
+    let get_acl = warp::path!("documents" / String / "acl")
+        .and(warp::get())
+        .and(requester.clone())
+        .and(state_filter.clone())
+        .and_then(get_acl_handler);
+
+    let set_acl = warp::path!("documents" / String / "acl")
+        .and(warp::put())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(set_acl_handler);
+
+    let remove_acl = warp::path!("documents" / String / "acl")
+        .and(warp::delete())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(remove_acl_handler);
+
+    let share_doc = warp::path!("documents" / String / "share")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(share_document_handler);
+
+    let publish_doc = warp::path!("documents" / String / "publish")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(warp::query::<PublishQuery>())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(publish_document_handler);
+
+    let published = warp::path!("published" / String)
+        .and(warp::get())
+        .and(warp::query::<PublishedQuery>())
+        .and(state_filter.clone())
+        .and_then(published_handler);
+
+    let published_embed = warp::path!("published" / String / "embed")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(published_embed_handler);
+
+    let list_versions = warp::path!("documents" / String / "versions")
+        .and(warp::get())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(list_versions_handler);
+
+    let get_version = warp::path!("documents" / String / "versions" / i64)
+        .and(warp::get())
+        .and(requester.clone())
+        .and(document_key.clone())
+        .and(state_filter.clone())
+        .and_then(get_version_handler);
+
+    let restore_doc = warp::path!("documents" / String / "restore")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(state_filter.clone())
+        .and_then(restore_document_handler);
+
+    let archive_doc = warp::path!("documents" / String / "archive")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::any().map(|| true))
+        .and(state_filter.clone())
+        .and_then(archive_document_handler);
+
+    let unarchive_doc = warp::path!("documents" / String / "unarchive")
+        .and(warp::post())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::any().map(|| false))
+        .and(state_filter.clone())
+        .and_then(archive_document_handler);
+
+    let report_doc = warp::path!("documents" / String / "report")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(requester.clone())
+        .and(warp::addr::remote())
+        .and(state_filter.clone())
+        .and_then(report_document_handler);
+
+    let trash = warp::path!("trash")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(trash_handler);
+
+    let move_doc = warp::path!("documents" / String / "folder")
+        .and(warp::put())
+        .and(api_key.clone())
+        .and(requester.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(move_document_handler);
+
+    let list_folders = warp::path!("folders")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(list_folders_handler);
+
+    let create_folder = warp::path!("folders")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(create_folder_handler);
+
+    let get_folder = warp::path!("folders" / i64)
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(get_folder_handler);
+
+    let update_folder = warp::path!("folders" / i64)
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(update_folder_handler);
+
+    let delete_folder = warp::path!("folders" / i64)
+        .and(warp::delete())
+        .and(state_filter.clone())
+        .and_then(delete_folder_handler);
+
+    let folders = list_folders
+        .or(create_folder)
+        .or(get_folder)
+        .or(update_folder)
+        .or(delete_folder);
+
+    // This has no requester, but is tried among the routes that do, before
+    // deleting the document with the ID `all`.
+    let delete_all_docs = warp::path!("documents" / "all")
+        .and(warp::delete())
+        .and(authenticated.clone())
+        .and(api_key)
+        .and(state_filter.clone())
+        .and_then(delete_all_documents_handler);
+
+    let vacuum_db = warp::path!("admin" / "db" / "vacuum")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(vacuum_handler);
+
+    let check_db = warp::path!("admin" / "db" / "check")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(integrity_check_handler);
+
+    let evict_doc = warp::path!("admin" / "documents" / String / "evict")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(evict_document_handler);
+
+    let quarantine_doc = warp::path!("admin" / "documents" / String / "quarantine")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(warp::any().map(|| true))
+        .and(state_filter.clone())
+        .and_then(quarantine_document_handler);
+
+    let release_doc = warp::path!("admin" / "documents" / String / "release")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(warp::any().map(|| false))
+        .and(state_filter.clone())
+        .and_then(quarantine_document_handler);
+
+    let list_reports = warp::path!("admin" / "reports")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(warp::query::<ReportsQuery>())
+        .and(state_filter.clone())
+        .and_then(list_reports_handler);
+
+    let resolve_report = warp::path!("admin" / "reports" / i64 / "resolve")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(resolve_report_handler);
+
+    let list_jobs = warp::path!("admin" / "jobs")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(list_jobs_handler);
+
+    let expiry = HOUR * 24 * config.expiry_days;
+    let active_docs = warp::path!("admin" / "active")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(warp::any().map(move || expiry))
+        .and(state_filter.clone())
+        .map(active_documents_handler);
+
+    let rejections = warp::path!("admin" / "rejections")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .map(rejections_handler);
+
+    let client_versions = warp::path!("admin" / "client-versions")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .map(client_versions_handler);
+
+    let export_user = warp::path!("admin" / "users" / String / "export")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(export_user_handler);
+
+    let delete_user = warp::path!("admin" / "users" / String)
+        .and(warp::delete())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(delete_user_handler);
+
+    let anonymize_user = warp::path!("admin" / "users" / String / "anonymize")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(anonymize_user_handler);
+
+    let get_language_map = warp::path!("admin" / "language-map")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .map(get_language_map_handler);
+
+    let put_language_map = warp::path!("admin" / "language-map")
+        .and(warp::put())
+        .and(operator.clone())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(put_language_map_handler);
+
+    let list_api_keys = warp::path!("admin" / "api-keys")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(list_api_keys_handler);
+
+    let create_api_key = warp::path!("admin" / "api-keys")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(create_api_key_handler);
+
+    let revoke_api_key = warp::path!("admin" / "api-keys" / i64)
+        .and(warp::delete())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(revoke_api_key_handler);
+
+    let standby_status = warp::path!("admin" / "standby")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(standby_status_handler);
+
+    let promote_standby = warp::path!("admin" / "standby" / "promote")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(promote_standby_handler);
+
+    let export_all = warp::path!("admin" / "export.zip")
+        .and(warp::get())
+        .and(operator.clone())
+        .and(state_filter.clone())
+        .and_then(export_all_handler);
+
+    let admin = vacuum_db
+        .or(check_db)
+        .or(evict_doc)
+        .or(quarantine_doc)
+        .or(release_doc)
+        .or(list_reports)
+        .or(resolve_report)
+        .or(active_docs)
+        .or(list_jobs)
+        .or(rejections)
+        .or(client_versions)
+        .or(export_user)
+        .or(delete_user)
+        .or(anonymize_user)
+        .or(get_language_map)
+        .or(put_language_map)
+        .or(list_api_keys)
+        .or(create_api_key)
+        .or(revoke_api_key)
+        .or(standby_status)
+        .or(promote_standby)
+        .or(export_all);
+
+    // Routes that identify their requester authenticate it themselves, and
+    // the rest are authenticated up front, so that each request is
+    // authenticated once.
+    let identified = text.or(replace_text).or(append_text).or(edit_lines).or(apply_patch).or(revert_doc).or(user_identity).or(search).or(list_docs).or(create_doc).or(bulk_docs).or(delete_all_docs).or(rename_doc).or(delete_doc).or(star_doc).or(unstar_doc).or(add_tag).or(remove_tag).or(add_relation).or(remove_relation).or(patch_metadata).or(patch_settings).or(duplicate_doc).or(download_doc).or(raw_doc).or(diff_doc).or(blame_doc).or(history_doc).or(timeline_doc).or(export_doc).or(transfer_doc).or(get_acl).or(set_acl).or(remove_acl).or(share_doc).or(publish_doc).or(list_versions).or(get_version).or(restore_doc).or(archive_doc).or(unarchive_doc).or(report_doc).or(move_doc);
+    let rest = feed.or(stats).or(stats_history).or(status).or(activity).or(version).or(import_docs).or(get_doc).or(warm_doc).or(persist_doc).or(get_metadata).or(get_settings).or(doc_stats).or(doc_session).or(published).or(published_embed).or(trash).or(folders).or(admin);
+
+    // Capabilities are served without authentication, so that the frontend
+    // can find out how to sign in.
+    let routes = socket
+        .or(capabilities)
+        .or(identified)
+        .or(authenticated.and(rest))
+        .with(track);
+
+    warp::header::optional::<String>("accept-language")
+        .and(routes.map(Reply::into_response).recover(recover_rejection).unify())
+        .map(localize_error)
+        .and(warp::any().map(move || rfc3339_timestamps))
+        .and_then(format_timestamps)
+        .boxed()
+}
+
+/// Add RFC 3339 copies of the timestamps in a JSON response, if enabled.
+async fn format_timestamps(
+    response: warp::reply::Response,
+    enabled: bool,
+) -> Result<warp::reply::Response, Infallible> {
+    let is_json = response
+        .headers()
+        .get("content-type")
+        .map_or(false, |value| value == "application/json");
+    if !enabled || !is_json {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Failed to read response body: {}", e);
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    let bytes = match serde_json::from_slice(&bytes) {
+        Ok(mut value) => {
+            timestamps::annotate(&mut value);
+            parts.headers.remove("content-length");
+            serde_json::to_vec(&value).expect("failed serialize").into()
+        }
+        Err(_) => bytes,
+    };
+    Ok(warp::reply::Response::from_parts(parts, bytes.into()))
+}
+
+/// Convert a rejection into an error response, tagged with a [`Notice`].
+///
+/// When several routes rejected the request, the most specific error wins, as
+/// in warp's default handling: server errors first, then the highest status,
+/// with "method not allowed" and "not found" last.
+async fn recover_rejection(rejection: Rejection) -> Result<warp::reply::Response, Infallible> {
+    use warp::reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, UnsupportedMediaType,
+    };
+
+    let (status, code) = if rejection.find::<CustomReject>().is_some() {
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+    } else if rejection.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "unauthorized")
+    } else if rejection.find::<Forbidden>().is_some() {
+        (StatusCode::FORBIDDEN, "forbidden")
+    } else if rejection.find::<UnsupportedMediaType>().is_some() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type")
+    } else if rejection.find::<PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large")
+    } else if rejection.find::<LengthRequired>().is_some() {
+        (StatusCode::LENGTH_REQUIRED, "bad_request")
+    } else if rejection.find::<warp::body::BodyDeserializeError>().is_some()
+        || rejection.find::<InvalidQuery>().is_some()
+        || rejection.find::<InvalidHeader>().is_some()
+        || rejection.find::<MissingHeader>().is_some()
+    {
+        (StatusCode::BAD_REQUEST, "bad_request")
+    } else if rejection.find::<MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed")
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found")
+    } else {
+        (StatusCode::BAD_REQUEST, "bad_request")
+    };
+    let mut response = status.into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        let challenge = HeaderValue::from_static("Bearer");
+        response.headers_mut().insert("www-authenticate", challenge);
+    }
+    response.extensions_mut().insert(Notice::new(code));
+    Ok(response)
+}
+
+/// A localized error body returned by the REST API.
+#[derive(Serialize)]
+struct ErrorBody {
+    #[serde(flatten)]
+    notice: Notice,
+    message: String,
+}
+
+/// Render the [`Notice`] of an error response in the client's language.
+fn localize_error(
+    accept_language: Option<String>,
+    mut response: warp::reply::Response,
+) -> warp::reply::Response {
+    match response.extensions_mut().remove::<Notice>() {
+        Some(notice) => {
+            let message = notice.localize(accept_language.as_deref());
+            let body = ErrorBody { notice, message };
+            warp::reply::with_status(warp::reply::json(&body), response.status()).into_response()
+        }
+        None => response,
+    }
+}
+
+/// Fetch an in-memory document, loading it from the database if needed.
+///
+/// Newly loaded documents have their persister task started. Either way, the
+/// document's last access time is refreshed.
+async fn open_document(state: &ServerState, id: &str) -> Result<Arc<Rustpad>, Rejection> {
+    use dashmap::mapref::entry::Entry;
+
+    let mut entry = match state.documents.entry(id.to_owned()) {
+        Entry::Occupied(e) => e.into_ref(),
+        Entry::Vacant(e) => {
+            // Deleted documents stay deleted until explicitly restored, rather
+            // than being resurrected from their old row by a reconnecting client.
+            match state.database.is_deleted(id).await {
+                Ok(false) => {}
+                Ok(true) => return Err(warp::reject::not_found()),
+                Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+            }
+            let rustpad = Arc::new(match state.database.load(id).await {
+                Ok(doc) => {
+                    let history = match load_history(state, id).await {
+                        Ok(history) => history,
+                        Err(e) => {
+                            warn!("when loading history of document {}: {}", id, e);
+                            Vec::new()
+                        }
+                    };
+                    Rustpad::from_document(doc, history, state.database.clone())
+                }
+                Err(_) => Rustpad::new(state.database.clone()),
+            });
+            let settings = match state.database.settings(id).await {
+                Ok(settings) => settings.unwrap_or_default(),
+                Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+            };
+            match state.database.is_locked(id).await {
+                Ok(locked) => {
+                    rustpad.set_read_only(locked || settings.read_only || in_standby(state))
+                }
+                Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+            }
+            rustpad.set_settings(settings);
+            // Load user colors from database
+            rustpad.load_colors().await;
+            tokio::spawn(persister(
+                id.to_owned(),
+                Arc::clone(&rustpad),
+                state.database.clone(),
+                state.versions,
+            ));
+            e.insert(Document::new(rustpad))
+        }
+    };
+
+    let value = entry.value_mut();
+    value.last_accessed = Instant::now();
+    Ok(Arc::clone(&value.rustpad))
+}
+
+/// Register handlers for each kind of background job.
+fn register_jobs(jobs: &Jobs, database: &Database) {
+    let db = database.clone();
+    jobs.register(SAVE_USER_COLOR, move |payload| {
+        let db = db.clone();
+        async move {
+            let job: UserColorJob = serde_json::from_value(payload)?;
+            db.save_user_color(&job.email, job.hue).await
+        }
+    });
+    let db = database.clone();
+    jobs.register(RECORD_ACTIVITY, move |payload| {
+        let db = db.clone();
+        async move {
+            let job: ActivityJob = serde_json::from_value(payload)?;
+            db.record_activity(&job.document_id, job.revisions, job.created_at)
+                .await
+        }
+    });
+}
+
+/// Authenticate a request with its bearer token, if an OpenID Connect
+/// provider is configured, returning the identity of the user.
+///
+/// The token is taken from the `Authorization` header, or else from the
+/// `access_token` query parameter.
+async fn authenticate_handler(
+    authorization: Option<String>,
+    query: TokenQuery,
+    state: ServerState,
+) -> Result<Option<Identity>, Rejection> {
+    let oidc = match &state.oidc {
+        Some(oidc) => oidc,
+        None => return Ok(None),
+    };
+    let token = authorization
+        .as_deref()
+        .and_then(oidc::bearer_token)
+        .or(query.access_token.as_deref())
+        .ok_or_else(|| warp::reject::custom(Unauthorized))?;
+    if token.starts_with(API_KEY_PREFIX) {
+        return match verify_api_key(&state, token).await? {
+            Some(id) => Ok(Some(Identity {
+                subject: format!("{}{}", API_KEY_SUBJECT, id),
+                email: None,
+            })),
+            None => Err(warp::reject::custom(Unauthorized)),
+        };
+    }
+    match oidc.verify(token).await {
+        Ok(identity) => Ok(Some(identity)),
+        Err(e) => {
+            warn!("refusing request with invalid bearer token: {}", e);
+            Err(warp::reject::custom(Unauthorized))
+        }
+    }
+}
+
+/// Prefix of API keys, which tells them apart from other bearer tokens.
+const API_KEY_PREFIX: &str = "rpk_";
+
+/// Prefix of the subject of identities authenticated with an API key.
+const API_KEY_SUBJECT: &str = "api-key:";
+
+/// Returns the hex-encoded SHA-256 hash of an API key, as it is stored.
+fn hash_api_key(key: &str) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, key.as_bytes()))
+}
+
+/// Returns the ID of a valid API key, recording its use.
+async fn verify_api_key(state: &ServerState, key: &str) -> Result<Option<i64>, Rejection> {
+    match state.database.use_api_key(&hash_api_key(key)).await {
+        Ok(id) => Ok(id),
+        Err(e) => {
+            error!("Failed to check API key: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Check that a request to a route changing documents carries a valid API
+/// key, if keys are required.
+///
+/// When an OpenID Connect provider is configured, bearer tokens from it are
+/// accepted too, as users of the frontend have no API key. Every route that
+/// requires a key also authenticates the request, which then verifies both
+/// kinds of token, so they are not verified here again.
+async fn require_api_key_handler(
+    authorization: Option<String>,
+    state: ServerState,
+) -> Result<(), Rejection> {
+    if !state.require_api_key {
+        return Ok(());
+    }
+    match authorization.as_deref().and_then(oidc::bearer_token) {
+        Some(_) if state.oidc.is_some() => Ok(()),
+        Some(token) if token.starts_with(API_KEY_PREFIX) => {
+            match verify_api_key(&state, token).await? {
+                Some(_) => Ok(()),
+                None => Err(warp::reject::custom(Unauthorized)),
+            }
+        }
+        _ => Err(warp::reject::custom(Unauthorized)),
+    }
+}
+
+/// Checks that a request to an admin route carries the operator token, or an
+/// API key if keys are required. Admin routes stay open when neither is
+/// configured.
+async fn require_operator_handler(
+    authorization: Option<String>,
+    state: ServerState,
+) -> Result<(), Rejection> {
+    let token = authorization.as_deref().and_then(oidc::bearer_token);
+    if let (Some(expected), Some(token)) = (&state.admin_token, token) {
+        if hash_api_key(token) == *expected {
+            return Ok(());
+        }
+    }
+    if state.require_api_key {
+        require_api_key_handler(authorization, state).await
+    } else if state.admin_token.is_some() {
+        Err(warp::reject::custom(Unauthorized))
+    } else {
+        Ok(())
+    }
+}
+
+/// Hashes the password of a document, off the async runtime.
+async fn hash_password(password: String) -> Result<String, Rejection> {
+    tokio::task::spawn_blocking(move || password::hash(&password))
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))
+}
+
+/// Checks a password against the hash of a document, off the async runtime.
+async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || password::verify(&password, &hash))
+        .await
+        .unwrap_or(false)
+}
+
+/// Checks the share token of a request to a document, if it has one,
+/// returning `Err` if the token is invalid, expired or for another document.
+fn share_claims(
+    state: &ServerState,
+    id: &str,
+    token: Option<&str>,
+) -> Result<Option<ShareClaims>, ()> {
+    match token.map(|token| state.shares.verify(token)) {
+        None => Ok(None),
+        Some(Some(claims)) if claims.document == id => Ok(Some(claims)),
+        Some(_) => Err(()),
+    }
+}
+
+/// Checks that a request may read a document protected by a password, or
+/// edit it if `write` is set, returning the claims of its share token.
+///
+/// The request needs the password of the document, or a share token for it
+/// in place of the password. Only tokens with the `write` scope allow edits.
+async fn unlock_document(
+    state: &ServerState,
+    id: &str,
+    key: &DocumentKey,
+    write: bool,
+) -> Result<Option<ShareClaims>, Rejection> {
+    match share_claims(state, id, key.token.as_deref()) {
+        Ok(Some(claims)) if write && matches!(claims.scope, ShareScope::Read) => {
+            return Err(warp::reject::custom(Forbidden));
+        }
+        Ok(Some(claims)) => return Ok(Some(claims)),
+        Ok(None) => {}
+        Err(()) => {
+            warn!("refusing request to {} with an invalid share token", id);
+            return Err(warp::reject::custom(Forbidden));
+        }
+    }
+    let hash = match state.database.password_hash(id).await {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    };
+    let accepted = match key.password.clone() {
+        Some(password) => verify_password(password, hash).await,
+        None => false,
+    };
+    if !accepted {
+        warn!("refusing request to {} without its password", id);
+        return Err(warp::reject::custom(Forbidden));
+    }
+    Ok(None)
+}
+
+/// Checks that a user may read a document, or edit it if `write` is set.
+///
+/// The request must unlock a document protected by a password, and the user
+/// needs a role on its access control list, above viewer for edits. A share
+/// token grants access by its own scope instead of a role.
+async fn authorize_document(
+    state: &ServerState,
+    id: &str,
+    email: Option<&str>,
+    key: &DocumentKey,
+    write: bool,
+) -> Result<(), Rejection> {
+    if unlock_document(state, id, key, write).await?.is_some() {
+        return Ok(());
+    }
+    match document_role(state, id, email).await? {
+        Some(Role::Viewer) if write => Err(warp::reject::custom(Forbidden)),
+        Some(_) => Ok(()),
+        None => Err(warp::reject::custom(Forbidden)),
+    }
+}
+
+/// Returns the email of the user making a request, from a verified bearer
+/// token or Cloudflare Access token.
+///
+/// The plain Cloudflare Access email header can be forged, so it is only
+/// trusted when no verification is configured. Otherwise a request carrying
+/// it without a matching valid token is refused, the same as on sockets.
+async fn requester_handler(
+    identity: Option<Identity>,
+    cf_email: Option<String>,
+    cf_token: Option<String>,
+    state: ServerState,
+) -> Result<Option<String>, Rejection> {
+    match (identity, &state.access) {
+        (Some(identity), _) => Ok(identity.email),
+        (None, Some(_)) if cf_email.is_none() && cf_token.is_none() => Ok(None),
+        (None, Some(access)) => {
+            let verified = access.authenticate(cf_token.as_deref(), cf_email.as_deref());
+            match verified.await {
+                Ok(identity) => Ok(identity.email),
+                Err(e) => {
+                    warn!("refusing request with unverified identity: {}", e);
+                    Err(warp::reject::custom(Forbidden))
+                }
+            }
+        }
+        (None, None) => Ok(cf_email),
+    }
+}
+
+/// Loads the access control list of a document.
+async fn load_acl(state: &ServerState, id: &str) -> Result<Vec<AclEntry>, Rejection> {
+    state.database.acl(id).await.map_err(|e| {
+        error!("Failed to load access control list of document {}: {}", id, e);
+        warp::reject::custom(CustomReject(e))
+    })
+}
+
+/// Returns the role of a user on a document, or `None` if the document has
+/// an access control list that they are not on. The owner of a document is
+/// always an owner on its list, and everyone may do what an owner may with a
+/// document without a list.
+///
+/// The email must come from a verified identity, such as the requester of a
+/// REST call, since it may claim ownership of the document.
+async fn document_role(
+    state: &ServerState,
+    id: &str,
+    email: Option<&str>,
+) -> Result<Option<Role>, Rejection> {
+    let meta = state.database.get_meta(id).await.map_err(|e| {
+        error!("Failed to load metadata of document {}: {}", id, e);
+        warp::reject::custom(CustomReject(e))
+    })?;
+    // Deleted documents have no metadata, but their owner may restore them.
+    let owner = match meta {
+        Some(meta) => meta.owner_email,
+        None => state.database.owner_email(id).await.map_err(|e| {
+            error!("Failed to load owner of document {}: {}", id, e);
+            warp::reject::custom(CustomReject(e))
+        })?,
+    };
+    if email.is_some() && owner.as_deref() == email {
+        return Ok(Some(Role::Owner));
+    }
+    let acl = load_acl(state, id).await?;
+    if acl.is_empty() {
+        return Ok(Some(Role::Owner));
+    }
+    let entry = email.and_then(|email| acl.into_iter().find(|entry| entry.email == email));
+    Ok(entry.map(|entry| entry.role))
+}
+
+/// Checks that a user may act as an owner of a document.
+async fn require_owner(
+    state: &ServerState,
+    id: &str,
+    email: Option<&str>,
+) -> Result<(), Rejection> {
+    match document_role(state, id, email).await? {
+        Some(Role::Owner) => Ok(()),
+        _ => Err(warp::reject::custom(Forbidden)),
+    }
+}
+
+/// Checks that a user may change a document, as an editor or owner.
+async fn require_editor(
+    state: &ServerState,
+    id: &str,
+    email: Option<&str>,
+) -> Result<(), Rejection> {
+    match document_role(state, id, email).await? {
+        Some(Role::Owner | Role::Editor) => Ok(()),
+        _ => Err(warp::reject::custom(Forbidden)),
+    }
+}
+
+/// Handler for the `/api/socket/{id}` endpoint.
+///
+/// Connections to a document protected by a password need the password,
+/// either in the `password` query parameter or as the first message.
+///
+/// If the document has an access control list, only users on it may
+/// connect, and viewers get a read-only session. A share token grants
+/// access by its own scope instead.
+async fn socket_handler(
+    id: String,
+    ws: Ws,
+    identity: Option<Identity>,
+    query: SocketQuery,
+    cf_email: Option<String>,
+    cf_token: Option<String>,
+    user_agent: Option<String>,
+    remote: Option<SocketAddr>,
+    config: Arc<SocketConfig>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let identity = match (identity, &state.access) {
+        (Some(identity), _) => Some(identity),
+        (None, Some(access)) => {
+            let verified = access.authenticate(cf_token.as_deref(), cf_email.as_deref());
+            match verified.await {
+                Ok(identity) => Some(identity),
+                Err(e) => {
+                    warn!("refusing connection to {}: {}", id, e);
+                    return Ok(StatusCode::FORBIDDEN.into_response());
+                }
+            }
+        }
+        (None, None) => cf_email.map(Identity::from_email),
+    };
+    let client = identity
+        .as_ref()
+        .map(|identity| identity.subject.clone())
+        .or_else(|| remote.map(|addr| addr.ip().to_string()));
+    if let Some(client) = &client {
+        if state.offenders.blocked(client) {
+            warn!("refusing connection from {} after oversized messages", client);
+            return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
+        }
+    }
+    let share = match share_claims(&state, &id, query.token.as_deref()) {
+        Ok(share) => share,
+        Err(()) => {
+            warn!("refusing connection to {} with an invalid share token", id);
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+    };
+    // API keys belong to automated clients, such as a standby following this
+    // server, rather than to users who could be on the list.
+    let api_key = identity
+        .as_ref()
+        .map_or(false, |identity| identity.subject.starts_with(API_KEY_SUBJECT));
+    let email = identity.as_ref().and_then(|identity| identity.email.as_deref());
+    let role = match share {
+        Some(_) => None,
+        None if api_key => None,
+        None => match document_role(&state, &id, email).await? {
+            Some(role) => Some(role),
+            None => {
+                warn!("refusing connection to {} from {:?} without a role", id, email);
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            }
+        },
+    };
+    let mut password_hash = match share {
+        Some(_) => None,
+        None => match state.database.password_hash(&id).await {
+            Ok(hash) => hash,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        },
+    };
+    if let (Some(hash), Some(password)) = (&password_hash, query.password) {
+        if !verify_password(password, hash.clone()).await {
+            warn!("refusing connection to {} with a wrong password", id);
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+        password_hash = None;
+    }
+    let rustpad = open_document(&state, &id).await?;
+    let metrics = Arc::clone(&state.socket_metrics);
+    let truncate = |value: String| value.chars().take(MAX_CLIENT_AGENT_LENGTH).collect::<String>();
+    let agent = ClientAgent {
+        user_agent: user_agent.map(truncate),
+        client_version: query.client_version.map(truncate),
+    };
+    let read_only = matches!(
+        share,
+        Some(ShareClaims {
+            scope: ShareScope::Read,
+            ..
+        })
+    ) || role == Some(Role::Viewer);
+    let config = if read_only {
+        Arc::new(SocketConfig {
+            read_only: true,
+            ..SocketConfig::clone(&config)
+        })
+    } else {
+        config
+    };
+    // The socket drops messages far over the limit without buffering them,
+    // and the connection replies with an error to those slightly over it.
+    let ws = ws
+        .max_message_size(2 * config.max_message_size)
+        .max_frame_size(2 * config.max_message_size);
+    Ok(ws
+        .on_upgrade(move |mut socket| async move {
+            if let Some(hash) = password_hash {
+                let password = receive_password(&mut socket).await;
+                let sent = password.is_some();
+                let accepted = match password {
+                    Some(password) => verify_password(password, hash).await,
+                    None => false,
+                };
+                if !accepted {
+                    refuse_password(socket, sent).await;
+                    return;
+                }
+            }
+            let oversized = rustpad
+                .on_connection(socket, identity, agent, metrics, config)
+                .await;
+            if let (true, Some(client)) = (oversized, client) {
+                state.offenders.record(&client);
+            }
+        })
+        .into_response())
+}
+
+/// Handler for the `/api/feed` endpoint.
+///
+/// Subscribes before upgrading, so no events are missed in between.
+async fn feed_handler(
+    ws: Ws,
+    filter: FeedFilter,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let events = state.events.subscribe();
+    Ok(ws.on_upgrade(move |socket| feed::serve(socket, filter, state.database, events)))
+}
+
+/// Handler for the `/api/text/{id}` endpoint.
+///
+/// Responses carry an `ETag` hashed from the body, and requests whose
+/// `If-None-Match` header lists it get an empty 304 Not Modified instead, so
+/// that polling clients don't download unchanged text again. A single byte
+/// range may be requested with a `Range` header, such as to fetch only the
+/// tail of a large log.
+///
+/// The text at an earlier revision may be requested with `?revision=N`, for
+/// finding out when something was changed. It is rebuilt from the operation
+/// history, and 404 Not Found is returned if that is no longer available.
+async fn text_handler(
+    id: String,
+    query: TextQuery,
+    if_none_match: Option<String>,
+    range: Option<String>,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    let loaded = state.documents.get(&id).map(|value| value.rustpad.text_snapshot());
+    let snapshot = match (query.revision, loaded) {
+        (Some(revision), _) => match text_snapshot_at(&state, &id, revision).await? {
+            Some(snapshot) => snapshot,
+            None => return Ok(StatusCode::BAD_REQUEST.into_response()),
+        },
+        (None, Some(snapshot)) => snapshot,
+        // The revision is the one the document would have if it were loaded,
+        // which depends on whether its stored history reproduces the text.
+        (None, None) => match state.database.load(&id).await {
+            Ok(document) => {
+                let history = match load_history(&state, &id).await {
+                    Ok(history) => history,
+                    Err(e) => {
+                        warn!("when loading history of document {}: {}", id, e);
+                        Vec::new()
+                    }
+                };
+                Rustpad::from_document(document, history, state.database.clone()).text_snapshot()
+            }
+            Err(_) => TextSnapshot::default(),
+        },
+    };
+    let (body, content_type) = if query.with_revision {
+        let json = serde_json::to_vec(&snapshot)
+            .map_err(|e| warp::reject::custom(CustomReject(e.into())))?;
+        (json, "application/json")
+    } else {
+        (snapshot.text.into_bytes(), "text/plain; charset=utf-8")
+    };
+    let etag = format!("\"{}\"", sha256_hex(&body));
+    if if_none_match.map_or(false, |header| etag_matches(&header, &etag)) {
+        let reply = warp::reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED);
+        return Ok(warp::reply::with_header(reply, "etag", etag).into_response());
+    }
+    let len = body.len();
+    let response = match byte_range(range.as_deref(), len) {
+        ByteRange::Full => body.into_response(),
+        ByteRange::Partial(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+            let reply = warp::reply::with_status(body[range].to_vec(), StatusCode::PARTIAL_CONTENT);
+            warp::reply::with_header(reply, "content-range", content_range).into_response()
+        }
+        ByteRange::Unsatisfiable => {
+            let reply = warp::reply::with_status(warp::reply(), StatusCode::RANGE_NOT_SATISFIABLE);
+            let reply =
+                warp::reply::with_header(reply, "content-range", format!("bytes */{}", len));
+            return Ok(reply.into_response());
+        }
+    };
+    let reply = warp::reply::with_header(response, "content-type", content_type);
+    let reply = warp::reply::with_header(reply, "accept-ranges", "bytes");
+    Ok(warp::reply::with_header(reply, "etag", etag).into_response())
+}
+
+/// Part of a body requested with a `Range` header.
+enum ByteRange {
+    /// The whole body, if no range or an unsupported one was requested.
+    Full,
+    /// A range of bytes within the body.
+    Partial(std::ops::Range<usize>),
+    /// A range starting past the end of the body.
+    Unsatisfiable,
+}
+
+/// Interpret a `Range` header against a body of `len` bytes.
+///
+/// Only a single range in bytes is supported, and other headers are ignored
+/// as permitted by RFC 9110.
+fn byte_range(header: Option<&str>, len: usize) -> ByteRange {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => start..len.min(end.saturating_add(1)),
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        (Err(_), Ok(suffix)) if start.is_empty() => len.saturating_sub(suffix)..len,
+        _ => return ByteRange::Full,
+    };
+    if range.start >= len || range.is_empty() {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+/// Return the hex-encoded SHA-256 hash of some bytes.
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, data))
+}
+
+/// Check whether an `If-None-Match` header value matches an entity tag,
+/// using weak comparison.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Apply an edit made over REST to a document, loading or creating it first.
+///
+/// The operation is built by `build` from the latest text and based on its
+/// revision, so that it is transformed against concurrent edits from clients
+/// just like their own edits. `build` may return a response instead to reject
+/// the request. Read-only documents and edits exceeding the maximum document
+/// size are rejected before anything is applied, and line endings are
+/// normalized first if the document's settings ask for it. The user must be
+/// allowed to edit the document, see [`authorize_document`].
+async fn apply_external(
+    state: &ServerState,
+    id: &str,
+    email: Option<String>,
+    key: DocumentKey,
+    build: impl FnOnce(&str) -> Result<OperationSeq, warp::reply::Response>,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(state, id, email.as_deref(), &key, true).await?;
+    let rustpad = open_document(state, id).await?;
+    if rustpad.read_only() {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+    let snapshot = rustpad.text_snapshot();
+    let mut operation = match build(&snapshot.text) {
+        Ok(operation) => operation,
+        Err(response) => return Ok(response),
+    };
+    if rustpad.settings().normalize_newlines {
+        operation = ot::normalize_newlines(&operation);
+    }
+    let len = operation.target_len();
+    if len > rustpad.max_size() && len > operation.base_len() {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    match rustpad.apply_external(snapshot.revision, operation, email) {
+        Ok(revision) => Ok(warp::reply::json(&ReplaceTextResponse { revision }).into_response()),
+        Err(e) => {
+            error!("Failed to apply edit to document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Rebuild the text of a document at an earlier revision from its operation
+/// history, returning `None` if the revision is in the future.
+///
+/// Rejects with "not found" if the document does not exist or the history of
+/// the revision is no longer available.
+async fn text_snapshot_at(
+    state: &ServerState,
+    id: &str,
+    revision: usize,
+) -> Result<Option<TextSnapshot>, Rejection> {
+    let rustpad = history_rustpad(state, id).await?;
+    let current = rustpad.text_snapshot();
+    if revision > current.revision {
+        return Ok(None);
+    }
+    let mut text = rustpad.text_at(revision, &[]);
+    if text.is_none() {
+        let stored = match load_history(state, id).await {
+            Ok(stored) => stored,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        };
+        text = rustpad.text_at(revision, &stored);
+    }
+    match text {
+        Some(text) => Ok(Some(TextSnapshot {
+            text,
+            revision,
+            language: current.language,
+        })),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// Handler for the PUT `/api/text/{id}` endpoint.
+///
+/// Replaces the text through the same pipeline as edits from clients, so that
+/// connected clients see the change live.
+async fn replace_text_handler(
+    id: String,
+    body: warp::hyper::body::Bytes,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    if text.chars().count() > MAX_DOCUMENT_SIZE {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    apply_external(&state, &id, email, key, |current| {
+        let mut operation = OperationSeq::default();
+        operation.delete(current.chars().count() as u64);
+        operation.insert(&text);
+        Ok(operation)
+    })
+    .await
+}
+
+/// Handler for the POST `/api/text/{id}/append` endpoint.
+///
+/// Appends the body to the end of the text, for streaming logs into a
+/// document. Appends that would exceed the maximum document size are rejected.
+async fn append_text_handler(
+    id: String,
+    body: warp::hyper::body::Bytes,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    apply_external(&state, &id, email, key, |current| {
+        let mut operation = OperationSeq::default();
+        operation.retain(current.chars().count() as u64);
+        operation.insert(&text);
+        Ok(operation)
+    })
+    .await
+}
+
+/// Handler for the POST `/api/documents/{id}/lines` endpoint.
+///
+/// Inserts, replaces or deletes whole lines by number, so that scripts can
+/// edit documents without computing character offsets. Line numbers out of
+/// range are rejected with 400 Bad Request.
+async fn edit_lines_handler(
+    id: String,
+    edit: LineEdit,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    apply_external(&state, &id, email, key, |current| {
+        edit.operation(current)
+            .ok_or_else(|| StatusCode::BAD_REQUEST.into_response())
+    })
+    .await
+}
+
+/// Handler for the POST `/api/documents/{id}/patch` endpoint.
+///
+/// Applies a unified diff as a single edit. If the context of any hunk does
+/// not match the latest text, nothing is changed and the numbers of the
+/// failed hunks are returned with 409 Conflict.
+async fn apply_patch_handler(
+    id: String,
+    body: warp::hyper::body::Bytes,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let diff = match std::str::from_utf8(&body) {
+        Ok(diff) => diff,
+        Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    let patch = match Patch::parse(diff) {
+        Ok(patch) => patch,
+        Err(e) => {
+            warn!("Rejected patch for document {}: {}", id, e);
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        }
+    };
+    apply_external(&state, &id, email, key, |current| {
+        patch.operation(current).map_err(|failed_hunks| {
+            warp::reply::with_status(
+                warp::reply::json(&PatchConflict { failed_hunks }),
+                StatusCode::CONFLICT,
+            )
+            .into_response()
+        })
+    })
+    .await
+}
+
+/// Handler for the POST `/api/documents/{id}/revert` endpoint.
+///
+/// Applies the inverse of the edits made since the requested revision, so
+/// that connected clients converge on the old text like on any other edit.
+/// History trimmed from memory is read from the database, and 404 Not Found
+/// is returned if it is missing there as well.
+async fn revert_document_handler(
+    id: String,
+    request: RevertRequest,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, true).await?;
+    let rustpad = open_document(&state, &id).await?;
+    if rustpad.read_only() {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+    if request.revision > rustpad.revision() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let mut revert = rustpad.revert_operation(request.revision, &[]);
+    if revert.is_none() {
+        let stored = match load_history(&state, &id).await {
+            Ok(stored) => stored,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        };
+        revert = rustpad.revert_operation(request.revision, &stored);
+    }
+    let (revision, operation) = match revert {
+        Some(revert) => revert,
+        None => return Err(warp::reject::not_found()),
+    };
+    if operation.is_noop() {
+        return Ok(warp::reply::json(&ReplaceTextResponse { revision }).into_response());
+    }
+    match rustpad.apply_external(revision, operation, email) {
+        Ok(revision) => Ok(warp::reply::json(&ReplaceTextResponse { revision }).into_response()),
+        Err(e) => {
+            error!("Failed to revert document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the `/api/stats` endpoint.
+async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
+    let num_documents = state.documents.len();
+    let mut rejected_edits = RejectionStats::default();
+    for entry in state.documents.iter() {
+        rejected_edits.merge(&entry.rustpad.rejections());
+    }
+    let database_size = match state.database.count().await {
+        Ok(size) => size,
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    };
+    Ok(warp::reply::json(&Stats {
+        start_time,
+        num_documents,
+        database_size,
+        cleaner: state.cleaner_metrics.snapshot(),
+        rejected_edits,
+        routes: state.route_metrics.snapshot(),
+        sockets: state.socket_metrics.snapshot(),
+        cache: state.database.cache_stats(),
+    }))
+}
+
+/// Handler for the `/api/status` endpoint, an HTML page of server health.
+async fn status_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
+    let documents_persisted = match state.database.count().await {
+        Ok(count) => count,
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime returned before UNIX_EPOCH")
+        .as_secs();
+    let mut page = StatusPage {
+        uptime: Duration::from_secs(now.saturating_sub(start_time)),
+        documents_loaded: state.documents.len(),
+        documents_persisted,
+        connections: state.socket_metrics.snapshot().open,
+        unsaved_documents: 0,
+        unsaved_revisions: 0,
+        recent_errors: state.route_metrics.recent_errors(),
+    };
+    for entry in state.documents.iter() {
+        let unpersisted = entry.rustpad.unpersisted();
+        if unpersisted > 0 {
+            page.unsaved_documents += 1;
+            page.unsaved_revisions += unpersisted;
+        }
+    }
+    Ok(warp::reply::html(page.render()))
+}
+
+/// Handler for the `/api/activity` endpoint.
+///
+/// Lists the documents edited after a timestamp with the number of revisions
+/// since then. Edits are counted once persisted, a few seconds after they are
+/// made.
+async fn activity_handler(
+    query: ActivityQuery,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let limit = query.limit.clamp(0, MAX_ACTIVITY_LIMIT);
+    match state.database.activity(query.since, limit).await {
+        Ok(activity) => Ok(warp::reply::json(&activity)),
+        Err(e) => {
+            error!("Failed to list document activity: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the `/api/stats/history` endpoint.
+///
+/// Returns the samples of server activity recorded over the last hours,
+/// oldest first, for drawing graphs on the status page.
+async fn stats_history_handler(
+    query: StatsHistoryQuery,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let hours = query.hours.min(STATS_HISTORY_HOURS) as i64;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime returned before UNIX_EPOCH")
+        .as_secs() as i64;
+    let since = now - hours * 3600;
+    match state.database.stats_samples(since).await {
+        Ok(samples) => Ok(warp::reply::json(&samples)),
+        Err(e) => {
+            error!("Failed to load stats history: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Generate a random document ID.
+fn generate_document_id() -> String {
+    const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    let mut rng = rand::thread_rng();
+    (0..6)
+        .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+        .collect()
+}
+
+/// Handler for the GET `/api/documents` endpoint.
+///
+/// The total number of matching documents is returned in `X-Total-Count`.
+/// Parameters such as `meta.ticket=ENG-42` filter by custom metadata, with
+/// dots separating the keys of nested objects, and `starred=true` lists only
+/// the documents starred by the authenticated user.
+async fn list_documents_handler(
+    mut options: ListOptions,
+    params: Vec<(String, String)>,
+    email: Option<String>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    for (key, value) in params {
+        if key == "starred" && value == "true" {
+            match &email {
+                Some(email) => options.starred_by = Some(email.clone()),
+                None => return Ok(StatusCode::UNAUTHORIZED.into_response()),
+            }
+        } else if let Some(key) = key.strip_prefix("meta.") {
+            match metadata_path(key) {
+                Some(path) => options.metadata.push((path, value)),
+                None => return Ok(StatusCode::BAD_REQUEST.into_response()),
+            }
+        }
+    }
+    match state.database.list(&options).await {
+        Ok(page) => Ok(warp::reply::with_header(
+            warp::reply::json(&page.documents),
+            "x-total-count",
+            page.total.to_string(),
+        )
+        .into_response()),
+        Err(e) => {
+            error!("Failed to list documents: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Convert a dotted metadata key to the JSON path recorded in the metadata index.
+///
+/// Only plain identifiers are accepted, since SQLite quotes other keys.
+fn metadata_path(key: &str) -> Option<String> {
+    let mut path = String::from("$");
+    for segment in key.split('.') {
+        let mut chars = segment.chars();
+        let valid = chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return None;
+        }
+        path.push('.');
+        path.push_str(segment);
+    }
+    Some(path)
+}
+
+/// Handler for the GET `/api/search` endpoint.
+///
+/// Results only include documents that the user could open without a
+/// password, so that snippets don't leak protected text.
+async fn search_handler(
+    query: SearchQuery,
+    email: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let limit = query.limit.clamp(1, MAX_SEARCH_LIMIT);
+    match state.database.search(&query.q, email.as_deref(), limit).await {
+        Ok(results) => Ok(warp::reply::json(&results)),
+        Err(e) => {
+            error!("Failed to search documents: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/documents` endpoint.
+///
+/// A custom ID must be made of letters, digits, `-` and `_`, and is rejected
+/// with 409 Conflict if a document with that ID already exists. The document
+/// is owned by the user creating it, if they are authenticated.
+async fn create_document_handler(
+    email: Option<String>,
+    body: CreateDocumentRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    if let Some(id) = &body.id {
+        if !valid_document_id(id) {
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        }
+    }
+    for _ in 0..MAX_CREATE_ATTEMPTS {
+        let id = body.id.clone().unwrap_or_else(generate_document_id);
+        let created = if state.documents.contains_key(&id) {
+            Ok(None)
+        } else {
+            let name = body.name.as_deref();
+            state.database.create(&id, name, email.as_deref()).await
+        };
+        match created {
+            Ok(Some(meta)) => {
+                return Ok(
+                    warp::reply::with_status(warp::reply::json(&meta), StatusCode::CREATED)
+                        .into_response(),
+                )
+            }
+            Ok(None) if body.id.is_some() => return Ok(StatusCode::CONFLICT.into_response()),
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to create document: {}", e);
+                return Err(warp::reject::custom(CustomReject(e)));
+            }
+        }
+    }
+    Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
+        "no unused document ID after {} attempts",
+        MAX_CREATE_ATTEMPTS
+    ))))
+}
+
+/// Check whether a custom document ID is safe to use in URLs.
+fn valid_document_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_DOCUMENT_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Handler for the GET `/api/documents/{id}` endpoint.
+async fn get_document_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    document_details(&state, &id).await.map(|details| warp::reply::json(&details))
+}
+
+/// Fetch the metadata and relations of a non-deleted document.
+async fn document_details(state: &ServerState, id: &str) -> Result<DocumentDetails, Rejection> {
+    let meta = match state.database.get_meta(id).await {
+        Ok(Some(meta)) => meta,
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to get document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    match state.database.relations(id).await {
+        Ok(relations) => Ok(DocumentDetails { meta, relations }),
+        Err(e) => {
+            error!("Failed to get relations of document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the PATCH `/api/documents/{id}` endpoint.
+///
+/// Renames the document and merges a patch into its custom metadata, each only
+/// if given. The metadata is patched first, so an invalid or oversized patch
+/// leaves the name unchanged too. Only owners may update a document with an
+/// access control list.
+async fn update_document_handler(
+    id: String,
+    email: Option<String>,
+    body: UpdateDocumentRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    if let Some(patch) = &body.metadata {
+        if !patch.is_object() {
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        }
+        match state.database.patch_metadata(&id, patch, MAX_METADATA_SIZE).await {
+            Ok(MetadataUpdate::Updated(_)) => (),
+            Ok(MetadataUpdate::NotFound) => return Err(warp::reject::not_found()),
+            Ok(MetadataUpdate::TooLarge) => {
+                return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response())
+            }
+            Err(e) => {
+                error!("Failed to update metadata of document {}: {}", id, e);
+                return Err(warp::reject::custom(CustomReject(e)));
+            }
+        }
+    }
+    if let Some(password) = body.password {
+        if password.as_deref() == Some("") {
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        }
+        let hash = match password {
+            Some(password) => Some(hash_password(password).await?),
+            None => None,
+        };
+        match state.database.set_password_hash(&id, hash.as_deref()).await {
+            Ok(true) => (),
+            Ok(false) => return Err(warp::reject::not_found()),
+            Err(e) => {
+                error!("Failed to set password of document {}: {}", id, e);
+                return Err(warp::reject::custom(CustomReject(e)));
+            }
+        }
+    }
+    if let Some(name) = &body.name {
+        if let Err(e) = state.database.rename(&id, name).await {
+            error!("Failed to rename document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta).into_response()),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e)))
+    }
+}
+
+/// Handler for the POST `/api/documents/{id}/warm` endpoint.
+async fn warm_document_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let already_loaded = state.documents.contains_key(&id);
+    let rustpad = open_document(&state, &id).await?;
+    Ok(warp::reply::json(&WarmResponse {
+        already_loaded,
+        revision: rustpad.revision(),
+    }))
+}
+
+/// Handler for the POST `/api/documents/{id}/persist` endpoint.
+///
+/// Stores the in-memory document right away instead of waiting for the next
+/// tick of its persister, so that a backup of the database file includes it.
+async fn persist_document_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let rustpad = match state.documents.get(&id) {
+        Some(document) => Arc::clone(&document.rustpad),
+        None => return Err(warp::reject::not_found()),
+    };
+    let revision = match store_document(&state.database, &id, &rustpad).await {
+        Ok(revision) => revision,
+        Err(e) => {
+            error!("Failed to persist document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    info!("persisted revision {} for id = {} on request", revision, id);
+    record_persisted(&state.database, &id, &rustpad, revision).await;
+    rustpad.trim_history(revision);
+    Ok(warp::reply::json(&PersistResponse { revision }))
+}
+
+/// Load the stored operation history of a document, up to
+/// [`MAX_LOADED_HISTORY`] operations.
+async fn load_history(state: &ServerState, id: &str) -> anyhow::Result<Vec<StoredOperation>> {
+    state.database.load_operations(id, MAX_LOADED_HISTORY).await
+}
+
+/// Fetch the metadata and latest text of a document.
+///
+/// The text includes unsaved edits if the document is currently loaded in
+/// memory. Rejects with "not found" if the document exists nowhere.
+async fn load_latest(
+    state: &ServerState,
+    id: &str,
+) -> Result<(Option<DocumentMeta>, PersistedDocument), Rejection> {
+    let meta = match state.database.get_meta(id).await {
+        Ok(meta) => meta,
+        Err(e) => {
+            error!("Failed to get document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let loaded = state.documents.get(id).map(|doc| doc.rustpad.snapshot());
+    let document = match (loaded, &meta) {
+        (Some(document), _) => document,
+        (None, Some(_)) => match state.database.load(id).await {
+            Ok(document) => document,
+            Err(e) => {
+                error!("Failed to load document {}: {}", id, e);
+                return Err(warp::reject::custom(CustomReject(e)));
+            }
+        },
+        (None, None) => return Err(warp::reject::not_found()),
+    };
+    Ok((meta, document))
+}
+
+/// Return the language of a document, falling back to the one detected from
+/// its text when it was last persisted.
+fn display_language<'a>(
+    document: &'a PersistedDocument,
+    meta: Option<&'a DocumentMeta>,
+) -> Option<&'a str> {
+    document
+        .language
+        .as_deref()
+        .or_else(|| meta.and_then(|meta| meta.detected_language.as_deref()))
+}
+
+/// Handler for the GET `/api/documents/{id}/download` endpoint.
+///
+/// Returns the latest text as an attachment, named after the document with an
+/// extension matching its language.
+async fn download_document_handler(
+    id: String,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    let (meta, document) = load_latest(&state, &id).await?;
+    let language = display_language(&document, meta.as_ref());
+    let name = meta.as_ref().and_then(|meta| meta.name.as_deref()).unwrap_or(&id);
+    let filename = download_filename(name, language, &state.language_map.read());
+    let text = state.normalization.apply(&document.text).into_owned();
+    let reply = warp::reply::with_header(text, "content-type", "text/plain; charset=utf-8");
+    Ok(warp::reply::with_header(
+        reply,
+        "content-disposition",
+        content_disposition(&filename),
+    ))
+}
+
+/// Handler for the GET and HEAD `/api/documents/{id}/raw` endpoints.
+///
+/// Returns the latest text with a hash of its content in the `x-content-hash`
+/// header, so that sync tools can detect changes and verify integrity. HEAD
+/// requests return only the hash.
+async fn raw_document_handler(
+    id: String,
+    method: warp::http::Method,
+    query: RawQuery,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    let (_, document) = load_latest(&state, &id).await?;
+    let hash = match query.hash {
+        HashAlgorithm::Sha256 => format!("sha256:{}", sha256_hex(document.text.as_bytes())),
+    };
+    let body = if method == warp::http::Method::HEAD {
+        String::new()
+    } else {
+        document.text
+    };
+    let reply = warp::reply::with_header(body, "content-type", "text/plain; charset=utf-8");
+    Ok(warp::reply::with_header(reply, "x-content-hash", hash).into_response())
+}
+
+/// Handler for the GET `/api/documents/{id}/diff` endpoint.
+///
+/// Returns a unified diff between the text at two revisions, rebuilt from the
+/// operation history. Documents that are not loaded are read from the
+/// database without loading them, and 404 Not Found is returned if the
+/// history of either revision is no longer available.
+async fn diff_document_handler(
+    id: String,
+    query: DiffQuery,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    let rustpad = history_rustpad(&state, &id).await?;
+    let to = query.to.unwrap_or_else(|| rustpad.revision());
+    if query.from > to || to > rustpad.revision() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let mut texts = rustpad
+        .text_at(query.from, &[])
+        .zip(rustpad.text_at(to, &[]));
+    if texts.is_none() {
+        let stored = match load_history(&state, &id).await {
+            Ok(stored) => stored,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        };
+        texts = rustpad
+            .text_at(query.from, &stored)
+            .zip(rustpad.text_at(to, &stored));
+    }
+    let (old, new) = match texts {
+        Some(texts) => texts,
+        None => return Err(warp::reject::not_found()),
+    };
+    let diff = diff::unified_diff(
+        &old,
+        &new,
+        &format!("{}@{}", id, query.from),
+        &format!("{}@{}", id, to),
+    );
+    let reply = warp::reply::with_header(diff, "content-type", "text/x-diff; charset=utf-8");
+    Ok(reply.into_response())
+}
+
+/// Persists a document if it is loaded, so that its whole operation history
+/// is in the database, or rejects with 404 Not Found if it does not exist.
+async fn store_history(state: &ServerState, id: &str) -> Result<(), Rejection> {
+    store_loaded(state, id).await?;
+    match state.database.get_meta(id).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to get document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Number of operations read from the database at a time when reading the
+/// history of a document.
+const HISTORY_PAGE_SIZE: usize = 1000;
+
+/// Handler for the GET `/api/documents/{id}/history.ndjson` endpoint.
+///
+/// Streams every stored operation of the document as newline-delimited JSON,
+/// in order of revision, with the time and authenticated email of each edit.
+/// A loaded document is persisted first so that the stream includes its
+/// latest edits.
+async fn history_document_handler(
+    id: String,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    store_history(&state, &id).await?;
+
+    let chunks = futures::stream::unfold(Some((state, id, 0)), |page| async move {
+        let (state, id, start) = page?;
+        let operations = state
+            .database
+            .operations_from(&id, start, HISTORY_PAGE_SIZE)
+            .await;
+        let operations = match operations {
+            Ok(operations) => operations,
+            Err(e) => {
+                error!("Failed to load history of document {}: {}", id, e);
+                return Some((Err(e), None));
+            }
+        };
+        let next = operations.last()?.revision + 1;
+        let mut chunk = Vec::new();
+        for operation in &operations {
+            if let Err(e) = serde_json::to_writer(&mut chunk, operation) {
+                return Some((Err(e.into()), None));
+            }
+            chunk.push(b'\n');
+        }
+        Some((Ok(chunk), Some((state, id, next))))
+    });
+
+    let reply = warp::reply::with_header(
+        warp::reply::Response::new(warp::hyper::Body::wrap_stream(chunks)),
+        "content-type",
+        "application/x-ndjson",
+    );
+    Ok(reply.into_response())
+}
+
+/// Handler for the GET `/api/documents/{id}/timeline` endpoint.
+///
+/// Summarizes the stored operations of the document as sessions of edits by
+/// one author, oldest first. A loaded document is persisted first so that
+/// the timeline includes its latest edits.
+async fn timeline_document_handler(
+    id: String,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    store_history(&state, &id).await?;
+
+    let mut timeline = Timeline::default();
+    let mut start = 0;
+    loop {
+        let operations = state
+            .database
+            .operations_from(&id, start, HISTORY_PAGE_SIZE)
+            .await;
+        let operations = match operations {
+            Ok(operations) => operations,
+            Err(e) => {
+                error!("Failed to load history of document {}: {}", id, e);
+                return Err(warp::reject::custom(CustomReject(e)));
+            }
+        };
+        let last = match operations.last() {
+            Some(last) => last.revision,
+            None => break,
+        };
+        for operation in &operations {
+            timeline.push(operation);
+        }
+        start = last + 1;
+    }
+    Ok(warp::reply::json(&timeline.finish()))
+}
+
+/// Handler for the GET `/api/documents/{id}/blame` endpoint.
+///
+/// Returns the revision and authenticated email of the last edit to each line
+/// of the text, or 404 Not Found if the operation history is no longer
+/// available.
+async fn blame_document_handler(
+    id: String,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    let rustpad = history_rustpad(&state, &id).await?;
+    let mut blame = rustpad.blame(&[]);
+    if blame.is_none() {
+        let stored = match load_history(&state, &id).await {
+            Ok(stored) => stored,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        };
+        blame = rustpad.blame(&stored);
+    }
+    match blame {
+        Some(blame) => Ok(warp::reply::json(&blame)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// Returns the loaded document, or a temporary copy read from the database
+/// with its operation history if it is not loaded, without loading it.
+async fn history_rustpad(state: &ServerState, id: &str) -> Result<Arc<Rustpad>, Rejection> {
+    if let Some(doc) = state.documents.get(id) {
+        return Ok(Arc::clone(&doc.rustpad));
+    }
+    let (_, document) = load_latest(state, id).await?;
+    let history = match load_history(state, id).await {
+        Ok(history) => history,
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    };
+    let rustpad = Rustpad::from_document(document, history, state.database.clone());
+    Ok(Arc::new(rustpad))
+}
+
+/// Handler for the GET `/api/documents/{id}/stats` endpoint.
+///
+/// Counts are taken from the in-memory document if it is loaded, and from
+/// the database otherwise.
+async fn document_stats_handler(
+    id: String,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let loaded = state.documents.get(&id).map(|doc| Arc::clone(&doc.rustpad));
+    let (text, revision, users) = match loaded {
+        Some(rustpad) => {
+            let snapshot = rustpad.text_snapshot();
+            (snapshot.text, snapshot.revision, rustpad.connections())
+        }
+        // Loading the row into memory yields a single initial operation.
+        None => (load_latest(&state, &id).await?.1.text, 1, 0),
+    };
+    Ok(warp::reply::json(&DocumentStats {
+        chars: text.chars().count(),
+        bytes: text.len(),
+        words: ot::count_words(&text),
+        lines: text.lines().count(),
+        revision,
+        users,
+    }))
+}
+
+/// Handler for the GET `/api/documents/{id}/session` endpoint.
+///
+/// Documents that are not loaded in memory have nobody connected, so their
+/// session is read from the database without loading them.
+async fn document_session_handler(
+    id: String,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let loaded = state.documents.get(&id).map(|doc| Arc::clone(&doc.rustpad));
+    let session = match loaded {
+        Some(rustpad) => rustpad.session(),
+        None => Session {
+            users: Vec::new(),
+            revision: 1,
+            language: load_latest(&state, &id).await?.1.language,
+        },
+    };
+    Ok(warp::reply::json(&session))
+}
+
+/// Handler for the GET `/api/documents/{id}/export` endpoint.
+///
+/// Markdown documents are rendered to sanitized HTML, and other documents are
+/// wrapped in a code block. PDFs are streamed to the client page by page.
+async fn export_document_handler(
+    id: String,
+    query: ExportQuery,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    let (meta, document) = load_latest(&state, &id).await?;
+    let text = state.normalization.apply(&document.text);
+    let (body, content_type) = match query.format {
+        ExportFormat::Html => (
+            export::render_html(&text, display_language(&document, meta.as_ref())).into(),
+            "text/html; charset=utf-8",
+        ),
+        ExportFormat::Markdown => (text.into_owned().into(), "text/markdown; charset=utf-8"),
+        ExportFormat::Pdf => {
+            let chunks = PdfWriter::new(&text).map(Ok::<_, Infallible>);
+            (
+                warp::hyper::Body::wrap_stream(futures::stream::iter(chunks)),
+                "application/pdf",
+            )
+        }
+    };
+    Ok(warp::reply::with_header(warp::reply::Response::new(body), "content-type", content_type)
+        .into_response())
+}
+
+/// Handler for the POST `/api/documents/{id}/publish` endpoint.
+///
+/// Freezes the current text as the public version of the document, served by
+/// the `/api/published/{id}` endpoints while editing continues. Publishing
+/// again replaces the frozen copy.
+///
+/// A future `publish_at` time leaves the document to be published by the
+/// scheduler then, with 202 Accepted, and an `unpublish_at` time has the
+/// scheduler withdraw the published version. Each request replaces any
+/// earlier schedule.
+///
+/// Publishing makes the text public, so only owners may publish, and a
+/// protected document needs its password as well.
+async fn publish_document_handler(
+    id: String,
+    query: PublishQuery,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    unlock_document(&state, &id, &key, true).await?;
+    require_owner(&state, &id, email.as_deref()).await?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let publish_at = query.publish_at.filter(|&publish_at| publish_at > now);
+    let live_at = publish_at.unwrap_or(now);
+    if query.unpublish_at.map_or(false, |unpublish_at| unpublish_at <= live_at) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let mut response = PublishResponse {
+        revision: None,
+        published_at: None,
+        publish_at,
+        unpublish_at: query.unpublish_at,
+    };
+    let status = match publish_at {
+        Some(_) => {
+            store_loaded(&state, &id).await?;
+            StatusCode::ACCEPTED
+        }
+        None => {
+            let (revision, published_at) = publish_now(&state, &id).await?;
+            response.revision = Some(revision);
+            response.published_at = Some(published_at);
+            StatusCode::OK
+        }
+    };
+    let scheduled = state
+        .database
+        .set_publish_schedule(&id, publish_at, query.unpublish_at);
+    match scheduled.await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to schedule publishing document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    let reply = warp::reply::with_status(warp::reply::json(&response), status);
+    Ok(reply.into_response())
+}
+
+/// Handler for the POST `/api/documents/{id}/transfer` endpoint.
+///
+/// Makes another user the owner of the document. While anyone may change a
+/// document without an access control list, only its owner may give it away.
+async fn transfer_document_handler(
+    id: String,
+    email: Option<String>,
+    body: TransferRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    let owner = body.email.trim();
+    if owner.is_empty() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    store_loaded(&state, &id).await?;
+    let current = match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => meta.owner_email,
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    };
+    if current.is_some() && current != email && load_acl(&state, &id).await?.is_empty() {
+        return Err(warp::reject::custom(Forbidden));
+    }
+    match state.database.set_owner(&id, owner).await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to transfer document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta).into_response()),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the GET `/api/documents/{id}/acl` endpoint.
+///
+/// Only users with a role on the document may see who else has one.
+async fn get_acl_handler(
+    id: String,
+    email: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    match state.database.get_meta(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    }
+    if document_role(&state, &id, email.as_deref()).await?.is_none() {
+        return Err(warp::reject::custom(Forbidden));
+    }
+    load_acl(&state, &id).await.map(|acl| warp::reply::json(&acl))
+}
+
+/// Handler for the PUT `/api/documents/{id}/acl` endpoint.
+///
+/// Gives a user a role on the document, replacing any role they had. A list
+/// must keep at least one owner, so the first entry of a document must be an
+/// owner and the last owner cannot be demoted.
+async fn set_acl_handler(
+    id: String,
+    email: Option<String>,
+    body: AclRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    let user = body.email.trim();
+    if user.is_empty() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let acl = load_acl(&state, &id).await?;
+    let owners = acl.iter().filter(|entry| entry.role == Role::Owner && entry.email != user);
+    if body.role != Role::Owner && owners.count() == 0 {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+    store_loaded(&state, &id).await?;
+    match state.database.set_role(&id, user, body.role).await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to set role on document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    let acl = load_acl(&state, &id).await?;
+    Ok(warp::reply::json(&acl).into_response())
+}
+
+/// Handler for the DELETE `/api/documents/{id}/acl` endpoint.
+///
+/// Removing the last owner is only allowed along with everyone else, which
+/// opens the document to all users again.
+async fn remove_acl_handler(
+    id: String,
+    email: Option<String>,
+    body: AclRemoveRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    let user = body.email.trim();
+    let acl = load_acl(&state, &id).await?;
+    let (removed, kept): (Vec<_>, Vec<_>) = acl.iter().partition(|entry| entry.email == user);
+    if removed.is_empty() {
+        return Err(warp::reject::not_found());
+    }
+    if !kept.is_empty() && kept.iter().all(|entry| entry.role != Role::Owner) {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+    if let Err(e) = state.database.remove_role(&id, user).await {
+        error!("Failed to remove role on document {}: {}", id, e);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+    let acl = load_acl(&state, &id).await?;
+    Ok(warp::reply::json(&acl).into_response())
+}
+
+/// Handler for the POST `/api/documents/{id}/share` endpoint.
+///
+/// Mints a link that opens the document for a limited time, even if it is
+/// protected by a password, and only for reading with the `read` scope.
+/// Links bypass the access control list, so only owners may mint them.
+async fn share_document_handler(
+    id: String,
+    email: Option<String>,
+    body: ShareRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let expires_at = body
+        .expires_at
+        .unwrap_or(now + DEFAULT_SHARE_EXPIRY.as_secs() as i64);
+    if expires_at <= now || expires_at > now + MAX_SHARE_EXPIRY.as_secs() as i64 {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    store_loaded(&state, &id).await?;
+    match state.database.get_meta(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    }
+    let token = state.shares.sign(&ShareClaims {
+        document: id,
+        scope: body.scope,
+        expires_at,
+    });
+    Ok(warp::reply::json(&ShareResponse {
+        token,
+        scope: body.scope,
+        expires_at,
+    })
+    .into_response())
+}
+
+/// Stores a document right away if it is loaded, so that it has a row in the
+/// database to refer to.
+async fn store_loaded(state: &ServerState, id: &str) -> Result<(), Rejection> {
+    let rustpad = match state.documents.get(id) {
+        Some(document) => Arc::clone(&document.rustpad),
+        None => return Ok(()),
+    };
+    let revision = match store_document(&state.database, id, &rustpad).await {
+        Ok(revision) => revision,
+        Err(e) => {
+            error!("Failed to persist document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    record_persisted(&state.database, id, &rustpad, revision).await;
+    rustpad.trim_history(revision);
+    Ok(())
+}
+
+/// Publishes the current text of a document, returning the revision
+/// published and when.
+async fn publish_now(state: &ServerState, id: &str) -> Result<(usize, i64), Rejection> {
+    store_loaded(state, id).await?;
+    let snapshot = history_rustpad(state, id).await?.text_snapshot();
+    let document = PersistedDocument {
+        text: snapshot.text,
+        language: snapshot.language,
+    };
+    let published = state.database.publish(id, snapshot.revision, &document);
+    match published.await {
+        Ok(Some(published_at)) => Ok((snapshot.revision, published_at)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to publish document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the GET `/api/documents/{id}/versions` endpoint.
+///
+/// Lists the versions of a document stored periodically by its persister,
+/// newest first.
+async fn list_versions_handler(
+    id: String,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    match state.database.versions(&id).await {
+        Ok(versions) => Ok(warp::reply::json(&versions)),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the GET `/api/documents/{id}/versions/{version}` endpoint.
+///
+/// Returns the text of a stored version, so that it can be recovered.
+async fn get_version_handler(
+    id: String,
+    version_id: i64,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    match state.database.load_version(&id, version_id).await {
+        Ok(Some(document)) => {
+            let reply = warp::reply::with_header(
+                document.text,
+                "content-type",
+                "text/plain; charset=utf-8",
+            );
+            Ok(reply.into_response())
+        }
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Fetch the published version of a document, rejecting with "not found" if
+/// it was never published, or is deleted or quarantined.
+async fn load_published(state: &ServerState, id: &str) -> Result<PublishedDocument, Rejection> {
+    match state.database.published(id).await {
+        Ok(Some(published)) => Ok(published),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to get published document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the GET `/api/published/{id}` endpoint.
+///
+/// Returns the published text, or JSON with its revision and language like the
+/// text endpoint when requested.
+async fn published_handler(
+    id: String,
+    query: PublishedQuery,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let published = load_published(&state, &id).await?;
+    if query.with_revision {
+        return Ok(warp::reply::json(&published).into_response());
+    }
+    let reply =
+        warp::reply::with_header(published.text, "content-type", "text/plain; charset=utf-8");
+    Ok(reply.into_response())
+}
+
+/// Handler for the GET `/api/published/{id}/embed` endpoint.
+///
+/// Returns the published text rendered as an HTML fragment, like the HTML
+/// export of the live text.
+async fn published_embed_handler(
+    id: String,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let published = load_published(&state, &id).await?;
+    let meta = match state.database.get_meta(&id).await {
+        Ok(meta) => meta,
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    };
+    let document = PersistedDocument {
+        text: published.text,
+        language: published.language,
+    };
+    let html = export::render_html(&document.text, display_language(&document, meta.as_ref()));
+    let reply = warp::reply::with_header(html, "content-type", "text/html; charset=utf-8");
+    Ok(reply.into_response())
+}
+
+/// Build a file name from a document name, adding an extension for its language.
+fn download_filename(name: &str, language: Option<&str>, language_map: &LanguageMap) -> String {
+    let stem: String = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let stem = match stem.trim_matches('.') {
+        "" => "document",
+        stem => stem,
+    };
+    let language = language.unwrap_or("plaintext");
+    if language_map.from_filename(stem) == Some(language) {
+        return stem.to_owned();
+    }
+    let extension = language_map.extension_for(language).unwrap_or("txt");
+    format!("{}.{}", stem, extension)
+}
+
+/// Format a `Content-Disposition` header for downloading a file.
+///
+/// Includes an ASCII fallback name and the exact name encoded per RFC 5987.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '%' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Handler for the POST `/api/documents/{id}/duplicate` endpoint.
+///
+/// Copies the latest text of the document, see [`load_latest`].
+async fn duplicate_document_handler(
+    id: String,
+    email: Option<String>,
+    key: DocumentKey,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    authorize_document(&state, &id, email.as_deref(), &key, false).await?;
+    let (meta, document) = load_latest(&state, &id).await?;
+    let copy = NewDocument {
+        id: generate_document_id(),
+        name: meta
+            .and_then(|meta| meta.name)
+            .map(|name| format!("{} (copy)", name)),
+        text: document.text,
+        language: document.language,
+    };
+    match state.database.create_many(&[copy]).await {
+        Ok(mut metas) => Ok(warp::reply::with_status(
+            warp::reply::json(&metas.remove(0)),
+            StatusCode::CREATED,
+        )),
+        Err(e) => {
+            error!("Failed to duplicate document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/documents/bulk` endpoint.
+async fn bulk_documents_handler(
+    email: Option<String>,
+    body: BulkRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    if body.ids.len() > MAX_BULK_IDS {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    for id in &body.ids {
+        match &body.action {
+            BulkAction::Delete | BulkAction::Restore => {
+                require_owner(&state, id, email.as_deref()).await?;
+            }
+            BulkAction::RenamePrefix { .. } | BulkAction::SetLanguage { .. } => {
+                require_editor(&state, id, email.as_deref()).await?;
+            }
+        }
+    }
+    let changed = match state.database.bulk(&body.action, &body.ids).await {
+        Ok(changed) => changed,
+        Err(e) => {
+            error!("Failed to apply bulk operation: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+
+    // Bring in-memory documents in line, now that the database is updated.
+    for id in &body.ids {
+        match &body.action {
+            BulkAction::Delete => {
+                state.documents.remove(id);
+            }
+            BulkAction::SetLanguage { language } => {
+                let rustpad = state.documents.get(id).map(|doc| Arc::clone(&doc.rustpad));
+                if let Some(rustpad) = rustpad {
+                    rustpad.set_language(language.clone());
+                }
+            }
+            BulkAction::Restore | BulkAction::RenamePrefix { .. } => {}
+        }
+    }
+
+    Ok(warp::reply::json(&BulkResponse { changed }).into_response())
+}
+
+/// Handler for the POST `/api/documents/import` endpoint.
+///
+/// Each uploaded file becomes a new document named after the file, with its
+/// language inferred from the extension. Either all files are imported, or
+/// none of them are.
+async fn import_documents_handler(
+    form: FormData,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let parts: Vec<Part> = match form.try_collect().await {
+        Ok(parts) => parts,
+        Err(e) => {
+            warn!("Failed to read import upload: {}", e);
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        }
+    };
+
+    let mut documents = Vec::new();
+    for part in parts {
+        let filename = match part.filename() {
+            Some(filename) => filename.to_owned(),
+            None => continue, // Ignore fields that are not files
+        };
+        let bytes = part
+            .stream()
+            .try_fold(Vec::new(), |mut bytes, chunk| async move {
+                bytes.extend_from_slice(chunk.chunk());
+                Ok(bytes)
+            })
+            .await;
+        let text = match bytes.map(String::from_utf8) {
+            Ok(Ok(text)) => text,
+            _ => return Ok(StatusCode::BAD_REQUEST.into_response()),
+        };
+        if text.chars().count() > MAX_DOCUMENT_SIZE {
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        documents.push(NewDocument {
+            id: generate_document_id(),
+            language: state.language_map.read().from_filename(&filename).map(String::from),
+            name: Some(filename),
+            text,
+        });
+    }
+
+    match state.database.create_many(&documents).await {
+        Ok(metas) => Ok(
+            warp::reply::with_status(warp::reply::json(&metas), StatusCode::CREATED)
+                .into_response(),
+        ),
+        Err(e) => {
+            error!("Failed to import documents: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the GET `/api/trash` endpoint.
+async fn trash_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    match state.database.list_deleted().await {
+        Ok(documents) => Ok(warp::reply::json(&documents)),
+        Err(e) => {
+            error!("Failed to list deleted documents: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/documents/{id}/restore` endpoint.
+async fn restore_document_handler(
+    id: String,
+    email: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    match state.database.restore(&id).await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to restore document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the POST `/api/documents/{id}/archive` and
+/// `/api/documents/{id}/unarchive` endpoints.
+///
+/// Archived documents are hidden from the default document list, and stay
+/// readable but cannot be edited.
+async fn archive_document_handler(
+    id: String,
+    email: Option<String>,
+    archived: bool,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    match state.database.set_archived(&id, archived).await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to archive document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    update_read_only(&state, &id).await?;
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Makes a loaded document read-only if it is archived, quarantined or set to
+/// read-only in its settings, or the server is a standby, and editable
+/// otherwise.
+async fn update_read_only(state: &ServerState, id: &str) -> Result<(), Rejection> {
+    let loaded = state.documents.get(id).map(|doc| Arc::clone(&doc.rustpad));
+    if let Some(rustpad) = loaded {
+        let locked = match state.database.is_locked(id).await {
+            Ok(locked) => locked,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        };
+        rustpad.set_read_only(locked || rustpad.settings().read_only || in_standby(state));
+    }
+    Ok(())
+}
+
+/// Handler for the POST `/api/documents/{id}/report` endpoint.
+///
+/// Files an abuse report for admins to review. Each client may only file a
+/// few reports an hour, counted by email if authenticated and by address
+/// otherwise.
+async fn report_document_handler(
+    id: String,
+    request: ReportRequest,
+    email: Option<String>,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let reason = request.reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_LENGTH {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let client = email
+        .clone()
+        .or_else(|| remote.map(|addr| addr.ip().to_string()));
+    if let Some(client) = &client {
+        if !state.reporters.try_record(client) {
+            warn!("refusing abuse report from {} after too many", client);
+            return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
+        }
+    }
+    let report = state.database.add_report(&id, reason, email.as_deref());
+    match report.await {
+        Ok(Some(report_id)) => {
+            info!("abuse report {} filed against document {}", report_id, id);
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to file report against document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the PUT `/api/documents/{id}/folder` endpoint.
+async fn move_document_handler(
+    id: String,
+    email: Option<String>,
+    body: MoveDocumentRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_editor(&state, &id, email.as_deref()).await?;
+    if !valid_folder_parent(&state, None, body.folder_id).await? {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    match state.database.move_document(&id, body.folder_id).await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to move document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta).into_response()),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Returns if `parent` exists and placing `folder` under it makes no cycle.
+///
+/// A `None` parent is the top level, which is always valid.
+async fn valid_folder_parent(
+    state: &ServerState,
+    folder: Option<i64>,
+    parent: Option<i64>,
+) -> Result<bool, Rejection> {
+    let parent = match parent {
+        Some(parent) => parent,
+        None => return Ok(true),
+    };
+    let result = match state.database.get_folder(parent).await {
+        Ok(None) => Ok(false),
+        Ok(Some(_)) => match folder {
+            Some(folder) => state
+                .database
+                .folder_within(parent, folder)
+                .await
+                .map(|within| !within),
+            None => Ok(true),
+        },
+        Err(e) => Err(e),
+    };
+    result.map_err(|e| {
+        error!("Failed to check parent folder {}: {}", parent, e);
+        warp::reject::custom(CustomReject(e))
+    })
+}
+
+/// Handler for the GET `/api/folders` endpoint.
+async fn list_folders_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    match state.database.list_folders().await {
+        Ok(folders) => Ok(warp::reply::json(&folders)),
+        Err(e) => {
+            error!("Failed to list folders: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/folders` endpoint.
+async fn create_folder_handler(
+    body: FolderRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let name = body.name.trim();
+    if name.is_empty() || !valid_folder_parent(&state, None, body.parent_id).await? {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    match state.database.create_folder(name, body.parent_id).await {
+        Ok(folder) => Ok(
+            warp::reply::with_status(warp::reply::json(&folder), StatusCode::CREATED)
+                .into_response(),
+        ),
+        Err(e) => {
+            error!("Failed to create folder: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the GET `/api/folders/{id}` endpoint.
+async fn get_folder_handler(id: i64, state: ServerState) -> Result<impl Reply, Rejection> {
+    match state.database.get_folder(id).await {
+        Ok(Some(folder)) => Ok(warp::reply::json(&folder)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to get folder {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the PUT `/api/folders/{id}` endpoint.
+async fn update_folder_handler(
+    id: i64,
+    body: FolderRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let name = body.name.trim();
+    if name.is_empty() || !valid_folder_parent(&state, Some(id), body.parent_id).await? {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    match state.database.update_folder(id, name, body.parent_id).await {
+        Ok(Some(folder)) => Ok(warp::reply::json(&folder).into_response()),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to update folder {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the DELETE `/api/folders/{id}` endpoint.
+///
+/// Only empty folders can be deleted.
+async fn delete_folder_handler(
+    id: i64,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    match state.database.count_folder_children(id).await {
+        Ok(0) => {}
+        Ok(_) => return Ok(StatusCode::CONFLICT.into_response()),
+        Err(e) => {
+            error!("Failed to check folder {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    match state.database.delete_folder(id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(false) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to delete folder {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/documents/{id}/tags` endpoint.
+async fn add_tag_handler(
+    id: String,
+    email: Option<String>,
+    body: TagRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_editor(&state, &id, email.as_deref()).await?;
+    let tag = body.tag.trim();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH || tag.contains(char::is_control) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    if let Err(e) = state.database.add_tag(&id, tag).await {
+        error!("Failed to tag document {}: {}", id, e);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta).into_response()),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the PUT `/api/documents/{id}/star` endpoint.
+///
+/// Stars the document for the authenticated user, who must be signed in.
+async fn star_document_handler(
+    id: String,
+    email: Option<String>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let email = match email {
+        Some(email) => email,
+        None => return Ok(StatusCode::UNAUTHORIZED.into_response()),
+    };
+    if let Err(e) = state.database.star(&email, &id).await {
+        error!("Failed to star document {}: {}", id, e);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(_)) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the DELETE `/api/documents/{id}/star` endpoint.
+async fn unstar_document_handler(
+    id: String,
+    email: Option<String>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let email = match email {
+        Some(email) => email,
+        None => return Ok(StatusCode::UNAUTHORIZED.into_response()),
+    };
+    match state.database.unstar(&email, &id).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => {
+            error!("Failed to unstar document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the GET `/api/documents/{id}/metadata` endpoint.
+async fn get_metadata_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    match state.database.get_metadata(&id).await {
+        Ok(Some(metadata)) => Ok(warp::reply::json(&metadata)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to get metadata of document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the GET `/api/documents/{id}/settings` endpoint.
+async fn get_settings_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    match state.database.settings(&id).await {
+        Ok(Some(settings)) => Ok(warp::reply::json(&settings)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to get settings of document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the PATCH `/api/documents/{id}/settings` endpoint.
+///
+/// Updates the given settings, and broadcasts the result to clients connected
+/// to the document.
+async fn patch_settings_handler(
+    id: String,
+    email: Option<String>,
+    body: UpdateSettingsRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    let mut settings = match state.database.settings(&id).await {
+        Ok(Some(settings)) => settings,
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to get settings of document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    body.apply(&mut settings);
+    match state.database.set_settings(&id, &settings).await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to update settings of document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    if let Some(document) = state.documents.get(&id) {
+        document.rustpad.set_settings(settings.clone());
+    }
+    update_read_only(&state, &id).await?;
+    Ok(warp::reply::json(&settings))
+}
+
+/// Handler for the PATCH `/api/documents/{id}/metadata` endpoint.
+///
+/// The body is a JSON merge patch: keys set to `null` are removed and all
+/// other keys are added or replaced.
+async fn patch_metadata_handler(
+    id: String,
+    email: Option<String>,
+    patch: serde_json::Value,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_editor(&state, &id, email.as_deref()).await?;
+    if !patch.is_object() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    match state.database.patch_metadata(&id, &patch, MAX_METADATA_SIZE).await {
+        Ok(MetadataUpdate::Updated(metadata)) => Ok(warp::reply::json(&metadata).into_response()),
+        Ok(MetadataUpdate::NotFound) => Err(warp::reject::not_found()),
+        Ok(MetadataUpdate::TooLarge) => Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response()),
+        Err(e) => {
+            error!("Failed to update metadata of document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the DELETE `/api/documents/{id}/tags` endpoint.
+async fn remove_tag_handler(
+    id: String,
+    email: Option<String>,
+    body: TagRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    require_editor(&state, &id, email.as_deref()).await?;
+    if let Err(e) = state.database.remove_tag(&id, body.tag.trim()).await {
+        error!("Failed to untag document {}: {}", id, e);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the POST `/api/documents/{id}/relations` endpoint.
+async fn add_relation_handler(
+    id: String,
+    email: Option<String>,
+    body: RelationRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    require_editor(&state, &id, email.as_deref()).await?;
+    if body.target == id {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    match state.database.get_meta(&body.target).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    }
+    match state.database.get_meta(&id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    }
+    if let Err(e) = state.database.add_relation(&id, &body.target, body.kind).await {
+        error!("Failed to link document {}: {}", id, e);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+    let details = document_details(&state, &id).await?;
+    Ok(warp::reply::json(&details).into_response())
+}
+
+/// Handler for the DELETE `/api/documents/{id}/relations` endpoint.
+async fn remove_relation_handler(
+    id: String,
+    email: Option<String>,
+    body: RelationRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    require_editor(&state, &id, email.as_deref()).await?;
+    if let Err(e) = state.database.remove_relation(&id, &body.target, body.kind).await {
+        error!("Failed to unlink document {}: {}", id, e);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+    document_details(&state, &id).await.map(|details| warp::reply::json(&details))
+}
+
+/// Handler for the DELETE `/api/documents/{id}` endpoint.
+///
+/// With `?purge=true`, the document is permanently deleted instead. Only
+/// owners may delete a document with an access control list.
+async fn delete_document_handler(
+    id: String,
+    email: Option<String>,
+    query: DeleteQuery,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    require_owner(&state, &id, email.as_deref()).await?;
+    if query.purge {
+        let result = state.database.purge(&id).await;
+        state.documents.remove(&id);
+        return match result {
+            Ok(true) => Ok(StatusCode::NO_CONTENT),
+            Ok(false) => Err(warp::reject::not_found()),
+            Err(e) => {
+                error!("Failed to purge document {}: {}", id, e);
+                Err(warp::reject::custom(CustomReject(e)))
+            }
+        };
+    }
+
+    // Write the tombstone before evicting, so that a client reconnecting in
+    // between cannot reload the document from its old row.
+    let result = state.database.soft_delete(&id).await;
+    state.documents.remove(&id);
+
+    match result {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => {
+            error!("Failed to delete document {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the DELETE `/api/documents/all` endpoint.
+async fn delete_all_documents_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    let result = state.database.delete_all_documents().await;
+    // Clear all in-memory documents, after the tombstones are written
+    state.documents.clear();
+
+    match result {
+        Ok(deleted) => Ok(warp::reply::json(&DeleteAllResponse { deleted })),
+        Err(e) => {
+            error!("Failed to delete all documents: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/admin/db/vacuum` endpoint.
+async fn vacuum_handler(state: ServerState) -> Result<warp::reply::Response, Rejection> {
+    let _guard = match state.maintenance.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => return Ok(StatusCode::CONFLICT.into_response()),
+    };
+    info!("running database vacuum");
+    match state.database.vacuum().await {
+        Ok(report) => Ok(warp::reply::json(&report).into_response()),
+        Err(e) => {
+            error!("Failed to vacuum database: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/admin/db/check` endpoint.
+async fn integrity_check_handler(state: ServerState) -> Result<warp::reply::Response, Rejection> {
+    let _guard = match state.maintenance.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => return Ok(StatusCode::CONFLICT.into_response()),
+    };
+    info!("running database integrity check");
+    match state.database.integrity_check().await {
+        Ok(report) => Ok(warp::reply::json(&report).into_response()),
+        Err(e) => {
+            error!("Failed to check database integrity: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/admin/documents/{id}/evict` endpoint.
+///
+/// The document is persisted before it is removed, and once more afterwards
+/// if edits landed while the first write was in flight, since removing it
+/// kills the `Rustpad` and no further edits can be applied.
+async fn evict_document_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let rustpad = match state.documents.get(&id) {
+        Some(document) => Arc::clone(&document.rustpad),
+        None => return Err(warp::reject::not_found()),
+    };
+    let mut revision = 0;
+    if rustpad.revision() > 0 {
+        revision = match store_document(&state.database, &id, &rustpad).await {
+            Ok(revision) => revision,
+            Err(e) => {
+                error!("Failed to persist document {} before eviction: {}", id, e);
+                return Err(warp::reject::custom(CustomReject(e)));
+            }
+        };
+    }
+    info!("evicting document {} on request", id);
+    state.documents.remove(&id);
+    let mut final_revision = revision;
+    if rustpad.revision() > revision {
+        final_revision = match store_document(&state.database, &id, &rustpad).await {
+            Ok(revision) => revision,
+            Err(e) => {
+                error!("Failed to persist document {} after eviction: {}", id, e);
+                return Err(warp::reject::custom(CustomReject(e)));
+            }
+        };
+    }
+    record_persisted(&state.database, &id, &rustpad, final_revision).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Handler for the POST `/api/admin/documents/{id}/quarantine` and
+/// `/api/admin/documents/{id}/release` endpoints.
+///
+/// Quarantined documents are read-only until released, so that reported
+/// content can be reviewed without it changing further.
+async fn quarantine_document_handler(
+    id: String,
+    quarantined: bool,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    match state.database.set_quarantined(&id, quarantined).await {
+        Ok(true) => {}
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to quarantine document {}: {}", id, e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    }
+    update_read_only(&state, &id).await?;
+    match state.database.get_meta(&id).await {
+        Ok(Some(meta)) => Ok(warp::reply::json(&meta)),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the GET `/api/admin/reports` endpoint.
+async fn list_reports_handler(
+    query: ReportsQuery,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    match state.database.reports(query.resolved).await {
+        Ok(reports) => Ok(warp::reply::json(&reports)),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the POST `/api/admin/reports/{id}/resolve` endpoint.
+async fn resolve_report_handler(
+    report_id: i64,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    match state.database.resolve_report(report_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(warp::reject::not_found()),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the GET `/api/admin/jobs` endpoint.
+async fn list_jobs_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    let counts = match state.database.count_jobs_by_status().await {
+        Ok(counts) => counts.into_iter().collect(),
+        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+    };
+    match state.database.list_jobs(100).await {
+        Ok(recent) => Ok(warp::reply::json(&JobsResponse { counts, recent })),
+        Err(e) => {
+            error!("Failed to list jobs: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the GET `/api/admin/active` endpoint.
+///
+/// Lists the documents loaded in memory, those idle the longest first, along
+/// with how soon the cleaner may evict them.
+fn active_documents_handler(expiry: Duration, state: ServerState) -> impl Reply {
+    let mut documents: Vec<ActiveDocument> = state
+        .documents
+        .iter()
+        .map(|entry| {
+            let idle = entry.last_accessed.elapsed();
+            ActiveDocument {
+                id: entry.key().clone(),
+                idle_secs: idle.as_secs(),
+                evict_in_secs: expiry.saturating_sub(idle).as_secs(),
+                revision: entry.rustpad.revision(),
+                users: entry.rustpad.connections(),
+            }
+        })
+        .collect();
+    documents.sort_by_key(|document| std::cmp::Reverse(document.idle_secs));
+    warp::reply::json(&documents)
+}
+
+/// Handler for the GET `/api/admin/standby` endpoint.
+async fn standby_status_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    let standby = state.standby.as_ref().ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&StandbyStatus {
+        primary: standby.primary().to_owned(),
+        promoted: standby.promoted(),
+        followed: standby.followed(),
+    }))
+}
+
+/// Handler for the POST `/api/admin/standby/promote` endpoint.
+///
+/// Stops following the primary and makes the loaded documents editable, so
+/// that this server can take over from a primary that failed. Returns 409
+/// Conflict if the server is not a standby or was already promoted.
+async fn promote_standby_handler(state: ServerState) -> Result<StatusCode, Rejection> {
+    match &state.standby {
+        Some(standby) if standby.promote() => {
+            info!("promoted standby of {}", standby.primary());
+        }
+        _ => return Ok(StatusCode::CONFLICT),
+    }
+    let ids: Vec<String> = state
+        .documents
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect();
+    for id in ids {
+        update_read_only(&state, &id).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Handler for the GET `/api/admin/client-versions` endpoint.
+///
+/// Counts the open connections to loaded documents by the client version
+/// they reported, the most common first, to follow the rollout of protocol
+/// changes.
+fn client_versions_handler(state: ServerState) -> impl Reply {
+    let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+    for entry in state.documents.iter() {
+        for agent in entry.rustpad.agents() {
+            *counts.entry(agent.client_version).or_default() += 1;
+        }
+    }
+    let mut versions: Vec<ClientVersionCount> = counts
+        .into_iter()
+        .map(|(client_version, connections)| ClientVersionCount {
+            client_version,
+            connections,
+        })
+        .collect();
+    versions.sort_by(|a, b| {
+        b.connections
+            .cmp(&a.connections)
+            .then_with(|| a.client_version.cmp(&b.client_version))
+    });
+    warp::reply::json(&versions)
+}
+
+/// Handler for the GET `/api/admin/rejections` endpoint.
+fn rejections_handler(state: ServerState) -> impl Reply {
+    let mut documents: Vec<DocumentRejections> = state
+        .documents
+        .iter()
+        .map(|entry| DocumentRejections {
+            id: entry.key().clone(),
+            rejections: entry.rustpad.rejections(),
+        })
+        .filter(|document| document.rejections.total() > 0)
+        .collect();
+    documents.sort_by_key(|document| std::cmp::Reverse(document.rejections.total()));
+    warp::reply::json(&documents)
+}
+
+/// Handler for the GET `/api/admin/users/{email}/export` endpoint.
+///
+/// Edits are collected from the stored history of documents, along with
+/// those not stored yet from the retained history of loaded documents.
+async fn export_user_handler(email: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let color = match state.database.get_user_color(&email).await {
+        Ok(color) => color,
+        Err(e) => {
+            error!("Failed to load color for user export: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let starred = match state.database.starred(&email).await {
+        Ok(starred) => starred,
+        Err(e) => {
+            error!("Failed to load starred documents for user export: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let owned = match state.database.owned_documents(&email).await {
+        Ok(owned) => owned,
+        Err(e) => {
+            error!("Failed to load owned documents for user export: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let roles = match state.database.user_roles(&email).await {
+        Ok(roles) => roles,
+        Err(e) => {
+            error!("Failed to load roles for user export: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let reports = match state.database.user_reports(&email).await {
+        Ok(reports) => reports,
+        Err(e) => {
+            error!("Failed to load reports for user export: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let stored = match state.database.user_operations(&email).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            error!("Failed to load edits for user export: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+
+    // Edits are keyed by the revision they produced, so that those both
+    // stored and still in memory are only listed once.
+    let mut by_document: BTreeMap<String, BTreeMap<usize, AuthoredEdit>> = BTreeMap::new();
+    for (id, op) in stored {
+        let edit = AuthoredEdit {
+            revision: op.revision + 1,
+            operation: op.operation,
+        };
+        by_document.entry(id).or_default().insert(edit.revision, edit);
+    }
+    for entry in state.documents.iter() {
+        for edit in entry.rustpad.edits_by(&email) {
+            let edits = by_document.entry(entry.key().clone()).or_default();
+            edits.insert(edit.revision, edit);
+        }
+    }
+    let edits = by_document
+        .into_iter()
+        .map(|(id, edits)| DocumentEdits {
+            id,
+            edits: edits.into_values().collect(),
+        })
+        .collect();
+    let exported_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime returned before UNIX_EPOCH")
+        .as_secs() as i64;
+    Ok(warp::reply::json(&UserExport {
+        email,
+        exported_at,
+        color,
+        starred,
+        owned,
+        roles,
+        reports,
+        edits,
+    }))
+}
+
+/// Handler for the GET `/api/admin/language-map` endpoint.
+fn get_language_map_handler(state: ServerState) -> impl Reply {
+    warp::reply::json(&state.language_map.read().extensions())
+}
+
+/// Handler for the PUT `/api/admin/language-map` endpoint.
+///
+/// Replaces the mapping between file extensions and languages used to name
+/// exported files and detect the language of imported ones. The first
+/// extension listed for a language is the one its exports get, and an empty
+/// list restores the built-in mapping.
+async fn put_language_map_handler(
+    extensions: Vec<LanguageExtension>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    if !languages::is_valid(&extensions) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    if let Err(e) = state.database.set_language_extensions(&extensions).await {
+        error!("Failed to store language map: {}", e);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+    let language_map = LanguageMap::new(extensions);
+    let reply = warp::reply::json(&language_map.extensions());
+    *state.language_map.write() = language_map;
+    Ok(reply.into_response())
+}
+
+/// Handler for the GET `/api/admin/api-keys` endpoint.
+async fn list_api_keys_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    match state.database.list_api_keys().await {
+        Ok(keys) => Ok(warp::reply::json(&keys)),
+        Err(e) => {
+            error!("Failed to list API keys: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the POST `/api/admin/api-keys` endpoint.
+///
+/// Mints a random key, which is returned only in this response, as just its
+/// hash is stored.
+async fn create_api_key_handler(
+    body: ApiKeyRequest,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill(&mut secret);
+    let key = format!("{}{}", API_KEY_PREFIX, hex::encode(secret));
+    let key_hash = hash_api_key(&key);
+    match state.database.create_api_key(name, &key_hash).await {
+        Ok(info) => {
+            let body = NewApiKey { info, key };
+            let reply = warp::reply::json(&body);
+            Ok(warp::reply::with_status(reply, StatusCode::CREATED).into_response())
+        }
+        Err(e) => {
+            error!("Failed to create API key: {}", e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Handler for the DELETE `/api/admin/api-keys/{id}` endpoint.
+///
+/// Revoked keys are kept in the list, so that their use can be audited.
+async fn revoke_api_key_handler(
+    id: i64,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    match state.database.revoke_api_key(id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(false) => Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to revoke API key {}: {}", id, e);
+            Err(warp::reject::custom(CustomReject(e)))
+        }
+    }
+}
+
+/// Loads the mapping between file extensions and languages customized by
+/// admins, if any.
+async fn load_language_map(state: ServerState) {
+    match state.database.language_extensions().await {
+        Ok(extensions) => *state.language_map.write() = LanguageMap::new(extensions),
+        Err(e) => error!("Failed to load language map: {}", e),
+    }
+}
+
+/// Number of documents listed at a time for the ZIP export.
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+/// Lists a page of the documents to export, with the number of documents in
+/// total. Documents are ordered by creation time, so that edits during the
+/// export don't move them between pages.
+async fn export_page(
+    state: &ServerState,
+    offset: i64,
+) -> anyhow::Result<(Vec<DocumentMeta>, i64)> {
+    let options = ListOptions {
+        limit: Some(EXPORT_PAGE_SIZE),
+        offset: Some(offset),
+        sort: SortOrder::CreatedAt,
+        archived: ArchiveFilter::Include,
+        ..Default::default()
+    };
+    let page = state.database.list(&options).await?;
+    Ok((page.documents, page.total))
+}
+
+/// Handler for the GET `/api/admin/export.zip` endpoint.
+///
+/// Streams a ZIP archive with the latest text of every non-deleted document,
+/// listing a page of documents and loading one document at a time.
+async fn export_all_handler(state: ServerState) -> Result<warp::reply::Response, Rejection> {
+    let (documents, total) = match export_page(&state, 0).await {
+        Ok(page) => page,
+        Err(e) => {
+            error!("Failed to list documents for export: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    if total as usize > zip::MAX_ENTRIES {
+        let e = anyhow::anyhow!("too many documents to export: {}", total);
+        return Err(warp::reject::custom(CustomReject(e)));
+    }
+
+    let offset = documents.len() as i64;
+    let done = documents.len() < EXPORT_PAGE_SIZE as usize;
+    let pages = (documents.into_iter(), offset, done);
+    let archive = (state, pages, ZipWriter::default(), HashSet::new());
+    let chunks = futures::stream::unfold(Some(archive), |archive| async move {
+        let (state, mut pages, mut writer, mut names) = archive?;
+        let meta = loop {
+            let (documents, offset, done) = &mut pages;
+            if let Some(meta) = documents.next() {
+                break Some(meta);
+            }
+            if *done {
+                break None;
+            }
+            match export_page(&state, *offset).await {
+                Ok((page, _)) => {
+                    *offset += page.len() as i64;
+                    *done = page.len() < EXPORT_PAGE_SIZE as usize;
+                    *documents = page.into_iter();
+                }
+                Err(e) => {
+                    error!("Failed to list documents for export: {}", e);
+                    return Some((Err(e), None));
+                }
+            }
+        };
+        let meta = match meta {
+            Some(meta) if names.len() < zip::MAX_ENTRIES => meta,
+            _ => return Some((Ok(writer.finish()), None)),
+        };
+        let loaded = state.documents.get(&meta.id).map(|doc| doc.rustpad.snapshot());
+        let document = match loaded {
+            Some(document) => document,
+            None => match state.database.load(&meta.id).await {
+                Ok(document) => document,
+                Err(e) => {
+                    error!("Failed to load document {} for export: {}", meta.id, e);
+                    return Some((Err(e), None));
+                }
+            },
+        };
+        let name = meta.name.as_deref().unwrap_or(&meta.id);
+        let language = display_language(&document, Some(&meta));
+        let filename = download_filename(name, language, &state.language_map.read());
+        let filename = unique_filename(&mut names, filename);
+        let text = state.normalization.apply(&document.text);
+        let chunk = writer.add_file(&filename, meta.updated_at, text.as_bytes());
+        Some((Ok(chunk), Some((state, pages, writer, names))))
+    });
+
+    let reply = warp::reply::with_header(
+        warp::reply::Response::new(warp::hyper::Body::wrap_stream(chunks)),
+        "content-type",
+        "application/zip",
+    );
+    Ok(warp::reply::with_header(
+        reply,
+        "content-disposition",
+        content_disposition("rustpad-export.zip"),
+    )
+    .into_response())
+}
+
+/// Make a file name unique among those already used, by adding a number.
+fn unique_filename(names: &mut HashSet<String>, filename: String) -> String {
+    let (stem, extension) = match filename.rsplit_once('.') {
+        Some((stem, extension)) => (stem, format!(".{}", extension)),
+        None => (filename.as_str(), String::new()),
+    };
+    let mut unique = filename.clone();
+    for n in 2.. {
+        if !names.contains(&unique) {
+            break;
+        }
+        unique = format!("{} ({}){}", stem, n, extension);
+    }
+    names.insert(unique.clone());
+    unique
+}
+
+/// Handler for the DELETE `/api/admin/users/{email}` endpoint.
+async fn delete_user_handler(email: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let rows_deleted = match state.database.delete_user_data(&email).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to delete user data: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let edits_anonymized = state
+        .documents
+        .iter()
+        .map(|entry| entry.rustpad.replace_author(&email, None))
+        .sum();
+    info!("deleted data for a user: {} rows, {} edits", rows_deleted, edits_anonymized);
+    Ok(warp::reply::json(&UserDeletion {
+        rows_deleted,
+        edits_anonymized,
+    }))
+}
+
+/// Handler for the POST `/api/admin/users/{email}/anonymize` endpoint.
+///
+/// The pseudonym is random, so it cannot be traced back to the email.
+async fn anonymize_user_handler(
+    email: String,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let pseudonym = format!("anonymous-{:016x}", rand::thread_rng().gen::<u64>());
+    let rows_updated = match state.database.anonymize_user(&email, &pseudonym).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to anonymize user data: {}", e);
+            return Err(warp::reject::custom(CustomReject(e)));
+        }
+    };
+    let edits_anonymized = state
+        .documents
+        .iter()
+        .map(|entry| entry.rustpad.replace_author(&email, Some(&pseudonym)))
+        .sum();
+    info!("anonymized a user as {}: {} rows, {} edits", pseudonym, rows_updated, edits_anonymized);
+    Ok(warp::reply::json(&UserAnonymization {
+        pseudonym,
+        rows_updated,
+        edits_anonymized,
+    }))
+}
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Default maximum size of a WebSocket message from a client, enough for an
+/// edit inserting a whole document of multi-byte characters.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 8 * MAX_DOCUMENT_SIZE;
+
+/// Number of oversized messages after which a client is refused connections.
+const OFFENDER_STRIKES: u32 = 3;
+
+/// How long strikes for oversized messages count against a client.
+const OFFENDER_WINDOW: Duration = Duration::from_secs(600);
+
+/// Number of clients with strikes tracked before expired ones are dropped.
+const MAX_OFFENDERS: usize = 1024;
+
+/// Number of abuse reports a client may file within [`REPORT_WINDOW`].
+const REPORT_LIMIT: u32 = 5;
+
+/// How long abuse reports count against the limit of a client.
+const REPORT_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Number of reporting clients tracked before expired ones are dropped.
+const MAX_REPORTERS: usize = 1024;
+
+/// Maximum length of the reason given for an abuse report, in characters.
+const MAX_REPORT_REASON_LENGTH: usize = 2000;
+
+/// Default time between incremental cleaner ticks.
+pub const DEFAULT_CLEANER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default number of in-memory documents examined per cleaner tick.
+pub const DEFAULT_CLEANER_BATCH_SIZE: usize = 1000;
+
+/// Default time between samples of server activity for the stats history.
+pub const DEFAULT_STATS_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of hours that samples of server activity are kept.
+const STATS_HISTORY_HOURS: u32 = 7 * 24;
+
+/// Default number of days deleted documents are kept in the trash.
+pub const DEFAULT_PURGE_AFTER_DAYS: u32 = 30;
+
+/// Records samples of server activity for the stats history.
+async fn stats_sampler(state: ServerState, interval: Duration) {
+    let mut last_edits = state.socket_metrics.snapshot().edits;
+    let mut last_sample = Instant::now();
+    loop {
+        time::sleep(interval).await;
+        let sockets = state.socket_metrics.snapshot();
+        let elapsed = last_sample.elapsed().as_secs_f64();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("SystemTime returned before UNIX_EPOCH")
+            .as_secs() as i64;
+        let sample = StatsSample {
+            created_at: now,
+            documents: state.documents.len() as i64,
+            connections: sockets.open as i64,
+            ops_per_sec: (sockets.edits - last_edits) as f64 / elapsed,
+        };
+        last_edits = sockets.edits;
+        last_sample = Instant::now();
+        let before = now - STATS_HISTORY_HOURS as i64 * 3600;
+        if let Err(e) = state.database.record_stats_sample(&sample, before).await {
+            error!("Failed to record stats sample: {}", e);
+        }
+    }
+}
+
+/// Time between checks for documents whose settings expire them.
+const EXPIRER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Moves documents to the trash once their settings expire them.
+async fn expirer(state: ServerState) {
+    loop {
+        time::sleep(EXPIRER_INTERVAL).await;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        match state.database.trash_expired(now).await {
+            Ok(ids) => {
+                // Evict only after the tombstones are written, as when deleting.
+                for id in &ids {
+                    state.documents.remove(id);
+                }
+                if !ids.is_empty() {
+                    info!("expired documents: {:?}", ids);
+                }
+            }
+            Err(e) => error!("Failed to expire documents: {}", e),
+        }
+    }
+}
+
+/// Time between checks for documents scheduled to be published or withdrawn.
+const PUBLISH_SCHEDULER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Publishes documents and withdraws their published versions at the times
+/// they are scheduled for.
+async fn publish_scheduler(state: ServerState) {
+    loop {
+        time::sleep(PUBLISH_SCHEDULER_INTERVAL).await;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        match state.database.due_publications(now).await {
+            Ok(ids) => {
+                for id in &ids {
+                    if let Err(e) = publish_now(&state, id).await {
+                        error!("Failed to publish document {} on schedule: {:?}", id, e);
+                    }
+                }
+                if !ids.is_empty() {
+                    info!("published documents on schedule: {:?}", ids);
+                }
+            }
+            Err(e) => error!("Failed to find documents due to be published: {}", e),
+        }
+        match state.database.unpublish_expired(now).await {
+            Ok(ids) if !ids.is_empty() => info!("unpublished documents: {:?}", ids),
+            Ok(_) => {}
+            Err(e) => error!("Failed to unpublish documents: {}", e),
+        }
+    }
+}
+
+/// Permanently deletes documents that have been in the trash for too long.
+async fn purger(database: Database, purge_after_days: u32) {
+    let retention = HOUR * 24 * purge_after_days;
     loop {
+        let before = SystemTime::now()
+            .checked_sub(retention)
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as i64);
+        match database.purge_deleted(before).await {
+            Ok(0) => {}
+            Ok(purged) => info!("purged {} deleted documents", purged),
+            Err(e) => error!("Failed to purge deleted documents: {}", e),
+        }
+        if let Err(e) = database.prune_activity(before).await {
+            error!("Failed to prune document activity: {}", e);
+        }
         time::sleep(HOUR).await;
+    }
+}
+
+/// Reclaims memory for documents.
+///
+/// Each pass snapshots the keys of the document map, and then examines at most
+/// `batch_size` of them per tick, so that very large maps are never locked up
+/// by a single long scan.
+async fn cleaner(state: ServerState, expiry_days: u32, interval: Duration, batch_size: usize) {
+    let expiry = HOUR * 24 * expiry_days;
+    let mut pending: Vec<String> = Vec::new();
+    loop {
+        time::sleep(interval).await;
+        let start = std::time::Instant::now();
+        if pending.is_empty() {
+            pending = state.documents.iter().map(|entry| entry.key().clone()).collect();
+            state.cleaner_metrics.passes.fetch_add(1, Ordering::Relaxed);
+        }
+        let batch = pending.split_off(pending.len().saturating_sub(batch_size));
         let mut keys = Vec::new();
-        for entry in &*state.documents {
-            if entry.last_accessed.elapsed() > HOUR * 24 * expiry_days {
-                keys.push(entry.key().clone());
+        for key in batch {
+            if state
+                .documents
+                .remove_if(&key, |_, document| document.last_accessed.elapsed() > expiry)
+                .is_some()
+            {
+                keys.push(key);
             }
         }
-        info!("cleaner removing keys: {:?}", keys);
-        for key in keys {
-            state.documents.remove(&key);
+        if !keys.is_empty() {
+            info!("cleaner removing keys: {:?}", keys);
         }
+
+        let metrics = &state.cleaner_metrics;
+        let elapsed = start.elapsed().as_micros() as u64;
+        metrics.evicted.fetch_add(keys.len() as u64, Ordering::Relaxed);
+        metrics.last_tick_micros.store(elapsed, Ordering::Relaxed);
+        metrics.max_tick_micros.fetch_max(elapsed, Ordering::Relaxed);
     }
 }
 
 const PERSIST_INTERVAL: Duration = Duration::from_secs(3);
 const PERSIST_INTERVAL_JITTER: Duration = Duration::from_secs(1);
 
-/// Persists changed documents after a fixed time interval.
-async fn persister(id: String, rustpad: Arc<Rustpad>, db: Database) {
+/// Longest interval between checks of a document that isn't being edited.
+const PERSIST_INTERVAL_IDLE: Duration = Duration::from_secs(30);
+
+/// Number of characters changed between two saves below which the edits are
+/// small, and the persister only gradually returns to its shortest interval.
+const PERSIST_SMALL_EDITS: u64 = 64;
+
+/// Longest stored history restored when a document is loaded. Longer histories
+/// are dropped, and stored again from the text alone.
+const MAX_LOADED_HISTORY: usize = 10_000;
+
+/// Persists changed documents periodically, along with a numbered version
+/// whenever one is due under `versions`.
+///
+/// The interval doubles while the document goes unchanged, up to
+/// [`PERSIST_INTERVAL_IDLE`], and shrinks back as it is edited: at once after
+/// larger edits, and gradually while they stay small. Many edits or a large
+/// paste are persisted right away, without waiting for the interval.
+async fn persister(id: String, rustpad: Arc<Rustpad>, db: Database, versions: VersionPolicy) {
     let mut last_revision = 0;
+    let (mut version_revision, mut version_time) = (rustpad.revision(), Instant::now());
+    let mut interval = PERSIST_INTERVAL;
     while !rustpad.killed() {
-        let interval = PERSIST_INTERVAL
-            + rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
-        time::sleep(interval).await;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
+        tokio::select! {
+            _ = time::sleep(interval + jitter) => {}
+            _ = rustpad.persist_due() => {}
+        }
         let revision = rustpad.revision();
         if revision > last_revision {
+            interval = match rustpad.take_unsaved() {
+                changed if changed < PERSIST_SMALL_EDITS => interval / 2,
+                _ => PERSIST_INTERVAL,
+            }
+            .max(PERSIST_INTERVAL);
             info!("persisting revision {} for id = {}", revision, id);
-            if let Err(e) = db.store(&id, &rustpad.snapshot()).await {
-                error!("when persisting document {}: {}", id, e);
-            } else {
-                last_revision = revision;
+            match store_document(&db, &id, &rustpad).await {
+                Ok(revision) => {
+                    last_revision = revision;
+                    record_persisted(&db, &id, &rustpad, last_revision).await;
+                }
+                Err(e) => error!("when persisting document {}: {}", id, e),
+            }
+        } else {
+            interval = (interval * 2).min(PERSIST_INTERVAL_IDLE);
+        }
+        // Trim even without new edits, as clients may have caught up since.
+        rustpad.trim_history(last_revision);
+        if versions.is_due(last_revision, version_revision, version_time) {
+            let snapshot = rustpad.text_snapshot();
+            let document = PersistedDocument {
+                text: snapshot.text,
+                language: snapshot.language,
+            };
+            let stored = db.store_version(&id, snapshot.revision, &document, versions.keep);
+            match stored.await {
+                Ok(()) => (version_revision, version_time) = (snapshot.revision, Instant::now()),
+                Err(e) => error!("when storing a version of document {}: {}", id, e),
+            }
+        }
+    }
+}
+
+/// Time to wait before reconnecting to the primary of a standby.
+const STANDBY_RETRY: Duration = Duration::from_secs(5);
+
+/// Returns whether the server is a standby that has not been promoted, in
+/// which case all documents are read-only.
+fn in_standby(state: &ServerState) -> bool {
+    state
+        .standby
+        .as_ref()
+        .map_or(false, |standby| !standby.promoted())
+}
+
+/// Follows the primary of a warm standby until it is promoted, reconnecting
+/// whenever the connection drops.
+async fn follow_primary(state: ServerState, standby: Arc<Standby>) {
+    let follow = async {
+        loop {
+            if let Err(e) = tail_feed(&state, &standby).await {
+                warn!("when following primary {}: {}", standby.primary(), e);
+            }
+            time::sleep(STANDBY_RETRY).await;
+        }
+    };
+    tokio::select! {
+        _ = follow => {}
+        _ = standby.wait_promoted() => {}
+    }
+}
+
+/// Replicates the documents loaded on the primary, and those that its live
+/// feed reports changes to, until the feed closes. Documents deleted on the
+/// primary are deleted here too.
+async fn tail_feed(state: &ServerState, standby: &Arc<Standby>) -> anyhow::Result<()> {
+    // Subscribe before listing, so that no document is missed in between.
+    let mut feed = standby.connect("/api/feed").await?;
+    for id in standby.active_documents().await? {
+        follow_document(state, standby, id);
+    }
+    info!("following primary {}", standby.primary());
+    while let Some(message) = standby::recv_text(&mut feed).await {
+        let event: standby::FeedEvent = serde_json::from_str(&message?)?;
+        if event.kind == "deleted" {
+            standby.unfollow(&event.document_id);
+            if let Err(e) = state.database.soft_delete(&event.document_id).await {
+                error!("Failed to delete document {}: {}", event.document_id, e);
+            }
+            state.documents.remove(&event.document_id);
+        } else {
+            follow_document(state, standby, event.document_id);
+        }
+    }
+    Ok(())
+}
+
+/// Starts replicating a document from the primary, unless it already is.
+fn follow_document(state: &ServerState, standby: &Arc<Standby>, id: String) {
+    if standby.follow(&id) {
+        tokio::spawn(replicate_document(state.clone(), Arc::clone(standby), id));
+    }
+}
+
+/// Keeps a read-only copy of a document identical to the primary's until the
+/// standby is promoted or the copy is evicted.
+///
+/// Any copy already loaded is replaced by an empty one, which the primary
+/// brings up to its revision with the messages that new clients get.
+async fn replicate_document(state: ServerState, standby: Arc<Standby>, id: String) {
+    let rustpad = Arc::new(Rustpad::new(state.database.clone()));
+    rustpad.set_read_only(true);
+    tokio::spawn(persister(
+        id.clone(),
+        Arc::clone(&rustpad),
+        state.database.clone(),
+        state.versions,
+    ));
+    state
+        .documents
+        .insert(id.clone(), Document::new(Arc::clone(&rustpad)));
+    while !standby.promoted() && !rustpad.killed() {
+        tokio::select! {
+            result = mirror_document(&state, &standby, &id, &rustpad) => {
+                if let Err(e) = result {
+                    warn!("when replicating document {}: {}", id, e);
+                }
+            }
+            _ = standby.wait_promoted() => break,
+        }
+        time::sleep(STANDBY_RETRY).await;
+    }
+    standby.unfollow(&id);
+}
+
+/// Applies the messages that the primary sends about a document to the copy
+/// of it, until the connection closes or the copy is evicted.
+async fn mirror_document(
+    state: &ServerState,
+    standby: &Standby,
+    id: &str,
+    rustpad: &Arc<Rustpad>,
+) -> anyhow::Result<()> {
+    let mut socket = standby.connect(&format!("/api/socket/{}", id)).await?;
+    while let Some(message) = standby::recv_text(&mut socket).await {
+        rustpad.replicate(&message?)?;
+        match state.documents.get_mut(id) {
+            // Edits on the primary count as accesses to the copy.
+            Some(mut document) if Arc::ptr_eq(&document.rustpad, rustpad) => {
+                document.last_accessed = Instant::now();
             }
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+/// Stores the text of a document along with the operations made since its
+/// history was last stored, returning the revision stored.
+async fn store_document(db: &Database, id: &str, rustpad: &Rustpad) -> anyhow::Result<usize> {
+    let snapshot = rustpad.history_snapshot();
+    db.store_operations(id, snapshot.start, &snapshot.operations)
+        .await?;
+    db.store(id, &snapshot.document).await?;
+    // A document without an owner belongs to whoever first edits it while
+    // authenticated.
+    if let Some(email) = snapshot.operations.iter().find_map(|op| op.email.as_deref()) {
+        db.claim_owner(id, email).await?;
+    }
+    Ok(snapshot.revision)
+}
+
+/// Marks a document as stored at `revision`, and queues recording the
+/// revisions newly stored for the activity endpoint.
+async fn record_persisted(db: &Database, id: &str, rustpad: &Rustpad, revision: usize) {
+    let revisions = rustpad.set_persisted(revision);
+    if revisions > 0 {
+        let created_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let job = ActivityJob {
+            document_id: id.to_owned(),
+            revisions,
+            created_at,
+        };
+        let payload = serde_json::to_string(&job).expect("failed serialize");
+        if let Err(e) = db.enqueue_job(RECORD_ACTIVITY, &payload).await {
+            error!("when queueing activity for document {}: {}", id, e);
         }
     }
 }
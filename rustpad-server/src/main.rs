@@ -1,4 +1,11 @@
-use rustpad_server::{server, database::Database, ServerConfig};
+use std::time::Duration;
+
+use rustpad_server::{
+    server, access::AccessConfig, database::Database, normalize::Normalization, oidc::OidcConfig, standby::StandbyConfig, SecurityHeaders, ServerConfig,
+    DEFAULT_CLEANER_BATCH_SIZE, DEFAULT_CLEANER_INTERVAL, DEFAULT_MAX_MESSAGE_SIZE,
+    DEFAULT_PURGE_AFTER_DAYS, DEFAULT_REFERRER_POLICY, DEFAULT_STATS_SAMPLE_INTERVAL,
+    DEFAULT_VERSIONS_KEPT, DEFAULT_VERSION_INTERVAL, DEFAULT_VERSION_REVISIONS, VersionPolicy,
+};
 
 #[tokio::main]
 async fn main() {
@@ -15,6 +22,95 @@ async fn main() {
             .unwrap_or_else(|_| String::from("1"))
             .parse()
             .expect("Unable to parse EXPIRY_DAYS"),
+        cleaner_interval: std::env::var("CLEANER_INTERVAL_SECS")
+            .map(|secs| {
+                Duration::from_secs(secs.parse().expect("Unable to parse CLEANER_INTERVAL_SECS"))
+            })
+            .unwrap_or(DEFAULT_CLEANER_INTERVAL),
+        cleaner_batch_size: std::env::var("CLEANER_BATCH_SIZE")
+            .map(|size| size.parse().expect("Unable to parse CLEANER_BATCH_SIZE"))
+            .unwrap_or(DEFAULT_CLEANER_BATCH_SIZE),
+        purge_after_days: std::env::var("PURGE_AFTER_DAYS")
+            .map(|days| days.parse().expect("Unable to parse PURGE_AFTER_DAYS"))
+            .unwrap_or(DEFAULT_PURGE_AFTER_DAYS),
+        rfc3339_timestamps: std::env::var("RFC3339_TIMESTAMPS")
+            .map(|flag| flag.parse().expect("Unable to parse RFC3339_TIMESTAMPS"))
+            .unwrap_or(true),
+        derive_names: std::env::var("DERIVE_NAMES")
+            .map(|flag| flag.parse().expect("Unable to parse DERIVE_NAMES"))
+            .unwrap_or(false),
+        require_api_key: std::env::var("REQUIRE_API_KEY")
+            .map(|flag| flag.parse().expect("Unable to parse REQUIRE_API_KEY"))
+            .unwrap_or(false),
+        admin_token: std::env::var("ADMIN_TOKEN").ok(),
+        share_secret: std::env::var("SHARE_SECRET").ok(),
+        signing_secret: std::env::var("SIGNING_SECRET").ok(),
+        standby: std::env::var("STANDBY_PRIMARY").ok().map(|primary| StandbyConfig {
+            primary,
+            token: std::env::var("STANDBY_TOKEN").ok(),
+        }),
+        stats_sample_interval: std::env::var("STATS_SAMPLE_INTERVAL_SECS")
+            .map(|secs| {
+                Duration::from_secs(
+                    secs.parse().expect("Unable to parse STATS_SAMPLE_INTERVAL_SECS"),
+                )
+            })
+            .unwrap_or(DEFAULT_STATS_SAMPLE_INTERVAL),
+        max_message_size: std::env::var("MAX_MESSAGE_SIZE")
+            .map(|size| size.parse().expect("Unable to parse MAX_MESSAGE_SIZE"))
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+        region: std::env::var("REGION").ok(),
+        alternates: std::env::var("ALTERNATE_ENDPOINTS")
+            .map(|urls| urls.split(',').map(|url| url.trim().to_owned()).collect())
+            .unwrap_or_default(),
+        security_headers: SecurityHeaders {
+            content_security_policy: std::env::var("CONTENT_SECURITY_POLICY").ok(),
+            frame_ancestors: std::env::var("FRAME_ANCESTORS")
+                .map(|origins| {
+                    origins
+                        .split(',')
+                        .map(|origin| origin.trim().to_owned())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            referrer_policy: std::env::var("REFERRER_POLICY")
+                .unwrap_or_else(|_| DEFAULT_REFERRER_POLICY.to_owned()),
+        },
+        versions: VersionPolicy {
+            interval: std::env::var("VERSION_INTERVAL_MINS")
+                .map(|mins| {
+                    let mins: u64 = mins.parse().expect("Unable to parse VERSION_INTERVAL_MINS");
+                    Duration::from_secs(60 * mins)
+                })
+                .unwrap_or(DEFAULT_VERSION_INTERVAL),
+            revisions: std::env::var("VERSION_REVISIONS")
+                .map(|count| count.parse().expect("Unable to parse VERSION_REVISIONS"))
+                .unwrap_or(DEFAULT_VERSION_REVISIONS),
+            keep: std::env::var("VERSIONS_KEPT")
+                .map(|count| count.parse().expect("Unable to parse VERSIONS_KEPT"))
+                .unwrap_or(DEFAULT_VERSIONS_KEPT),
+        },
+        normalization: Normalization {
+            strip_bom: std::env::var("STRIP_BOM")
+                .map(|flag| flag.parse().expect("Unable to parse STRIP_BOM"))
+                .unwrap_or(true),
+            tab_width: std::env::var("TAB_WIDTH")
+                .ok()
+                .map(|width| width.parse().expect("Unable to parse TAB_WIDTH")),
+            trailing_newline: std::env::var("TRAILING_NEWLINE")
+                .map(|policy| policy.parse().expect("Unable to parse TRAILING_NEWLINE"))
+                .unwrap_or_default(),
+        },
+        access: std::env::var("CF_ACCESS_TEAM_DOMAIN").ok().map(|domain| {
+            let audience = std::env::var("CF_ACCESS_AUD")
+                .expect("CF_ACCESS_AUD is required with CF_ACCESS_TEAM_DOMAIN");
+            AccessConfig::new(&domain, &audience)
+        }),
+        oidc: std::env::var("OIDC_ISSUER").ok().map(|issuer| OidcConfig {
+            issuer,
+            audience: std::env::var("OIDC_AUDIENCE")
+                .expect("OIDC_AUDIENCE is required with OIDC_ISSUER"),
+        }),
         database: Database::new(
             &std::env::var("SQLITE_URI").expect("SQLITE_URI environment variable is required")
         )
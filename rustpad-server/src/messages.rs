@@ -0,0 +1,150 @@
+//! Localizable messages sent to clients as a code with parameters.
+//!
+//! Clients are expected to render messages from their code, but the server
+//! keeps a small catalog so that REST error bodies can carry a readable message
+//! in the language requested by the `Accept-Language` header.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A message identified by a stable code, with named parameters.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notice {
+    /// Stable identifier of the message, such as `"not_found"`.
+    pub code: String,
+    /// Values substituted for `{name}` placeholders in the message.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+}
+
+impl Notice {
+    /// Creates a notice with no parameters.
+    pub fn new(code: &str) -> Self {
+        Self {
+            code: code.into(),
+            params: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a named parameter to the notice.
+    pub fn with(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.params.insert(name.into(), value.into());
+        self
+    }
+
+    /// Renders the notice in the first supported language of an
+    /// `Accept-Language` header, falling back to English.
+    pub fn localize(&self, accept_language: Option<&str>) -> String {
+        let catalog = accept_language
+            .into_iter()
+            .flat_map(|header| header.split(','))
+            .filter_map(|range| {
+                let tag = range.split(';').next()?.trim();
+                let primary = tag.split('-').next()?;
+                CATALOGS
+                    .iter()
+                    .find(|(language, _)| language.eq_ignore_ascii_case(primary))
+            })
+            .chain(CATALOGS.first())
+            .map(|(_, catalog)| *catalog);
+
+        let template = catalog
+            .flat_map(|catalog| catalog.iter())
+            .find(|(code, _)| *code == self.code)
+            .map_or(self.code.as_str(), |(_, template)| template);
+        let mut message = template.to_owned();
+        for (name, value) in &self.params {
+            message = message.replace(&format!("{{{}}}", name), value);
+        }
+        message
+    }
+}
+
+/// Message templates by code, for one language.
+type Catalog = &'static [(&'static str, &'static str)];
+
+/// Catalogs by primary language subtag, with the fallback language first.
+const CATALOGS: &[(&str, Catalog)] = &[
+    (
+        "en",
+        &[
+            ("not_found", "The requested resource was not found."),
+            ("method_not_allowed", "This method is not allowed here."),
+            ("bad_request", "The request was malformed."),
+            ("unauthorized", "A valid access token is required."),
+            (
+                "forbidden",
+                "Your role on this document does not allow this.",
+            ),
+            (
+                "password_required",
+                "This document is protected by a password.",
+            ),
+            ("invalid_password", "The password is incorrect."),
+            ("payload_too_large", "The request body is too large."),
+            (
+                "unsupported_media_type",
+                "The request body has an unsupported type.",
+            ),
+            ("internal_error", "Something went wrong on the server."),
+            (
+                "invalid_message",
+                "The server could not understand a message.",
+            ),
+            ("edit_rejected", "Your edit was rejected ({reason})."),
+            (
+                "message_too_large",
+                "A message exceeded the limit of {limit} bytes.",
+            ),
+        ],
+    ),
+    (
+        "de",
+        &[
+            (
+                "not_found",
+                "Die angeforderte Ressource wurde nicht gefunden.",
+            ),
+            (
+                "method_not_allowed",
+                "Diese Methode ist hier nicht erlaubt.",
+            ),
+            ("bad_request", "Die Anfrage ist fehlerhaft."),
+            (
+                "unauthorized",
+                "Ein gültiges Zugriffstoken ist erforderlich.",
+            ),
+            (
+                "forbidden",
+                "Deine Rolle für dieses Dokument erlaubt das nicht.",
+            ),
+            (
+                "password_required",
+                "Dieses Dokument ist durch ein Passwort geschützt.",
+            ),
+            ("invalid_password", "Das Passwort ist falsch."),
+            ("payload_too_large", "Der Anfrageinhalt ist zu groß."),
+            (
+                "unsupported_media_type",
+                "Der Anfrageinhalt hat einen nicht unterstützten Typ.",
+            ),
+            (
+                "internal_error",
+                "Auf dem Server ist ein Fehler aufgetreten.",
+            ),
+            (
+                "invalid_message",
+                "Der Server konnte eine Nachricht nicht verstehen.",
+            ),
+            (
+                "edit_rejected",
+                "Deine Änderung wurde abgelehnt ({reason}).",
+            ),
+            (
+                "message_too_large",
+                "Eine Nachricht hat die Grenze von {limit} Bytes überschritten.",
+            ),
+        ],
+    ),
+];
@@ -0,0 +1,158 @@
+//! Lightweight per-route request metrics, reported through `/api/stats`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use warp::http::{Method, StatusCode};
+
+/// Number of recent latencies kept per route for computing percentiles.
+const MAX_SAMPLES: usize = 1024;
+
+/// Maximum number of distinct routes tracked, to bound memory from junk paths.
+const MAX_ROUTES: usize = 256;
+
+/// Number of recent server errors kept for the status page.
+const MAX_RECENT_ERRORS: usize = 20;
+
+/// Path segments that are followed by a variable identifier.
+const ID_PREFIXES: &[&str] = &["socket", "text", "documents", "folders", "users"];
+
+/// Path segments that are never identifiers, even after an [`ID_PREFIXES`] entry.
+const RESERVED: &[&str] = &["all", "bulk", "import"];
+
+/// Request counts and recent latencies for every API route.
+#[derive(Default)]
+pub struct RouteMetrics {
+    routes: Mutex<HashMap<String, RouteSamples>>,
+    recent_errors: Mutex<VecDeque<RecentError>>,
+}
+
+/// Counters and latency samples for a single route.
+#[derive(Default)]
+struct RouteSamples {
+    requests: u64,
+    client_errors: u64,
+    errors: u64,
+    latencies: VecDeque<Duration>,
+}
+
+/// Snapshot of the metrics for a single route, returned as part of the stats.
+#[derive(Serialize, Clone, Debug)]
+pub struct RouteStats {
+    /// Number of requests handled.
+    pub requests: u64,
+    /// Number of responses with a 4xx status.
+    pub client_errors: u64,
+    /// Number of responses with a 5xx status.
+    pub errors: u64,
+    /// Fraction of requests that resulted in a 5xx status.
+    pub error_rate: f64,
+    /// Median latency of recent requests, in microseconds.
+    pub p50_micros: u64,
+    /// 95th percentile latency of recent requests, in microseconds.
+    pub p95_micros: u64,
+}
+
+/// A request that resulted in a 5xx status.
+#[derive(Serialize, Clone, Debug)]
+pub struct RecentError {
+    /// Time the request completed, in seconds since Unix epoch.
+    pub time: u64,
+    /// Normalized route of the request, with its method.
+    pub route: String,
+    /// Status code of the response.
+    pub status: u16,
+}
+
+impl RouteMetrics {
+    /// Records a completed request under its normalized route.
+    pub fn record(&self, method: &Method, path: &str, status: StatusCode, elapsed: Duration) {
+        let route = format!("{} {}", method, normalize(path));
+        if status.is_server_error() {
+            self.record_error(&route, status);
+        }
+        let mut routes = self.routes.lock();
+        if !routes.contains_key(&route) && routes.len() >= MAX_ROUTES {
+            return;
+        }
+        let samples = routes.entry(route).or_default();
+        samples.requests += 1;
+        if status.is_client_error() {
+            samples.client_errors += 1;
+        } else if status.is_server_error() {
+            samples.errors += 1;
+        }
+        if samples.latencies.len() >= MAX_SAMPLES {
+            samples.latencies.pop_front();
+        }
+        samples.latencies.push_back(elapsed);
+    }
+
+    /// Returns the most recent server errors, newest first.
+    pub fn recent_errors(&self) -> Vec<RecentError> {
+        self.recent_errors.lock().iter().rev().cloned().collect()
+    }
+
+    fn record_error(&self, route: &str, status: StatusCode) {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut errors = self.recent_errors.lock();
+        if errors.len() >= MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            time,
+            route: route.to_owned(),
+            status: status.as_u16(),
+        });
+    }
+
+    /// Returns the metrics for every route seen so far, sorted by route.
+    pub fn snapshot(&self) -> BTreeMap<String, RouteStats> {
+        let routes = self.routes.lock();
+        routes
+            .iter()
+            .map(|(route, samples)| {
+                let mut latencies: Vec<Duration> = samples.latencies.iter().copied().collect();
+                latencies.sort_unstable();
+                let stats = RouteStats {
+                    requests: samples.requests,
+                    client_errors: samples.client_errors,
+                    errors: samples.errors,
+                    error_rate: samples.errors as f64 / samples.requests as f64,
+                    p50_micros: percentile(&latencies, 50),
+                    p95_micros: percentile(&latencies, 95),
+                };
+                (route.clone(), stats)
+            })
+            .collect()
+    }
+}
+
+/// Replaces the identifiers in a request path with `{id}`.
+fn normalize(path: &str) -> String {
+    let mut route = String::new();
+    let mut previous = "";
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        route.push('/');
+        if ID_PREFIXES.contains(&previous) && !RESERVED.contains(&segment) {
+            route.push_str("{id}");
+        } else {
+            route.push_str(segment);
+        }
+        previous = segment;
+    }
+    route
+}
+
+/// Returns the given percentile of sorted latencies, in microseconds.
+fn percentile(sorted: &[Duration], pct: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let index = (sorted.len() * pct / 100).min(sorted.len() - 1);
+    sorted[index].as_micros() as u64
+}
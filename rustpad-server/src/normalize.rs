@@ -0,0 +1,120 @@
+//! Normalization of document text for consumers outside the editor.
+//!
+//! Exports and the search index read text through a [`Normalization`], so
+//! that artifacts of how a document was edited, such as a byte order mark
+//! pasted in from another program, look the same to every downstream
+//! consumer. The live text that clients edit is never changed.
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+
+/// Byte order mark, which some editors write at the start of a file.
+const BOM: char = '\u{feff}';
+
+/// How the end of the text is normalized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingNewline {
+    /// Leave the end of the text as it is.
+    #[default]
+    Keep,
+    /// End non-empty text with exactly one newline.
+    Ensure,
+    /// Remove all newlines from the end of the text.
+    Strip,
+}
+
+impl FromStr for TrailingNewline {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "ensure" => Ok(Self::Ensure),
+            "strip" => Ok(Self::Strip),
+            _ => bail!("expected one of keep, ensure or strip, got {:?}", s),
+        }
+    }
+}
+
+/// Steps applied to text before it is exported or indexed for search.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Normalization {
+    /// Remove a byte order mark from the start of the text.
+    pub strip_bom: bool,
+    /// Expand tabs to spaces up to the next multiple of this many columns, or
+    /// keep them if `None`.
+    pub tab_width: Option<usize>,
+    /// How the end of the text is normalized.
+    pub trailing_newline: TrailingNewline,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self {
+            strip_bom: true,
+            tab_width: None,
+            trailing_newline: TrailingNewline::Keep,
+        }
+    }
+}
+
+impl Normalization {
+    /// Applies the normalization steps to a text, borrowing it if none of
+    /// them change anything.
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if self.strip_bom {
+            if let Some(rest) = text.strip_prefix(BOM) {
+                text = Cow::Owned(rest.to_owned());
+            }
+        }
+        if let Some(width) = self.tab_width {
+            if text.contains('\t') {
+                text = Cow::Owned(expand_tabs(&text, width));
+            }
+        }
+        match self.trailing_newline {
+            TrailingNewline::Keep => {}
+            TrailingNewline::Ensure => {
+                if !text.is_empty() && (!text.ends_with('\n') || text.ends_with("\n\n")) {
+                    let trimmed = text.trim_end_matches('\n');
+                    text = Cow::Owned(format!("{}\n", trimmed));
+                }
+            }
+            TrailingNewline::Strip => {
+                if text.ends_with('\n') {
+                    text = Cow::Owned(text.trim_end_matches('\n').to_owned());
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Replaces each tab with spaces up to the next tab stop, counting columns
+/// in characters from the start of each line.
+fn expand_tabs(text: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut expanded = String::with_capacity(text.len());
+    let mut column = 0;
+    for c in text.chars() {
+        match c {
+            '\t' => {
+                let spaces = width - column % width;
+                expanded.extend(std::iter::repeat(' ').take(spaces));
+                column += spaces;
+            }
+            '\n' => {
+                expanded.push(c);
+                column = 0;
+            }
+            _ => {
+                expanded.push(c);
+                column += 1;
+            }
+        }
+    }
+    expanded
+}
@@ -0,0 +1,97 @@
+//! Authentication with bearer tokens from an OpenID Connect provider.
+//!
+//! For deployments that are not behind Cloudflare Access, the server can
+//! require a token from any OIDC provider instead, such as Keycloak, Dex or
+//! Auth0. Its signing keys are found through the provider's discovery
+//! document, and tokens are checked the same way as Access tokens.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use log::info;
+use parking_lot::RwLock;
+use serde::Deserialize;
+
+use crate::access::{AccessConfig, AccessVerifier, Identity};
+
+/// Settings for authenticating requests with an OpenID Connect provider.
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    /// Issuer URL of the provider, exactly as it appears in its tokens.
+    pub issuer: String,
+    /// Audience that tokens must be issued for, usually the client ID.
+    pub audience: String,
+}
+
+/// The parts of a provider's discovery document that are used.
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    jwks_uri: String,
+}
+
+/// Verifies bearer tokens from an OpenID Connect provider.
+pub struct OidcVerifier {
+    config: OidcConfig,
+    client: reqwest::Client,
+    verifier: RwLock<Option<Arc<AccessVerifier>>>,
+}
+
+impl OidcVerifier {
+    /// Creates a verifier, which discovers the provider's keys when first used.
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            verifier: Default::default(),
+        }
+    }
+
+    /// Verifies a bearer token, returning the identity it was issued to.
+    pub async fn verify(&self, token: &str) -> Result<Identity> {
+        let verifier = self.verifier.read().clone();
+        let verifier = match verifier {
+            Some(verifier) => verifier,
+            None => {
+                let verifier = Arc::new(self.discover().await?);
+                *self.verifier.write() = Some(Arc::clone(&verifier));
+                verifier
+            }
+        };
+        verifier.verify(token).await
+    }
+
+    /// Fetches the discovery document of the provider.
+    async fn discover(&self) -> Result<AccessVerifier> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        info!("discovering OpenID Connect provider at {}", url);
+        let discovery: Discovery = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if discovery.issuer != self.config.issuer {
+            bail!("provider reports a different issuer {:?}", discovery.issuer);
+        }
+        Ok(AccessVerifier::new(AccessConfig {
+            issuer: discovery.issuer,
+            audience: self.config.audience.clone(),
+            certs_url: discovery.jwks_uri,
+        }))
+    }
+}
+
+/// Returns the token of a bearer `Authorization` header.
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
@@ -21,3 +21,138 @@ pub fn transform_index(operation: &OperationSeq, position: u32) -> u32 {
     }
     new_index as u32
 }
+
+/// Return the number of characters inserted or deleted by an operation.
+pub fn changed_chars(operation: &OperationSeq) -> u64 {
+    let changed = |op: &Operation| match op {
+        Operation::Retain(_) => 0,
+        Operation::Insert(s) => bytecount::num_chars(s.as_bytes()) as u64,
+        &Operation::Delete(n) => n,
+    };
+    operation.ops().iter().map(changed).sum()
+}
+
+/// Return the number of characters inserted by an operation.
+pub fn inserted_chars(operation: &OperationSeq) -> u64 {
+    let inserted = |op: &Operation| match op {
+        Operation::Insert(s) => bytecount::num_chars(s.as_bytes()) as u64,
+        _ => 0,
+    };
+    operation.ops().iter().map(inserted).sum()
+}
+
+/// Return the attribution of each character after applying an operation,
+/// given their attribution before it. Inserted characters are attributed to
+/// `author`. Returns `None` if the operation does not fit the text length.
+pub fn carry_attribution<T: Copy>(
+    attribution: &[T],
+    operation: &OperationSeq,
+    author: T,
+) -> Option<Vec<T>> {
+    if operation.base_len() != attribution.len() {
+        return None;
+    }
+    let mut carried = Vec::with_capacity(operation.target_len());
+    let mut index = 0;
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => {
+                carried.extend_from_slice(&attribution[index..index + n as usize]);
+                index += n as usize;
+            }
+            &Operation::Delete(n) => index += n as usize,
+            Operation::Insert(s) => {
+                let len = bytecount::num_chars(s.as_bytes());
+                carried.extend(std::iter::repeat(author).take(len));
+            }
+        }
+    }
+    Some(carried)
+}
+
+/// Return the number of words in a string, separated by whitespace.
+pub fn count_words(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+/// Return the change in [`count_words`] caused by applying an operation.
+///
+/// Only deleted and inserted characters, and the first character retained
+/// after each change, are examined, since whether a character starts a word
+/// depends only on the character before it.
+pub fn word_count_delta(text: &str, operation: &OperationSeq) -> isize {
+    let starts_word = |prev: Option<char>, c: char| {
+        !c.is_whitespace() && prev.map_or(true, char::is_whitespace)
+    };
+    let mut chars = text.chars();
+    let mut prev_old = None;
+    let mut prev_new = None;
+    let mut changed = false;
+    let mut delta = 0;
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => {
+                let mut n = n as usize;
+                if n == 0 {
+                    continue;
+                }
+                if changed {
+                    if let Some(c) = chars.next() {
+                        delta += starts_word(prev_new, c) as isize;
+                        delta -= starts_word(prev_old, c) as isize;
+                        prev_old = Some(c);
+                        prev_new = Some(c);
+                    }
+                    n -= 1;
+                    changed = false;
+                }
+                if n > 0 {
+                    let last = chars.nth(n - 1);
+                    prev_old = last;
+                    prev_new = last;
+                }
+            }
+            Operation::Insert(s) => {
+                for c in s.chars() {
+                    delta += starts_word(prev_new, c) as isize;
+                    prev_new = Some(c);
+                }
+                changed = true;
+            }
+            &Operation::Delete(n) => {
+                for c in chars.by_ref().take(n as usize) {
+                    delta -= starts_word(prev_old, c) as isize;
+                    prev_old = Some(c);
+                }
+                changed = true;
+            }
+        }
+    }
+    delta
+}
+
+/// Return an operation with CRLF line endings in inserted text replaced by LF.
+pub fn normalize_newlines(operation: &OperationSeq) -> OperationSeq {
+    let mut normalized = OperationSeq::default();
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => normalized.retain(n),
+            Operation::Insert(s) => normalized.insert(&s.replace("\r\n", "\n")),
+            &Operation::Delete(n) => normalized.delete(n),
+        }
+    }
+    normalized
+}
+
+/// Return the 32-bit FNV-1a hash of the UTF-8 bytes of a string.
+///
+/// This is used to detect clients whose text has diverged from the server, and
+/// must match the implementation in `rustpad-wasm`.
+pub fn checksum(text: &str) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for &byte in text.as_bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
@@ -0,0 +1,75 @@
+//! Delivery of document events recorded in the database outbox.
+
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant};
+
+use crate::database::{Database, DocumentEvent};
+
+/// How long the dispatcher waits before polling an empty outbox again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum number of events read from the outbox at once.
+const BATCH_SIZE: i64 = 100;
+
+/// How often delivered events are pruned from the outbox.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long delivered events are kept before being pruned.
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Broadcasts outbox events recorded from `started_at` on to live feed
+/// subscribers, in commit order.
+///
+/// Broadcasting does not mark events as delivered, since nobody may be
+/// listening. Events are only marked as delivered once every durable feed
+/// consumer has acknowledged them, so those consumers receive any events
+/// recorded while they were away, even across restarts.
+pub async fn dispatcher(
+    database: Database,
+    events: broadcast::Sender<DocumentEvent>,
+    started_at: i64,
+) {
+    let mut next_prune = Instant::now();
+    let mut last_id = loop {
+        match database.last_event_before(started_at).await {
+            Ok(id) => break id,
+            Err(e) => {
+                error!("when reading last outbox event: {}", e);
+                time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    };
+    loop {
+        match database.events_after(last_id, BATCH_SIZE).await {
+            Ok(batch) if !batch.is_empty() => {
+                last_id = batch[batch.len() - 1].id;
+                info!("broadcasting {} outbox events", batch.len());
+                for event in batch {
+                    // Having no subscribers at the moment is not a failure.
+                    events.send(event).ok();
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => error!("when reading outbox events: {}", e),
+        }
+
+        if Instant::now() >= next_prune {
+            let cutoff = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("SystemTime returned before UNIX_EPOCH")
+                .saturating_sub(RETENTION)
+                .as_secs() as i64;
+            match database.prune_events(cutoff).await {
+                Ok(0) => {}
+                Ok(n) => info!("pruned {} delivered outbox events", n),
+                Err(e) => error!("when pruning outbox events: {}", e),
+            }
+            next_prune = Instant::now() + PRUNE_INTERVAL;
+        }
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
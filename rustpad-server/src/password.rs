@@ -0,0 +1,71 @@
+//! Hashing of document passwords.
+//!
+//! Hashes are stored as `pbkdf2-sha256$<iterations>$<salt>$<hash>`, with the
+//! salt and hash hex-encoded, so that the number of iterations can be raised
+//! later without invalidating existing passwords.
+
+use std::num::NonZeroU32;
+
+use rand::RngCore;
+use ring::pbkdf2;
+
+/// Name of the hashing scheme, at the start of each stored hash.
+const SCHEME: &str = "pbkdf2-sha256";
+
+/// Number of PBKDF2 iterations for new hashes.
+const ITERATIONS: u32 = 100_000;
+
+/// Length of the random salt of each hash, in bytes.
+const SALT_LEN: usize = 16;
+
+/// Length of the derived key, in bytes.
+const HASH_LEN: usize = 32;
+
+/// Hashes a password with a random salt.
+pub fn hash(password: &str) -> String {
+    let mut salt = [0; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let iterations = NonZeroU32::new(ITERATIONS).expect("iterations should be non-zero");
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "{}${}${}${}",
+        SCHEME,
+        ITERATIONS,
+        hex::encode(salt),
+        hex::encode(hash)
+    )
+}
+
+/// Returns whether a password matches a stored hash.
+pub fn verify(password: &str, stored: &str) -> bool {
+    match parse(stored) {
+        Some((iterations, salt, hash)) => pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok(),
+        None => false,
+    }
+}
+
+/// Splits a stored hash into its iterations, salt and derived key.
+fn parse(stored: &str) -> Option<(NonZeroU32, Vec<u8>, Vec<u8>)> {
+    let mut parts = stored.split('$');
+    if parts.next()? != SCHEME {
+        return None;
+    }
+    let iterations = NonZeroU32::new(parts.next()?.parse().ok()?)?;
+    let salt = hex::decode(parts.next()?).ok()?;
+    let hash = hex::decode(parts.next()?).ok()?;
+    parts.next().is_none().then_some((iterations, salt, hash))
+}
@@ -0,0 +1,222 @@
+//! Minimal PDF writer for exporting documents as paginated monospace text.
+//!
+//! Output uses the standard Courier font, which every PDF reader provides, so
+//! no fonts are embedded. Characters outside of Latin-1 are replaced by `?`.
+
+/// Page width in points, US Letter.
+const PAGE_WIDTH: u32 = 612;
+
+/// Page height in points, US Letter.
+const PAGE_HEIGHT: u32 = 792;
+
+/// Margin around the text on every side, in points.
+const MARGIN: u32 = 54;
+
+/// Font size of the text, in points.
+const FONT_SIZE: u32 = 9;
+
+/// Distance between consecutive baselines, in points.
+const LEADING: u32 = 11;
+
+/// Width of a Courier glyph, in thousandths of the font size.
+const GLYPH_WIDTH: u32 = 600;
+
+/// Number of columns a tab character advances to.
+const TAB_WIDTH: usize = 4;
+
+/// Number of text lines that fit on a page, leaving room for the footer.
+const LINES_PER_PAGE: usize = ((PAGE_HEIGHT - 2 * MARGIN) / LEADING) as usize - 2;
+
+/// Number of characters that fit on a line.
+const CHARS_PER_LINE: usize =
+    ((PAGE_WIDTH - 2 * MARGIN) * 1000 / (FONT_SIZE * GLYPH_WIDTH)) as usize;
+
+/// Object numbers of the fixed objects at the start of the file.
+const CATALOG: usize = 1;
+const PAGES: usize = 2;
+const FONT: usize = 3;
+
+/// Writes a PDF in chunks, one page at a time, so that it can be streamed.
+///
+/// Iterating yields the file header first, then one chunk per page, and
+/// finally the cross-reference table.
+pub struct PdfWriter {
+    /// Lines of text after wrapping, in Latin-1.
+    lines: Vec<Vec<u8>>,
+    /// Total number of pages.
+    pages: usize,
+    /// Index of the next page to write, or `pages + 1` once finished.
+    next_page: usize,
+    /// Whether the file header has been written.
+    started: bool,
+    /// Number of bytes written so far.
+    offset: usize,
+    /// Byte offset of each object written so far, by object number minus one.
+    objects: Vec<usize>,
+}
+
+impl PdfWriter {
+    /// Lays out the text into pages.
+    pub fn new(text: &str) -> Self {
+        let lines: Vec<Vec<u8>> = text.lines().flat_map(wrap_line).collect();
+        let pages = lines.len().div_ceil(LINES_PER_PAGE).max(1);
+        Self {
+            lines,
+            pages,
+            next_page: 0,
+            started: false,
+            offset: 0,
+            objects: Vec::new(),
+        }
+    }
+
+    /// Appends an object to a chunk, recording its offset.
+    fn push_object(&mut self, chunk: &mut Vec<u8>, body: &[u8]) {
+        self.objects.push(self.offset + chunk.len());
+        let number = self.objects.len();
+        chunk.extend_from_slice(format!("{} 0 obj\n", number).as_bytes());
+        chunk.extend_from_slice(body);
+        chunk.extend_from_slice(b"\nendobj\n");
+    }
+
+    fn header(&mut self) -> Vec<u8> {
+        let mut chunk = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let catalog = format!("<< /Type /Catalog /Pages {} 0 R >>", PAGES);
+        self.push_object(&mut chunk, catalog.as_bytes());
+        let kids: Vec<String> = (0..self.pages)
+            .map(|page| format!("{} 0 R", page_object(page)))
+            .collect();
+        let pages = format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            self.pages
+        );
+        self.push_object(&mut chunk, pages.as_bytes());
+        self.push_object(
+            &mut chunk,
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Courier /Encoding /WinAnsiEncoding >>",
+        );
+        chunk
+    }
+
+    fn page(&mut self, page: usize) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        let dictionary = format!(
+            "<< /Type /Page /Parent {} 0 R /MediaBox [0 0 {} {}] \
+             /Resources << /Font << /F1 {} 0 R >> >> /Contents {} 0 R >>",
+            PAGES,
+            PAGE_WIDTH,
+            PAGE_HEIGHT,
+            FONT,
+            page_object(page) + 1
+        );
+        self.push_object(&mut chunk, dictionary.as_bytes());
+
+        let mut content = format!(
+            "BT\n/F1 {} Tf\n{} TL\n{} {} Td\n",
+            FONT_SIZE,
+            LEADING,
+            MARGIN,
+            PAGE_HEIGHT - MARGIN - FONT_SIZE
+        )
+        .into_bytes();
+        let start = page * LINES_PER_PAGE;
+        let end = (start + LINES_PER_PAGE).min(self.lines.len());
+        for line in &self.lines[start..end] {
+            push_string(&mut content, line);
+            content.extend_from_slice(b" Tj T*\n");
+        }
+        content.extend_from_slice(b"ET\nBT\n");
+        let footer = format!("{} / {}", page + 1, self.pages);
+        let footer_width = footer.len() as u32 * FONT_SIZE * GLYPH_WIDTH / 1000;
+        content.extend_from_slice(
+            format!(
+                "/F1 {} Tf\n{} {} Td\n",
+                FONT_SIZE,
+                (PAGE_WIDTH - footer_width) / 2,
+                MARGIN / 2
+            )
+            .as_bytes(),
+        );
+        push_string(&mut content, footer.as_bytes());
+        content.extend_from_slice(b" Tj\nET");
+
+        let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+        stream.extend_from_slice(&content);
+        stream.extend_from_slice(b"\nendstream");
+        self.push_object(&mut chunk, &stream);
+        chunk
+    }
+
+    fn trailer(&mut self) -> Vec<u8> {
+        let mut chunk = format!("xref\n0 {}\n0000000000 65535 f \n", self.objects.len() + 1);
+        for offset in &self.objects {
+            chunk.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        chunk.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.objects.len() + 1,
+            CATALOG,
+            self.offset
+        ));
+        chunk.into_bytes()
+    }
+}
+
+impl Iterator for PdfWriter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let chunk = if !self.started {
+            self.started = true;
+            self.header()
+        } else if self.next_page < self.pages {
+            self.next_page += 1;
+            self.page(self.next_page - 1)
+        } else if self.next_page == self.pages {
+            self.next_page += 1;
+            self.trailer()
+        } else {
+            return None;
+        };
+        self.offset += chunk.len();
+        Some(chunk)
+    }
+}
+
+/// Returns the object number of a page dictionary; its contents follow it.
+fn page_object(page: usize) -> usize {
+    FONT + 1 + 2 * page
+}
+
+/// Encodes a line in Latin-1 and splits it into lines that fit the page width.
+fn wrap_line(line: &str) -> Vec<Vec<u8>> {
+    let mut encoded = Vec::new();
+    for c in line.chars() {
+        match c {
+            '\t' => {
+                let spaces = TAB_WIDTH - encoded.len() % TAB_WIDTH;
+                encoded.extend(std::iter::repeat(b' ').take(spaces));
+            }
+            ' '..='~' | '\u{a0}'..='\u{ff}' => encoded.push(c as u8),
+            c if c.is_control() => (),
+            _ => encoded.push(b'?'),
+        }
+    }
+    if encoded.is_empty() {
+        return vec![encoded];
+    }
+    encoded.chunks(CHARS_PER_LINE).map(<[u8]>::to_vec).collect()
+}
+
+/// Appends a PDF literal string, escaping delimiters.
+fn push_string(content: &mut Vec<u8>, text: &[u8]) {
+    content.push(b'(');
+    for &byte in text {
+        if matches!(byte, b'(' | b')' | b'\\') {
+            content.push(b'\\');
+        }
+        content.push(byte);
+    }
+    content.push(b')');
+}
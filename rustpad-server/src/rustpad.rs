@@ -1,18 +1,36 @@
 //! Eventually consistent server-side logic for Rustpad.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use futures::prelude::*;
 use log::{info, warn};
 use operational_transform::OperationSeq;
 use parking_lot::{RwLock, RwLockUpgradableReadGuard};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, Notify};
+use tokio::time::{self, Instant, MissedTickBehavior};
 use warp::ws::{Message, WebSocket};
 
-use crate::{database::{Database, PersistedDocument}, ot::transform_index};
+use crate::{
+    access::Identity,
+    database::{Database, DocumentSettings, EditSource, PersistedDocument, StoredOperation},
+    jobs::{UserColorJob, SAVE_USER_COLOR},
+    messages::Notice,
+    ot::{
+        carry_attribution, changed_chars, checksum, count_words, inserted_chars, transform_index,
+        word_count_delta,
+    },
+    signing::{EditSignature, EditSigner, SessionKey},
+};
+
+#[cfg(feature = "protocol-tests")]
+pub mod protocol_tests;
+#[cfg(feature = "simulation")]
+pub mod simulation;
 
 /// The main object representing a collaborative session.
 pub struct Rustpad {
@@ -26,8 +44,390 @@ pub struct Rustpad {
     update: broadcast::Sender<ServerMsg>,
     /// Set to true when the document is destroyed.
     killed: AtomicBool,
+    /// Set to true while the document is archived or its settings make it
+    /// read-only, rejecting all edits.
+    read_only: AtomicBool,
     /// Database for persisting user colors.
     database: Option<Database>,
+    /// Counts of edits rejected by this document, by category.
+    rejections: RejectionCounters,
+    /// Number of edits since the document was last persisted.
+    unsaved_edits: AtomicU64,
+    /// Number of characters changed since the document was last persisted.
+    unsaved_chars: AtomicU64,
+    /// Used to wake the persister once enough has changed to persist early.
+    persist: Notify,
+}
+
+/// Version of the WebSocket message protocol, bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Maximum length of a document's text, in characters.
+pub const MAX_DOCUMENT_SIZE: usize = 256 * 1024;
+
+/// Number of unsaved edits after which a document is persisted right away.
+const PERSIST_EDITS: u64 = 200;
+
+/// Number of characters changed by unsaved edits, such as by a large paste,
+/// after which a document is persisted right away.
+const PERSIST_CHARS: u64 = 4 * 1024;
+
+/// Number of characters that an edit without a source hint must insert at
+/// once to be recorded as a paste rather than typing.
+const PASTE_CHARS: u64 = 32;
+
+/// Longest wait for the password of a protected document from a client.
+const PASSWORD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often each client is sent a checksum of the text it should have.
+const CHECKSUM_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Minimum time between document statistics sent to each client.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reading speed assumed for estimating reading time, in words per minute.
+const WORDS_PER_MINUTE: usize = 200;
+
+/// Number of recent checksums remembered for verifying client replies.
+const MAX_CHECKSUMS: usize = 16;
+
+/// Number of operations kept in memory before history may be trimmed.
+const MAX_HISTORY: usize = 1000;
+
+/// Smoothed send latency above which a client is sent periodic snapshots of
+/// the text instead of every operation, so that messages don't queue up.
+const SLOW_LATENCY: Duration = Duration::from_millis(500);
+
+/// Smoothed send latency below which a slow client is sent operations again.
+const FAST_LATENCY: Duration = Duration::from_millis(100);
+
+/// Minimum time between snapshots of the text sent to a slow client.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Categories of edits rejected by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RejectReason {
+    /// The edit is based on a revision older than the retained history.
+    StaleRevision,
+    /// The edit is based on a revision the server has not reached.
+    InvalidRevision,
+    /// The edit does not fit the document at its base revision.
+    OutOfSync,
+    /// The edit would make the document larger than the maximum size.
+    TooLarge,
+    /// The client message could not be deserialized.
+    ParseError,
+    /// The edit carried a signature that does not match its payload.
+    InvalidSignature,
+    /// The document is archived, so it cannot be edited.
+    ReadOnly,
+}
+
+impl RejectReason {
+    /// Returns if the client can recover from this rejection by a full resync.
+    fn resyncable(self) -> bool {
+        matches!(
+            self,
+            RejectReason::StaleRevision | RejectReason::OutOfSync | RejectReason::ReadOnly
+        )
+    }
+
+    /// Returns the code identifying this reason in client-facing messages.
+    fn code(self) -> &'static str {
+        match self {
+            RejectReason::StaleRevision => "stale_revision",
+            RejectReason::InvalidRevision => "invalid_revision",
+            RejectReason::OutOfSync => "out_of_sync",
+            RejectReason::TooLarge => "too_large",
+            RejectReason::ParseError => "parse_error",
+            RejectReason::InvalidSignature => "invalid_signature",
+            RejectReason::ReadOnly => "read_only",
+        }
+    }
+}
+
+/// Error for an edit that the server refused to apply.
+#[derive(Debug)]
+struct RejectedEdit {
+    reason: RejectReason,
+    message: String,
+}
+
+impl RejectedEdit {
+    fn new(reason: RejectReason, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+        }
+    }
+
+    /// Returns the notice sent to a client whose edit cannot be recovered
+    /// from by a resync, before it is disconnected.
+    fn notice(&self) -> Notice {
+        Notice::new("edit_rejected").with("reason", self.reason.code())
+    }
+}
+
+impl std::fmt::Display for RejectedEdit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.reason, self.message)
+    }
+}
+
+impl std::error::Error for RejectedEdit {}
+
+/// Returns the notice sent to a client whose message could not be parsed,
+/// before it is disconnected.
+fn invalid_message_notice() -> Notice {
+    Notice::new("invalid_message")
+}
+
+/// Returns the notice sent to a client whose message is over the size limit,
+/// before it is disconnected.
+fn oversized_notice(limit: usize) -> Notice {
+    Notice::new("message_too_large").with("limit", limit.to_string())
+}
+
+/// Waits for the first message of a client connecting to a password-protected
+/// document, returning the password it holds, if it is a password at all.
+pub async fn receive_password(socket: &mut WebSocket) -> Option<String> {
+    let message = match time::timeout(PASSWORD_TIMEOUT, socket.next()).await {
+        Ok(Some(Ok(message))) => message,
+        _ => return None,
+    };
+    match serde_json::from_str(message.to_str().ok()?) {
+        Ok(ClientMsg::Password(password)) => Some(password),
+        _ => None,
+    }
+}
+
+/// Tells a client that the password of a protected document is required, or
+/// that the one it sent was wrong, and closes the connection.
+pub async fn refuse_password(mut socket: WebSocket, sent: bool) {
+    let code = if sent {
+        "invalid_password"
+    } else {
+        "password_required"
+    };
+    let notice = Notice::new(code);
+    socket.send(ServerMsg::Error(notice).into()).await.ok();
+    socket.close().await.ok();
+}
+
+/// Error for a client message larger than its connection allows.
+#[derive(Debug)]
+struct MessageTooLarge {
+    size: usize,
+    limit: usize,
+}
+
+impl std::fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message of {} bytes exceeds {} limit", self.size, self.limit)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// Counts of rejected edits in each category, updated without locking.
+#[derive(Default)]
+struct RejectionCounters {
+    stale_revision: AtomicU64,
+    invalid_revision: AtomicU64,
+    out_of_sync: AtomicU64,
+    too_large: AtomicU64,
+    parse_error: AtomicU64,
+    invalid_signature: AtomicU64,
+    read_only: AtomicU64,
+}
+
+impl RejectionCounters {
+    fn record(&self, reason: RejectReason) {
+        let counter = match reason {
+            RejectReason::StaleRevision => &self.stale_revision,
+            RejectReason::InvalidRevision => &self.invalid_revision,
+            RejectReason::OutOfSync => &self.out_of_sync,
+            RejectReason::TooLarge => &self.too_large,
+            RejectReason::ParseError => &self.parse_error,
+            RejectReason::InvalidSignature => &self.invalid_signature,
+            RejectReason::ReadOnly => &self.read_only,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> RejectionStats {
+        RejectionStats {
+            stale_revision: self.stale_revision.load(Ordering::Relaxed),
+            invalid_revision: self.invalid_revision.load(Ordering::Relaxed),
+            out_of_sync: self.out_of_sync.load(Ordering::Relaxed),
+            too_large: self.too_large.load(Ordering::Relaxed),
+            parse_error: self.parse_error.load(Ordering::Relaxed),
+            invalid_signature: self.invalid_signature.load(Ordering::Relaxed),
+            read_only: self.read_only.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Number of rejected edits in each category, for reporting.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RejectionStats {
+    /// Edits based on a revision older than the retained history.
+    pub stale_revision: u64,
+    /// Edits based on a revision the server has not reached.
+    pub invalid_revision: u64,
+    /// Edits that did not fit the document at their base revision.
+    pub out_of_sync: u64,
+    /// Edits that would exceed the maximum document size.
+    pub too_large: u64,
+    /// Client messages that could not be deserialized.
+    pub parse_error: u64,
+    /// Edits whose signature did not match their payload.
+    pub invalid_signature: u64,
+    /// Edits to archived documents.
+    pub read_only: u64,
+}
+
+impl RejectionStats {
+    /// Returns the total number of rejections across all categories.
+    pub fn total(&self) -> u64 {
+        self.stale_revision
+            + self.invalid_revision
+            + self.out_of_sync
+            + self.too_large
+            + self.parse_error
+            + self.invalid_signature
+            + self.read_only
+    }
+
+    /// Adds the counts from another set of statistics to these.
+    pub fn merge(&mut self, other: &RejectionStats) {
+        self.stale_revision += other.stale_revision;
+        self.invalid_revision += other.invalid_revision;
+        self.out_of_sync += other.out_of_sync;
+        self.too_large += other.too_large;
+        self.parse_error += other.parse_error;
+        self.invalid_signature += other.invalid_signature;
+        self.read_only += other.read_only;
+    }
+}
+
+/// Counters for WebSocket traffic across all documents, updated without locking.
+#[derive(Default)]
+pub struct SocketMetrics {
+    open: AtomicU64,
+    connections: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    edits: AtomicU64,
+    oversized: AtomicU64,
+}
+
+impl SocketMetrics {
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> SocketStats {
+        SocketStats {
+            open: self.open.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            edits: self.edits.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of WebSocket traffic, for reporting.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct SocketStats {
+    /// Number of currently open connections.
+    pub open: u64,
+    /// Number of connections accepted since the server started.
+    pub connections: u64,
+    /// Number of messages received from clients.
+    pub messages_in: u64,
+    /// Number of messages sent to clients.
+    pub messages_out: u64,
+    /// Total size of messages received from clients, in bytes.
+    pub bytes_in: u64,
+    /// Total size of messages sent to clients, in bytes.
+    pub bytes_out: u64,
+    /// Number of edits from clients applied to documents.
+    pub edits: u64,
+    /// Number of connections closed for a message over the size limit.
+    pub oversized: u64,
+}
+
+/// Settings shared by all WebSocket connections.
+#[derive(Clone, Debug, Default)]
+pub struct SocketConfig {
+    /// Maximum size of a message from a client, in bytes.
+    pub max_message_size: usize,
+    /// Label of the region this server runs in, sent to clients.
+    pub region: Option<String>,
+    /// URLs of other instances of this deployment, sent to clients.
+    pub alternates: Vec<String>,
+    /// Whether edits from the client are refused, as for a connection opened
+    /// with a read-only share link.
+    pub read_only: bool,
+    /// Signer that issues the keys authenticated users sign edits with.
+    pub signer: Arc<EditSigner>,
+}
+
+/// Software that a client reported when opening its WebSocket connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ClientAgent {
+    /// User-Agent header of the WebSocket upgrade request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Version of the client, from the `client_version` query parameter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+}
+
+/// State of a single WebSocket connection, owned by the task handling it.
+struct Connection {
+    /// Unique ID of the user on this connection.
+    id: u64,
+    /// The authenticated email of the user, if any.
+    email: Option<String>,
+    /// Key for verifying edit signatures, issued to authenticated users.
+    signing_key: Option<SessionKey>,
+    /// Number of operations that have been sent to the client.
+    revision: usize,
+    /// Document statistics last sent to the client, if any.
+    stats: Option<DocStats>,
+    /// The underlying WebSocket.
+    socket: WebSocket,
+    /// Traffic counters shared by all connections.
+    metrics: Arc<SocketMetrics>,
+    /// Settings shared by all connections.
+    config: Arc<SocketConfig>,
+    /// Smoothed time taken to send a message to the client.
+    latency: Duration,
+    /// Whether the client is sent snapshots instead of operations.
+    slow: bool,
+    /// When a snapshot was last sent to the client.
+    snapshot_at: Instant,
+}
+
+impl Connection {
+    /// Sends a message to the client, counting it in the traffic metrics.
+    async fn send(&mut self, msg: ServerMsg) -> Result<()> {
+        let message = Message::from(msg);
+        self.metrics.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .bytes_out
+            .fetch_add(message.as_bytes().len() as u64, Ordering::Relaxed);
+        let start = Instant::now();
+        self.socket.send(message).await?;
+        // Weigh each send by 1/8, like the smoothed round-trip time of TCP.
+        self.latency = (self.latency * 7 + start.elapsed()) / 8;
+        Ok(())
+    }
 }
 
 /// Shared state involving multiple users, protected by a lock.
@@ -40,6 +440,178 @@ struct State {
     cursors: HashMap<u64, CursorData>,
     /// Color preferences by email (for authenticated users).
     user_colors: HashMap<String, u32>,
+    /// Recently sent checksums, as pairs of revision and hash.
+    checksums: VecDeque<(usize, u32)>,
+    /// Number of operations trimmed from the front of the history.
+    trimmed: usize,
+    /// Text at revision `trimmed`, that the retained operations apply to.
+    base: String,
+    /// Lowest revision each connected client may base its next edit on.
+    bases: HashMap<u64, usize>,
+    /// Software that each connected client reported.
+    agents: HashMap<u64, ClientAgent>,
+    /// Number of words in the text, kept up to date as edits apply.
+    words: usize,
+    /// Number of characters in the text.
+    chars: usize,
+    /// Settings of the document, as last persisted.
+    settings: DocumentSettings,
+    /// Latest revision known to be stored in the database.
+    persisted: usize,
+    /// Number of operations from the start of the history stored in the
+    /// database.
+    stored_operations: usize,
+}
+
+impl State {
+    /// Returns the messages that bring a client with no text up to the
+    /// current revision: the base text left by trimming the history, if any,
+    /// followed by the operations retained after it.
+    fn catch_up(&self) -> Vec<ServerMsg> {
+        let mut messages = Vec::new();
+        if self.trimmed > 0 {
+            messages.push(ServerMsg::Resync {
+                text: self.base.clone(),
+                revision: self.trimmed,
+            });
+        }
+        if !self.operations.is_empty() {
+            messages.push(ServerMsg::History {
+                start: self.trimmed,
+                operations: self.operations.clone(),
+            });
+        }
+        messages
+    }
+
+    /// Returns the current revision, including trimmed operations.
+    fn revision(&self) -> usize {
+        self.trimmed + self.operations.len()
+    }
+
+    /// Returns every operation since the document was created with the email
+    /// of its author and how it was made, taking those trimmed from memory
+    /// from `stored`, or `None` if they are missing there.
+    #[allow(clippy::type_complexity)]
+    fn history<'a>(
+        &'a self,
+        stored: &'a [StoredOperation],
+    ) -> Option<Vec<(&'a OperationSeq, Option<&'a str>, Option<EditSource>)>> {
+        let trimmed = stored.get(..self.trimmed)?;
+        if trimmed.iter().enumerate().any(|(i, op)| op.revision != i) {
+            return None;
+        }
+        let operations = trimmed
+            .iter()
+            .map(|op| (&op.operation, op.email.as_deref(), op.source))
+            .chain(
+                self.operations
+                    .iter()
+                    .map(|op| (&op.operation, op.email.as_deref(), op.source)),
+            )
+            .collect();
+        Some(operations)
+    }
+
+    /// Returns the word and character counts of the text.
+    fn stats(&self) -> DocStats {
+        DocStats {
+            words: self.words,
+            chars: self.chars,
+            reading_minutes: self.words.div_ceil(WORDS_PER_MINUTE),
+        }
+    }
+}
+
+/// A document with the operations not yet stored, taken at one revision.
+#[derive(Clone, Debug)]
+pub struct HistorySnapshot {
+    /// Text and language of the document.
+    pub document: PersistedDocument,
+    /// Revision of the document.
+    pub revision: usize,
+    /// Revision of the first operation in `operations`.
+    pub start: usize,
+    /// Operations made since the history was last stored.
+    pub operations: Vec<StoredOperation>,
+}
+
+/// Word and character counts of a document, broadcast to clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocStats {
+    /// Number of words, separated by whitespace.
+    pub words: usize,
+    /// Number of characters.
+    pub chars: usize,
+    /// Estimated time to read the text, rounded up to whole minutes.
+    pub reading_minutes: usize,
+}
+
+/// The text of a document paired with the revision it was read at.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TextSnapshot {
+    /// Text content of the document.
+    pub text: String,
+    /// Revision number that the text corresponds to.
+    pub revision: usize,
+    /// Language of the document for editor syntax highlighting.
+    pub language: Option<String>,
+}
+
+/// Users connected to a document, for showing who is in it without a socket.
+#[derive(Clone, Debug, Serialize)]
+pub struct Session {
+    /// Connected users that have sent their info, ordered by ID.
+    pub users: Vec<SessionUser>,
+    /// Current revision of the document.
+    pub revision: usize,
+    /// Language of the document for editor syntax highlighting.
+    pub language: Option<String>,
+}
+
+/// A user connected to a document.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionUser {
+    /// Client ID of the connection.
+    pub id: u64,
+    /// Display name chosen by the user.
+    pub name: String,
+    /// Hue of the user's cursor color.
+    pub hue: u32,
+    /// Software that the user connected with.
+    #[serde(flatten)]
+    pub agent: ClientAgent,
+}
+
+/// The last edit to each line of a document.
+#[derive(Clone, Debug, Serialize)]
+pub struct Blame {
+    /// Revision of the document that the lines are taken from.
+    pub revision: usize,
+    /// Last edit to each line, in order.
+    pub lines: Vec<BlameLine>,
+}
+
+/// The last edit to a line of a document.
+#[derive(Clone, Debug, Serialize)]
+pub struct BlameLine {
+    /// Line number, counted from 1.
+    pub line: usize,
+    /// Revision of the document produced by the edit.
+    pub revision: usize,
+    /// Authenticated email of the user who made the edit, if any.
+    pub email: Option<String>,
+    /// How the edit was made, if known.
+    pub source: Option<EditSource>,
+}
+
+/// An edit made by an authenticated user, for exporting their data.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuthoredEdit {
+    /// Revision of the document produced by this edit.
+    pub revision: usize,
+    /// The operation applied to the document.
+    pub operation: OperationSeq,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -49,12 +621,32 @@ struct UserOperation {
     /// The authenticated email of the user who made this edit (for persistent ownership).
     #[serde(skip_serializing_if = "Option::is_none")]
     email: Option<String>,
+    /// Hex-encoded HMAC-SHA256 signature of the edit, if the client signed it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    /// ID of the session key that the edit was signed with.
+    #[serde(skip)]
+    key_id: Option<String>,
+    /// Time the edit was applied, in seconds since Unix epoch, if known.
+    #[serde(skip)]
+    created_at: Option<i64>,
+    /// How the edit was made, if known.
+    #[serde(skip)]
+    source: Option<EditSource>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct UserInfo {
     name: String,
     hue: u32,
+    /// Whether the user's connection is slow, so it is sent snapshots of the
+    /// text instead of every edit. Set by the server only.
+    #[serde(skip_deserializing, skip_serializing_if = "is_false")]
+    slow: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -63,13 +655,93 @@ struct CursorData {
     selections: Vec<(u32, u32)>,
 }
 
+/// An edit of whole lines, numbered from 1 and separated by `\n`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum LineEdit {
+    /// Insert text as new lines before a line, or after the last line if the
+    /// line number is one past the end.
+    Insert { line: usize, text: String },
+    /// Replace the contents of a line, keeping its line break.
+    Replace { line: usize, text: String },
+    /// Delete a number of lines, starting at a line.
+    Delete {
+        line: usize,
+        #[serde(default = "default_line_count")]
+        count: usize,
+    },
+}
+
+fn default_line_count() -> usize {
+    1
+}
+
+impl LineEdit {
+    /// Builds the operation making this edit to a text, or returns `None` if a
+    /// line number is out of range.
+    pub fn operation(&self, text: &str) -> Option<OperationSeq> {
+        // Character offset of the start of each line.
+        let mut starts = vec![0];
+        let mut len = 0;
+        for c in text.chars() {
+            len += 1;
+            if c == '\n' {
+                starts.push(len);
+            }
+        }
+        let lines = starts.len();
+
+        let (start, end, inserted) = match self {
+            LineEdit::Insert { line, text } if (1..=lines).contains(line) => {
+                let start = starts[line - 1];
+                (start, start, format!("{}\n", text))
+            }
+            LineEdit::Insert { line, text } if *line == lines + 1 => {
+                (len, len, format!("\n{}", text))
+            }
+            LineEdit::Replace { line, text } if (1..=lines).contains(line) => {
+                let end = starts.get(*line).map_or(len, |next| next - 1);
+                (starts[line - 1], end, text.clone())
+            }
+            LineEdit::Delete { line, count } if *line >= 1 && *count >= 1 => {
+                let last = line.checked_add(count - 1).filter(|&last| last <= lines)?;
+                if last < lines {
+                    (starts[line - 1], starts[last], String::new())
+                } else if *line > 1 {
+                    // Remove the line break before the deleted lines instead.
+                    (starts[line - 1] - 1, len, String::new())
+                } else {
+                    (0, len, String::new())
+                }
+            }
+            _ => return None,
+        };
+        let mut operation = OperationSeq::default();
+        operation.retain(start as u64);
+        operation.delete((end - start) as u64);
+        operation.insert(&inserted);
+        operation.retain((len - end) as u64);
+        Some(operation)
+    }
+}
+
 /// A message received from the client over WebSocket.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 enum ClientMsg {
     /// Represents a sequence of local edits from the user.
+    ///
+    /// Authenticated clients may include a hex-encoded HMAC-SHA256 signature
+    /// of `"{revision}:{operation}"`, with the operation serialized as JSON,
+    /// using the key from [`ServerMsg::SigningKey`].
     Edit {
         revision: usize,
         operation: OperationSeq,
+        #[serde(default)]
+        signature: Option<String>,
+        /// How the edit was made. If omitted, large insertions are taken to
+        /// be pastes and anything else typing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<EditSource>,
     },
     /// Sets the language of the editor.
     SetLanguage(String),
@@ -79,6 +751,10 @@ enum ClientMsg {
     CursorData(CursorData),
     /// Sets the authenticated user's color preference.
     SetColor(u32),
+    /// Reports the checksum of the client's text at a revision.
+    Checksum { revision: usize, hash: u32 },
+    /// Sends the password of a protected document, as the first message.
+    Password(String),
 }
 
 /// A message sent to the client over WebSocket.
@@ -86,8 +762,16 @@ enum ClientMsg {
 enum ServerMsg {
     /// Informs the client of their unique socket ID.
     Identity(u64),
+    /// Informs the client of the region of this server and of alternative
+    /// endpoints, if configured, so it can pick a closer instance.
+    ServerInfo {
+        region: Option<String>,
+        alternates: Vec<String>,
+    },
     /// Informs the client of their authenticated email (from Cloudflare Access).
     AuthenticatedEmail(Option<String>),
+    /// Informs an authenticated client of its hex-encoded edit signing key.
+    SigningKey(String),
     /// Broadcasts text operations to all clients.
     History {
         start: usize,
@@ -101,6 +785,18 @@ enum ServerMsg {
     UserCursor { id: u64, data: CursorData },
     /// Broadcasts an authenticated user's color preference.
     UserColor { email: String, hue: u32 },
+    /// Informs the client of the checksum of the text at a revision.
+    Checksum { revision: usize, hash: u32 },
+    /// Replaces the client's text after it has diverged from the server.
+    Resync { text: String, revision: usize },
+    /// Informs the client of an error, sent before the server disconnects it.
+    Error(Notice),
+    /// Informs the client of the word and character counts of the text.
+    DocStats(DocStats),
+    /// Informs the client whether the document is read-only.
+    ReadOnly(bool),
+    /// Informs the client of the document's settings.
+    Settings(DocumentSettings),
 }
 
 impl From<ServerMsg> for Message {
@@ -119,7 +815,12 @@ impl Default for Rustpad {
             notify: Default::default(),
             update: tx,
             killed: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
             database: None,
+            rejections: Default::default(),
+            unsaved_edits: Default::default(),
+            unsaved_chars: Default::default(),
+            persist: Default::default(),
         }
     }
 }
@@ -134,25 +835,62 @@ impl Rustpad {
             notify: Default::default(),
             update: tx,
             killed: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
             database: Some(database),
+            rejections: Default::default(),
+            unsaved_edits: Default::default(),
+            unsaved_chars: Default::default(),
+            persist: Default::default(),
         }
     }
 
     /// Create a Rustpad from a persisted document with database support.
-    pub fn from_document(document: PersistedDocument, database: Database) -> Self {
-        let mut operation = OperationSeq::default();
-        operation.insert(&document.text);
-
+    ///
+    /// The stored operation history is restored if it reproduces the text;
+    /// otherwise history starts over with the text inserted at once.
+    pub fn from_document(
+        document: PersistedDocument,
+        history: Vec<StoredOperation>,
+        database: Database,
+    ) -> Self {
         let rustpad = Self::new(database);
         {
             let mut state = rustpad.state.write();
+            state.words = count_words(&document.text);
+            state.chars = document.text.chars().count();
+            match replay(&history) {
+                Some(text) if !history.is_empty() && text == document.text => {
+                    state.stored_operations = history.len();
+                    state.operations = history
+                        .into_iter()
+                        .map(|op| UserOperation {
+                            id: op.user_id,
+                            operation: op.operation,
+                            email: op.email,
+                            signature: op.signature,
+                            key_id: op.key_id,
+                            created_at: op.created_at,
+                            source: op.source,
+                        })
+                        .collect();
+                }
+                _ => {
+                    let mut operation = OperationSeq::default();
+                    operation.insert(&document.text);
+                    state.operations.push(UserOperation {
+                        id: u64::MAX,
+                        operation,
+                        email: None,
+                        signature: None,
+                        key_id: None,
+                        created_at: None,
+                        source: None,
+                    });
+                }
+            }
             state.text = document.text;
             state.language = document.language;
-            state.operations.push(UserOperation {
-                id: u64::MAX,
-                operation,
-                email: None,
-            })
+            state.persisted = state.revision();
         }
         rustpad
     }
@@ -177,24 +915,87 @@ impl Rustpad {
 
 impl Rustpad {
     /// Handle a connection from a WebSocket.
-    pub async fn on_connection(&self, socket: WebSocket, cf_email: Option<String>) {
+    ///
+    /// Returns whether the client was disconnected for sending a message
+    /// larger than the configured maximum size.
+    pub async fn on_connection(
+        &self,
+        socket: WebSocket,
+        identity: Option<Identity>,
+        agent: ClientAgent,
+        metrics: Arc<SocketMetrics>,
+        config: Arc<SocketConfig>,
+    ) -> bool {
         let id = self.count.fetch_add(1, Ordering::Relaxed);
-        info!("connection! id = {}, cf_email = {:?}", id, cf_email);
-        if let Err(e) = self.handle_connection(id, socket, cf_email).await {
+        info!(
+            "connection! id = {}, identity = {:?}, user_agent = {:?}, client_version = {:?}",
+            id, identity, agent.user_agent, agent.client_version
+        );
+        self.state.write().agents.insert(id, agent);
+        metrics.open.fetch_add(1, Ordering::Relaxed);
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        let conn = Connection {
+            id,
+            email: identity.and_then(|identity| identity.email),
+            signing_key: None,
+            revision: 0,
+            stats: None,
+            socket,
+            metrics: Arc::clone(&metrics),
+            config,
+            latency: Duration::ZERO,
+            slow: false,
+            snapshot_at: Instant::now(),
+        };
+        let mut oversized = false;
+        if let Err(e) = self.handle_connection(conn).await {
+            oversized = e.is::<MessageTooLarge>();
             warn!("connection terminated early: {}", e);
         }
+        metrics.open.fetch_sub(1, Ordering::Relaxed);
         info!("disconnection, id = {}", id);
-        self.state.write().users.remove(&id);
-        self.state.write().cursors.remove(&id);
+        {
+            let mut state = self.state.write();
+            state.users.remove(&id);
+            state.cursors.remove(&id);
+            state.bases.remove(&id);
+            state.agents.remove(&id);
+        }
         self.update
             .send(ServerMsg::UserInfo { id, info: None })
             .ok();
+        oversized
     }
 
-    /// Returns a snapshot of the latest text.
-    pub fn text(&self) -> String {
+    /// Returns the latest text, revision and language under a single lock.
+    pub fn text_snapshot(&self) -> TextSnapshot {
         let state = self.state.read();
-        state.text.clone()
+        TextSnapshot {
+            text: state.text.clone(),
+            revision: state.revision(),
+            language: state.language.clone(),
+        }
+    }
+
+    /// Returns the connected users, revision and language under a single lock.
+    pub fn session(&self) -> Session {
+        let state = self.state.read();
+        let mut users: Vec<_> = state
+            .users
+            .iter()
+            .map(|(&id, info)| SessionUser {
+                id,
+                name: info.name.clone(),
+                hue: info.hue,
+                agent: state.agents.get(&id).cloned().unwrap_or_default(),
+            })
+            .collect();
+        users.sort_by_key(|user| user.id);
+        Session {
+            users,
+            revision: state.revision(),
+            language: state.language.clone(),
+        }
     }
 
     /// Returns a snapshot of the current document for persistence.
@@ -206,10 +1007,336 @@ impl Rustpad {
         }
     }
 
+    /// Sets whether the document is read-only and broadcasts it to all clients.
+    pub fn set_read_only(&self, read_only: bool) {
+        if self.read_only.swap(read_only, Ordering::Relaxed) != read_only {
+            self.update.send(ServerMsg::ReadOnly(read_only)).ok();
+        }
+    }
+
+    /// Returns if this document is read-only.
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the settings of the document and broadcasts them to all
+    /// clients if they changed.
+    ///
+    /// Whether the document is read-only is set separately, since archiving
+    /// makes it read-only too.
+    pub fn set_settings(&self, settings: DocumentSettings) {
+        let mut state = self.state.write();
+        if state.settings != settings {
+            state.settings = settings.clone();
+            self.update.send(ServerMsg::Settings(settings)).ok();
+        }
+    }
+
+    /// Returns the settings of the document.
+    pub fn settings(&self) -> DocumentSettings {
+        self.state.read().settings.clone()
+    }
+
+    /// Returns the maximum length of the text, in characters.
+    pub fn max_size(&self) -> usize {
+        self.state.read().settings.max_size(MAX_DOCUMENT_SIZE)
+    }
+
+    /// Sets the language of the document and broadcasts it to all clients.
+    pub fn set_language(&self, language: String) {
+        self.state.write().language = Some(language.clone());
+        self.update.send(ServerMsg::Language(language)).ok();
+    }
+
+    /// Applies an operation made outside of any connection, such as over the
+    /// REST API, as an edit by a new client ID. Returns the new revision.
+    ///
+    /// The operation is based on `revision` and takes the same path as edits
+    /// from clients: it is transformed against any later edits, broadcast to
+    /// connected clients, and rejected if it no longer applies.
+    pub fn apply_external(
+        &self,
+        revision: usize,
+        operation: OperationSeq,
+        email: Option<String>,
+    ) -> Result<usize> {
+        let id = self.count.fetch_add(1, Ordering::Relaxed);
+        let result = self.apply_edit(
+            id,
+            revision,
+            operation,
+            email,
+            None,
+            EditSource::Programmatic,
+        );
+        // There is no connection to base further edits, so don't hold back
+        // history trimming for this ID.
+        self.state.write().bases.remove(&id);
+        if let Err(rejected) = result {
+            self.rejections.record(rejected.reason);
+            return Err(rejected).context("failed to apply external edit");
+        }
+        self.notify.notify_waiters();
+        Ok(self.revision())
+    }
+
+    /// Mirrors a message that the primary of a warm standby sent about this
+    /// document, keeping the text and history identical to the primary's.
+    ///
+    /// Operations that are already known are skipped, and a snapshot ahead of
+    /// the current revision replaces the history. Fails on an error from the
+    /// primary or a gap in the history, after which the replica should
+    /// reconnect to catch up again.
+    pub fn replicate(&self, message: &str) -> Result<()> {
+        let msg: ServerMsg = serde_json::from_str(message).context("invalid message")?;
+        match msg {
+            ServerMsg::History { start, operations } => {
+                let mut state = self.state.write();
+                for (i, op) in operations.into_iter().enumerate() {
+                    let revision = state.revision();
+                    if start + i < revision {
+                        continue;
+                    } else if start + i > revision {
+                        bail!("missed the operation at revision {}", revision);
+                    }
+                    let text = op
+                        .operation
+                        .apply(&state.text)
+                        .map_err(|e| anyhow!("history does not apply: {}", e))?;
+                    state.words = count_words(&text);
+                    state.chars = op.operation.target_len();
+                    state.text = text;
+                    state.operations.push(op);
+                }
+            }
+            ServerMsg::Resync { text, revision } => {
+                let mut state = self.state.write();
+                if revision <= state.revision() {
+                    return Ok(());
+                }
+                state.words = count_words(&text);
+                state.chars = text.chars().count();
+                state.base = text.clone();
+                state.text = text;
+                state.trimmed = revision;
+                state.operations.clear();
+            }
+            ServerMsg::Language(language) => self.set_language(language),
+            ServerMsg::Error(notice) => bail!("primary sent error {:?}", notice.code),
+            _ => return Ok(()),
+        }
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    /// Returns an operation reverting the text to how it was at an earlier
+    /// revision, along with the revision that the operation is based on.
+    ///
+    /// The operation is the inverse of the edits made since. Operations that
+    /// were trimmed from memory are taken from `stored`, the stored history of
+    /// the document, and `None` is returned if they are missing there too.
+    pub fn revert_operation(
+        &self,
+        revision: usize,
+        stored: &[StoredOperation],
+    ) -> Option<(usize, OperationSeq)> {
+        let state = self.state.read();
+        if revision > state.revision() {
+            return None;
+        }
+        let operations = state.history(stored)?;
+        let mut text = String::new();
+        for (operation, ..) in &operations[..revision] {
+            text = operation.apply(&text).ok()?;
+        }
+        let mut since = OperationSeq::default();
+        since.retain(text.chars().count() as u64);
+        for (operation, ..) in &operations[revision..] {
+            since = since.compose(operation).ok()?;
+        }
+        Some((state.revision(), since.invert(&text)))
+    }
+
+    /// Returns the text as it was at an earlier revision, taking operations
+    /// trimmed from memory from `stored` like [`Rustpad::revert_operation`].
+    pub fn text_at(&self, revision: usize, stored: &[StoredOperation]) -> Option<String> {
+        let state = self.state.read();
+        if revision > state.revision() {
+            return None;
+        }
+        let mut text = String::new();
+        for (operation, ..) in &state.history(stored)?[..revision] {
+            text = operation.apply(&text).ok()?;
+        }
+        Some(text)
+    }
+
+    /// Returns the last edit to each line of the text, taking operations
+    /// trimmed from memory from `stored` like [`Rustpad::revert_operation`].
+    ///
+    /// Each character is attributed to the edit that inserted it, and a line
+    /// to the latest edit among its characters, including its line break.
+    pub fn blame(&self, stored: &[StoredOperation]) -> Option<Blame> {
+        let state = self.state.read();
+        let history = state.history(stored)?;
+        let mut attribution = Vec::new();
+        for (index, (operation, ..)) in history.iter().enumerate() {
+            attribution = carry_attribution(&attribution, operation, index)?;
+        }
+        if attribution.len() != state.chars {
+            return None;
+        }
+
+        let mut lines = Vec::new();
+        let mut latest = None;
+        let mut chars = state.text.chars().zip(attribution).peekable();
+        while let Some((c, index)) = chars.next() {
+            latest = latest.max(Some(index));
+            if c == '\n' || chars.peek().is_none() {
+                let index = latest.take().expect("line has a character");
+                lines.push(BlameLine {
+                    line: lines.len() + 1,
+                    revision: index + 1,
+                    email: history[index].1.map(String::from),
+                    source: history[index].2,
+                });
+            }
+        }
+        Some(Blame {
+            revision: state.revision(),
+            lines,
+        })
+    }
+
+    /// Returns the number of connected clients.
+    pub fn connections(&self) -> usize {
+        self.state.read().bases.len()
+    }
+
+    /// Returns the software reported by each connected client.
+    pub fn agents(&self) -> Vec<ClientAgent> {
+        self.state.read().agents.values().cloned().collect()
+    }
+
     /// Returns the current revision.
     pub fn revision(&self) -> usize {
         let state = self.state.read();
-        state.operations.len()
+        state.revision()
+    }
+
+    /// Returns the document along with the operations not yet stored.
+    pub fn history_snapshot(&self) -> HistorySnapshot {
+        let state = self.state.read();
+        let start = state.stored_operations.max(state.trimmed);
+        let operations = state.operations[start - state.trimmed..]
+            .iter()
+            .enumerate()
+            .map(|(i, op)| StoredOperation {
+                revision: start + i,
+                user_id: op.id,
+                email: op.email.clone(),
+                created_at: op.created_at,
+                source: op.source,
+                signature: op.signature.clone(),
+                key_id: op.key_id.clone(),
+                operation: op.operation.clone(),
+            })
+            .collect();
+        HistorySnapshot {
+            document: PersistedDocument {
+                text: state.text.clone(),
+                language: state.language.clone(),
+            },
+            revision: state.revision(),
+            start,
+            operations,
+        }
+    }
+
+    /// Records that the document and its history were stored in the database
+    /// at `revision`, returning the number of revisions newly stored.
+    pub fn set_persisted(&self, revision: usize) -> usize {
+        let mut state = self.state.write();
+        let stored = revision.saturating_sub(state.persisted);
+        state.persisted = state.persisted.max(revision);
+        state.stored_operations = state.stored_operations.max(revision);
+        stored
+    }
+
+    /// Returns the number of revisions not yet stored in the database.
+    pub fn unpersisted(&self) -> usize {
+        let state = self.state.read();
+        state.revision().saturating_sub(state.persisted)
+    }
+
+    /// Squashes history that neither the database nor any client still needs
+    /// into the base text, which new clients start from.
+    ///
+    /// Only operations older than both `persisted_revision` and the oldest
+    /// revision that a connected client may base an edit on are removed, and
+    /// only once the history has grown past [`MAX_HISTORY`] operations.
+    pub fn trim_history(&self, persisted_revision: usize) {
+        let mut state = self.state.write();
+        if state.operations.len() <= MAX_HISTORY {
+            return;
+        }
+        let client_base = state.bases.values().copied().min().unwrap_or(usize::MAX);
+        let cutoff = persisted_revision.min(client_base);
+        if cutoff <= state.trimmed {
+            return;
+        }
+        let count = (cutoff - state.trimmed).min(state.operations.len());
+        let mut base = std::mem::take(&mut state.base);
+        for op in state.operations.drain(..count) {
+            base = op
+                .operation
+                .apply(&base)
+                .expect("history should apply to its base text");
+        }
+        state.base = base;
+        state.trimmed += count;
+        info!("trimmed history up to revision {}", state.trimmed);
+    }
+
+    /// Returns the edits in the retained history made by an authenticated user.
+    pub fn edits_by(&self, email: &str) -> Vec<AuthoredEdit> {
+        let state = self.state.read();
+        state
+            .operations
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.email.as_deref() == Some(email))
+            .map(|(i, op)| AuthoredEdit {
+                revision: state.trimmed + i + 1,
+                operation: op.operation.clone(),
+            })
+            .collect()
+    }
+
+    /// Replaces a user's email in the history and color preferences.
+    ///
+    /// With no replacement, the email and color preference are removed
+    /// entirely. Text is never altered. Returns the number of edits changed.
+    pub fn replace_author(&self, email: &str, replacement: Option<&str>) -> usize {
+        let mut state = self.state.write();
+        if let Some(hue) = state.user_colors.remove(email) {
+            if let Some(replacement) = replacement {
+                state.user_colors.insert(replacement.to_owned(), hue);
+            }
+        }
+        let mut count = 0;
+        for op in &mut state.operations {
+            if op.email.as_deref() == Some(email) {
+                op.email = replacement.map(String::from);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Returns the number of edits rejected so far, by category.
+    pub fn rejections(&self) -> RejectionStats {
+        self.rejections.snapshot()
     }
 
     /// Kill this object immediately, dropping all current connections.
@@ -218,15 +1345,34 @@ impl Rustpad {
         self.notify.notify_waiters();
     }
 
+    /// Waits until enough has changed since the document was last persisted
+    /// that it should be persisted before its next scheduled time.
+    pub async fn persist_due(&self) {
+        self.persist.notified().await;
+    }
+
+    /// Resets the changes counted since the document was last persisted,
+    /// returning the number of characters they changed.
+    pub fn take_unsaved(&self) -> u64 {
+        self.unsaved_edits.store(0, Ordering::Relaxed);
+        self.unsaved_chars.swap(0, Ordering::Relaxed)
+    }
+
     /// Returns if this Rustpad object has been killed.
     pub fn killed(&self) -> bool {
         self.killed.load(Ordering::Relaxed)
     }
 
-    async fn handle_connection(&self, id: u64, mut socket: WebSocket, cf_email: Option<String>) -> Result<()> {
+    async fn handle_connection(&self, mut conn: Connection) -> Result<()> {
         let mut update_rx = self.update.subscribe();
+        let mut checksum_interval =
+            time::interval_at(Instant::now() + CHECKSUM_INTERVAL, CHECKSUM_INTERVAL);
+        checksum_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut stats_interval =
+            time::interval_at(Instant::now() + STATS_INTERVAL, STATS_INTERVAL);
+        stats_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        let mut revision: usize = self.send_initial(id, &mut socket, cf_email.clone()).await?;
+        self.send_initial(&mut conn).await?;
 
         loop {
             // In order to avoid the "lost wakeup" problem, we first request a
@@ -236,20 +1382,43 @@ impl Rustpad {
             if self.killed() {
                 break;
             }
-            if self.revision() > revision {
-                revision = self.send_history(revision, &mut socket).await?
+            self.update_mode(&mut conn);
+            if self.revision() > conn.revision {
+                if !conn.slow {
+                    self.send_history(&mut conn).await?;
+                } else if conn.snapshot_at.elapsed() >= SNAPSHOT_INTERVAL {
+                    self.resync(&mut conn).await?;
+                    conn.snapshot_at = Instant::now();
+                }
             }
 
             tokio::select! {
                 _ = notified => {}
                 update = update_rx.recv() => {
-                    socket.send(update?.into()).await?;
+                    match update? {
+                        // Unarchiving doesn't make a read-only connection writable.
+                        ServerMsg::ReadOnly(false) if conn.config.read_only => {}
+                        update => conn.send(update).await?,
+                    }
+                }
+                _ = checksum_interval.tick() => {
+                    self.send_checksum(&mut conn).await?;
                 }
-                result = socket.next() => {
+                _ = stats_interval.tick() => {
+                    self.send_stats(&mut conn).await?;
+                }
+                result = conn.socket.next() => {
                     match result {
                         None => break,
                         Some(message) => {
-                            self.handle_message(id, message?, cf_email.clone()).await?;
+                            let message = message?;
+                            let size = message.as_bytes().len();
+                            conn.metrics.messages_in.fetch_add(1, Ordering::Relaxed);
+                            conn.metrics.bytes_in.fetch_add(size as u64, Ordering::Relaxed);
+                            if size > conn.config.max_message_size {
+                                return self.reject_oversized(&mut conn, size).await;
+                            }
+                            self.handle_message(&mut conn, message).await?;
                         }
                     }
                 }
@@ -259,85 +1428,237 @@ impl Rustpad {
         Ok(())
     }
 
-    async fn send_initial(&self, id: u64, socket: &mut WebSocket, cf_email: Option<String>) -> Result<usize> {
-        socket.send(ServerMsg::Identity(id).into()).await?;
-        socket.send(ServerMsg::AuthenticatedEmail(cf_email).into()).await?;
-        let mut messages = Vec::new();
-        let revision = {
-            let state = self.state.read();
-            if !state.operations.is_empty() {
-                messages.push(ServerMsg::History {
-                    start: 0,
-                    operations: state.operations.clone(),
-                });
-            }
-            if let Some(language) = &state.language {
-                messages.push(ServerMsg::Language(language.clone()));
-            }
-            for (&id, info) in &state.users {
-                messages.push(ServerMsg::UserInfo {
-                    id,
-                    info: Some(info.clone()),
-                });
-            }
-            for (&id, data) in &state.cursors {
-                messages.push(ServerMsg::UserCursor {
-                    id,
-                    data: data.clone(),
-                });
-            }
-            // Send known user color preferences
-            for (email, &hue) in &state.user_colors {
-                messages.push(ServerMsg::UserColor {
-                    email: email.clone(),
-                    hue,
-                });
+    async fn send_initial(&self, conn: &mut Connection) -> Result<()> {
+        conn.signing_key = conn.email.as_ref().map(|_| conn.config.signer.issue());
+        let key = conn.signing_key.as_ref().map(SessionKey::bytes);
+        let (messages, revision) =
+            self.initial_messages(conn.id, conn.email.clone(), key, &conn.config);
+        for msg in messages {
+            conn.send(msg).await?;
+        }
+        conn.revision = revision;
+        Ok(())
+    }
+
+    /// Returns the messages that start a connection, in order, and the
+    /// revision they bring the client to, which becomes its base revision.
+    fn initial_messages(
+        &self,
+        id: u64,
+        email: Option<String>,
+        key: Option<&[u8; 32]>,
+        config: &SocketConfig,
+    ) -> (Vec<ServerMsg>, usize) {
+        let mut messages = vec![ServerMsg::Identity(id)];
+        if config.region.is_some() || !config.alternates.is_empty() {
+            messages.push(ServerMsg::ServerInfo {
+                region: config.region.clone(),
+                alternates: config.alternates.clone(),
+            });
+        }
+        messages.push(ServerMsg::AuthenticatedEmail(email));
+        if let Some(key) = key {
+            messages.push(ServerMsg::SigningKey(hex::encode(key)));
+        }
+        let mut state = self.state.write();
+        let revision = state.revision();
+        messages.extend(state.catch_up());
+        if let Some(language) = &state.language {
+            messages.push(ServerMsg::Language(language.clone()));
+        }
+        if self.read_only() || config.read_only {
+            messages.push(ServerMsg::ReadOnly(true));
+        }
+        if state.settings != DocumentSettings::default() {
+            messages.push(ServerMsg::Settings(state.settings.clone()));
+        }
+        for (&id, info) in &state.users {
+            messages.push(ServerMsg::UserInfo {
+                id,
+                info: Some(info.clone()),
+            });
+        }
+        for (&id, data) in &state.cursors {
+            messages.push(ServerMsg::UserCursor {
+                id,
+                data: data.clone(),
+            });
+        }
+        // Send known user color preferences
+        for (email, &hue) in &state.user_colors {
+            messages.push(ServerMsg::UserColor {
+                email: email.clone(),
+                hue,
+            });
+        }
+        state.bases.insert(id, revision);
+        (messages, revision)
+    }
+
+    /// Switches a client to snapshots while its sends are slow, and back to
+    /// operations once they are fast again, updating its presence to match.
+    fn update_mode(&self, conn: &mut Connection) {
+        let limit = if conn.slow { FAST_LATENCY } else { SLOW_LATENCY };
+        let slow = conn.latency > limit;
+        if slow == conn.slow {
+            return;
+        }
+        info!("slow mode: id = {}, slow = {}, latency = {:?}", conn.id, slow, conn.latency);
+        conn.slow = slow;
+        let mut state = self.state.write();
+        if let Some(info) = state.users.get_mut(&conn.id) {
+            info.slow = slow;
+            let msg = ServerMsg::UserInfo {
+                id: conn.id,
+                info: Some(info.clone()),
+            };
+            self.update.send(msg).ok();
+        }
+    }
+
+    async fn send_history(&self, conn: &mut Connection) -> Result<()> {
+        let start = conn.revision;
+        let (messages, revision) = {
+            let mut state = self.state.write();
+            if start < state.trimmed {
+                // The operations the client is missing were trimmed, so
+                // start it over from the base text.
+                let revision = state.revision();
+                state.bases.insert(conn.id, revision);
+                (state.catch_up(), revision)
+            } else if start < state.revision() {
+                let operations = state.operations[start - state.trimmed..].to_owned();
+                let revision = start + operations.len();
+                (vec![ServerMsg::History { start, operations }], revision)
+            } else {
+                (Vec::new(), start)
             }
-            state.operations.len()
         };
         for msg in messages {
-            socket.send(msg.into()).await?;
+            conn.send(msg).await?;
         }
-        Ok(revision)
+        conn.revision = revision;
+        Ok(())
     }
 
-    async fn send_history(&self, start: usize, socket: &mut WebSocket) -> Result<usize> {
-        let operations = {
-            let state = self.state.read();
-            let len = state.operations.len();
-            if start < len {
-                state.operations[start..].to_owned()
-            } else {
-                Vec::new()
+    /// Sends the checksum of the text at the client's revision, if it is current.
+    async fn send_checksum(&self, conn: &mut Connection) -> Result<()> {
+        let hash = {
+            let mut state = self.state.write();
+            if state.revision() != conn.revision {
+                // History is still in flight; try again on the next tick.
+                return Ok(());
             }
+            let hash = checksum(&state.text);
+            if !state.checksums.contains(&(conn.revision, hash)) {
+                if state.checksums.len() >= MAX_CHECKSUMS {
+                    state.checksums.pop_front();
+                }
+                state.checksums.push_back((conn.revision, hash));
+            }
+            hash
+        };
+        let msg = ServerMsg::Checksum {
+            revision: conn.revision,
+            hash,
         };
-        let num_ops = operations.len();
-        if num_ops > 0 {
-            let msg = ServerMsg::History { start, operations };
-            socket.send(msg.into()).await?;
+        conn.send(msg).await?;
+        Ok(())
+    }
+
+    /// Sends the document statistics, if they changed since last sent.
+    async fn send_stats(&self, conn: &mut Connection) -> Result<()> {
+        let stats = self.state.read().stats();
+        if conn.stats != Some(stats) {
+            conn.stats = Some(stats);
+            conn.send(ServerMsg::DocStats(stats)).await?;
         }
-        Ok(start + num_ops)
+        Ok(())
+    }
+
+    /// Replaces the client's document with the latest text and revision.
+    async fn resync(&self, conn: &mut Connection) -> Result<()> {
+        let (text, revision) = {
+            let mut state = self.state.write();
+            let revision = state.revision();
+            state.bases.insert(conn.id, revision);
+            (state.text.clone(), revision)
+        };
+        info!("resync: id = {}, revision = {}", conn.id, revision);
+        conn.send(ServerMsg::Resync { text, revision }).await?;
+        conn.revision = revision;
+        Ok(())
     }
 
-    async fn handle_message(&self, id: u64, message: Message, cf_email: Option<String>) -> Result<()> {
+    /// Tells the client that its message was too large, ending the connection.
+    async fn reject_oversized(&self, conn: &mut Connection, size: usize) -> Result<()> {
+        let limit = conn.config.max_message_size;
+        conn.metrics.oversized.fetch_add(1, Ordering::Relaxed);
+        let notice = oversized_notice(limit);
+        conn.send(ServerMsg::Error(notice)).await.ok();
+        Err(MessageTooLarge { size, limit }.into())
+    }
+
+    async fn handle_message(&self, conn: &mut Connection, message: Message) -> Result<()> {
+        let id = conn.id;
         let msg: ClientMsg = match message.to_str() {
-            Ok(text) => serde_json::from_str(text).context("failed to deserialize message")?,
+            Ok(text) => match serde_json::from_str(text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.rejections.record(RejectReason::ParseError);
+                    conn.send(ServerMsg::Error(invalid_message_notice()))
+                        .await
+                        .ok();
+                    return Err(e).context("failed to deserialize message");
+                }
+            },
             Err(()) => return Ok(()), // Ignore non-text messages
         };
         match msg {
             ClientMsg::Edit {
                 revision,
                 operation,
+                signature,
+                source,
             } => {
-                self.apply_edit(id, revision, operation, cf_email)
-                    .context("invalid edit operation")?;
-                self.notify.notify_waiters();
+                let source = source.unwrap_or_else(|| default_source(&operation));
+                let result = if conn.config.read_only {
+                    Err(RejectedEdit::new(
+                        RejectReason::ReadOnly,
+                        "connection is read-only",
+                    ))
+                } else {
+                    verify_signature(conn, revision, &operation, signature).and_then(|signature| {
+                        let email = conn.email.clone();
+                        self.apply_edit(id, revision, operation, email, signature, source)
+                    })
+                };
+                match result {
+                    Ok(()) => {
+                        conn.metrics.edits.fetch_add(1, Ordering::Relaxed);
+                        self.notify.notify_waiters();
+                    }
+                    Err(rejected) => {
+                        self.rejections.record(rejected.reason);
+                        warn!("rejected edit: id = {}, {}", id, rejected);
+                        if !rejected.reason.resyncable() {
+                            conn.send(ServerMsg::Error(rejected.notice())).await.ok();
+                            return Err(rejected).context("invalid edit operation");
+                        }
+                        self.resync(conn).await?;
+                    }
+                }
             }
             ClientMsg::SetLanguage(language) => {
-                self.state.write().language = Some(language.clone());
-                self.update.send(ServerMsg::Language(language)).ok();
+                if !self.read_only()
+                    && !conn.config.read_only
+                    && !self.state.read().settings.language_locked
+                {
+                    self.set_language(language);
+                }
             }
-            ClientMsg::ClientInfo(info) => {
+            ClientMsg::ClientInfo(mut info) => {
+                info.slow = conn.slow;
                 self.state.write().users.insert(id, info.clone());
                 let msg = ServerMsg::UserInfo {
                     id,
@@ -352,22 +1673,51 @@ impl Rustpad {
             }
             ClientMsg::SetColor(hue) => {
                 // Only authenticated users can set persistent colors
-                if let Some(ref email) = cf_email {
+                if let Some(ref email) = conn.email {
                     self.state.write().user_colors.insert(email.clone(), hue);
                     let msg = ServerMsg::UserColor {
                         email: email.clone(),
                         hue,
                     };
                     self.update.send(msg).ok();
-                    // Persist to database
+                    // Persist to database from the background job queue
                     if let Some(ref db) = self.database {
-                        let db = db.clone();
-                        let email = email.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = db.save_user_color(&email, hue).await {
-                                warn!("Failed to save user color: {}", e);
-                            }
-                        });
+                        let payload = serde_json::to_string(&UserColorJob {
+                            email: email.clone(),
+                            hue,
+                        })?;
+                        if let Err(e) = db.enqueue_job(SAVE_USER_COLOR, &payload).await {
+                            warn!("Failed to queue user color save: {}", e);
+                        }
+                    }
+                }
+            }
+            // Passwords are only checked before the client is attached.
+            ClientMsg::Password(_) => {}
+            ClientMsg::Checksum { revision, hash } => {
+                let expected = {
+                    let state = self.state.read();
+                    state
+                        .checksums
+                        .iter()
+                        .find(|&&(r, _)| r == revision)
+                        .map(|&(_, h)| h)
+                };
+                // Checksums for revisions we no longer remember are ignored.
+                if let Some(expected) = expected {
+                    if expected != hash {
+                        warn!(
+                            "divergence: id = {}, email = {:?}, revision = {}, \
+                             expected checksum = {:08x}, client checksum = {:08x}, \
+                             current revision = {}",
+                            id,
+                            conn.email,
+                            revision,
+                            expected,
+                            hash,
+                            self.revision()
+                        );
+                        self.resync(conn).await?;
                     }
                 }
             }
@@ -375,30 +1725,80 @@ impl Rustpad {
         Ok(())
     }
 
-    fn apply_edit(&self, id: u64, revision: usize, mut operation: OperationSeq, email: Option<String>) -> Result<()> {
+    fn apply_edit(
+        &self,
+        id: u64,
+        revision: usize,
+        mut operation: OperationSeq,
+        email: Option<String>,
+        signature: Option<EditSignature>,
+        source: EditSource,
+    ) -> Result<(), RejectedEdit> {
         info!(
-            "edit: id = {}, revision = {}, base_len = {}, target_len = {}, email = {:?}",
+            "edit: id = {}, revision = {}, base_len = {}, target_len = {}, email = {:?}, \
+             source = {:?}",
             id,
             revision,
             operation.base_len(),
             operation.target_len(),
-            email
+            email,
+            source
         );
+        if self.read_only() {
+            return Err(RejectedEdit::new(RejectReason::ReadOnly, "document is archived"));
+        }
         let state = self.state.upgradable_read();
-        let len = state.operations.len();
+        let len = state.revision();
         if revision > len {
-            bail!("got revision {}, but current is {}", revision, len);
+            return Err(RejectedEdit::new(
+                RejectReason::InvalidRevision,
+                format!("got revision {}, but current is {}", revision, len),
+            ));
         }
-        for history_op in &state.operations[revision..] {
-            operation = operation.transform(&history_op.operation)?.0;
+        if revision < state.trimmed {
+            return Err(RejectedEdit::new(
+                RejectReason::StaleRevision,
+                format!(
+                    "revision {} is older than retained history at {}",
+                    revision, state.trimmed
+                ),
+            ));
+        }
+        for history_op in &state.operations[revision - state.trimmed..] {
+            operation = match operation.transform(&history_op.operation) {
+                Ok((transformed, _)) => transformed,
+                Err(e) => {
+                    return Err(RejectedEdit::new(
+                        RejectReason::OutOfSync,
+                        format!("transform failed: {}", e),
+                    ))
+                }
+            };
         }
-        if operation.target_len() > 256 * 1024 {
-            bail!(
-                "target length {} is greater than 256 KiB maximum",
-                operation.target_len()
-            );
+        // Edits that don't grow the text are allowed even above the limit, so
+        // that documents can be shrunk after lowering their maximum size.
+        let max_size = state.settings.max_size(MAX_DOCUMENT_SIZE);
+        if operation.target_len() > max_size && operation.target_len() > operation.base_len() {
+            return Err(RejectedEdit::new(
+                RejectReason::TooLarge,
+                format!(
+                    "target length {} is greater than {} maximum",
+                    operation.target_len(),
+                    max_size
+                ),
+            ));
         }
-        let new_text = operation.apply(&state.text)?;
+        let new_text = match operation.apply(&state.text) {
+            Ok(text) => text,
+            Err(e) => {
+                return Err(RejectedEdit::new(
+                    RejectReason::OutOfSync,
+                    format!("apply failed: {}", e),
+                ))
+            }
+        };
+        let delta = word_count_delta(&state.text, &operation);
+        let words = state.words.saturating_add_signed(delta);
         let mut state = RwLockUpgradableReadGuard::upgrade(state);
         for (_, data) in state.cursors.iter_mut() {
             for cursor in data.cursors.iter_mut() {
@@ -409,8 +1809,90 @@ impl Rustpad {
                 *end = transform_index(&operation, *end);
             }
         }
-        state.operations.push(UserOperation { id, operation, email });
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let changed = changed_chars(&operation);
+        // A signature covers the edit as the client sent it, so it is only
+        // kept if the edit is stored unchanged, at the revision it was signed
+        // for, where it can be checked again later.
+        let signature = signature.filter(|_| revision == len);
+        state.chars = operation.target_len();
+        state.operations.push(UserOperation {
+            id,
+            operation,
+            email,
+            signature: signature.as_ref().map(|signature| signature.tag.clone()),
+            key_id: signature.map(|signature| signature.key_id),
+            created_at: Some(created_at),
+            source: Some(source),
+        });
         state.text = new_text;
+        state.words = words;
+        state.bases.insert(id, revision);
+        drop(state);
+
+        let edits = self.unsaved_edits.fetch_add(1, Ordering::Relaxed) + 1;
+        let chars = self.unsaved_chars.fetch_add(changed, Ordering::Relaxed) + changed;
+        if edits >= PERSIST_EDITS || chars >= PERSIST_CHARS {
+            self.persist.notify_one();
+        }
         Ok(())
     }
 }
+
+/// Guesses how an edit without a source hint was made from the number of
+/// characters it inserts.
+fn default_source(operation: &OperationSeq) -> EditSource {
+    if inserted_chars(operation) >= PASTE_CHARS {
+        EditSource::Paste
+    } else {
+        EditSource::Typing
+    }
+}
+
+/// Checks an edit's signature against the connection's signing key.
+///
+/// Unsigned edits are accepted as-is, while a signature that does not verify,
+/// or that was sent without a key having been issued, rejects the edit.
+fn verify_signature(
+    conn: &Connection,
+    revision: usize,
+    operation: &OperationSeq,
+    signature: Option<String>,
+) -> Result<Option<EditSignature>, RejectedEdit> {
+    let signature = match signature {
+        Some(signature) => signature,
+        None => return Ok(None),
+    };
+    let key = conn.signing_key.as_ref().ok_or_else(|| {
+        RejectedEdit::new(RejectReason::InvalidSignature, "no signing key was issued")
+    })?;
+    let tag = hex::decode(&signature).map_err(|e| {
+        RejectedEdit::new(RejectReason::InvalidSignature, format!("bad encoding: {}", e))
+    })?;
+    if !key.verify(revision, operation, &tag) {
+        return Err(RejectedEdit::new(
+            RejectReason::InvalidSignature,
+            "signature does not match",
+        ));
+    }
+    Ok(Some(EditSignature {
+        key_id: key.id.clone(),
+        tag: signature,
+    }))
+}
+
+/// Rebuilds the text of a document from its stored history, or returns `None`
+/// if operations are missing or do not apply.
+fn replay(history: &[StoredOperation]) -> Option<String> {
+    let mut text = String::new();
+    for (i, op) in history.iter().enumerate() {
+        if op.revision != i {
+            return None;
+        }
+        text = op.operation.apply(&text).ok()?;
+    }
+    Some(text)
+}
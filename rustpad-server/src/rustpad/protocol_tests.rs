@@ -0,0 +1,343 @@
+//! Canonical test vectors for the WebSocket protocol.
+//!
+//! Alternative client implementations can check their serialization and
+//! operational transform against these vectors. They are built from the
+//! message types that the server actually sends and receives, and edits go
+//! through the same code that applies them for connected clients, so the
+//! vectors follow any change to the wire format.
+
+use operational_transform::OperationSeq;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::{
+    invalid_message_notice, oversized_notice, ClientMsg, CursorData, RejectReason, RejectedEdit,
+    Rustpad, ServerMsg, SocketConfig, UserInfo, PROTOCOL_VERSION,
+};
+use crate::database::{DocumentSettings, EditSource};
+
+/// Test vectors for the WebSocket protocol.
+#[derive(Serialize, Debug)]
+pub struct ProtocolVectors {
+    /// Version of the protocol that the vectors describe.
+    pub protocol_version: u32,
+    /// Messages that the server sends to a client as it connects.
+    pub handshakes: Vec<Exchange>,
+    /// Results of transforming pairs of concurrent operations.
+    pub transforms: Vec<Transform>,
+    /// Client messages that the server rejects, and how it responds.
+    pub errors: Vec<Exchange>,
+}
+
+/// Messages that a client sends and the messages the server sends back.
+#[derive(Serialize, Debug)]
+pub struct Exchange {
+    /// Short description of the case.
+    pub name: &'static str,
+    /// Messages sent by the client, as JSON text.
+    pub client: Vec<String>,
+    /// Messages sent back by the server, in order.
+    pub server: Vec<Value>,
+}
+
+/// Two concurrent operations on a text, and the operations that apply each
+/// one after the other.
+#[derive(Serialize, Debug)]
+pub struct Transform {
+    /// Short description of the case.
+    pub name: &'static str,
+    /// Text that both operations apply to.
+    pub text: &'static str,
+    /// Operation made by one client.
+    pub a: OperationSeq,
+    /// Operation made concurrently by another client.
+    pub b: OperationSeq,
+    /// `a` transformed to apply after `b`.
+    pub a_prime: OperationSeq,
+    /// `b` transformed to apply after `a`.
+    pub b_prime: OperationSeq,
+    /// Text after applying both operations, in either order.
+    pub result: String,
+}
+
+/// Builds the protocol test vectors.
+pub fn protocol_vectors() -> ProtocolVectors {
+    ProtocolVectors {
+        protocol_version: PROTOCOL_VERSION,
+        handshakes: handshakes(),
+        transforms: transforms(),
+        errors: errors(),
+    }
+}
+
+fn handshakes() -> Vec<Exchange> {
+    let config = SocketConfig::default();
+    let mut handshakes = Vec::new();
+
+    let rustpad = Rustpad::default();
+    let (messages, _) = rustpad.initial_messages(0, None, None, &config);
+    handshakes.push(exchange("empty document", Vec::new(), messages));
+
+    let (messages, _) =
+        rustpad.initial_messages(1, Some("alice@example.com".into()), Some(&[0; 32]), &config);
+    handshakes.push(exchange("authenticated user", Vec::new(), messages));
+
+    let regional = SocketConfig {
+        region: Some("eu-west".into()),
+        alternates: vec!["wss://us-east.example.com".into()],
+        ..config.clone()
+    };
+    let (messages, _) = rustpad.initial_messages(2, None, None, &regional);
+    handshakes.push(exchange("regional server", Vec::new(), messages));
+
+    let rustpad = Rustpad::default();
+    apply(&rustpad, 0, 0, insert_at("", 0, "Hello"));
+    apply(&rustpad, 0, 1, insert_at("Hello", 5, ", world"));
+    rustpad.set_language("markdown".into());
+    {
+        let mut state = rustpad.state.write();
+        state.settings = DocumentSettings {
+            max_size: Some(1024),
+            ..Default::default()
+        };
+        let info = UserInfo {
+            name: "Alice".into(),
+            hue: 120,
+            slow: false,
+        };
+        state.users.insert(0, info);
+        let cursor = CursorData {
+            cursors: vec![5],
+            selections: vec![(0, 5)],
+        };
+        state.cursors.insert(0, cursor);
+        state.user_colors.insert("alice@example.com".into(), 120);
+    }
+    let (messages, _) = rustpad.initial_messages(1, None, None, &config);
+    handshakes.push(exchange(
+        "document with history and users",
+        Vec::new(),
+        messages,
+    ));
+
+    rustpad.set_read_only(true);
+    let (messages, _) = rustpad.initial_messages(2, None, None, &config);
+    handshakes.push(exchange("read-only document", Vec::new(), messages));
+
+    let rustpad = Rustpad::default();
+    apply(&rustpad, 0, 0, insert_at("", 0, "one"));
+    apply(&rustpad, 0, 1, insert_at("one", 3, " two"));
+    apply(&rustpad, 0, 2, insert_at("one two", 7, " three"));
+    {
+        // Drop the first two operations, as trimming the history does.
+        let mut state = rustpad.state.write();
+        let mut base = std::mem::take(&mut state.base);
+        for op in state.operations.drain(..2) {
+            base = op.operation.apply(&base).expect("history should apply");
+        }
+        state.base = base;
+        state.trimmed = 2;
+    }
+    let (messages, _) = rustpad.initial_messages(1, None, None, &config);
+    handshakes.push(exchange(
+        "document with trimmed history",
+        Vec::new(),
+        messages,
+    ));
+
+    let rustpad = Rustpad::default();
+    let operation = insert_at("", 0, "Hi");
+    let client = vec![edit_message(0, operation.clone(), None)];
+    apply(&rustpad, 0, 0, operation);
+    let history = rustpad.state.read().catch_up();
+    handshakes.push(exchange("edit broadcast to all clients", client, history));
+
+    handshakes
+}
+
+fn transforms() -> Vec<Transform> {
+    vec![
+        transform(
+            "inserts at different positions",
+            "abc",
+            insert_at("abc", 0, "X"),
+            insert_at("abc", 3, "Y"),
+        ),
+        transform(
+            "inserts at the same position",
+            "abc",
+            insert_at("abc", 1, "X"),
+            insert_at("abc", 1, "Y"),
+        ),
+        transform(
+            "insert inside a deleted range",
+            "abcdef",
+            insert_at("abcdef", 3, "X"),
+            delete_range("abcdef", 1, 5),
+        ),
+        transform(
+            "overlapping deletes",
+            "abcdef",
+            delete_range("abcdef", 0, 4),
+            delete_range("abcdef", 2, 6),
+        ),
+        transform(
+            "characters outside the basic multilingual plane",
+            "a😀b",
+            insert_at("a😀b", 2, "🎉"),
+            delete_range("a😀b", 0, 2),
+        ),
+    ]
+}
+
+fn errors() -> Vec<Exchange> {
+    let mut errors = Vec::new();
+
+    errors.push(exchange(
+        "malformed message",
+        vec![r#"{"Edit":{"revision":0}}"#.into()],
+        vec![ServerMsg::Error(invalid_message_notice())],
+    ));
+
+    let limit = 64;
+    errors.push(exchange(
+        "message over a 64 byte size limit",
+        vec![format!(r#"{{"SetLanguage":"{}"}}"#, "x".repeat(limit))],
+        vec![ServerMsg::Error(oversized_notice(limit))],
+    ));
+
+    let rustpad = Rustpad::default();
+    apply(&rustpad, 0, 0, insert_at("", 0, "abc"));
+
+    let future = insert_at("abc", 0, "X");
+    errors.push(rejected(
+        &rustpad,
+        "edit based on a future revision",
+        5,
+        future,
+    ));
+
+    let mut mismatched = OperationSeq::default();
+    mismatched.retain(10);
+    mismatched.insert("X");
+    errors.push(rejected(
+        &rustpad,
+        "edit that does not fit the text",
+        1,
+        mismatched,
+    ));
+
+    let signature = Some("00".repeat(32));
+    let notice = RejectedEdit::new(RejectReason::InvalidSignature, "no signing key").notice();
+    errors.push(exchange(
+        "signed edit from an unauthenticated client",
+        vec![edit_message(1, insert_at("abc", 3, "d"), signature)],
+        vec![ServerMsg::Error(notice)],
+    ));
+
+    rustpad.set_read_only(true);
+    let archived = insert_at("abc", 3, "d");
+    errors.push(rejected(
+        &rustpad,
+        "edit to a read-only document",
+        1,
+        archived,
+    ));
+
+    errors
+}
+
+/// Sends an edit to a document, returning the server's response to a client
+/// whose edit is rejected.
+fn rejected(
+    rustpad: &Rustpad,
+    name: &'static str,
+    revision: usize,
+    operation: OperationSeq,
+) -> Exchange {
+    let client = vec![edit_message(revision, operation.clone(), None)];
+    let rejected = rustpad
+        .apply_edit(1, revision, operation, None, None, EditSource::Typing)
+        .expect_err("edit should be rejected");
+    let response = if rejected.reason.resyncable() {
+        let state = rustpad.state.read();
+        ServerMsg::Resync {
+            text: state.text.clone(),
+            revision: state.revision(),
+        }
+    } else {
+        ServerMsg::Error(rejected.notice())
+    };
+    exchange(name, client, vec![response])
+}
+
+/// Serializes an edit as a client sends it.
+fn edit_message(revision: usize, operation: OperationSeq, signature: Option<String>) -> String {
+    let msg = ClientMsg::Edit {
+        revision,
+        operation,
+        signature,
+        source: None,
+    };
+    serde_json::to_string(&msg).expect("failed serialize")
+}
+
+fn exchange(name: &'static str, client: Vec<String>, server: Vec<ServerMsg>) -> Exchange {
+    let server = server
+        .iter()
+        .map(|msg| serde_json::to_value(msg).expect("failed serialize"))
+        .collect();
+    Exchange {
+        name,
+        client,
+        server,
+    }
+}
+
+fn transform(
+    name: &'static str,
+    text: &'static str,
+    a: OperationSeq,
+    b: OperationSeq,
+) -> Transform {
+    let (a_prime, b_prime) = a.transform(&b).expect("operations should be concurrent");
+    let result = a
+        .compose(&b_prime)
+        .and_then(|op| op.apply(text))
+        .expect("transformed operations should apply");
+    Transform {
+        name,
+        text,
+        a,
+        b,
+        a_prime,
+        b_prime,
+        result,
+    }
+}
+
+fn apply(rustpad: &Rustpad, id: u64, revision: usize, operation: OperationSeq) {
+    rustpad
+        .apply_edit(id, revision, operation, None, None, EditSource::Typing)
+        .expect("edit should apply");
+}
+
+/// Returns an operation inserting text at a character offset.
+fn insert_at(text: &str, offset: usize, inserted: &str) -> OperationSeq {
+    let len = text.chars().count();
+    let mut operation = OperationSeq::default();
+    operation.retain(offset as u64);
+    operation.insert(inserted);
+    operation.retain((len - offset) as u64);
+    operation
+}
+
+/// Returns an operation deleting the characters between two offsets.
+fn delete_range(text: &str, start: usize, end: usize) -> OperationSeq {
+    let len = text.chars().count();
+    let mut operation = OperationSeq::default();
+    operation.retain(start as u64);
+    operation.delete((end - start) as u64);
+    operation.retain((len - end) as u64);
+    operation
+}
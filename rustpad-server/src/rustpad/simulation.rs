@@ -0,0 +1,224 @@
+//! Scripted sessions of concurrent clients, for regression testing.
+//!
+//! Embedders that extend the server can replay a script of edits from
+//! several clients against a document and check that every client ends up
+//! with the same text as the server. Clients connect over in-memory
+//! WebSockets and speak the real protocol, so edits go through the same
+//! transforms and broadcasts as in production.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use operational_transform::OperationSeq;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use warp::test::WsClient;
+use warp::ws::Ws;
+use warp::Filter;
+
+use super::{ClientAgent, ClientMsg, Rustpad, ServerMsg, SocketConfig, SocketMetrics};
+use crate::DEFAULT_MAX_MESSAGE_SIZE;
+
+/// Longest wait for a message from the server before a simulation fails.
+const RECV_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Edits made by several clients to one document.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Script {
+    /// Number of clients connected to the document.
+    pub clients: usize,
+    /// Text of the document before the first round, inserted by the first
+    /// client.
+    #[serde(default)]
+    pub text: String,
+    /// Rounds of edits. The edits of a round are all sent before any client
+    /// hears of the others, so they are concurrent, and every client catches
+    /// up before the next round starts.
+    pub rounds: Vec<Vec<ScriptedEdit>>,
+}
+
+/// An edit made by one client of a script.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScriptedEdit {
+    /// Index of the client making the edit.
+    pub client: usize,
+    /// Change to the text as the client last saw it.
+    pub change: Change,
+}
+
+/// A change to a text. Character offsets past the end of the text are
+/// clamped to its length, so that any script can be replayed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Change {
+    /// Inserts text at an offset.
+    Insert { offset: usize, text: String },
+    /// Deletes a number of characters from an offset.
+    Delete { offset: usize, count: usize },
+}
+
+impl Change {
+    /// Returns the operation making this change to a text.
+    fn operation(&self, text: &str) -> OperationSeq {
+        let len = text.chars().count();
+        let mut operation = OperationSeq::default();
+        match self {
+            Change::Insert { offset, text } => {
+                let offset = (*offset).min(len);
+                operation.retain(offset as u64);
+                operation.insert(text);
+                operation.retain((len - offset) as u64);
+            }
+            Change::Delete { offset, count } => {
+                let offset = (*offset).min(len);
+                let count = (*count).min(len - offset);
+                operation.retain(offset as u64);
+                operation.delete(count as u64);
+                operation.retain((len - offset - count) as u64);
+            }
+        }
+        operation
+    }
+}
+
+/// Outcome of a simulated session in which all clients converged.
+#[derive(Clone, Debug, Serialize)]
+pub struct SimulationReport {
+    /// Final text of the document.
+    pub text: String,
+    /// Final revision of the document.
+    pub revision: usize,
+}
+
+/// A simulated client, which knows the text at the last revision it heard of.
+struct Client {
+    socket: WsClient,
+    text: String,
+    revision: usize,
+}
+
+impl Client {
+    /// Sends a change to the text, returning whether it was an edit at all.
+    async fn send(&mut self, change: &Change) -> Result<bool> {
+        let operation = change.operation(&self.text);
+        if operation.is_noop() {
+            return Ok(false);
+        }
+        let msg = ClientMsg::Edit {
+            revision: self.revision,
+            operation,
+            signature: None,
+        };
+        self.socket.send_text(serde_json::to_string(&msg)?).await;
+        Ok(true)
+    }
+
+    /// Receives messages until the client has heard of a revision.
+    async fn catch_up(&mut self, revision: usize) -> Result<()> {
+        while self.revision < revision {
+            let msg = time::timeout(RECV_TIMEOUT, self.socket.recv())
+                .await
+                .context("timed out waiting for the server")??;
+            let msg = msg.to_str().map_err(|_| anyhow!("non-string message"))?;
+            match serde_json::from_str(msg)? {
+                ServerMsg::History { start, operations } => {
+                    for (i, op) in operations.into_iter().enumerate() {
+                        if start + i < self.revision {
+                            continue;
+                        } else if start + i > self.revision {
+                            bail!("missed the operation at revision {}", self.revision);
+                        }
+                        self.text = op
+                            .operation
+                            .apply(&self.text)
+                            .map_err(|e| anyhow!("history does not apply: {:?}", e))?;
+                        self.revision += 1;
+                    }
+                }
+                ServerMsg::Resync { text, revision } => {
+                    self.text = text;
+                    self.revision = revision;
+                }
+                ServerMsg::Error(notice) => bail!("server sent error {:?}", notice.code),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replays a script against a new document, failing if any client's text
+/// differs from the server's once all edits have been applied.
+pub async fn simulate_session(script: &Script) -> Result<SimulationReport> {
+    if script.clients == 0 {
+        bail!("script has no clients");
+    }
+    let rustpad = Arc::new(Rustpad::default());
+    let metrics = Arc::new(SocketMetrics::default());
+    let config = Arc::new(SocketConfig {
+        max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        ..Default::default()
+    });
+    let filter = {
+        let rustpad = Arc::clone(&rustpad);
+        warp::ws().map(move |ws: Ws| {
+            let rustpad = Arc::clone(&rustpad);
+            let metrics = Arc::clone(&metrics);
+            let config = Arc::clone(&config);
+            ws.on_upgrade(move |socket| async move {
+                let agent = ClientAgent::default();
+                rustpad
+                    .on_connection(socket, None, agent, metrics, config)
+                    .await;
+            })
+        })
+    };
+
+    let mut clients = Vec::with_capacity(script.clients);
+    for _ in 0..script.clients {
+        clients.push(Client {
+            socket: warp::test::ws().handshake(filter.clone()).await?,
+            text: String::new(),
+            revision: 0,
+        });
+    }
+
+    let initial = Change::Insert {
+        offset: 0,
+        text: script.text.clone(),
+    };
+    let mut revision = clients[0].send(&initial).await? as usize;
+    for (round, edits) in script.rounds.iter().enumerate() {
+        for client in &mut clients {
+            client.catch_up(revision).await?;
+        }
+        for edit in edits {
+            let client = clients
+                .get_mut(edit.client)
+                .with_context(|| format!("round {} has unknown client {}", round, edit.client))?;
+            revision += client.send(&edit.change).await? as usize;
+        }
+    }
+    for client in &mut clients {
+        client.catch_up(revision).await?;
+    }
+
+    let snapshot = rustpad.text_snapshot();
+    rustpad.kill();
+    if snapshot.revision != revision {
+        bail!(
+            "server is at revision {} rather than {}",
+            snapshot.revision,
+            revision
+        );
+    }
+    for (i, client) in clients.iter().enumerate() {
+        if client.text != snapshot.text {
+            bail!("client {} diverged from the server", i);
+        }
+    }
+    Ok(SimulationReport {
+        text: snapshot.text,
+        revision,
+    })
+}
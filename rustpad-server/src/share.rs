@@ -0,0 +1,82 @@
+//! Signed links for sharing a document for a limited time.
+//!
+//! A share token names one document, a scope and an expiry time, followed by
+//! an HMAC of those fields, as `<claims>.<tag>` with both parts encoded in
+//! unpadded URL-safe base64. Tokens are not stored, so they can only be
+//! revoked by changing the secret, which revokes all of them.
+
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+/// What the holder of a share token may do with the document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShareScope {
+    /// Read the text and follow edits, without making any.
+    Read,
+    /// Edit the document like any other client.
+    Write,
+}
+
+/// The fields signed into a share token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareClaims {
+    /// ID of the shared document.
+    #[serde(rename = "doc")]
+    pub document: String,
+    /// What the token allows.
+    pub scope: ShareScope,
+    /// Unix timestamp after which the token is no longer accepted.
+    #[serde(rename = "exp")]
+    pub expires_at: i64,
+}
+
+/// Signs and verifies share tokens with a secret key.
+pub struct ShareSigner {
+    key: hmac::Key,
+}
+
+impl ShareSigner {
+    /// Creates a signer from a configured secret.
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    /// Creates a signer with a random secret, whose tokens stop being valid
+    /// when the server restarts.
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::new(&secret)
+    }
+
+    /// Returns a token for the given claims.
+    pub fn sign(&self, claims: &ShareClaims) -> String {
+        let payload = serde_json::to_vec(claims).expect("claims should serialize");
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let tag = hmac::sign(&self.key, payload.as_bytes());
+        format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(tag))
+    }
+
+    /// Returns the claims of a token with a valid signature that has not
+    /// expired.
+    pub fn verify(&self, token: &str) -> Option<ShareClaims> {
+        let (payload, tag) = token.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(&self.key, payload.as_bytes(), &tag).ok()?;
+        let claims: ShareClaims =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("SystemTime returned before UNIX_EPOCH")
+            .as_secs() as i64;
+        (claims.expires_at > now).then_some(claims)
+    }
+}
@@ -0,0 +1,112 @@
+//! Keys that authenticated users sign their edits with.
+//!
+//! Each connection is issued its own session key, derived from a server secret
+//! and a random key ID. Signed edits are stored with the ID of their key, so
+//! that their signatures can be checked again later by anyone holding the
+//! secret, without storing the keys themselves.
+
+use std::fmt;
+
+use operational_transform::OperationSeq;
+use rand::RngCore;
+use ring::hmac;
+
+/// Derives session keys for signing edits from a secret.
+pub struct EditSigner {
+    secret: hmac::Key,
+}
+
+impl EditSigner {
+    /// Creates a signer from a configured secret.
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    /// Creates a signer with a random secret, whose signatures can no longer
+    /// be checked once the server restarts.
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::new(&secret)
+    }
+
+    /// Issues a session key with a new random ID.
+    pub fn issue(&self) -> SessionKey {
+        let mut id = [0; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        self.session_key(&hex::encode(id))
+    }
+
+    /// Derives the session key with an ID.
+    pub fn session_key(&self, id: &str) -> SessionKey {
+        let tag = hmac::sign(&self.secret, format!("edit-signing:{}", id).as_bytes());
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(tag.as_ref());
+        SessionKey {
+            id: id.to_owned(),
+            bytes,
+            key: hmac::Key::new(hmac::HMAC_SHA256, &bytes),
+        }
+    }
+
+    /// Checks a stored hex-encoded signature of an edit, made with the
+    /// session key with an ID.
+    pub fn verify(
+        &self,
+        key_id: &str,
+        revision: usize,
+        operation: &OperationSeq,
+        signature: &str,
+    ) -> bool {
+        match hex::decode(signature) {
+            Ok(tag) => self.session_key(key_id).verify(revision, operation, &tag),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Default for EditSigner {
+    fn default() -> Self {
+        Self::random()
+    }
+}
+
+impl fmt::Debug for EditSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EditSigner").finish_non_exhaustive()
+    }
+}
+
+/// A key that a client signs its edits with during one connection.
+pub struct SessionKey {
+    /// ID of the key, stored with the edits signed with it.
+    pub id: String,
+    bytes: [u8; 32],
+    key: hmac::Key,
+}
+
+impl SessionKey {
+    /// Returns the raw key, as sent to the client.
+    pub fn bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    /// Checks a signature of `"{revision}:{operation}"`, with the operation
+    /// serialized as JSON.
+    pub fn verify(&self, revision: usize, operation: &OperationSeq, tag: &[u8]) -> bool {
+        let operation = serde_json::to_string(operation).expect("failed serialize");
+        let payload = format!("{}:{}", revision, operation);
+        hmac::verify(&self.key, payload.as_bytes(), tag).is_ok()
+    }
+}
+
+/// A signature of an edit, with the ID of the session key that made it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EditSignature {
+    /// ID of the session key.
+    pub key_id: String,
+    /// Hex-encoded HMAC-SHA256 tag.
+    pub tag: String,
+}
@@ -0,0 +1,149 @@
+//! Warm standby of another instance, ready to take over if it fails.
+//!
+//! A standby tails the live feed of its primary to learn which documents
+//! exist, and replicates each one by connecting to it like any client: the
+//! history and resync messages that the primary sends keep the standby's copy
+//! identical, revision for revision. Replicated documents are read-only until
+//! the standby is promoted, after which it stops following the primary and
+//! serves its copies as an ordinary server.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use futures::StreamExt;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Settings for running as a warm standby of a primary instance.
+#[derive(Clone, Debug)]
+pub struct StandbyConfig {
+    /// Base URL of the primary, such as `http://10.0.0.2:3030`.
+    pub primary: String,
+    /// Bearer token sent to the primary, if its API requires one.
+    pub token: Option<String>,
+}
+
+/// A WebSocket connection to the primary.
+pub type PrimarySocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A document loaded on the primary.
+#[derive(Deserialize)]
+struct ActiveDocument {
+    id: String,
+}
+
+/// An event from the primary's live feed.
+#[derive(Deserialize)]
+pub struct FeedEvent {
+    /// What happened, such as `created` or `deleted`.
+    pub kind: String,
+    /// Identifier of the document that the event concerns.
+    pub document_id: String,
+}
+
+/// State of a standby, shared by the tasks following its primary.
+pub struct Standby {
+    config: StandbyConfig,
+    client: reqwest::Client,
+    promoted: watch::Sender<bool>,
+    /// Documents being replicated from the primary.
+    followed: Mutex<HashSet<String>>,
+}
+
+impl Standby {
+    /// Creates a standby that has not been promoted yet.
+    pub fn new(config: StandbyConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            promoted: watch::channel(false).0,
+            followed: Default::default(),
+        }
+    }
+
+    /// Returns the base URL of the primary.
+    pub fn primary(&self) -> &str {
+        &self.config.primary
+    }
+
+    /// Returns whether the standby has been promoted to serve on its own.
+    pub fn promoted(&self) -> bool {
+        *self.promoted.borrow()
+    }
+
+    /// Promotes the standby, returning `false` if it already was.
+    pub fn promote(&self) -> bool {
+        !self.promoted.send_replace(true)
+    }
+
+    /// Waits until the standby is promoted.
+    pub async fn wait_promoted(&self) {
+        let mut promoted = self.promoted.subscribe();
+        promoted.wait_for(|&promoted| promoted).await.ok();
+    }
+
+    /// Starts following a document, returning `false` if it already is.
+    pub fn follow(&self, id: &str) -> bool {
+        self.followed.lock().insert(id.to_owned())
+    }
+
+    /// Stops following a document, so that it is replicated anew if it is
+    /// followed again.
+    pub fn unfollow(&self, id: &str) {
+        self.followed.lock().remove(id);
+    }
+
+    /// Returns the number of documents being replicated.
+    pub fn followed(&self) -> usize {
+        self.followed.lock().len()
+    }
+
+    /// Lists the IDs of the documents loaded on the primary.
+    pub async fn active_documents(&self) -> Result<Vec<String>> {
+        let mut request = self.client.get(self.url("/api/admin/active"));
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        let documents: Vec<ActiveDocument> =
+            request.send().await?.error_for_status()?.json().await?;
+        Ok(documents.into_iter().map(|document| document.id).collect())
+    }
+
+    /// Opens a WebSocket to a path of the primary's API.
+    pub async fn connect(&self, path: &str) -> Result<PrimarySocket> {
+        let url = match self.url(path) {
+            url if url.starts_with("http://") => url.replacen("http", "ws", 1),
+            url => bail!("primary URL {} is not a plain http:// address", url),
+        };
+        let mut request = url.into_client_request()?;
+        if let Some(token) = &self.config.token {
+            let value = format!("Bearer {}", token).parse()?;
+            request.headers_mut().insert("authorization", value);
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(socket)
+    }
+
+    /// Returns the URL of a path on the primary.
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.primary.trim_end_matches('/'), path)
+    }
+}
+
+/// Receives the next text message from the primary, or `None` once the
+/// connection closes.
+pub async fn recv_text(socket: &mut PrimarySocket) -> Option<Result<String>> {
+    while let Some(message) = socket.next().await {
+        match message {
+            Ok(Message::Text(text)) => return Some(Ok(text)),
+            Ok(Message::Close(_)) => return None,
+            Ok(_) => continue,
+            Err(e) => return Some(Err(e.into())),
+        }
+    }
+    None
+}
@@ -0,0 +1,83 @@
+//! Minimal server-rendered status page, for self-hosters without a metrics
+//! dashboard.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::export::escape;
+use crate::metrics::RecentError;
+use crate::timestamps::rfc3339;
+
+/// Figures shown on the status page.
+pub struct StatusPage {
+    /// Time since the server started.
+    pub uptime: Duration,
+    /// Number of documents currently loaded in memory.
+    pub documents_loaded: usize,
+    /// Number of documents persisted in the database.
+    pub documents_persisted: usize,
+    /// Number of open WebSocket connections.
+    pub connections: u64,
+    /// Number of loaded documents with edits not yet stored.
+    pub unsaved_documents: usize,
+    /// Total number of revisions not yet stored, across all documents.
+    pub unsaved_revisions: usize,
+    /// Most recent server errors, newest first.
+    pub recent_errors: Vec<RecentError>,
+}
+
+impl StatusPage {
+    /// Renders the page as a standalone HTML document.
+    pub fn render(&self) -> String {
+        let mut html = String::from(concat!(
+            "<!DOCTYPE html>\n",
+            "<html><head><meta charset=\"utf-8\"><title>Rustpad status</title>\n",
+            "<style>body{font-family:sans-serif;margin:2em}",
+            "td,th{padding:0.2em 1em;text-align:left}</style>\n",
+            "</head><body>\n<h1>Rustpad status</h1>\n<table>\n",
+        ));
+        let rows = [
+            ("Uptime", format_duration(self.uptime)),
+            ("Documents in memory", self.documents_loaded.to_string()),
+            ("Documents persisted", self.documents_persisted.to_string()),
+            ("Active connections", self.connections.to_string()),
+            ("Unsaved documents", self.unsaved_documents.to_string()),
+            ("Unsaved revisions", self.unsaved_revisions.to_string()),
+        ];
+        for (label, value) in rows {
+            writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", label, value).unwrap();
+        }
+        html.push_str("</table>\n<h2>Recent errors</h2>\n");
+        if self.recent_errors.is_empty() {
+            html.push_str("<p>None</p>\n");
+        } else {
+            html.push_str("<table>\n<tr><th>Time</th><th>Route</th><th>Status</th></tr>\n");
+            for error in &self.recent_errors {
+                writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    rfc3339(error.time as i64),
+                    escape(&error.route),
+                    error.status
+                )
+                .unwrap();
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body></html>\n");
+        html
+    }
+}
+
+/// Formats a duration in days, hours, minutes and seconds.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, secs % 60)
+    }
+}
@@ -0,0 +1,99 @@
+//! Summaries of who edited a document and when, built from its operation log.
+//!
+//! Consecutive edits by one author are grouped into a session, which ends
+//! when someone else edits the document or after a pause longer than
+//! [`SESSION_GAP`]. Edits stored before operations were timestamped have no
+//! time, so only a change of author separates their sessions.
+
+use serde::Serialize;
+
+use crate::{
+    database::{EditSource, StoredOperation},
+    ot::inserted_chars,
+};
+
+/// Longest pause between two edits of the same session, in seconds.
+pub const SESSION_GAP: i64 = 10 * 60;
+
+/// A run of consecutive edits to a document by one author.
+#[derive(Clone, Debug, Serialize)]
+pub struct EditSession {
+    /// ID of the user who made the edits, unique among the connections to
+    /// the document while it was loaded.
+    pub user_id: u64,
+    /// Authenticated email of the author, if any.
+    pub email: Option<String>,
+    /// Time of the first edit, in seconds since Unix epoch, if known.
+    pub started_at: Option<i64>,
+    /// Time of the last edit, in seconds since Unix epoch, if known.
+    pub ended_at: Option<i64>,
+    /// Revision that the first edit applied to.
+    pub start_revision: usize,
+    /// Number of operations in the session.
+    pub operations: usize,
+    /// Net change in the length of the text, in characters.
+    pub char_delta: i64,
+    /// Number of operations in the session that were pastes.
+    pub pastes: usize,
+    /// Number of characters inserted by pastes in the session.
+    pub pasted_chars: u64,
+}
+
+impl EditSession {
+    /// Returns whether an edit made after the session belongs to it.
+    fn continues(&self, op: &StoredOperation) -> bool {
+        let same_author = match (&self.email, &op.email) {
+            (Some(email), Some(other)) => email == other,
+            (None, None) => self.user_id == op.user_id,
+            _ => false,
+        };
+        let paused = match (self.ended_at, op.created_at) {
+            (Some(ended_at), Some(created_at)) => created_at - ended_at > SESSION_GAP,
+            _ => false,
+        };
+        same_author && !paused
+    }
+}
+
+/// Sessions of a document, built from its operations in order of revision.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    sessions: Vec<EditSession>,
+}
+
+impl Timeline {
+    /// Adds the next operation of the document to the timeline.
+    pub fn push(&mut self, op: &StoredOperation) {
+        let delta = op.operation.target_len() as i64 - op.operation.base_len() as i64;
+        let pasted = match op.source {
+            Some(EditSource::Paste) => Some(inserted_chars(&op.operation)),
+            _ => None,
+        };
+        match self.sessions.last_mut() {
+            Some(session) if session.continues(op) => {
+                session.started_at = session.started_at.or(op.created_at);
+                session.ended_at = op.created_at.or(session.ended_at);
+                session.operations += 1;
+                session.char_delta += delta;
+                session.pastes += pasted.is_some() as usize;
+                session.pasted_chars += pasted.unwrap_or(0);
+            }
+            _ => self.sessions.push(EditSession {
+                user_id: op.user_id,
+                email: op.email.clone(),
+                started_at: op.created_at,
+                ended_at: op.created_at,
+                start_revision: op.revision,
+                operations: 1,
+                char_delta: delta,
+                pastes: pasted.is_some() as usize,
+                pasted_chars: pasted.unwrap_or(0),
+            }),
+        }
+    }
+
+    /// Returns the sessions, oldest first.
+    pub fn finish(self) -> Vec<EditSession> {
+        self.sessions
+    }
+}
@@ -0,0 +1,68 @@
+//! RFC 3339 formatting of the Unix timestamps returned by the REST API.
+//!
+//! Timestamps are stored and returned as seconds since the epoch in fields
+//! named `*_at`. When enabled, every such field in a JSON response gains a
+//! sibling `*_at_rfc3339` with the same instant as an RFC 3339 string in UTC,
+//! so existing clients keep working unchanged.
+
+use serde_json::{Map, Value};
+
+/// Suffix of fields holding Unix timestamps.
+const TIMESTAMP_SUFFIX: &str = "_at";
+
+/// Suffix appended to the name of the formatted copy of a timestamp field.
+const RFC3339_SUFFIX: &str = "_rfc3339";
+
+/// Formats a Unix timestamp in seconds as an RFC 3339 string in UTC.
+pub fn rfc3339(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let time = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Adds an RFC 3339 copy of every timestamp field in a JSON value.
+pub fn annotate(value: &mut Value) {
+    match value {
+        Value::Object(map) => annotate_object(map),
+        Value::Array(values) => values.iter_mut().for_each(annotate),
+        _ => (),
+    }
+}
+
+fn annotate_object(map: &mut Map<String, Value>) {
+    let formatted: Vec<(String, Value)> = map
+        .iter()
+        .filter(|(key, _)| key.ends_with(TIMESTAMP_SUFFIX))
+        .filter_map(|(key, value)| {
+            let secs = value.as_i64()?;
+            Some((key.clone() + RFC3339_SUFFIX, Value::String(rfc3339(secs))))
+        })
+        .collect();
+    map.values_mut().for_each(annotate);
+    map.extend(formatted);
+}
+
+/// Converts days since the Unix epoch to a proleptic Gregorian date.
+///
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,159 @@
+//! Minimal streaming writer for ZIP archives of text files.
+//!
+//! Each file is compressed in memory and written as soon as it is added, and
+//! the central directory is written at the end. ZIP64 is not supported, so an
+//! archive holds at most [`MAX_ENTRIES`] files of up to 4 GiB each.
+
+use crate::timestamps::civil_from_days;
+
+/// Maximum number of files in an archive without ZIP64 extensions.
+pub const MAX_ENTRIES: usize = u16::MAX as usize;
+
+/// Version 2.0 of the format, the first supporting deflate.
+const VERSION: u16 = 20;
+
+/// General purpose flag marking file names as UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Compression method number for deflate.
+const METHOD_DEFLATE: u16 = 8;
+
+/// Table for computing CRC-32 checksums with the IEEE polynomial.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A file already written to the archive, kept for the central directory.
+struct Entry {
+    name: String,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a ZIP archive in chunks, one per file.
+#[derive(Default)]
+pub struct ZipWriter {
+    /// Number of bytes written so far.
+    offset: u32,
+    entries: Vec<Entry>,
+}
+
+impl ZipWriter {
+    /// Returns the bytes of a new file in the archive, modified at a Unix time.
+    pub fn add_file(&mut self, name: &str, modified: i64, data: &[u8]) -> Vec<u8> {
+        let compressed = miniz_oxide::deflate::compress_to_vec(data, 6);
+        let (time, date) = dos_datetime(modified);
+        let entry = Entry {
+            name: name.to_owned(),
+            time,
+            date,
+            crc: crc32(data),
+            compressed_size: compressed.len() as u32,
+            size: data.len() as u32,
+            offset: self.offset,
+        };
+
+        let mut chunk = Vec::with_capacity(30 + name.len() + compressed.len());
+        push_u32(&mut chunk, 0x04034b50);
+        push_u16(&mut chunk, VERSION);
+        push_u16(&mut chunk, FLAG_UTF8);
+        push_u16(&mut chunk, METHOD_DEFLATE);
+        push_u16(&mut chunk, entry.time);
+        push_u16(&mut chunk, entry.date);
+        push_u32(&mut chunk, entry.crc);
+        push_u32(&mut chunk, entry.compressed_size);
+        push_u32(&mut chunk, entry.size);
+        push_u16(&mut chunk, name.len() as u16);
+        push_u16(&mut chunk, 0);
+        chunk.extend_from_slice(name.as_bytes());
+        chunk.extend_from_slice(&compressed);
+
+        self.offset += chunk.len() as u32;
+        self.entries.push(entry);
+        chunk
+    }
+
+    /// Returns the central directory that ends the archive.
+    pub fn finish(self) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        for entry in &self.entries {
+            push_u32(&mut chunk, 0x02014b50);
+            push_u16(&mut chunk, VERSION);
+            push_u16(&mut chunk, VERSION);
+            push_u16(&mut chunk, FLAG_UTF8);
+            push_u16(&mut chunk, METHOD_DEFLATE);
+            push_u16(&mut chunk, entry.time);
+            push_u16(&mut chunk, entry.date);
+            push_u32(&mut chunk, entry.crc);
+            push_u32(&mut chunk, entry.compressed_size);
+            push_u32(&mut chunk, entry.size);
+            push_u16(&mut chunk, entry.name.len() as u16);
+            push_u16(&mut chunk, 0); // extra field length
+            push_u16(&mut chunk, 0); // comment length
+            push_u16(&mut chunk, 0); // disk number
+            push_u16(&mut chunk, 0); // internal attributes
+            push_u32(&mut chunk, 0); // external attributes
+            push_u32(&mut chunk, entry.offset);
+            chunk.extend_from_slice(entry.name.as_bytes());
+        }
+        let directory_size = chunk.len() as u32;
+        push_u32(&mut chunk, 0x06054b50);
+        push_u16(&mut chunk, 0); // disk number
+        push_u16(&mut chunk, 0); // disk with the central directory
+        push_u16(&mut chunk, self.entries.len() as u16);
+        push_u16(&mut chunk, self.entries.len() as u16);
+        push_u32(&mut chunk, directory_size);
+        push_u32(&mut chunk, self.offset);
+        push_u16(&mut chunk, 0); // comment length
+        chunk
+    }
+}
+
+/// Computes the CRC-32 checksum of data.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Converts a Unix time to MS-DOS time and date fields, in UTC.
+///
+/// Times before 1980 are clamped to the earliest representable date.
+fn dos_datetime(secs: i64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = secs.rem_euclid(86400);
+    let dos_time = ((time / 3600) << 11) | ((time % 3600 / 60) << 5) | (time % 60 / 2);
+    let dos_date = (((year - 1980).min(127) as u32) << 9) | (month << 5) | day;
+    (dos_time as u16, dos_date as u16)
+}
+
+fn push_u16(chunk: &mut Vec<u8>, value: u16) {
+    chunk.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(chunk: &mut Vec<u8>, value: u32) {
+    chunk.extend_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,134 @@
+//! Tests for verifying Cloudflare Access tokens on WebSocket connections and
+//! requests.
+
+use anyhow::Result;
+use common::tokens::{claims, serve_provider, sign};
+use common::*;
+use rustpad_server::{access::AccessConfig, server, ServerConfig};
+use serde_json::{json, Value};
+
+pub mod common;
+
+const ISSUER: &str = "https://rustpad-test.cloudflareaccess.com";
+const AUDIENCE: &str = "rustpad-test-audience";
+
+#[tokio::test]
+async fn test_access_tokens() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        access: Some(AccessConfig {
+            issuer: ISSUER.into(),
+            audience: AUDIENCE.into(),
+            certs_url: format!("{}/certs", serve_provider()),
+        }),
+        ..test_config().await
+    });
+
+    let alice = sign(&claims(ISSUER, AUDIENCE, "alice@example.com", 300))?;
+
+    let mut client = connect_with_headers(
+        &filter,
+        "secret",
+        &[
+            ("cf-access-jwt-assertion", &alice),
+            ("cf-access-authenticated-user-email", "alice@example.com"),
+        ],
+    )
+    .await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(
+        client.recv().await?,
+        json!({ "AuthenticatedEmail": "alice@example.com" })
+    );
+
+    // The email is taken from the token, even without the header.
+    let headers = [("cf-access-jwt-assertion", alice.as_str())];
+    let mut client = connect_with_headers(&filter, "secret", &headers).await?;
+    client.recv().await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "AuthenticatedEmail": "alice@example.com" })
+    );
+
+    let expired = sign(&claims(ISSUER, AUDIENCE, "alice@example.com", -3600))?;
+    let other_app = sign(&claims(ISSUER, "other-app", "alice@example.com", 300))?;
+    let bob = sign(&claims(ISSUER, AUDIENCE, "bob@example.com", 300))?;
+    let parts: Vec<_> = alice.split('.').collect();
+    let bob_payload = bob.split('.').nth(1).expect("token should have a payload");
+    let forged = format!("{}.{}.{}", parts[0], bob_payload, parts[2]);
+    let refused = [
+        vec![("cf-access-authenticated-user-email", "alice@example.com")],
+        vec![
+            ("cf-access-jwt-assertion", alice.as_str()),
+            ("cf-access-authenticated-user-email", "bob@example.com"),
+        ],
+        vec![("cf-access-jwt-assertion", expired.as_str())],
+        vec![("cf-access-jwt-assertion", other_app.as_str())],
+        vec![("cf-access-jwt-assertion", forged.as_str())],
+        vec![("cf-access-jwt-assertion", "not a token")],
+    ];
+    for headers in refused {
+        assert!(connect_with_headers(&filter, "secret", &headers)
+            .await
+            .is_err());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_access_requests() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        access: Some(AccessConfig {
+            issuer: ISSUER.into(),
+            audience: AUDIENCE.into(),
+            certs_url: format!("{}/certs", serve_provider()),
+        }),
+        ..test_config().await
+    });
+
+    let resp = warp::test::request()
+        .path("/api/capabilities")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["auth"], "cloudflare-access");
+
+    let alice = sign(&claims(ISSUER, AUDIENCE, "alice@example.com", 300))?;
+    let resp = warp::test::request()
+        .path("/api/user-identity")
+        .header("cf-access-jwt-assertion", alice.as_str())
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["email"], "alice@example.com");
+
+    let resp = warp::test::request()
+        .path("/api/user-identity")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["email"], Value::Null);
+
+    // The email header alone is not trusted once tokens are verified.
+    let resp = warp::test::request()
+        .path("/api/user-identity")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/secret")
+        .header("cf-access-jwt-assertion", alice.as_str())
+        .header("cf-access-authenticated-user-email", "bob@example.com")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+
+    Ok(())
+}
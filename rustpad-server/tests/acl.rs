@@ -0,0 +1,364 @@
+//! Tests for role-based access control lists on documents.
+
+use anyhow::{bail, Result};
+use common::tokens::{claims, serve_provider, sign};
+use common::*;
+use rustpad_server::{access::AccessConfig, server, ServerConfig};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Send a request changing the access control list of a document as a user,
+/// returning the response status and body.
+async fn change_acl(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    method: &str,
+    email: &str,
+    body: Value,
+) -> (u16, Value) {
+    let resp = warp::test::request()
+        .method(method)
+        .path("/api/documents/plans/acl")
+        .header("cf-access-authenticated-user-email", email)
+        .json(&body)
+        .reply(filter)
+        .await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+/// Transfer the `deed` document to another owner as a user, returning the
+/// response status and body.
+async fn transfer(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    email: &str,
+    owner: &str,
+) -> (u16, Value) {
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/deed/transfer")
+        .header("cf-access-authenticated-user-email", email)
+        .json(&json!({ "email": owner }))
+        .reply(filter)
+        .await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+/// Send a request to the text of the `plans` document as a user, returning
+/// the response status and body.
+async fn request_text(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    method: &str,
+    email: Option<&str>,
+    body: &str,
+) -> (u16, String) {
+    let mut request = warp::test::request()
+        .method(method)
+        .path("/api/text/plans")
+        .body(body.to_owned());
+    if let Some(email) = email {
+        request = request.header("cf-access-authenticated-user-email", email);
+    }
+    let resp = request.reply(filter).await;
+    let body = String::from_utf8_lossy(resp.body()).into_owned();
+    (resp.status().as_u16(), body)
+}
+
+/// Receive messages until one of the given kind arrives.
+async fn recv_until(client: &mut JsonSocket, kind: &str) -> Result<Value> {
+    for _ in 0..20 {
+        let msg = client.recv().await?;
+        if msg.get(kind).is_some() {
+            return Ok(msg);
+        }
+    }
+    bail!("no {} message was received", kind)
+}
+
+#[tokio::test]
+async fn test_document_roles() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .json(&json!({ "id": "plans" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+
+    // The first entry must be an owner, so that someone can manage the list.
+    let viewer = json!({ "email": "carol@example.com", "role": "viewer" });
+    let (status, _) = change_acl(&filter, "PUT", "alice@example.com", viewer.clone()).await;
+    assert_eq!(status, 409);
+    let owner = json!({ "email": "alice@example.com", "role": "owner" });
+    let (status, _) = change_acl(&filter, "PUT", "alice@example.com", owner).await;
+    assert_eq!(status, 200);
+    let (status, _) = change_acl(&filter, "PUT", "alice@example.com", viewer.clone()).await;
+    assert_eq!(status, 200);
+    let editor = json!({ "email": "bob@example.com", "role": "editor" });
+    let (status, acl) = change_acl(&filter, "PUT", "alice@example.com", editor).await;
+    assert_eq!(status, 200);
+    let roles: Vec<_> = acl
+        .as_array()
+        .expect("list should be an array")
+        .iter()
+        .map(|entry| json!([entry["email"], entry["role"]]))
+        .collect();
+    assert_eq!(
+        roles,
+        [
+            json!(["alice@example.com", "owner"]),
+            json!(["bob@example.com", "editor"]),
+            json!(["carol@example.com", "viewer"]),
+        ]
+    );
+
+    // Only users on the list may see it.
+    for (email, status) in [("carol@example.com", 200), ("mallory@example.com", 403)] {
+        let resp = warp::test::request()
+            .path("/api/documents/plans/acl")
+            .header("cf-access-authenticated-user-email", email)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), status);
+    }
+    let resp = warp::test::request()
+        .path("/api/documents/plans/acl")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+
+    // Only owners may change the list, and it keeps an owner.
+    let (status, _) = change_acl(&filter, "PUT", "bob@example.com", viewer).await;
+    assert_eq!(status, 403);
+    let alice = json!({ "email": "alice@example.com" });
+    let (status, _) = change_acl(&filter, "DELETE", "alice@example.com", alice).await;
+    assert_eq!(status, 409);
+
+    let mut viewer = connect_as(&filter, "plans", "carol@example.com").await?;
+    assert_eq!(
+        recv_until(&mut viewer, "ReadOnly").await?,
+        json!({ "ReadOnly": true })
+    );
+    viewer
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    let resync = recv_until(&mut viewer, "Resync").await?;
+    assert_eq!(resync["Resync"]["text"], "");
+    let revision = resync["Resync"]["revision"].clone();
+
+    let mut editor = connect_as(&filter, "plans", "bob@example.com").await?;
+    editor
+        .send(&json!({ "Edit": { "revision": revision, "operation": ["hello"] } }))
+        .await;
+    recv_until(&mut editor, "History").await?;
+    let text = request_text(&filter, "GET", Some("carol@example.com"), "").await;
+    assert_eq!(text, (200, "hello".into()));
+
+    assert!(connect_as(&filter, "plans", "mallory@example.com")
+        .await
+        .is_err());
+    assert!(connect(&filter, "plans").await.is_err());
+
+    // The same roles apply over REST.
+    for email in [Some("mallory@example.com"), None] {
+        assert_eq!(request_text(&filter, "GET", email, "").await.0, 403);
+        assert_eq!(request_text(&filter, "PUT", email, "mine").await.0, 403);
+    }
+    let (status, _) = request_text(&filter, "PUT", Some("carol@example.com"), "mine").await;
+    assert_eq!(status, 403);
+    let (status, _) = request_text(&filter, "PUT", Some("bob@example.com"), "hello!").await;
+    assert_eq!(status, 200);
+    let text = request_text(&filter, "GET", Some("alice@example.com"), "").await;
+    assert_eq!(text, (200, "hello!".into()));
+
+    for email in ["bob@example.com", "carol@example.com"] {
+        let resp = warp::test::request()
+            .method("PATCH")
+            .path("/api/documents/plans")
+            .header("cf-access-authenticated-user-email", email)
+            .json(&json!({ "name": "Stolen plans" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 403);
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path("/api/documents/plans")
+            .header("cf-access-authenticated-user-email", email)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 403);
+    }
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/documents/plans")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .json(&json!({ "name": "Plans" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Editors may tag a document, but only owners may archive it or change
+    // its settings.
+    let change = |method: &str, path: &str, email: &str, body: Value| {
+        warp::test::request()
+            .method(method)
+            .path(&format!("/api/documents/plans/{}", path))
+            .header("cf-access-authenticated-user-email", email)
+            .json(&body)
+            .reply(&filter)
+    };
+    let tag = json!({ "tag": "secret" });
+    let resp = change("POST", "tags", "carol@example.com", tag.clone()).await;
+    assert_eq!(resp.status(), 403);
+    let resp = change("POST", "tags", "bob@example.com", tag).await;
+    assert_eq!(resp.status(), 200);
+    let read_only = json!({ "read_only": true });
+    let resp = change("PATCH", "settings", "bob@example.com", read_only.clone()).await;
+    assert_eq!(resp.status(), 403);
+    let resp = change("POST", "archive", "bob@example.com", json!({})).await;
+    assert_eq!(resp.status(), 403);
+    let resp = change("PATCH", "settings", "alice@example.com", read_only).await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/documents/plans")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    let resp = change("POST", "restore", "bob@example.com", json!({})).await;
+    assert_eq!(resp.status(), 403);
+    let resp = change("POST", "restore", "alice@example.com", json!({})).await;
+    assert_eq!(resp.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_owner() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .json(&json!({ "id": "deed" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["owner_email"], "alice@example.com");
+
+    let (status, _) = transfer(&filter, "bob@example.com", "bob@example.com").await;
+    assert_eq!(status, 403);
+    let (status, meta) = transfer(&filter, "alice@example.com", "bob@example.com").await;
+    assert_eq!(status, 200);
+    assert_eq!(meta["owner_email"], "bob@example.com");
+    let (status, _) = transfer(&filter, "alice@example.com", "alice@example.com").await;
+    assert_eq!(status, 403);
+
+    // The owner of a document is an owner on its access control list too.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/documents/deed/acl")
+        .header("cf-access-authenticated-user-email", "bob@example.com")
+        .json(&json!({ "email": "carol@example.com", "role": "owner" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/documents/deed")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    assert!(connect_as(&filter, "deed", "bob@example.com").await.is_ok());
+
+    // Documents created by editing belong to their first authenticated editor.
+    let mut client = connect(&filter, "scratch").await?;
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["draft"] } }))
+        .await;
+    recv_until(&mut client, "History").await?;
+    let mut client = connect_as(&filter, "scratch", "dave@example.com").await?;
+    client
+        .send(&json!({ "Edit": { "revision": 1, "operation": [5, "!"] } }))
+        .await;
+    while recv_until(&mut client, "History").await?["History"]["start"] != 1 {}
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/scratch/persist")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path("/api/documents/scratch")
+        .reply(&filter)
+        .await;
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["owner_email"], "dave@example.com");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verified_owner() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let issuer = "https://rustpad-test.cloudflareaccess.com";
+    let audience = "rustpad-test-audience";
+    let filter = server(ServerConfig {
+        access: Some(AccessConfig {
+            issuer: issuer.into(),
+            audience: audience.into(),
+            certs_url: format!("{}/certs", serve_provider()),
+        }),
+        ..test_config().await
+    });
+    let alice = sign(&claims(issuer, audience, "alice@example.com", 300))?;
+    let bob = sign(&claims(issuer, audience, "bob@example.com", 300))?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .header("cf-access-jwt-assertion", alice.as_str())
+        .json(&json!({ "id": "deed" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["owner_email"], "alice@example.com");
+
+    // Only a verified identity is taken to be the owner.
+    let transfer = |headers: Vec<(&'static str, String)>| {
+        let mut request = warp::test::request()
+            .method("POST")
+            .path("/api/documents/deed/transfer")
+            .json(&json!({ "email": "bob@example.com" }));
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        request.reply(&filter)
+    };
+    let email = "cf-access-authenticated-user-email";
+    let token = "cf-access-jwt-assertion";
+    let forged = vec![(email, "alice@example.com".to_owned())];
+    assert_eq!(transfer(forged).await.status(), 403);
+    let mismatched = vec![(token, bob.clone()), (email, "alice@example.com".to_owned())];
+    assert_eq!(transfer(mismatched).await.status(), 403);
+    assert_eq!(transfer(vec![(token, bob)]).await.status(), 403);
+    let resp = transfer(vec![(token, alice)]).await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["owner_email"], "bob@example.com");
+
+    Ok(())
+}
@@ -0,0 +1,967 @@
+//! Tests for the administrative maintenance endpoints.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::time;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_database_maintenance() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/db/check")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let report: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(report["task"], "integrity_check");
+    assert_eq!(report["ok"], true);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/db/vacuum")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let report: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(report["task"], "vacuum");
+    assert_eq!(report["ok"], true);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_evict_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/documents/evictme/evict")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    let mut client = connect(&filter, "evictme").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/documents/evictme/evict")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    client.recv_closed().await?;
+
+    expect_text(&filter, "evictme", "hello").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_active_documents() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let active = || async {
+        let resp = warp::test::request().path("/api/admin/active").reply(&filter).await;
+        assert_eq!(resp.status(), 200);
+        serde_json::from_slice::<Value>(resp.body()).unwrap()
+    };
+    assert_eq!(active().await, json!([]));
+
+    let mut client = connect(&filter, "busy").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let documents = active().await;
+    assert_eq!(documents.as_array().map(Vec::len), Some(1));
+    let document = &documents[0];
+    assert_eq!(document["id"], "busy");
+    assert_eq!(document["idle_secs"], 0);
+    let evict_in_secs = document["evict_in_secs"].as_u64().unwrap();
+    assert!((24 * 3600 - 60..=24 * 3600).contains(&evict_in_secs));
+    assert_eq!(document["revision"], 1);
+    assert_eq!(document["users"], 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_jobs() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .path("/api/admin/jobs")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "counts": {}, "recent": [] }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rejection_metrics() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "rejecting").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 3,
+            "operation": ["hello"]
+        }
+    });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "edit_rejected");
+    assert_eq!(msg["Error"]["params"]["reason"], "invalid_revision");
+    client.recv_closed().await?;
+
+    let resp = warp::test::request()
+        .path("/api/admin/rejections")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body[0]["id"], "rejecting");
+    assert_eq!(body[0]["rejections"]["invalid_revision"], 1);
+
+    let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["rejected_edits"]["invalid_revision"], 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_export_and_delete() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect_as(&filter, "gdpr", "alice@example.com").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv().await?; // AuthenticatedEmail
+    client.recv().await?; // SigningKey
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    // Edits to a document that is stored and no longer loaded are exported
+    // from its stored history.
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/stored/append")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .body("hi")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/documents/stored/evict")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/documents/stored/acl")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .json(&json!({ "email": "alice@example.com", "role": "owner" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/stored/report")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .json(&json!({ "reason": "Spam" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+
+    let resp = warp::test::request()
+        .path("/api/admin/users/alice@example.com/export")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        body["edits"],
+        json!([
+            { "id": "gdpr", "edits": [{ "revision": 1, "operation": ["hello"] }] },
+            { "id": "stored", "edits": [{ "revision": 1, "operation": ["hi"] }] },
+        ])
+    );
+    assert_eq!(body["owned"], json!(["stored"]));
+    assert_eq!(body["roles"][0]["document_id"], "stored");
+    assert_eq!(body["roles"][0]["role"], "owner");
+    assert_eq!(body["reports"][0]["document_id"], "stored");
+    assert_eq!(body["reports"][0]["reason"], "Spam");
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/admin/users/alice@example.com")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["edits_anonymized"], 1);
+
+    let resp = warp::test::request()
+        .path("/api/admin/users/alice@example.com/export")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["email"], "alice@example.com");
+    assert_eq!(body["color"], Value::Null);
+    assert_eq!(body["edits"], json!([]));
+    assert_eq!(body["owned"], json!([]));
+    assert_eq!(body["roles"], json!([]));
+    assert_eq!(body["reports"], json!([]));
+    assert!(body["exported_at_rfc3339"].as_str().is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_anonymize() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect_as(&filter, "departed", "bob@example.com").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv().await?; // AuthenticatedEmail
+    client.recv().await?; // SigningKey
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["goodbye"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/users/bob@example.com/anonymize")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["edits_anonymized"], 1);
+    let pseudonym = body["pseudonym"].as_str().expect("pseudonym should be a string");
+    assert!(pseudonym.starts_with("anonymous-"));
+
+    let resp = warp::test::request()
+        .path(&format!("/api/admin/users/{}/export", pseudonym))
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["edits"][0]["id"], "departed");
+
+    let resp = warp::test::request()
+        .path("/api/admin/users/bob@example.com/export")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["edits"], json!([]));
+
+    expect_text(&filter, "departed", "goodbye").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_route_metrics() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    for _ in 0..2 {
+        let resp = warp::test::request().path("/api/documents").reply(&filter).await;
+        assert_eq!(resp.status(), 200);
+    }
+    let resp = warp::test::request()
+        .path("/api/documents/missing")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    let list = &stats["routes"]["GET /api/documents"];
+    assert_eq!(list["requests"], 2);
+    assert_eq!(list["errors"], 0);
+    assert_eq!(list["error_rate"], 0.0);
+    let get = &stats["routes"]["GET /api/documents/{id}"];
+    assert_eq!(get["requests"], 1);
+    assert_eq!(get["client_errors"], 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_database_size() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    for id in ["one", "two"] {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/documents")
+            .json(&json!({ "id": id }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 201);
+    }
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/one")
+        .body("stored again")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let size = || async {
+        let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+        let stats: Value = serde_json::from_slice(resp.body()).unwrap();
+        stats["database_size"].clone()
+    };
+    assert_eq!(size().await, 2);
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/documents/two?purge=true")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    assert_eq!(size().await, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metadata_cache() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "id": "cached", "name": "Before" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+
+    let list = || warp::test::request().path("/api/documents").reply(&filter);
+    for _ in 0..2 {
+        let documents: Value = serde_json::from_slice(list().await.body())?;
+        assert_eq!(documents[0]["name"], "Before");
+    }
+
+    // Writes are visible immediately, rather than after the cache expires.
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/documents/cached")
+        .json(&json!({ "name": "After" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let documents: Value = serde_json::from_slice(list().await.body())?;
+    assert_eq!(documents[0]["name"], "After");
+
+    let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["cache"]["hits"], 1);
+    assert!(stats["cache"]["misses"].as_u64() >= Some(2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_metrics() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "chatty").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+    let msg = json!({ "SetLanguage": "rust" });
+    client.send(&msg).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    let sockets = &stats["sockets"];
+    assert_eq!(sockets["open"], 1);
+    assert_eq!(sockets["connections"], 1);
+    assert_eq!(sockets["messages_in"], 1);
+    assert_eq!(sockets["messages_out"], 3);
+    assert_eq!(sockets["bytes_in"], msg.to_string().len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_version() -> Result<()> {
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request().path("/api/version").reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert!(body["commit"].is_string());
+    assert!(body["protocol"].is_u64());
+    assert!(body["features"].as_array().is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_capabilities() -> Result<()> {
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .path("/api/capabilities")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["auth"], "none");
+    assert_eq!(body["api_keys"], false);
+    assert_eq!(body["chat"], false);
+    assert_eq!(body["limits"]["document_size"], 256 * 1024);
+
+    let filter = server(ServerConfig {
+        require_api_key: true,
+        ..test_config().await
+    });
+    let resp = warp::test::request()
+        .path("/api/capabilities")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["api_keys"], true);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_localized_errors() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .path("/api/nonexistent")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["code"], "not_found");
+    assert_eq!(body["message"], "The requested resource was not found.");
+
+    let resp = warp::test::request()
+        .path("/api/nonexistent")
+        .header("accept-language", "fr-CH, de;q=0.9, en;q=0.8")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["code"], "not_found");
+    assert_eq!(body["message"], "Die angeforderte Ressource wurde nicht gefunden.");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_zip() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    for name in ["notes", "notes", "gone"] {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/documents")
+            .json(&json!({ "name": name }))
+            .reply(&filter)
+            .await;
+        let meta: Value = serde_json::from_slice(resp.body())?;
+        if name == "gone" {
+            let id = meta["id"].as_str().expect("id should be a string");
+            warp::test::request()
+                .method("DELETE")
+                .path(&format!("/api/documents/{}", id))
+                .reply(&filter)
+                .await;
+        }
+    }
+
+    let resp = warp::test::request()
+        .path("/api/admin/export.zip")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/zip");
+    let zip = resp.body();
+    assert!(zip.starts_with(b"PK\x03\x04"));
+
+    // Read the file names from the central directory.
+    let u16_at = |i: usize| u16::from_le_bytes([zip[i], zip[i + 1]]) as usize;
+    let u32_at = |i: usize| u32::from_le_bytes([zip[i], zip[i + 1], zip[i + 2], zip[i + 3]]) as usize;
+    let end = zip.len() - 22;
+    assert_eq!(u32_at(end), 0x06054b50);
+    assert_eq!(u16_at(end + 10), 2);
+    let mut names = Vec::new();
+    let mut pos = u32_at(end + 16);
+    for _ in 0..2 {
+        assert_eq!(u32_at(pos), 0x02014b50);
+        let len = u16_at(pos + 28);
+        names.push(String::from_utf8(zip[pos + 46..pos + 46 + len].to_vec())?);
+        pos += 46 + len;
+    }
+    names.sort();
+    assert_eq!(names, ["notes (2).txt", "notes.txt"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stats_history() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        stats_sample_interval: Duration::from_millis(50),
+        ..test_config().await
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let samples = loop {
+        time::sleep(Duration::from_millis(100)).await;
+        let resp = warp::test::request()
+            .path("/api/stats/history?hours=1")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let samples: Vec<Value> = serde_json::from_slice(resp.body())?;
+        if !samples.is_empty() {
+            break samples;
+        }
+    };
+    let sample = samples.last().unwrap();
+    assert!(sample["created_at"].is_i64());
+    assert_eq!(sample["documents"], 1);
+    assert_eq!(sample["connections"], 1);
+    assert!(sample["ops_per_sec"].is_f64());
+
+    let resp = warp::test::request()
+        .path("/api/stats/history?hours=-1")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_status_page() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "unsaved").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv().await?; // AuthenticatedEmail
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = warp::test::request().path("/api/status").reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+    let page = std::str::from_utf8(resp.body())?;
+    assert!(page.contains("<th>Documents in memory</th><td>1</td>"));
+    assert!(page.contains("<th>Active connections</th><td>1</td>"));
+    assert!(page.contains("<th>Unsaved documents</th><td>1</td>"));
+    assert!(page.contains("<th>Unsaved revisions</th><td>1</td>"));
+    assert!(page.contains("<p>None</p>"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_abuse_reports() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let replace = |text: &'static str| {
+        warp::test::request()
+            .method("PUT")
+            .path("/api/text/pad")
+            .body(text)
+            .reply(&filter)
+    };
+    assert_eq!(replace("buy cheap watches").await.status(), 200);
+
+    let report = |id: &'static str, reason: &'static str| {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/documents/{}/report", id))
+            .header("cf-access-authenticated-user-email", "alice@example.com")
+            .json(&json!({ "reason": reason }))
+            .reply(&filter)
+    };
+    assert_eq!(report("pad", "  ").await.status(), 400);
+    assert_eq!(report("missing", "Spam").await.status(), 404);
+    for _ in 0..4 {
+        assert_eq!(report("pad", "Spam").await.status(), 204);
+    }
+    // The report against a missing document counted towards the limit too.
+    assert_eq!(report("pad", "Spam").await.status(), 429);
+
+    let resp = warp::test::request()
+        .path("/api/admin/reports")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let reports: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(reports.as_array().unwrap().len(), 4);
+    assert_eq!(reports[0]["document_id"], "pad");
+    assert_eq!(reports[0]["reporter"], "alice@example.com");
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/documents/pad/quarantine")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert!(meta["quarantined_at"].is_number());
+    assert_eq!(replace("more watches").await.status(), 409);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/documents/pad/release")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(replace("cleaned up").await.status(), 200);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/admin/reports/{}/resolve", reports[0]["id"]))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    let resp = warp::test::request()
+        .path("/api/admin/reports?resolved=true")
+        .reply(&filter)
+        .await;
+    let resolved: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(resolved.as_array().unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_language_map() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .path("/api/admin/language-map")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let extensions: Vec<Value> = serde_json::from_slice(resp.body())?;
+    assert!(extensions.contains(&json!({ "extension": "rs", "language": "rust" })));
+
+    let put = |body: Value| {
+        warp::test::request()
+            .method("PUT")
+            .path("/api/admin/language-map")
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let resp = put(json!([{ "extension": "rs", "language": "" }])).await;
+    assert_eq!(resp.status(), 400);
+    let resp = put(json!([
+        { "extension": "rs", "language": "rust" },
+        { "extension": "RS", "language": "rust" },
+    ]))
+    .await;
+    assert_eq!(resp.status(), 400);
+
+    let custom = json!([
+        { "extension": "foo", "language": "foolang" },
+        { "extension": "rs", "language": "rust" },
+    ]);
+    let resp = put(custom.clone()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(serde_json::from_slice::<Value>(resp.body())?, custom);
+
+    let body = "--BOUNDARY\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"notes.foo\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        hello\r\n\
+        --BOUNDARY\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"main.py\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        print()\r\n\
+        --BOUNDARY--\r\n";
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/import")
+        .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+        .body(body)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let metas: Vec<Value> = serde_json::from_slice(resp.body())?;
+    assert_eq!(metas[0]["language"], "foolang");
+    assert_eq!(metas[1]["language"], Value::Null);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "name": "draft" }))
+        .reply(&filter)
+        .await;
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    let id = meta["id"].as_str().expect("id should be a string");
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/bulk")
+        .json(&json!({ "action": "set-language", "language": "foolang", "ids": [id] }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}/download", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(
+        resp.headers()["content-disposition"],
+        "attachment; filename=\"draft.foo\"; filename*=UTF-8''draft.foo"
+    );
+
+    let resp = put(json!([])).await;
+    assert_eq!(resp.status(), 200);
+    let extensions: Vec<Value> = serde_json::from_slice(resp.body())?;
+    assert!(extensions.contains(&json!({ "extension": "py", "language": "python" })));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_versions() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut clients = Vec::new();
+    for version in ["1.2.0", "1.1.0", "1.2.0"] {
+        let mut client = connect_with_agent(&filter, "rollout", "rustpad-test", version).await?;
+        client.recv().await?;
+        clients.push(client);
+    }
+    let mut legacy = connect(&filter, "legacy").await?;
+    legacy.recv().await?;
+
+    let resp = warp::test::request()
+        .path("/api/admin/client-versions")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let versions: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        versions,
+        json!([
+            { "client_version": "1.2.0", "connections": 2 },
+            { "client_version": null, "connections": 1 },
+            { "client_version": "1.1.0", "connections": 1 },
+        ])
+    );
+
+    let client = &mut clients[0];
+    client.recv().await?;
+    let alice = json!({ "name": "Alice", "hue": 42 });
+    client.send(&json!({ "ClientInfo": alice })).await;
+    client.recv().await?;
+    let resp = warp::test::request()
+        .path("/api/documents/rollout/session")
+        .reply(&filter)
+        .await;
+    let session: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        session["users"],
+        json!([{
+            "id": 0,
+            "name": "Alice",
+            "hue": 42,
+            "user_agent": "rustpad-test",
+            "client_version": "1.2.0",
+        }])
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_keys() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        require_api_key: true,
+        admin_token: Some("operator-secret".into()),
+        ..test_config().await
+    });
+
+    let create = |key: Option<&str>| {
+        let mut request = warp::test::request()
+            .method("POST")
+            .path("/api/documents")
+            .json(&json!({ "name": "automated" }));
+        if let Some(key) = key {
+            request = request.header("authorization", format!("Bearer {}", key));
+        }
+        request.reply(&filter)
+    };
+
+    let resp = create(None).await;
+    assert_eq!(resp.status(), 401);
+    let resp = create(Some("rpk_0123")).await;
+    assert_eq!(resp.status(), 401);
+
+    let mint = |token: Option<&str>| {
+        let mut request = warp::test::request()
+            .method("POST")
+            .path("/api/admin/api-keys")
+            .json(&json!({ "name": "ci" }));
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        request.reply(&filter)
+    };
+
+    // Minting a key needs the operator token.
+    assert_eq!(mint(None).await.status(), 401);
+    assert_eq!(mint(Some("rpk_0123")).await.status(), 401);
+    assert_eq!(mint(Some("wrong-secret")).await.status(), 401);
+    let resp = warp::test::request()
+        .path("/api/admin/export.zip")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+
+    let resp = mint(Some("operator-secret")).await;
+    assert_eq!(resp.status(), 201);
+    let minted: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(minted["name"], "ci");
+    let id = minted["id"].as_i64().expect("id should be an integer");
+    let key = minted["key"].as_str().expect("key should be a string");
+
+    let resp = create(Some(key)).await;
+    assert_eq!(resp.status(), 201);
+    let doc: Value = serde_json::from_slice(resp.body())?;
+    let doc_id = doc["id"].as_str().expect("id should be a string");
+
+    // Routes that only read documents don't need a key.
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}", doc_id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    for (method, path) in [
+        ("POST", "archive"),
+        ("POST", "restore"),
+        ("POST", "tags"),
+        ("PATCH", "settings"),
+    ] {
+        let resp = warp::test::request()
+            .method(method)
+            .path(&format!("/api/documents/{}/{}", doc_id, path))
+            .json(&json!({ "tag": "ci" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 401, "{} {}", method, path);
+    }
+
+    let resp = warp::test::request()
+        .path("/api/admin/api-keys")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+
+    // Admin routes also accept API keys.
+    let resp = warp::test::request()
+        .path("/api/admin/api-keys")
+        .header("authorization", format!("Bearer {}", key))
+        .reply(&filter)
+        .await;
+    let keys: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(keys[0]["id"], id);
+    assert!(keys[0]["last_used_at"].is_i64());
+    assert!(keys[0]["revoked_at"].is_null());
+    assert!(keys[0].get("key").is_none());
+
+    let revoke = || {
+        warp::test::request()
+            .method("DELETE")
+            .path(&format!("/api/admin/api-keys/{}", id))
+            .header("authorization", "Bearer operator-secret")
+            .reply(&filter)
+    };
+    assert_eq!(revoke().await.status(), 204);
+    assert_eq!(revoke().await.status(), 404);
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/documents/{}", doc_id))
+        .header("authorization", format!("Bearer {}", key))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Database test suite for each backend, run with `--features backend-tests`.
+//!
+//! The suite runs against a temporary SQLite file and an in-memory database,
+//! and against any other database URIs listed, separated by commas, in the
+//! `RUSTPAD_TEST_DATABASES` environment variable. Only SQLite is supported by
+//! [`Database`] so far, so that other backends can be checked against the
+//! same queries once they are added.
+#![cfg(feature = "backend-tests")]
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rustpad_server::database::{Database, PersistedDocument};
+use tempfile::NamedTempFile;
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time should be after the epoch")
+        .as_secs() as i64
+}
+
+/// Returns the versions of the migrations in the `migrations` directory.
+fn migration_versions() -> Result<Vec<i64>> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+    let mut versions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let (version, _) = name.split_once('_').context("unversioned migration")?;
+        versions.push(version.parse()?);
+    }
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+async fn check_migrations(database: &Database) -> Result<()> {
+    assert_eq!(database.applied_migrations().await?, migration_versions()?);
+    Ok(())
+}
+
+async fn check_upserts(database: &Database) -> Result<()> {
+    let first = PersistedDocument {
+        text: "first".into(),
+        language: None,
+    };
+    let second = PersistedDocument {
+        text: "second".into(),
+        language: Some("markdown".into()),
+    };
+    database.store("upsert", &first).await?;
+    database.store("upsert", &second).await?;
+    assert_eq!(database.load("upsert").await?, second);
+
+    database.save_user_color("alice@example.com", 10).await?;
+    database.save_user_color("alice@example.com", 20).await?;
+    assert_eq!(
+        database.get_user_color("alice@example.com").await?,
+        Some(20)
+    );
+    Ok(())
+}
+
+async fn check_timestamps(database: &Database) -> Result<()> {
+    let before = now();
+    let meta = database
+        .create("timestamps", Some("Timestamps"), None)
+        .await?
+        .context("document should be new")?;
+    assert!(meta.created_at >= before && meta.created_at <= now());
+    assert_eq!(meta.created_at, meta.updated_at);
+
+    let document = PersistedDocument {
+        text: "changed".into(),
+        language: None,
+    };
+    database.store("timestamps", &document).await?;
+    let meta = database
+        .get_meta("timestamps")
+        .await?
+        .context("document should exist")?;
+    assert!(meta.updated_at >= meta.created_at && meta.updated_at <= now());
+    Ok(())
+}
+
+async fn check_soft_delete(database: &Database) -> Result<()> {
+    database.create("trashed", None, None).await?;
+    database.soft_delete("trashed").await?;
+    assert!(database.is_deleted("trashed").await?);
+    assert!(database.restore("trashed").await?);
+    assert!(!database.is_deleted("trashed").await?);
+    Ok(())
+}
+
+/// Runs the whole suite against a new database.
+async fn check_backend(uri: &str) -> Result<()> {
+    let database = Database::new(uri).await?;
+    check_migrations(&database).await?;
+    check_upserts(&database).await?;
+    check_timestamps(&database).await?;
+    check_soft_delete(&database).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backends() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let file = NamedTempFile::new()?.into_temp_path();
+    let mut uris = vec![
+        "sqlite::memory:".to_owned(),
+        format!("sqlite://{}", file.display()),
+    ];
+    if let Ok(extra) = std::env::var("RUSTPAD_TEST_DATABASES") {
+        uris.extend(extra.split(',').map(|uri| uri.trim().to_owned()));
+    }
+    for uri in &uris {
+        check_backend(uri)
+            .await
+            .with_context(|| format!("database suite failed for {}", uri))?;
+    }
+    Ok(())
+}
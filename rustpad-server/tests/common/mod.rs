@@ -1,8 +1,13 @@
 use anyhow::{anyhow, Result};
-use rustpad_server::{database::Database, ServerConfig};
+use rustpad_server::{
+    database::Database, ServerConfig, DEFAULT_CLEANER_BATCH_SIZE, DEFAULT_CLEANER_INTERVAL,
+    DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_PURGE_AFTER_DAYS, DEFAULT_STATS_SAMPLE_INTERVAL,
+};
 use serde_json::Value;
 use warp::{filters::BoxedFilter, test::WsClient, Reply};
 
+pub mod tokens;
+
 /// A test WebSocket client that sends and receives JSON messages.
 pub struct JsonSocket(WsClient);
 
@@ -34,6 +39,61 @@ pub async fn connect(
     Ok(JsonSocket(client))
 }
 
+/// Connect a new test client WebSocket as an authenticated user.
+pub async fn connect_as(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    email: &str,
+) -> Result<JsonSocket> {
+    let client = warp::test::ws()
+        .path(&format!("/api/socket/{}", id))
+        .header("cf-access-authenticated-user-email", email)
+        .handshake(filter.clone())
+        .await?;
+    Ok(JsonSocket(client))
+}
+
+/// Connect a new test client WebSocket with extra request headers.
+pub async fn connect_with_headers(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    headers: &[(&str, &str)],
+) -> Result<JsonSocket> {
+    let mut client = warp::test::ws().path(&format!("/api/socket/{}", id));
+    for &(name, value) in headers {
+        client = client.header(name, value);
+    }
+    Ok(JsonSocket(client.handshake(filter.clone()).await?))
+}
+
+/// Connect a new test client WebSocket that reports its user agent and version.
+pub async fn connect_with_agent(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    user_agent: &str,
+    client_version: &str,
+) -> Result<JsonSocket> {
+    let path = format!("/api/socket/{}?client_version={}", id, client_version);
+    let client = warp::test::ws()
+        .path(&path)
+        .header("user-agent", user_agent)
+        .handshake(filter.clone())
+        .await?;
+    Ok(JsonSocket(client))
+}
+
+/// Connect a test client to the document event feed, with a query string.
+pub async fn connect_feed(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    query: &str,
+) -> Result<JsonSocket> {
+    let client = warp::test::ws()
+        .path(&format!("/api/feed?{}", query))
+        .handshake(filter.clone())
+        .await?;
+    Ok(JsonSocket(client))
+}
+
 /// Check the text route.
 pub async fn expect_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str, text: &str) {
     let resp = warp::test::request()
@@ -46,10 +106,35 @@ pub async fn expect_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str
 
 /// Create a test server configuration with an in-memory SQLite database.
 pub async fn test_config() -> ServerConfig {
+    let database = Database::new("sqlite::memory:")
+        .await
+        .expect("Failed to create test database");
+    test_config_with(database)
+}
+
+/// Create a test server configuration with a given database.
+pub fn test_config_with(database: Database) -> ServerConfig {
     ServerConfig {
         expiry_days: 1,
-        database: Database::new("sqlite::memory:")
-            .await
-            .expect("Failed to create test database"),
+        cleaner_interval: DEFAULT_CLEANER_INTERVAL,
+        cleaner_batch_size: DEFAULT_CLEANER_BATCH_SIZE,
+        purge_after_days: DEFAULT_PURGE_AFTER_DAYS,
+        rfc3339_timestamps: true,
+        derive_names: false,
+        stats_sample_interval: DEFAULT_STATS_SAMPLE_INTERVAL,
+        max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        region: None,
+        alternates: Vec::new(),
+        security_headers: Default::default(),
+        versions: Default::default(),
+        normalization: Default::default(),
+        access: None,
+        oidc: None,
+        require_api_key: false,
+        admin_token: None,
+        share_secret: None,
+        signing_secret: None,
+        standby: None,
+        database,
     }
 }
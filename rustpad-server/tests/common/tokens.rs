@@ -0,0 +1,73 @@
+//! Signing keys and identity providers for testing token authentication.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde_json::{json, Value};
+use warp::Filter;
+
+/// Private key of the test signing key, as base64 PKCS#8.
+const PRIVATE_KEY: &str = "MIIEvQIBADANBgkqhkiG9w0BAQEFAASCBKcwggSjAgEAAoIBAQDP7g4i/BABhK433kbIWxrtEuOAylPvv1TzM17hH1XB526XyqKoFP+uoCg6WMTW7z5qkNrOeN4Eqp+mAbYWp/qNF5ctldjDm3Oi0yLZ5Wmq8v7TekbXyWPrBKfaXV9VJDP6tEPXarmcnTC3jUbr/ray0j6CrJ5o9Uu2DsVP2llLtWd1peI/CPR5uZOKr8Ur4G803dS4MGA2ZdbTm2hllecxjMGisS8InvLotchypnY01o5UQLaLH5AZ3o+xKgwJ4k4YSWHZdtaLoEfcAUmiwtXkMEdm1N3wApDqwujV0eU7mbD+aEkbeSAv/Tx0zltsY7BHJk7t5tkZKoro0L9PA+VjAgMBAAECggEACXfIBKzLQnPFsRKPJja2dP7BWuRw9P+WgSp2KAm9ZcJsL+o7WixL8NTRxXJe/YB7AZmN9Q0zjYKCxCmGHpBQjCsmUvOPTBI12OoEnL0MVjXmsj96SVTIwXNT/KaiyCWJXmGQnexPU655Vj+kb+uIaypqoUL18EaMcizzAW3/rJRFegZsFKEYbv8HjHkpaPzY8cP/T85DUz2ljI9E892CdmHeTPjRBnNTC/8G+rnC7HzPKGFbeNl0zJEY3gcfrqBvYox8C9eYOGbs62Q7xNFKw2rLw1ZWT89BWl3YcFCwKjVO97RoGoZVBfHQzu9zpBE2W2nEKuCs/8IrJ+BHdliHFQKBgQD19ZK+pAWdrZCw6aDUSi1sDMBUbb+m8abnewjR71kmef5Iy+HjDBEIIQQVozA02To13yn6RTR/l6GVkCzV6NWQDrWSjq/bHp88ZGoXGTS5e9It6kYKUifUPDzNXHD4YM1f5bUnfjR9Q4p9L1JwlTuajegEV6kt+INkDk5OpGhxtwKBgQDYaw0yTJM9ZZ6xvsA6WDghLBD/kkP18pRYi4CSVSQ0RA9g53iGQVp5ajH6uGhfrRoRH9Duueg8L6nnB95swNv1KAdBX7YXWXYAN13ZlkoEuiGE5MPfXvtWCuJYFSBuGa59tEFH8CDx/qJogX9p6qgrhE1/RpoZaUVR8WQzN/R5tQKBgCpeD9YI+PKtSCRBSjPnRyW6mJKyiPXf4Gk1V1KaURgAoZ22iQWMOY7V6Rc1EgO4e392Hov+yclEvE/AEwkR31++OOay73XmM3W6sk+iRuPAgXbpSQFUH4o+ihax2r/eJOJk9iyEX9RIAx4HPcMo4aiA0zuUQg8qJK/iivie92LHAoGBAJ7dFnbR11O+gNuhVqJ9l9Zh7qhsyg6E33iQDvVcIpNrUo6j28lRuARgfAe7zoJNI1FmtYxIU726j1HUaOYUPGKd25WVmKlTNle6TN4ogHUv3OMcutwBcSYcH0LhX0jy+S+1XiZckxKRBM8KIGmzo3cuS2UegogcypH4TMF8Va3xAoGAfOI8720x1waCqgj7uzOWo28xetRwDISBZUAJ0YrA2kIa365Lyu7VKbqcwjXXLygk2eQBBrn+LWaXHrDAINx5vOqrddlROPoYDtnzo+qE7KP9++mpB0HY32arLni2YCDi1E8dhTyD5tDWZyS600GjPadkWoPOOPivE9elv09CY3M=";
+
+/// Modulus of the test signing key, as base64url.
+const MODULUS: &str = "z-4OIvwQAYSuN95GyFsa7RLjgMpT779U8zNe4R9Vwedul8qiqBT_rqAoOljE1u8-apDaznjeBKqfpgG2Fqf6jReXLZXYw5tzotMi2eVpqvL-03pG18lj6wSn2l1fVSQz-rRD12q5nJ0wt41G6_62stI-gqyeaPVLtg7FT9pZS7VndaXiPwj0ebmTiq_FK-BvNN3UuDBgNmXW05toZZXnMYzBorEvCJ7y6LXIcqZ2NNaOVEC2ix-QGd6PsSoMCeJOGElh2XbWi6BH3AFJosLV5DBHZtTd8AKQ6sLo1dHlO5mw_mhJG3kgL_08dM5bbGOwRyZO7ebZGSqK6NC_TwPlYw";
+
+/// Serves the test signing key as a JSON Web Key Set at `/certs`, with an
+/// OpenID Connect discovery document, returning the base URL.
+pub fn serve_provider() -> String {
+    let jwks = json!({
+        "keys": [{ "kid": "test-key", "kty": "RSA", "alg": "RS256", "n": MODULUS, "e": "AQAB" }],
+    });
+    let certs = warp::path!("certs").map(move || warp::reply::json(&jwks));
+    let discovery = warp::path!(".well-known" / "openid-configuration")
+        .and(warp::header::<String>("host"))
+        .map(|host: String| {
+            let issuer = format!("http://{}", host);
+            let jwks_uri = format!("{}/certs", issuer);
+            warp::reply::json(&json!({ "issuer": issuer, "jwks_uri": jwks_uri }))
+        });
+    let (addr, serving) = warp::serve(certs.or(discovery)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serving);
+    format!("http://{}", addr)
+}
+
+/// Returns claims of a token for a user, expiring some seconds from now.
+pub fn claims(issuer: &str, audience: &str, email: &str, expires_in: i64) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time should be after the epoch")
+        .as_secs() as i64;
+    json!({
+        "aud": [audience],
+        "email": email,
+        "exp": now + expires_in,
+        "iat": now,
+        "iss": issuer,
+        "sub": format!("user-{}", email),
+    })
+}
+
+/// Signs a token with the test signing key.
+pub fn sign(claims: &Value) -> Result<String> {
+    let der = STANDARD.decode(PRIVATE_KEY)?;
+    let key = RsaKeyPair::from_pkcs8(&der).map_err(|e| anyhow!("{}", e))?;
+    let header = json!({ "alg": "RS256", "kid": "test-key", "typ": "JWT" });
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let mut signature = vec![0; key.public().modulus_len()];
+    key.sign(
+        &RSA_PKCS1_SHA256,
+        &SystemRandom::new(),
+        message.as_bytes(),
+        &mut signature,
+    )
+    .map_err(|e| anyhow!("{}", e))?;
+    Ok(format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature)))
+}
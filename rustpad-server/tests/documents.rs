@@ -0,0 +1,2088 @@
+//! Tests for the REST document management API.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    normalize::{Normalization, TrailingNewline},
+    server, ServerConfig,
+};
+use serde_json::{json, Value};
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_deleted_stays_deleted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "name": "doomed" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    let id = meta["id"].as_str().expect("id should be a string").to_owned();
+
+    let mut client = connect(&filter, &id).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/documents/{}", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+
+    assert!(connect(&filter, &id).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_text_with_revision() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "versioned").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["fn main() {}"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .path("/api/text/versioned?with_revision=true")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        body,
+        json!({
+            "text": "fn main() {}",
+            "revision": 1,
+            "language": "rust"
+        })
+    );
+
+    expect_text(&filter, "versioned", "fn main() {}").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_text_etag() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/polled")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request().path("/api/text/polled").reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    let etag = resp.headers()["etag"].to_str()?.to_owned();
+    assert_eq!(
+        etag,
+        "\"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\""
+    );
+
+    let get = |if_none_match: String| {
+        warp::test::request()
+            .path("/api/text/polled")
+            .header("if-none-match", if_none_match)
+            .reply(&filter)
+    };
+    let resp = get(etag.clone()).await;
+    assert_eq!(resp.status(), 304);
+    assert!(resp.body().is_empty());
+    let resp = get(format!("\"other\", W/{}", etag)).await;
+    assert_eq!(resp.status(), 304);
+
+    // The JSON representation has a tag of its own.
+    let resp = warp::test::request()
+        .path("/api/text/polled?with_revision=true")
+        .header("if-none-match", &etag)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/polled/append")
+        .body("!")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = get(etag.clone()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "hello!");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_text_range() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/log")
+        .body("line one\nline two\n")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let get = |range: &'static str| {
+        warp::test::request()
+            .path("/api/text/log")
+            .header("range", range)
+            .reply(&filter)
+    };
+
+    let resp = get("bytes=9-").await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers()["content-range"], "bytes 9-17/18");
+    assert_eq!(resp.body(), "line two\n");
+
+    let resp = get("bytes=-4").await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.body(), "two\n");
+
+    let resp = get("bytes=0-3").await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers()["content-range"], "bytes 0-3/18");
+    assert_eq!(resp.body(), "line");
+
+    let resp = get("bytes=5-100").await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.body(), "one\nline two\n");
+
+    let resp = get("bytes=0-18446744073709551615").await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers()["content-range"], "bytes 0-17/18");
+
+    let resp = get("bytes=18-").await;
+    assert_eq!(resp.status(), 416);
+    assert_eq!(resp.headers()["content-range"], "bytes */18");
+
+    // Multiple and malformed ranges return the whole text.
+    let resp = get("bytes=0-1,4-5").await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["accept-ranges"], "bytes");
+    assert_eq!(resp.body(), "line one\nline two\n");
+    let resp = get("lines=1-2").await;
+    assert_eq!(resp.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_warm_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/warmup/warm")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "already_loaded": false, "revision": 0 }));
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/warmup/warm")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "already_loaded": true, "revision": 0 }));
+
+    Ok(())
+}
+
+/// Create a document with the given name, returning its ID.
+async fn create_named(filter: &BoxedFilter<(impl Reply + 'static,)>, name: &str) -> String {
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "name": name }))
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body()).expect("invalid json");
+    meta["id"].as_str().expect("id should be a string").to_owned()
+}
+
+#[tokio::test]
+async fn test_document_feed() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut all = connect_feed(&filter, "").await?;
+    let outage = create_named(&filter, "Outage").await;
+    let other = create_named(&filter, "Other").await;
+    assert_eq!(all.recv().await?["document_id"], outage.as_str());
+    assert_eq!(all.recv().await?["document_id"], other.as_str());
+
+    let mut feed = connect_feed(&filter, "tag=incidents").await?;
+    let tags = format!("/api/documents/{}/tags", outage);
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&tags)
+        .json(&json!({ "tag": "incidents" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let event = feed.recv().await?;
+    assert_eq!(event["kind"], "updated");
+    assert_eq!(event["document_id"], outage.as_str());
+    assert_eq!(event["meta"]["tags"], json!(["incidents"]));
+
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path(&format!("/api/documents/{}", outage))
+        .json(&json!({ "name": "Outage resolved" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let event = feed.recv().await?;
+    assert_eq!(event["kind"], "renamed");
+    assert_eq!(event["meta"]["name"], "Outage resolved");
+
+    // Documents leaving the filter are sent once more.
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path(&tags)
+        .json(&json!({ "tag": "incidents" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let event = feed.recv().await?;
+    assert_eq!(event["kind"], "updated");
+    assert_eq!(event["meta"]["tags"], json!([]));
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/documents/{}", other))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    let event = feed.recv().await?;
+    assert_eq!(event["kind"], "deleted");
+    assert_eq!(event["document_id"], other.as_str());
+    assert_eq!(event["meta"], Value::Null);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_feed_patterns() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut team_a = connect_feed(&filter, "id=team-a-*").await?;
+    let mut renames = connect_feed(&filter, "id=team-a-*&kind=renamed").await?;
+    for id in ["team-b-notes", "team-a-notes"] {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/documents")
+            .json(&json!({ "id": id }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 201);
+    }
+    let event = team_a.recv().await?;
+    assert_eq!(event["kind"], "created");
+    assert_eq!(event["document_id"], "team-a-notes");
+
+    for id in ["team-b-notes", "team-a-notes"] {
+        let resp = warp::test::request()
+            .method("PATCH")
+            .path(&format!("/api/documents/{}", id))
+            .json(&json!({ "name": "Standup" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+    let event = renames.recv().await?;
+    assert_eq!(event["kind"], "renamed");
+    assert_eq!(event["document_id"], "team-a-notes");
+    assert_eq!(event["meta"]["name"], "Standup");
+
+    Ok(())
+}
+
+/// Names of the documents in a list response, in order.
+fn names(body: &[u8]) -> Vec<String> {
+    let list: Vec<Value> = serde_json::from_slice(body).expect("invalid json");
+    list.iter()
+        .map(|meta| meta["name"].as_str().unwrap_or_default().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_list_pagination() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    for name in ["banana", "apple", "cherry"] {
+        create_named(&filter, name).await;
+    }
+
+    let resp = warp::test::request()
+        .path("/api/documents?sort=name&limit=2")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-total-count"], "3");
+    assert_eq!(names(resp.body()), ["apple", "banana"]);
+
+    let resp = warp::test::request()
+        .path("/api/documents?sort=name&limit=2&offset=2")
+        .reply(&filter)
+        .await;
+    assert_eq!(names(resp.body()), ["cherry"]);
+
+    let resp = warp::test::request()
+        .path("/api/documents?sort=sideways")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tags() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let deploy = create_named(&filter, "deploy").await;
+    create_named(&filter, "notes").await;
+
+    for tag in ["ops", " scripts "] {
+        let resp = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/documents/{}/tags", deploy))
+            .json(&json!({ "tag": tag }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}", deploy))
+        .reply(&filter)
+        .await;
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["tags"], json!(["ops", "scripts"]));
+
+    let resp = warp::test::request()
+        .path("/api/documents?tag=ops")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.headers()["x-total-count"], "1");
+    assert_eq!(names(resp.body()), ["deploy"]);
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/documents/{}/tags", deploy))
+        .json(&json!({ "tag": "ops" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["tags"], json!(["scripts"]));
+
+    let resp = warp::test::request()
+        .path("/api/documents?tag=ops")
+        .reply(&filter)
+        .await;
+    assert!(names(resp.body()).is_empty());
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/documents/{}/tags", deploy))
+        .json(&json!({ "tag": "  " }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/missing/tags")
+        .json(&json!({ "tag": "ops" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_duplicate_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let id = create_named(&filter, "script").await;
+    let mut client = connect(&filter, &id).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+    client.send(&json!({ "SetLanguage": "shell" })).await;
+    client.recv().await?;
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["echo hi"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/documents/{}/duplicate", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["name"], "script (copy)");
+    assert_eq!(meta["language"], "shell");
+    let copy = meta["id"].as_str().expect("id should be a string");
+    assert_ne!(copy, id);
+    expect_text(&filter, copy, "echo hi").await;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/missing/duplicate")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trash_and_restore() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let id = create_named(&filter, "oops").await;
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/documents/{}", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+
+    let resp = warp::test::request().path("/api/trash").reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    let trash: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(trash[0]["id"], id.as_str());
+    assert!(trash[0]["deleted_at"].is_i64());
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/documents/{}/restore", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["name"], "oops");
+
+    let resp = warp::test::request().path("/api/trash").reply(&filter).await;
+    assert_eq!(resp.body(), "[]");
+    let mut client = connect(&filter, &id).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/documents/{}/restore", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bulk_operations() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let first = create_named(&filter, "first").await;
+    let second = create_named(&filter, "second").await;
+    let ids = json!([first, second, "missing"]);
+
+    let bulk = |body: Value| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/documents/bulk")
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let resp = bulk(json!({ "action": "rename-prefix", "prefix": "old ", "ids": ids })).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"changed":2}"#);
+
+    let resp = bulk(json!({ "action": "set-language", "language": "rust", "ids": ids })).await;
+    assert_eq!(resp.body(), r#"{"changed":2}"#);
+
+    let resp = warp::test::request()
+        .path("/api/documents?sort=name")
+        .reply(&filter)
+        .await;
+    assert_eq!(names(resp.body()), ["old first", "old second"]);
+    let list: Vec<Value> = serde_json::from_slice(resp.body())?;
+    assert!(list.iter().all(|meta| meta["language"] == "rust"));
+
+    let resp = bulk(json!({ "action": "delete", "ids": ids })).await;
+    assert_eq!(resp.body(), r#"{"changed":2}"#);
+    let resp = warp::test::request().path("/api/documents").reply(&filter).await;
+    assert_eq!(resp.headers()["x-total-count"], "0");
+
+    let resp = bulk(json!({ "action": "restore", "ids": [first] })).await;
+    assert_eq!(resp.body(), r#"{"changed":1}"#);
+
+    let resp = bulk(json!({ "action": "explode", "ids": ids })).await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import_documents() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let body = "--BOUNDARY\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"main.rs\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        fn main() {}\r\n\
+        --BOUNDARY\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"notes\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        remember the milk\r\n\
+        --BOUNDARY--\r\n";
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/import")
+        .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+        .body(body)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let metas: Vec<Value> = serde_json::from_slice(resp.body())?;
+    assert_eq!(metas.len(), 2);
+    assert_eq!(metas[0]["name"], "main.rs");
+    assert_eq!(metas[0]["language"], "rust");
+    assert_eq!(metas[1]["language"], Value::Null);
+
+    let id = metas[0]["id"].as_str().expect("id should be a string");
+    expect_text(&filter, id, "fn main() {}").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let notes = create_named(&filter, "Notes: draft").await;
+    let script = create_named(&filter, "main.rs").await;
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/bulk")
+        .json(&json!({ "action": "set-language", "language": "rust", "ids": [script] }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let download = |id: &str| {
+        warp::test::request()
+            .path(&format!("/api/documents/{}/download", id))
+            .reply(&filter)
+    };
+
+    let resp = download(&notes).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/plain; charset=utf-8");
+    assert_eq!(
+        resp.headers()["content-disposition"],
+        "attachment; filename=\"Notes_ draft.txt\"; filename*=UTF-8''Notes_%20draft.txt"
+    );
+
+    let resp = download(&script).await;
+    assert_eq!(
+        resp.headers()["content-disposition"],
+        "attachment; filename=\"main.rs\"; filename*=UTF-8''main.rs"
+    );
+
+    let resp = download("missing").await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_raw_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/blob")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let hash = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    let resp = warp::test::request()
+        .path("/api/documents/blob/raw?hash=sha256")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-content-hash"], hash);
+    assert_eq!(resp.body(), "hello");
+
+    let resp = warp::test::request()
+        .method("HEAD")
+        .path("/api/documents/blob/raw")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-content-hash"], hash);
+    assert!(resp.body().is_empty());
+
+    let resp = warp::test::request()
+        .path("/api/documents/blob/raw?hash=md5")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/raw")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_stats() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "counted").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/counted")
+        .body("héllo wörld\nx")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .path("/api/documents/counted/stats")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        stats,
+        json!({
+            "chars": 13,
+            "bytes": 15,
+            "words": 3,
+            "lines": 2,
+            "revision": 1,
+            "users": 1,
+        })
+    );
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/stats")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_session() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "meeting").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let alice = json!({ "name": "Alice", "hue": 42 });
+    client.send(&json!({ "ClientInfo": alice })).await;
+    client.recv().await?;
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    let resp = warp::test::request()
+        .path("/api/documents/meeting/session")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let session: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        session,
+        json!({
+            "users": [{ "id": 0, "name": "Alice", "hue": 42 }],
+            "revision": 0,
+            "language": "rust",
+        })
+    );
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/session")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rfc3339_timestamps() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let id = create_named(&filter, "dated").await;
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}", id))
+        .reply(&filter)
+        .await;
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert!(meta["created_at"].is_i64());
+    let created = meta["created_at_rfc3339"].as_str().expect("missing timestamp");
+    assert_eq!(created.len(), "1970-01-01T00:00:00Z".len());
+    assert!(created.ends_with('Z'));
+
+    let mut config = test_config().await;
+    config.rfc3339_timestamps = false;
+    let filter = server(config);
+    let id = create_named(&filter, "epoch only").await;
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}", id))
+        .reply(&filter)
+        .await;
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert!(meta["created_at"].is_i64());
+    assert!(meta.get("created_at_rfc3339").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_metadata() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let id = create_named(&filter, "interview").await;
+    let path = format!("/api/documents/{}/metadata", id);
+    let patch = |body: Value| {
+        warp::test::request()
+            .method("PATCH")
+            .path(&path)
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let resp = warp::test::request().path(&path).reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "{}");
+
+    let resp = patch(json!({ "ticket": "ENG-42", "candidate": { "name": "Sam" } })).await;
+    assert_eq!(resp.status(), 200);
+    let resp = patch(json!({ "ticket": null, "candidate": { "round": 2 } })).await;
+    assert_eq!(resp.status(), 200);
+    let metadata: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(metadata, json!({ "candidate": { "name": "Sam", "round": 2 } }));
+
+    let resp = warp::test::request().path(&path).reply(&filter).await;
+    let stored: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stored, metadata);
+
+    let resp = patch(json!(["not", "an", "object"])).await;
+    assert_eq!(resp.status(), 400);
+
+    let chunk = "x".repeat(10 * 1024);
+    assert_eq!(patch(json!({ "a": chunk })).await.status(), 200);
+    assert_eq!(patch(json!({ "b": chunk })).await.status(), 413);
+    let resp = warp::test::request().path(&path).reply(&filter).await;
+    let stored: Value = serde_json::from_slice(resp.body())?;
+    assert!(stored.get("b").is_none());
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/metadata")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_password() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let id = create_named(&filter, "secret").await;
+    let patch = |body: Value| {
+        warp::test::request()
+            .method("PATCH")
+            .path(&format!("/api/documents/{}", id))
+            .json(&body)
+            .reply(&filter)
+    };
+    assert_eq!(patch(json!({ "password": "" })).await.status(), 400);
+    assert_eq!(patch(json!({ "password": "hunter2" })).await.status(), 200);
+
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert!(!String::from_utf8_lossy(resp.body()).contains("pbkdf2"));
+
+    let mut client = connect(&filter, &id).await?;
+    client.send(&json!({ "SetColor": 0 })).await;
+    assert_eq!(client.recv().await?["Error"]["code"], "password_required");
+    client.recv_closed().await?;
+
+    let mut client = connect(&filter, &id).await?;
+    client.send(&json!({ "Password": "wrong" })).await;
+    assert_eq!(client.recv().await?["Error"]["code"], "invalid_password");
+    client.recv_closed().await?;
+
+    assert!(connect(&filter, &format!("{}?password=wrong", id))
+        .await
+        .is_err());
+    let mut client = connect(&filter, &format!("{}?password=hunter2", id)).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let mut client = connect(&filter, &id).await?;
+    client.send(&json!({ "Password": "hunter2" })).await;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+
+    // Reading or editing the text over REST needs the password as well.
+    let text = format!("/api/text/{}", id);
+    let resp = warp::test::request().path(&text).reply(&filter).await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}/raw?password=wrong", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path(&text)
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path(&format!("{}?password=hunter2", text))
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path(&text)
+        .header("x-document-password", "hunter2")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "hello");
+
+    // So does publishing it, which would make the text public.
+    let publish = format!("/api/documents/{}/publish", id);
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&publish)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&publish)
+        .header("x-document-password", "hunter2")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    assert_eq!(patch(json!({ "password": null })).await.status(), 200);
+    let mut client = connect(&filter, &id).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 2 }));
+
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/documents/missing")
+        .json(&json!({ "password": "hunter2" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let markdown = "# Title\n\n<script>alert(1)</script>\n\n[link](javascript:alert(1)) [ok](https://example.com)\n";
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/import")
+        .header("content-type", "multipart/form-data; boundary=X")
+        .body(format!(
+            "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.md\"\r\n\r\n{}\r\n--X--\r\n",
+            markdown
+        ))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let metas: Vec<Value> = serde_json::from_slice(resp.body())?;
+    let id = metas[0]["id"].as_str().expect("id should be a string");
+
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}/export?format=html", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+    let html = std::str::from_utf8(resp.body())?;
+    assert!(html.contains("<h1>Title</h1>"));
+    assert!(html.contains("&lt;script&gt;"));
+    assert!(!html.contains("<script>"));
+    assert!(!html.contains("javascript:"));
+    assert!(html.contains(r#"<a href="https://example.com">ok</a>"#));
+
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}/export?format=markdown", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.headers()["content-type"], "text/markdown; charset=utf-8");
+    assert_eq!(resp.body(), markdown);
+
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}/export?format=docx", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_normalization() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        normalization: Normalization {
+            strip_bom: true,
+            tab_width: Some(4),
+            trailing_newline: TrailingNewline::Ensure,
+        },
+        ..test_config().await
+    });
+
+    let text = "\u{feff}fn main() {\n\tok();\n}";
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/source")
+        .body(text)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .path("/api/documents/source/export?format=markdown")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "fn main() {\n    ok();\n}\n");
+    let resp = warp::test::request()
+        .path("/api/documents/source/download")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.body(), "fn main() {\n    ok();\n}\n");
+
+    // The live text is left as it was edited.
+    expect_text(&filter, "source", text).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_pdf() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let text: String = (1..=100).map(|i| format!("line {} (of 100)\n", i)).collect();
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/import")
+        .header("content-type", "multipart/form-data; boundary=X")
+        .body(format!(
+            "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"long.txt\"\r\n\r\n{}\r\n--X--\r\n",
+            text
+        ))
+        .reply(&filter)
+        .await;
+    let metas: Vec<Value> = serde_json::from_slice(resp.body())?;
+    let id = metas[0]["id"].as_str().expect("id should be a string");
+
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}/export?format=pdf", id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/pdf");
+    let pdf = String::from_utf8_lossy(resp.body());
+    assert!(pdf.starts_with("%PDF-1.4"));
+    assert!(pdf.ends_with("%%EOF\n"));
+    assert!(pdf.contains("/Count 2"));
+    assert!(pdf.contains(r"(line 42 \(of 100\)) Tj"));
+
+    // The cross-reference table must be where the trailer says it is.
+    let body = resp.body();
+    let startxref = pdf.rfind("startxref\n").expect("missing startxref");
+    let offset: usize = pdf[startxref + 10..].lines().next().unwrap_or_default().parse()?;
+    assert!(body[offset..].starts_with(b"xref"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_by_metadata() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let patch = |id: String, body: Value| {
+        warp::test::request()
+            .method("PATCH")
+            .path(&format!("/api/documents/{}/metadata", id))
+            .json(&body)
+            .reply(&filter)
+    };
+    let first = create_named(&filter, "first").await;
+    let second = create_named(&filter, "second").await;
+    create_named(&filter, "third").await;
+    patch(first, json!({ "ticket": "ENG-42", "candidate": { "round": 2 } })).await;
+    patch(second.clone(), json!({ "ticket": "ENG-42", "candidate": { "round": 1 } })).await;
+
+    let list = |query: &str| {
+        warp::test::request()
+            .path(&format!("/api/documents?sort=name&{}", query))
+            .reply(&filter)
+    };
+    let resp = list("meta.ticket=ENG-42").await;
+    assert_eq!(names(resp.body()), ["first", "second"]);
+    let resp = list("meta.ticket=ENG-42&meta.candidate.round=2").await;
+    assert_eq!(names(resp.body()), ["first"]);
+    assert_eq!(resp.headers()["x-total-count"], "1");
+
+    patch(second, json!({ "ticket": null })).await;
+    let resp = list("meta.ticket=ENG-42").await;
+    assert_eq!(names(resp.body()), ["first"]);
+
+    let resp = list("meta.bad%20key=1").await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_relations() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let old = create_named(&filter, "old").await;
+    let new = create_named(&filter, "new").await;
+    let relation = |method: &str, id: &str, body: Value| {
+        warp::test::request()
+            .method(method)
+            .path(&format!("/api/documents/{}/relations", id))
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let resp = relation("POST", &new, json!({ "kind": "supersedes", "target": old })).await;
+    assert_eq!(resp.status(), 200);
+    let details: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(details["name"], "new");
+    assert_eq!(
+        details["relations"],
+        json!([{ "kind": "supersedes", "document_id": old, "direction": "outgoing" }])
+    );
+
+    let resp = warp::test::request()
+        .path(&format!("/api/documents/{}", old))
+        .reply(&filter)
+        .await;
+    let details: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        details["relations"],
+        json!([{ "kind": "supersedes", "document_id": new, "direction": "incoming" }])
+    );
+
+    let resp = relation("POST", &new, json!({ "kind": "supersedes", "target": new })).await;
+    assert_eq!(resp.status(), 400);
+    let resp = relation("POST", &new, json!({ "kind": "supersedes", "target": "missing" })).await;
+    assert_eq!(resp.status(), 400);
+    let resp = relation("POST", &new, json!({ "kind": "parent-of", "target": old })).await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = relation("DELETE", &new, json!({ "kind": "supersedes", "target": old })).await;
+    assert_eq!(resp.status(), 200);
+    let details: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(details["relations"], json!([]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replace_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "scripted").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["draft"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/scripted")
+        .header("cf-access-authenticated-user-email", "bot@example.com")
+        .body("final")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":2}"#);
+
+    let msg = client.recv().await?;
+    assert_eq!(msg["History"]["start"], 1);
+    let operation = &msg["History"]["operations"][0];
+    assert_eq!(operation["operation"], json!([-5, "final"]));
+    assert_eq!(operation["email"], "bot@example.com");
+    expect_text(&filter, "scripted", "final").await;
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/scripted")
+        .body("a".repeat(300 * 1024))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 413);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_append_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "ci-log").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    for (line, revision) in [("building\n", 1), ("tests passed\n", 2)] {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/text/ci-log/append")
+            .body(line)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.body(), &format!(r#"{{"revision":{}}}"#, revision));
+    }
+
+    // Both appends may arrive in a single history message.
+    let mut operations = Vec::new();
+    while operations.len() < 2 {
+        let msg = client.recv().await?;
+        for operation in msg["History"]["operations"].as_array().expect("expected history") {
+            operations.push(operation["operation"].clone());
+        }
+    }
+    assert_eq!(operations, [json!(["building\n"]), json!([9, "tests passed\n"])]);
+    expect_text(&filter, "ci-log", "building\ntests passed\n").await;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/ci-log/append")
+        .body("a".repeat(256 * 1024))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 413);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_external_edit_transforms() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "shared").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["abc"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/shared/append")
+        .body("!")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":2}"#);
+
+    // The client edits before seeing the append, so its edit is transformed.
+    let msg = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": [1, "X", 2]
+        }
+    });
+    client.send(&msg).await;
+
+    let mut operations = Vec::new();
+    while operations.len() < 2 {
+        let msg = client.recv().await?;
+        for operation in msg["History"]["operations"].as_array().expect("expected history") {
+            operations.push(operation["operation"].clone());
+        }
+    }
+    assert_eq!(operations, [json!([3, "!"]), json!([1, "X", 3])]);
+    expect_text(&filter, "shared", "aXbc!").await;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/shared/lines")
+        .json(&json!({ "op": "insert", "line": 2, "text": "end" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":4}"#);
+    expect_text(&filter, "shared", "aXbc!\nend").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_with_custom_id() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let create = |body: Value| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/documents")
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let resp = create(json!({ "id": "standup-notes", "name": "Standup" })).await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["id"], "standup-notes");
+    assert_eq!(meta["name"], "Standup");
+
+    let resp = create(json!({ "id": "standup-notes" })).await;
+    assert_eq!(resp.status(), 409);
+
+    let long = "x".repeat(65);
+    for id in ["", "a/b", "spaces here", "ünïcode", long.as_str()] {
+        let resp = create(json!({ "id": id })).await;
+        assert_eq!(resp.status(), 400, "{:?}", id);
+    }
+
+    let mut client = connect(&filter, "live_only").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let resp = create(json!({ "id": "live_only" })).await;
+    assert_eq!(resp.status(), 409);
+
+    let resp = create(json!({})).await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["id"].as_str().map(str::len), Some(6));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_patch_document_metadata() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "id": "pad" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["metadata"], json!({}));
+
+    let patch = |body: Value| {
+        warp::test::request()
+            .method("PATCH")
+            .path("/api/documents/pad")
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let resp = patch(json!({
+        "name": "Standup",
+        "metadata": { "ticket": "ENG-42", "owner": "alice" }
+    }))
+    .await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["name"], "Standup");
+    assert_eq!(meta["metadata"], json!({ "ticket": "ENG-42", "owner": "alice" }));
+
+    let resp = patch(json!({ "metadata": { "owner": null } })).await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["name"], "Standup");
+    assert_eq!(meta["metadata"], json!({ "ticket": "ENG-42" }));
+
+    let resp = patch(json!({ "name": "Ignored", "metadata": ["not", "an", "object"] })).await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = warp::test::request().path("/api/documents").reply(&filter).await;
+    let documents: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(documents[0]["name"], "Standup");
+    assert_eq!(documents[0]["metadata"], json!({ "ticket": "ENG-42" }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_edit_lines() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/todo")
+        .body("one\ntwo\nthree")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let edit = |body: Value| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/documents/todo/lines")
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let resp = edit(json!({ "op": "insert", "line": 2, "text": "one and a half" })).await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "todo", "one\none and a half\ntwo\nthree").await;
+
+    let resp = edit(json!({ "op": "replace", "line": 3, "text": "TWO" })).await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "todo", "one\none and a half\nTWO\nthree").await;
+
+    let resp = edit(json!({ "op": "insert", "line": 5, "text": "four" })).await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "todo", "one\none and a half\nTWO\nthree\nfour").await;
+
+    let resp = edit(json!({ "op": "delete", "line": 1, "count": 2 })).await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "todo", "TWO\nthree\nfour").await;
+
+    let resp = edit(json!({ "op": "delete", "line": 3 })).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":6}"#);
+    expect_text(&filter, "todo", "TWO\nthree").await;
+
+    for body in [
+        json!({ "op": "insert", "line": 0, "text": "zero" }),
+        json!({ "op": "insert", "line": 4, "text": "far" }),
+        json!({ "op": "replace", "line": 3, "text": "missing" }),
+        json!({ "op": "delete", "line": 2, "count": 2 }),
+        json!({ "op": "delete", "line": 1, "count": 0 }),
+    ] {
+        let resp = edit(body.clone()).await;
+        assert_eq!(resp.status(), 400, "{}", body);
+    }
+    expect_text(&filter, "todo", "TWO\nthree").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_starred_documents() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    for id in ["alpha", "beta"] {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/documents")
+            .json(&json!({ "id": id }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 201);
+    }
+
+    let star = |method: &'static str, id: &str, email: Option<&str>| {
+        let mut request = warp::test::request()
+            .method(method)
+            .path(&format!("/api/documents/{}/star", id));
+        if let Some(email) = email {
+            request = request.header("cf-access-authenticated-user-email", email);
+        }
+        request.reply(&filter)
+    };
+    let starred = |email: Option<&str>| {
+        let mut request = warp::test::request().path("/api/documents?starred=true");
+        if let Some(email) = email {
+            request = request.header("cf-access-authenticated-user-email", email);
+        }
+        request.reply(&filter)
+    };
+
+    assert_eq!(star("PUT", "alpha", None).await.status(), 401);
+    assert_eq!(star("PUT", "missing", Some("ann@example.com")).await.status(), 404);
+    assert_eq!(star("PUT", "alpha", Some("ann@example.com")).await.status(), 204);
+    assert_eq!(star("PUT", "alpha", Some("ann@example.com")).await.status(), 204);
+    assert_eq!(star("PUT", "beta", Some("bob@example.com")).await.status(), 204);
+
+    let resp = starred(Some("ann@example.com")).await;
+    assert_eq!(resp.status(), 200);
+    let documents: Value = serde_json::from_slice(resp.body())?;
+    let ids: Vec<&str> = documents
+        .as_array()
+        .expect("expected a list")
+        .iter()
+        .filter_map(|meta| meta["id"].as_str())
+        .collect();
+    assert_eq!(ids, ["alpha"]);
+    assert_eq!(starred(None).await.status(), 401);
+
+    assert_eq!(star("DELETE", "alpha", Some("ann@example.com")).await.status(), 204);
+    let resp = starred(Some("ann@example.com")).await;
+    assert_eq!(resp.body(), "[]");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_apply_patch() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/notes")
+        .body("one\ntwo\nthree\n")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let patch = |diff: &'static str| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/documents/notes/patch")
+            .body(diff)
+            .reply(&filter)
+    };
+
+    let resp = patch(
+        "--- a/notes.txt\n+++ b/notes.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n",
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":2}"#);
+    expect_text(&filter, "notes", "one\nTWO\nthree\n").await;
+
+    let resp = patch("@@ -3 +3,2 @@\n three\n+four\n\\ No newline at end of file\n").await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "notes", "one\nTWO\nthree\nfour").await;
+
+    let resp = patch("@@ -1 +1 @@\n-one\n+ONE\n@@ -2 +2 @@\n-two\n+2\n").await;
+    assert_eq!(resp.status(), 409);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "failed_hunks": [2] }));
+    expect_text(&filter, "notes", "one\nTWO\nthree\nfour").await;
+
+    for diff in ["not a diff", "@@ -1,2 +1,2 @@\n one\n", "@@ bogus @@\n"] {
+        let resp = patch(diff).await;
+        assert_eq!(resp.status(), 400, "{:?}", diff);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_revert_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/notes")
+        .body("one")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    for line in [" two", " three"] {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/text/notes/append")
+            .body(line)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+    expect_text(&filter, "notes", "one two three").await;
+
+    let revert = |revision: usize| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/documents/notes/revert")
+            .json(&json!({ "revision": revision }))
+            .reply(&filter)
+    };
+
+    let resp = revert(2).await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["revision"], 4);
+    expect_text(&filter, "notes", "one two").await;
+
+    // Reverting undoes the revert as well.
+    let resp = revert(3).await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "notes", "one two three").await;
+
+    let resp = revert(9).await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_diff() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    for text in ["one\ntwo\nthree\n", "one\n2\nthree\n"] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/text/notes")
+            .body(text)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    let resp = warp::test::request()
+        .path("/api/documents/notes/diff?from=1&to=2")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.body(),
+        "--- notes@1\n+++ notes@2\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n"
+    );
+
+    let resp = warp::test::request()
+        .path("/api/documents/notes/diff?from=2")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "");
+
+    let resp = warp::test::request()
+        .path("/api/documents/notes/diff?from=2&to=1")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/diff?from=0")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_blame() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/notes")
+        .body("one\n")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/notes/append")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .body("two\nthree")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .path("/api/documents/notes/blame")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let blame: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        blame,
+        json!({
+            "revision": 2,
+            "lines": [
+                {
+                    "line": 1,
+                    "revision": 1,
+                    "email": null,
+                    "source": "programmatic",
+                },
+                {
+                    "line": 2,
+                    "revision": 2,
+                    "email": "alice@example.com",
+                    "source": "programmatic",
+                },
+                {
+                    "line": 3,
+                    "revision": 2,
+                    "email": "alice@example.com",
+                    "source": "programmatic",
+                },
+            ],
+        })
+    );
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/blame")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_publish_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .path("/api/published/notes")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/notes")
+        .body("# First")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/notes/publish")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["revision"], 1);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/notes")
+        .body("# Draft")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "notes", "# Draft").await;
+
+    let resp = warp::test::request()
+        .path("/api/published/notes")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "# First");
+    let resp = warp::test::request()
+        .path("/api/published/notes?with_revision=true")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["revision"], 1);
+    assert_eq!(body["text"], "# First");
+    let resp = warp::test::request()
+        .path("/api/published/notes/embed")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert!(std::str::from_utf8(resp.body())?.contains("First"));
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/notes/publish")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path("/api/published/notes")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.body(), "# Draft");
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/missing/publish")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_publish_schedule() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    for id in ["announcement", "expiring"] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/text/{}", id))
+            .body("Hello")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!(
+            "/api/documents/announcement/publish?publish_at={}&unpublish_at={}",
+            now + 60,
+            now + 30,
+        ))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!(
+            "/api/documents/announcement/publish?publish_at={}",
+            now + 1
+        ))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 202);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["revision"], Value::Null);
+    assert_eq!(body["publish_at"], now + 1);
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!(
+            "/api/documents/expiring/publish?unpublish_at={}",
+            now + 2
+        ))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/announcement")
+        .body("Hello, world")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path("/api/published/announcement")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+    let resp = warp::test::request()
+        .path("/api/published/expiring")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Wait for the scheduled times to pass, then let the scheduler run.
+    time::sleep(Duration::from_millis(2100)).await;
+    time::pause();
+    time::advance(Duration::from_secs(30)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(150)).await;
+
+    let resp = warp::test::request()
+        .path("/api/published/announcement")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "Hello, world");
+    let resp = warp::test::request()
+        .path("/api/published/expiring")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_archive_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "id": "retro" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/retro")
+        .body("went well")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let mut client = connect(&filter, "retro").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+    client.recv().await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/retro/archive")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert!(meta["archived_at"].as_i64().is_some());
+    assert_eq!(client.recv().await?, json!({ "ReadOnly": true }));
+
+    let list = |query: &'static str| {
+        warp::test::request()
+            .path(&format!("/api/documents{}", query))
+            .reply(&filter)
+    };
+    let documents: Value = serde_json::from_slice(list("").await.body())?;
+    assert_eq!(documents, json!([]));
+    let documents: Value = serde_json::from_slice(list("?archived=only").await.body())?;
+    assert_eq!(documents[0]["id"], "retro");
+
+    // Edits are undone by resynchronizing the client, and scripts are refused.
+    let msg = json!({
+        "Edit": {
+            "revision": 2,
+            "operation": [9, "!"]
+        }
+    });
+    client.send(&msg).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "Resync": { "text": "went well", "revision": 2 } })
+    );
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/retro/append")
+        .body("!")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 409);
+    expect_text(&filter, "retro", "went well").await;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/retro/unarchive")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let meta: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(meta["archived_at"], Value::Null);
+    assert_eq!(client.recv().await?, json!({ "ReadOnly": false }));
+
+    client.send(&msg).await;
+    client.recv().await?;
+    expect_text(&filter, "retro", "went well!").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_settings() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "id": "configured" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+
+    let resp = warp::test::request()
+        .path("/api/documents/configured/settings")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let settings: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        settings,
+        json!({
+            "read_only": false,
+            "max_size": null,
+            "language_locked": false,
+            "expires_at": null,
+            "normalize_newlines": false,
+        })
+    );
+
+    let mut client = connect(&filter, "configured").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+    client.recv().await?;
+
+    let patch = |body: Value| {
+        warp::test::request()
+            .method("PATCH")
+            .path("/api/documents/configured/settings")
+            .json(&body)
+            .reply(&filter)
+    };
+    let resp = patch(json!({ "max_size": 8, "language_locked": true, "normalize_newlines": true }))
+        .await;
+    assert_eq!(resp.status(), 200);
+    let settings: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(settings["max_size"], 8);
+    assert_eq!(client.recv().await?, json!({ "Settings": settings }));
+
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/configured")
+        .body("a\r\nb")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let msg = client.recv().await?;
+    assert_eq!(msg["History"]["operations"][0]["operation"], json!(["a\nb"]));
+    expect_text(&filter, "configured", "a\nb").await;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/configured/append")
+        .body("0123456789")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 413);
+
+    let resp = patch(json!({ "max_size": null, "read_only": true })).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(client.recv().await?, json!({ "ReadOnly": true }));
+    let msg = client.recv().await?;
+    assert_eq!(msg["Settings"]["max_size"], Value::Null);
+    assert_eq!(msg["Settings"]["read_only"], true);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/configured/append")
+        .body("!")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 409);
+
+    let resp = patch(json!({ "read_only": "yes" })).await;
+    assert_eq!(resp.status(), 400);
+    let resp = patch(json!({ "colour": "red" })).await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/documents/missing/settings")
+        .json(&json!({ "read_only": true }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! Tests for organizing documents into folders.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::server;
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Send a JSON request and return the response status and body.
+async fn request(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    method: &str,
+    path: &str,
+    body: Value,
+) -> (u16, Value) {
+    let resp = warp::test::request()
+        .method(method)
+        .path(path)
+        .json(&body)
+        .reply(filter)
+        .await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+#[tokio::test]
+async fn test_folders() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let (status, projects) = request(
+        &filter,
+        "POST",
+        "/api/folders",
+        json!({ "name": "projects" }),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(projects["parent_id"], Value::Null);
+    let projects = projects["id"].as_i64().unwrap();
+
+    let body = json!({ "name": "rustpad", "parent_id": projects });
+    let (status, rustpad) = request(&filter, "POST", "/api/folders", body).await;
+    assert_eq!(status, 201);
+    let rustpad = rustpad["id"].as_i64().unwrap();
+
+    // A folder cannot be moved inside itself.
+    let body = json!({ "name": "projects", "parent_id": rustpad });
+    let path = format!("/api/folders/{}", projects);
+    assert_eq!(request(&filter, "PUT", &path, body).await.0, 400);
+
+    let (_, doc) = request(&filter, "POST", "/api/documents", json!({ "name": "plan" })).await;
+    let doc = doc["id"].as_str().unwrap().to_owned();
+    let path = format!("/api/documents/{}/folder", doc);
+    let (status, meta) = request(&filter, "PUT", &path, json!({ "folder_id": rustpad })).await;
+    assert_eq!(status, 200);
+    assert_eq!(meta["folder_id"], rustpad);
+    assert_eq!(
+        request(&filter, "PUT", &path, json!({ "folder_id": 999 }))
+            .await
+            .0,
+        400
+    );
+
+    let resp = warp::test::request()
+        .path(&format!("/api/documents?folder={}", rustpad))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.headers()["x-total-count"], "1");
+
+    let path = format!("/api/folders/{}", rustpad);
+    assert_eq!(request(&filter, "DELETE", &path, Value::Null).await.0, 409);
+    let doc_path = format!("/api/documents/{}/folder", doc);
+    request(&filter, "PUT", &doc_path, json!({ "folder_id": null })).await;
+    assert_eq!(request(&filter, "DELETE", &path, Value::Null).await.0, 204);
+    assert_eq!(request(&filter, "GET", &path, Value::Null).await.0, 404);
+
+    let (status, folders) = request(&filter, "GET", "/api/folders", Value::Null).await;
+    assert_eq!(status, 200);
+    assert_eq!(folders.as_array().unwrap().len(), 1);
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Tests for requiring bearer tokens from an OpenID Connect provider.
+
+use anyhow::Result;
+use common::tokens::{claims, serve_provider, sign};
+use common::*;
+use rustpad_server::{oidc::OidcConfig, server, ServerConfig};
+use serde_json::{json, Value};
+
+pub mod common;
+
+const AUDIENCE: &str = "rustpad";
+
+#[tokio::test]
+async fn test_oidc_tokens() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let issuer = serve_provider();
+    let filter = server(ServerConfig {
+        oidc: Some(OidcConfig {
+            issuer: issuer.clone(),
+            audience: AUDIENCE.into(),
+        }),
+        ..test_config().await
+    });
+
+    let resp = warp::test::request()
+        .path("/api/text/notes")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()["www-authenticate"], "Bearer");
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["code"], "unauthorized");
+
+    let resp = warp::test::request()
+        .path("/api/capabilities")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["auth"], "oidc");
+
+    let alice = sign(&claims(&issuer, AUDIENCE, "alice@example.com", 300))?;
+    let resp = warp::test::request()
+        .path("/api/text/notes")
+        .header("authorization", format!("Bearer {}", alice))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let authorization = format!("Bearer {}", alice);
+    let headers = [("authorization", authorization.as_str())];
+    let mut client = connect_with_headers(&filter, "notes", &headers).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(
+        client.recv().await?,
+        json!({ "AuthenticatedEmail": "alice@example.com" })
+    );
+
+    // Browsers can't set headers on WebSockets, so they pass the token in
+    // the query string instead.
+    let mut client = connect(&filter, &format!("notes?access_token={}", alice)).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(
+        client.recv().await?,
+        json!({ "AuthenticatedEmail": "alice@example.com" })
+    );
+    assert!(connect(&filter, "notes").await.is_err());
+
+    let expired = sign(&claims(&issuer, AUDIENCE, "alice@example.com", -3600))?;
+    let other_issuer = sign(&claims(
+        "https://example.com",
+        AUDIENCE,
+        "alice@example.com",
+        300,
+    ))?;
+    for token in [expired, other_issuer, "not a token".into()] {
+        let resp = warp::test::request()
+            .path("/api/text/notes")
+            .header("authorization", format!("Bearer {}", token))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    Ok(())
+}
@@ -6,10 +6,14 @@ use anyhow::Result;
 use common::*;
 use operational_transform::OperationSeq;
 use rustpad_server::{
-    database::{Database, PersistedDocument},
-    server, ServerConfig,
+    database::{
+        Database, EditSource, ListOptions, NewDocument, PersistedDocument, Role, SortOrder,
+        StoredOperation,
+    },
+    normalize::Normalization,
+    server, ServerConfig, VersionPolicy,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use tempfile::NamedTempFile;
 use tokio::time;
 
@@ -59,13 +63,114 @@ async fn test_database() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_create_many_atomic() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+
+    let first = NewDocument {
+        id: "first".into(),
+        name: Some("First".into()),
+        text: "one".into(),
+        language: Some("markdown".into()),
+    };
+    let second = NewDocument {
+        id: "second".into(),
+        text: "two".into(),
+        ..Default::default()
+    };
+    let metas = database.create_many(&[first, second.clone()]).await?;
+    assert_eq!(metas.len(), 2);
+    assert_eq!(database.load("first").await?.text, "one");
+    assert_eq!(database.load("second").await?.text, "two");
+
+    // The duplicate id aborts the batch, so "third" must not be created.
+    let third = NewDocument {
+        id: "third".into(),
+        ..Default::default()
+    };
+    assert!(database.create_many(&[third, second]).await.is_err());
+    assert!(database.load("third").await.is_err());
+    assert_eq!(database.count().await?, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_outbox_events() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+
+    database.create("outboxed", Some("Outboxed"), None).await?;
+    database.rename("outboxed", "Renamed").await?;
+    assert!(database.rename("missing", "Nothing").await.is_err());
+    database.soft_delete("outboxed").await?;
+
+    let events = database.events_after(0, 10).await?;
+    let kinds: Vec<&str> = events.iter().map(|event| event.kind.as_str()).collect();
+    assert_eq!(kinds, ["created", "renamed", "deleted"]);
+    assert!(events.iter().all(|event| event.document_id == "outboxed"));
+    assert_eq!(database.events_after(events[1].id, 10).await?.len(), 1);
+
+    // Events are delivered once every durable consumer acknowledges them.
+    assert_eq!(database.consumer_cursor("search").await?, 0);
+    assert_eq!(database.consumer_cursor("audit").await?, 0);
+    database.ack_events("search", events[1].id).await?;
+    database.ack_events("audit", events[2].id).await?;
+    database.ack_events("audit", events[0].id).await?;
+    assert_eq!(database.consumer_cursor("audit").await?, events[2].id);
+    assert_eq!(database.consumer_cursor("backup").await?, events[1].id);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_feed_consumer() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(test_config_with(database.clone()));
+
+    let create = |id: &'static str| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/documents")
+            .json(&json!({ "id": id }))
+    };
+    let mut feed = connect_feed(&filter, "consumer=indexer").await?;
+    assert_eq!(create("first").reply(&filter).await.status(), 201);
+    let event = feed.recv().await?;
+    assert_eq!(event["document_id"], "first");
+    feed.send(&json!({ "ack": event["id"] })).await;
+    for _ in 0..50 {
+        if database.consumer_cursor("indexer").await? == event["id"] {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(database.consumer_cursor("indexer").await?, event["id"]);
+    drop(feed);
+
+    // Events recorded with no subscriber are delivered after a restart.
+    assert_eq!(create("second").reply(&filter).await.status(), 201);
+    let filter = server(test_config_with(database.clone()));
+    let mut feed = connect_feed(&filter, "consumer=indexer").await?;
+    let event = feed.recv().await?;
+    assert_eq!(event["kind"], "created");
+    assert_eq!(event["document_id"], "second");
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_persist() -> Result<()> {
     pretty_env_logger::try_init().ok();
 
     let filter = server(ServerConfig {
         expiry_days: 2,
-        database: Database::new(&temp_sqlite_uri()?).await?,
+        ..test_config_with(Database::new(&temp_sqlite_uri()?).await?)
     });
 
     expect_text(&filter, "persist", "").await;
@@ -104,3 +209,698 @@ async fn test_persist() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_persist_large_edit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(test_config_with(database.clone()));
+
+    let mut client = connect(&filter, "paste").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let text = "pasted text\n".repeat(1000);
+    let mut operation = OperationSeq::default();
+    operation.insert(&text);
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    loop {
+        if client.recv().await?.get("History").is_some() {
+            break;
+        }
+    }
+
+    // A paste this large is persisted well before the regular interval.
+    let mut persisted = None;
+    for _ in 0..20 {
+        if let Ok(doc) = database.load("paste").await {
+            persisted = Some(doc.text);
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(persisted, Some(text));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_force_persist() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(test_config_with(database.clone()));
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/backup/persist")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/backup")
+        .body("saved right away")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/backup/persist")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "revision": 1 }));
+    assert_eq!(database.load("backup").await?.text, "saved right away");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_persist_history() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(test_config_with(database.clone()));
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/history")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text/history/append")
+        .header("cf-access-authenticated-user-email", "alice@example.com")
+        .body(", world")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/history/persist")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let operations = database.load_operations("history", 100).await?;
+    assert_eq!(operations.len(), 2);
+    assert_eq!(operations[1].revision, 1);
+    assert_eq!(operations[1].email.as_deref(), Some("alice@example.com"));
+
+    // A fresh server loads the document with its history intact.
+    let filter = server(test_config_with(database.clone()));
+    let mut client = connect(&filter, "history").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+    let msg = client.recv().await?;
+    assert_eq!(msg["History"]["start"], 0);
+    let history = msg["History"]["operations"].as_array().unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1]["email"], "alice@example.com");
+    expect_text(&filter, "history", "hello, world").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_history_ndjson() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(test_config_with(database.clone()));
+    for text in ["hello\n", "hello world\n"] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/text/journal")
+            .body(text)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    let resp = warp::test::request()
+        .path("/api/documents/journal/history.ndjson")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/x-ndjson");
+    let body = std::str::from_utf8(resp.body())?;
+    assert!(body.ends_with('\n'));
+    let lines = body
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    assert_eq!(lines.len(), 2);
+    for (revision, line) in lines.iter().enumerate() {
+        assert_eq!(line["revision"], revision);
+        assert!(line["created_at"].is_i64());
+        assert!(line["operation"].is_array());
+    }
+
+    // The stream is served from the database once the document is unloaded.
+    let filter = server(test_config_with(database.clone()));
+    let resp = warp::test::request()
+        .path("/api/documents/journal/history.ndjson")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(std::str::from_utf8(resp.body())?, body);
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/history.ndjson")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_timeline() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(test_config_with(database.clone()));
+
+    let document = PersistedDocument {
+        text: "abcd".into(),
+        language: None,
+    };
+    database.store("minutes", &document).await?;
+    let edits = [
+        ("alice@example.com", 1000, "", "ab", EditSource::Typing),
+        ("alice@example.com", 1060, "ab", "c", EditSource::Paste),
+        ("bob@example.com", 1100, "abc", "de", EditSource::Paste),
+        ("alice@example.com", 5000, "abcd", "", EditSource::Typing),
+    ];
+    let operations: Vec<_> = edits
+        .iter()
+        .enumerate()
+        .map(
+            |(revision, &(email, created_at, retained, inserted, source))| {
+                let mut operation = OperationSeq::default();
+                operation.retain(retained.len() as u64);
+                operation.insert(inserted);
+                if inserted.is_empty() {
+                    operation.delete(1);
+                }
+                StoredOperation {
+                    revision,
+                    user_id: revision as u64,
+                    email: Some(email.into()),
+                    created_at: Some(created_at),
+                    source: Some(source),
+                    signature: None,
+                    key_id: None,
+                    operation,
+                }
+            },
+        )
+        .collect();
+    database.store_operations("minutes", 0, &operations).await?;
+
+    let resp = warp::test::request()
+        .path("/api/documents/minutes/timeline")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let timeline: Value = serde_json::from_slice(resp.body())?;
+    let summary: Vec<_> = timeline
+        .as_array()
+        .expect("timeline should be an array")
+        .iter()
+        .map(|session| {
+            json!([
+                session["email"],
+                session["started_at"],
+                session["ended_at"],
+                session["operations"],
+                session["char_delta"],
+                session["pasted_chars"],
+            ])
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            json!(["alice@example.com", 1000, 1060, 2, 3, 1]),
+            json!(["bob@example.com", 1100, 1100, 1, 2, 2]),
+            json!(["alice@example.com", 5000, 5000, 1, -1, 0]),
+        ]
+    );
+
+    let resp = warp::test::request()
+        .path("/api/documents/missing/timeline")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_text_at_revision() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(test_config_with(database.clone()));
+    for text in ["first paragraph\n", "first paragraph\nsecond\n", "second\n"] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/text/incident")
+            .body(text)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+    let resp = warp::test::request()
+        .path("/api/text/incident?revision=1")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "first paragraph\n");
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/incident/persist")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // A fresh server rebuilds the text from the stored operation history.
+    let filter = server(test_config_with(database.clone()));
+    let resp = warp::test::request()
+        .path("/api/text/incident?with_revision=true")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["revision"], 3);
+    assert_eq!(body["text"], "second\n");
+
+    let resp = warp::test::request()
+        .path("/api/text/incident?revision=2&with_revision=true")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["revision"], 2);
+    assert_eq!(body["text"], "first paragraph\nsecond\n");
+
+    let resp = warp::test::request()
+        .path("/api/text/incident?revision=0")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "");
+
+    let resp = warp::test::request()
+        .path("/api/text/incident?revision=4")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = warp::test::request()
+        .path("/api/text/missing?revision=0")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+    expect_text(&filter, "incident", "second\n").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_versions() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let filter = server(ServerConfig {
+        versions: VersionPolicy {
+            interval: Duration::from_secs(3600),
+            revisions: 2,
+            keep: 2,
+        },
+        ..test_config_with(Database::new(&temp_sqlite_uri()?).await?)
+    });
+
+    for text in ["one", "two", "three", "four", "five", "six"] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/text/versioned")
+            .body(text)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+
+        // Let the persister run, giving SQLite some time to update the database.
+        time::pause();
+        time::advance(Duration::from_secs(5)).await;
+        time::resume();
+        time::sleep(Duration::from_millis(150)).await;
+    }
+
+    let resp = warp::test::request()
+        .path("/api/documents/versioned/versions")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let versions: Value = serde_json::from_slice(resp.body())?;
+    let versions = versions.as_array().unwrap();
+    let revisions: Vec<_> = versions
+        .iter()
+        .map(|version| &version["revision"])
+        .collect();
+    assert_eq!(revisions, [6, 4]);
+
+    let resp = warp::test::request()
+        .path(&format!(
+            "/api/documents/versioned/versions/{}",
+            versions[1]["id"]
+        ))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "four");
+
+    let resp = warp::test::request()
+        .path("/api/documents/versioned/versions/0")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_activity() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config_with(Database::new(&temp_sqlite_uri()?).await?));
+
+    for text in ["first", "second"] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/text/busy")
+            .body(text)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/busy/persist")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Activity is recorded by the background job queue.
+    let mut activity = json!([]);
+    for _ in 0..50 {
+        let resp = warp::test::request()
+            .path("/api/activity?since=0")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+        activity = serde_json::from_slice(resp.body())?;
+        if activity != json!([]) {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(activity.as_array().map(Vec::len), Some(1));
+    assert_eq!(activity[0]["id"], "busy");
+    assert_eq!(activity[0]["revisions"], 2);
+    assert!(activity[0]["edited_at"].is_i64());
+
+    let resp = warp::test::request()
+        .path("/api/activity?since=9999999999")
+        .reply(&filter)
+        .await;
+    let activity: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(activity, json!([]));
+
+    let resp = warp::test::request()
+        .path("/api/activity")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search() -> Result<()> {
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+
+    let deploy = PersistedDocument {
+        text: "#!/bin/sh\n# deploy <prod> script\nkubectl apply -f app.yaml\n".into(),
+        language: Some("shell".into()),
+    };
+    database.store("deploy", &deploy).await?;
+    let notes = PersistedDocument {
+        text: "meeting notes, nothing to deploy here".into(),
+        language: None,
+    };
+    database.store("notes", &notes).await?;
+
+    let results = database.search("kubectl", None, 10).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].meta.id, "deploy");
+    assert!(results[0].snippet.contains("<mark>kubectl</mark>"));
+    assert!(results[0].snippet.contains("&lt;prod&gt;"));
+
+    assert_eq!(database.search("deploy", None, 10).await?.len(), 2);
+    assert!(database.search("\"unbalanced -(", None, 10).await?.is_empty());
+    assert!(database.search("   ", None, 10).await?.is_empty());
+
+    database.soft_delete("deploy").await?;
+    let results = database.search("deploy", None, 10).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].meta.id, "notes");
+
+    // Protected documents are only found by users who may open them.
+    database.set_password_hash("notes", Some("hash")).await?;
+    assert!(database.search("deploy", None, 10).await?.is_empty());
+    database.set_password_hash("notes", None).await?;
+    database.set_role("notes", "alice@example.com", Role::Editor).await?;
+    assert!(database.search("deploy", None, 10).await?.is_empty());
+    assert!(database.search("deploy", Some("bob@example.com"), 10).await?.is_empty());
+    let results = database.search("deploy", Some("alice@example.com"), 10).await?;
+    assert_eq!(results.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_normalization() -> Result<()> {
+    let database = Database::new(&temp_sqlite_uri()?)
+        .await?
+        .with_normalization(Normalization {
+            tab_width: Some(2),
+            ..Default::default()
+        });
+
+    let doc = PersistedDocument {
+        text: "\u{feff}release\tchecklist".into(),
+        language: None,
+    };
+    database.store("release", &doc).await?;
+    assert_eq!(database.load("release").await?, doc);
+
+    let results = database.search("release", None, 10).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].snippet, "<mark>release</mark>  checklist");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_purge_deleted() -> Result<()> {
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+
+    let doc = PersistedDocument {
+        text: "secret".into(),
+        language: None,
+    };
+    database.store("old", &doc).await?;
+    database.store("kept", &doc).await?;
+    database.soft_delete("old").await?;
+
+    assert_eq!(database.purge_deleted(0).await?, 0);
+    assert!(database.load("old").await.is_ok());
+
+    assert_eq!(database.purge_deleted(i64::MAX).await?, 1);
+    assert!(database.load("old").await.is_err());
+    assert!(database.list_deleted().await?.is_empty());
+    assert_eq!(database.load("kept").await?, doc);
+
+    assert!(database.purge("kept").await?);
+    assert!(!database.purge("kept").await?);
+    assert_eq!(database.count().await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_derived_names() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?)
+        .await?
+        .with_derived_names(true);
+
+    let notes = PersistedDocument {
+        text: "\n   \n  first line  \nsecond line".into(),
+        language: None,
+    };
+    database.store("notes", &notes).await?;
+    let readme = PersistedDocument {
+        text: "<!-- badge -->\n# Project Title\n\nbody".into(),
+        language: Some("markdown".into()),
+    };
+    database.store("readme", &readme).await?;
+    let empty = PersistedDocument {
+        text: String::new(),
+        language: None,
+    };
+    database.store("empty", &empty).await?;
+
+    let meta = database.get_meta("notes").await?.expect("missing document");
+    assert_eq!(meta.name, None);
+    assert_eq!(meta.derived_name.as_deref(), Some("first line"));
+    let meta = database
+        .get_meta("readme")
+        .await?
+        .expect("missing document");
+    assert_eq!(meta.derived_name.as_deref(), Some("Project Title"));
+    let meta = database.get_meta("empty").await?.expect("missing document");
+    assert_eq!(meta.derived_name, None);
+
+    let options = ListOptions {
+        sort: SortOrder::Name,
+        ..Default::default()
+    };
+    let ids: Vec<String> = database
+        .list(&options)
+        .await?
+        .documents
+        .into_iter()
+        .map(|meta| meta.id)
+        .collect();
+    assert_eq!(ids, ["notes", "readme", "empty"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_filters() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+
+    let script = PersistedDocument {
+        text: "print('hello')".into(),
+        language: Some("python".into()),
+    };
+    database.store("script", &script).await?;
+    let detected = PersistedDocument {
+        text: "#!/usr/bin/env python3\nprint('hello')\n".into(),
+        language: None,
+    };
+    database.store("detected", &detected).await?;
+    let notes = PersistedDocument {
+        text: "notes".into(),
+        language: Some("markdown".into()),
+    };
+    database.store("notes", &notes).await?;
+
+    let list = |options: ListOptions| {
+        let database = database.clone();
+        async move {
+            let page = database.list(&options).await?;
+            let mut ids: Vec<String> = page.documents.into_iter().map(|meta| meta.id).collect();
+            ids.sort();
+            Ok::<_, anyhow::Error>(ids)
+        }
+    };
+    let python = ListOptions {
+        language: Some("python".into()),
+        ..Default::default()
+    };
+    assert_eq!(list(python).await?, ["detected", "script"]);
+
+    let now = database
+        .get_meta("notes")
+        .await?
+        .expect("missing document")
+        .updated_at;
+    let recent = ListOptions {
+        updated_after: Some(now - 60),
+        ..Default::default()
+    };
+    assert_eq!(list(recent).await?.len(), 3);
+    let future = ListOptions {
+        updated_after: Some(now + 60),
+        ..Default::default()
+    };
+    assert!(list(future).await?.is_empty());
+    let old = ListOptions {
+        language: Some("markdown".into()),
+        updated_before: Some(now + 60),
+        ..Default::default()
+    };
+    assert_eq!(list(old).await?, ["notes"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_detected_language() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+
+    let script = PersistedDocument {
+        text: "#!/usr/bin/env python3\nprint('hello')\n".into(),
+        language: None,
+    };
+    database.store("script", &script).await?;
+    let program = PersistedDocument {
+        text: "use std::io;\n\nfn main() {\n    let mut line = String::new();\n}\n".into(),
+        language: None,
+    };
+    database.store("program", &program).await?;
+    let config = PersistedDocument {
+        text: "{\"key\": [1, 2]}".into(),
+        language: None,
+    };
+    database.store("config", &config).await?;
+    let prose = PersistedDocument {
+        text: "Select a time for the meeting.\nThanks!".into(),
+        language: None,
+    };
+    database.store("prose", &prose).await?;
+    let explicit = PersistedDocument {
+        text: "#!/bin/sh\necho hi".into(),
+        language: Some("plaintext".into()),
+    };
+    database.store("explicit", &explicit).await?;
+
+    for (id, expected) in [
+        ("script", Some("python")),
+        ("program", Some("rust")),
+        ("config", Some("json")),
+        ("prose", None),
+        ("explicit", None),
+    ] {
+        let meta = database.get_meta(id).await?.expect("missing document");
+        assert_eq!(meta.detected_language.as_deref(), expected, "{}", id);
+    }
+
+    Ok(())
+}
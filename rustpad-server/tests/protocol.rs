@@ -0,0 +1,54 @@
+//! Tests for the protocol test vectors, run with `--features protocol-tests`.
+#![cfg(feature = "protocol-tests")]
+
+use anyhow::Result;
+use operational_transform::OperationSeq;
+use rustpad_server::protocol_tests::protocol_vectors;
+use serde_json::{json, Value};
+
+#[test]
+fn test_protocol_vectors() -> Result<()> {
+    let vectors = serde_json::to_value(protocol_vectors())?;
+    assert!(vectors["protocol_version"].is_u64());
+
+    let case = |kind: &str, name: &str| -> Value {
+        vectors[kind]
+            .as_array()
+            .and_then(|cases| cases.iter().find(|case| case["name"] == name))
+            .cloned()
+            .unwrap_or_else(|| panic!("missing {} vector {:?}", kind, name))
+    };
+    assert_eq!(
+        case("handshakes", "empty document")["server"],
+        json!([{ "Identity": 0 }, { "AuthenticatedEmail": null }])
+    );
+    assert_eq!(
+        case("handshakes", "document with trimmed history")["server"],
+        json!([
+            { "Identity": 1 },
+            { "AuthenticatedEmail": null },
+            { "Resync": { "text": "one two", "revision": 2 } },
+            { "History": { "start": 2, "operations": [{ "id": 0, "operation": [7, " three"] }] } },
+        ])
+    );
+    assert_eq!(
+        case("errors", "edit based on a future revision")["server"],
+        json!([{ "Error": { "code": "edit_rejected", "params": { "reason": "invalid_revision" } } }])
+    );
+
+    // Both orders of applying a pair of concurrent operations converge.
+    let transforms = vectors["transforms"].as_array();
+    for transform in transforms.expect("transforms should be a list") {
+        let op = |field: &str| -> Result<OperationSeq> {
+            Ok(serde_json::from_value(transform[field].clone())?)
+        };
+        let text = transform["text"].as_str().expect("text should be a string");
+        let result = op("b")?
+            .compose(&op("a_prime")?)
+            .and_then(|op| op.apply(text))
+            .expect("transformed operations should apply");
+        assert_eq!(transform["result"], result);
+    }
+
+    Ok(())
+}
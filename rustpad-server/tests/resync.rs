@@ -0,0 +1,184 @@
+//! Tests for divergence detection and full resynchronization of clients.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::server;
+use serde_json::{json, Value};
+use tokio::time;
+
+pub mod common;
+
+/// Receive the next message that is not a periodic checksum or statistics.
+async fn recv_skip_periodic(client: &mut JsonSocket) -> Result<Value> {
+    loop {
+        let msg = client.recv().await?;
+        if msg.get("Checksum").is_none() && msg.get("DocStats").is_none() {
+            return Ok(msg);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_checksum_mismatch() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "checksum").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    time::pause();
+    time::advance(Duration::from_secs(30)).await;
+    let mut msg = client.recv().await?;
+    while msg.get("DocStats").is_some() {
+        msg = client.recv().await?;
+    }
+    assert_eq!(msg["Checksum"]["revision"], 1);
+    let hash = msg["Checksum"]["hash"]
+        .as_u64()
+        .expect("checksum should be a number");
+
+    let bad_checksum = json!({
+        "Checksum": {
+            "revision": 1,
+            "hash": hash ^ 1
+        }
+    });
+    client.send(&bad_checksum).await;
+    assert_eq!(
+        recv_skip_periodic(&mut client).await?,
+        json!({
+            "Resync": {
+                "text": "hello",
+                "revision": 1
+            }
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bad_base_length() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "diverged").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    // This client believes the document is 3 characters long at revision 1.
+    let msg = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": [3, "!"]
+        }
+    });
+    client.send(&msg).await;
+    assert_eq!(
+        recv_skip_periodic(&mut client).await?,
+        json!({
+            "Resync": {
+                "text": "hello",
+                "revision": 1
+            }
+        })
+    );
+
+    // The connection stays usable after resynchronizing.
+    let msg = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": [5, "!"]
+        }
+    });
+    client.send(&msg).await;
+    assert_eq!(
+        recv_skip_periodic(&mut client).await?,
+        json!({
+            "History": {
+                "start": 1,
+                "operations": [
+                    { "id": 0, "operation": [5, "!"] }
+                ]
+            }
+        })
+    );
+    expect_text(&filter, "diverged", "hello!").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trimmed_history() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "trimmed").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let num_edits = 1100;
+    for revision in 0..num_edits {
+        let msg = json!({
+            "Edit": {
+                "revision": revision,
+                "operation": [revision, "a"]
+            }
+        });
+        client.send(&msg).await;
+    }
+    let mut total = 0;
+    while total < num_edits {
+        let msg = recv_skip_periodic(&mut client).await?;
+        total += msg["History"]["operations"]
+            .as_array()
+            .expect("should receive history")
+            .len();
+    }
+
+    // Wait for the persister to store the document and trim its history.
+    time::sleep(Duration::from_secs(5)).await;
+
+    let mut client2 = connect(&filter, "trimmed").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client2.recv().await?, json!({ "AuthenticatedEmail": null }));
+    // History that the first client may still edit on is retained after
+    // the base text.
+    assert_eq!(
+        client2.recv().await?,
+        json!({
+            "Resync": {
+                "text": "a".repeat(num_edits - 1),
+                "revision": num_edits - 1
+            }
+        })
+    );
+    let msg = client2.recv().await?;
+    assert_eq!(msg["History"]["start"], num_edits - 1);
+    let operations = msg["History"]["operations"].as_array().unwrap();
+    assert_eq!(operations.len(), 1);
+    assert_eq!(operations[0]["operation"], json!([num_edits - 1, "a"]));
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Tests for expiring share links to documents.
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use common::*;
+use rustpad_server::server;
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Mint a share link to a document, returning the response status and body.
+async fn share(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    body: Value,
+) -> (u16, Value) {
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/documents/{}/share", id))
+        .json(&body)
+        .reply(filter)
+        .await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+/// Receive messages until one of the given kind arrives.
+async fn recv_until(client: &mut JsonSocket, kind: &str) -> Result<Value> {
+    for _ in 0..20 {
+        let msg = client.recv().await?;
+        if msg.get(kind).is_some() {
+            return Ok(msg);
+        }
+    }
+    bail!("no {} message was received", kind)
+}
+
+#[tokio::test]
+async fn test_share_links() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "id": "contract" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/documents/contract")
+        .json(&json!({ "password": "hunter2" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let (status, read) = share(&filter, "contract", json!({})).await;
+    assert_eq!(status, 200);
+    assert_eq!(read["scope"], "read");
+    let read = read["token"].as_str().expect("token should be a string");
+    let (status, write) = share(&filter, "contract", json!({ "scope": "write" })).await;
+    assert_eq!(status, 200);
+    let write = write["token"].as_str().expect("token should be a string");
+
+    let resp = warp::test::request()
+        .path(&format!("/api/text/contract?token={}", read))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path("/api/text/contract?token=forged.token")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+
+    let mut reader = connect(&filter, &format!("contract?token={}", read)).await?;
+    assert_eq!(
+        recv_until(&mut reader, "ReadOnly").await?,
+        json!({ "ReadOnly": true })
+    );
+    reader
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    let resync = recv_until(&mut reader, "Resync").await?;
+    assert_eq!(resync["Resync"]["text"], "");
+    let revision = resync["Resync"]["revision"].clone();
+
+    let mut writer = connect(&filter, &format!("contract?token={}", write)).await?;
+    writer
+        .send(&json!({ "Edit": { "revision": revision, "operation": ["hello"] } }))
+        .await;
+    recv_until(&mut writer, "History").await?;
+    let resp = warp::test::request()
+        .path(&format!("/api/text/contract?token={}", write))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.body(), "hello");
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents")
+        .json(&json!({ "id": "other" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 201);
+    assert!(connect(&filter, &format!("other?token={}", write))
+        .await
+        .is_err());
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+    let (status, _) = share(&filter, "contract", json!({ "expires_at": now - 1 })).await;
+    assert_eq!(status, 400);
+    let far = now + 365 * 24 * 60 * 60;
+    let (status, _) = share(&filter, "contract", json!({ "expires_at": far })).await;
+    assert_eq!(status, 400);
+    let (status, _) = share(&filter, "missing", json!({})).await;
+    assert_eq!(status, 404);
+
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Tests for signed edits from authenticated users.
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use ring::hmac;
+use rustpad_server::{database::Database, server, signing::EditSigner, ServerConfig};
+use serde_json::{json, Value};
+use tempfile::NamedTempFile;
+
+pub mod common;
+
+/// Sign an edit payload with a hex-encoded key, as a client would.
+fn sign(key: &str, revision: usize, operation: &OperationSeq) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &hex::decode(key).unwrap());
+    let payload = format!("{}:{}", revision, serde_json::to_string(operation).unwrap());
+    hex::encode(hmac::sign(&key, payload.as_bytes()))
+}
+
+#[tokio::test]
+async fn test_signed_edit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect_as(&filter, "signed", "alice@example.com").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(
+        client.recv().await?,
+        json!({ "AuthenticatedEmail": "alice@example.com" })
+    );
+    let msg = client.recv().await?;
+    let key = msg["SigningKey"]
+        .as_str()
+        .expect("should receive signing key")
+        .to_owned();
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let signature = sign(&key, 0, &operation);
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation,
+            "signature": signature
+        }
+    });
+    client.send(&msg).await;
+
+    let msg: Value = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [{
+                    "id": 0,
+                    "operation": ["hello"],
+                    "email": "alice@example.com",
+                    "signature": signature
+                }]
+            }
+        })
+    );
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": operation,
+            "signature": sign(&key, 0, &operation)
+        }
+    });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "edit_rejected");
+    assert_eq!(msg["Error"]["params"]["reason"], "invalid_signature");
+    client.recv_closed().await?;
+
+    expect_text(&filter, "signed", "hello").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stored_signature() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let path = NamedTempFile::new()?.into_temp_path();
+    let database = Database::new(&format!("sqlite://{}", path.display())).await?;
+    let filter = server(ServerConfig {
+        signing_secret: Some("edit-secret".into()),
+        ..test_config_with(database.clone())
+    });
+
+    let mut client = connect_as(&filter, "stored", "alice@example.com").await?;
+    client.recv().await?; // Identity
+    client.recv().await?; // AuthenticatedEmail
+    let msg = client.recv().await?;
+    let key = msg["SigningKey"]
+        .as_str()
+        .expect("should receive signing key")
+        .to_owned();
+
+    let mut operation = OperationSeq::default();
+    operation.insert("signed");
+    let signature = sign(&key, 0, &operation);
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation,
+            "signature": signature
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?; // History
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/stored/persist")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // The key can be derived again from the secret to check the signature.
+    let operations = database.load_operations("stored", 100).await?;
+    assert_eq!(operations[0].signature.as_deref(), Some(signature.as_str()));
+    let key_id = operations[0].key_id.as_deref().expect("should store key ID");
+    let signer = EditSigner::new(b"edit-secret");
+    assert_eq!(hex::encode(signer.session_key(key_id).bytes()), key);
+    assert!(signer.verify(key_id, 0, &operations[0].operation, &signature));
+    assert!(!EditSigner::new(b"other-secret").verify(key_id, 0, &operation, &signature));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_signature_without_key() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "anonymous").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello"],
+            "signature": "00"
+        }
+    });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "edit_rejected");
+    assert_eq!(msg["Error"]["params"]["reason"], "invalid_signature");
+    client.recv_closed().await?;
+
+    expect_text(&filter, "anonymous", "").await;
+    Ok(())
+}
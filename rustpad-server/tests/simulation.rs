@@ -0,0 +1,55 @@
+//! Tests for simulated sessions, run with `--features simulation`.
+#![cfg(feature = "simulation")]
+
+use anyhow::Result;
+use rustpad_server::simulation::{simulate_session, Change, Script, ScriptedEdit};
+
+fn insert(client: usize, offset: usize, text: &str) -> ScriptedEdit {
+    let text = text.into();
+    let change = Change::Insert { offset, text };
+    ScriptedEdit { client, change }
+}
+
+fn delete(client: usize, offset: usize, count: usize) -> ScriptedEdit {
+    let change = Change::Delete { offset, count };
+    ScriptedEdit { client, change }
+}
+
+#[tokio::test]
+async fn test_simulate_session() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let script = Script {
+        clients: 3,
+        text: "hello world".into(),
+        rounds: vec![
+            vec![insert(0, 0, "> "), insert(1, 11, "!"), delete(2, 5, 6)],
+            vec![insert(0, 2, "a"), insert(1, 2, "b"), insert(2, 2, "c")],
+            vec![delete(0, 0, 4), delete(1, 2, 100), insert(2, 1000, "😀")],
+            vec![],
+        ],
+    };
+    let report = simulate_session(&script).await?;
+    assert_eq!(report.revision, 10);
+    assert!(report.text.ends_with("😀"));
+
+    // Scripts may also be written as JSON.
+    let script: Script = serde_json::from_str(
+        r#"{"clients": 2, "rounds": [[
+            {"client": 0, "change": {"Insert": {"offset": 0, "text": "a"}}},
+            {"client": 1, "change": {"Insert": {"offset": 0, "text": "b"}}}
+        ]]}"#,
+    )?;
+    let report = simulate_session(&script).await?;
+    assert_eq!(report.revision, 2);
+    assert!(report.text == "ab" || report.text == "ba");
+
+    let unknown = Script {
+        clients: 1,
+        rounds: vec![vec![insert(1, 0, "x")]],
+        ..Default::default()
+    };
+    assert!(simulate_session(&unknown).await.is_err());
+    assert!(simulate_session(&Script::default()).await.is_err());
+
+    Ok(())
+}
@@ -6,7 +6,7 @@ use anyhow::Result;
 use common::*;
 use log::info;
 use operational_transform::OperationSeq;
-use rustpad_server::server;
+use rustpad_server::{server, ServerConfig};
 use serde_json::json;
 use tokio::time;
 
@@ -73,6 +73,9 @@ async fn test_invalid_operation() -> Result<()> {
     info!("sending ClientMsg {}", msg);
     client.send(&msg).await;
 
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "edit_rejected");
+    assert_eq!(msg["Error"]["params"]["reason"], "invalid_revision");
     client.recv_closed().await?;
     Ok(())
 }
@@ -228,3 +231,153 @@ async fn test_set_language() -> Result<()> {
     expect_text(&filter, "foobar", "").await;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_doc_stats() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "counted").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": ["hello  big\nworld"]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    time::pause();
+    time::advance(Duration::from_secs(1)).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "DocStats": { "words": 3, "chars": 16, "reading_minutes": 1 } })
+    );
+
+    // Deleting the middle word also joins the other two into one.
+    let msg = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": [5, -6, 5]
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+    expect_text(&filter, "counted", "helloworld").await;
+
+    time::advance(Duration::from_secs(1)).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "DocStats": { "words": 1, "chars": 10, "reading_minutes": 1 } })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_oversized_message() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_message_size: 64,
+        ..test_config().await
+    });
+
+    let msg = json!({ "SetLanguage": "x".repeat(100) });
+    for _ in 0..3 {
+        let mut client = connect_as(&filter, "limited", "spammer@example.com").await?;
+        client.recv().await?; // Identity
+        client.recv().await?; // AuthenticatedEmail
+        client.recv().await?; // SigningKey
+        client.send(&msg).await;
+        let error = client.recv().await?;
+        assert_eq!(error["Error"]["code"], "message_too_large");
+        assert_eq!(error["Error"]["params"]["limit"], "64");
+        client.recv_closed().await?;
+    }
+
+    // Strikes are recorded just after the socket closes.
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Repeat offenders are refused, while other clients may still connect.
+    assert!(connect_as(&filter, "limited", "spammer@example.com").await.is_err());
+    let mut client = connect(&filter, "limited").await?;
+    client.recv().await?; // Identity
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?; // AuthenticatedEmail
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+    let stats: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["sockets"]["oversized"], 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_info() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        region: Some("eu-west".into()),
+        alternates: vec!["wss://us.example.com".into()],
+        ..test_config().await
+    });
+
+    let mut client = connect(&filter, "regional").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "ServerInfo": {
+                "region": "eu-west",
+                "alternates": ["wss://us.example.com"]
+            }
+        })
+    );
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_edit_source() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(test_config().await);
+
+    let mut client = connect(&filter, "pasted").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv().await?, json!({ "AuthenticatedEmail": null }));
+
+    // Without a hint, a large insertion counts as a paste.
+    let pasted = format!("{}\n", "x".repeat(40));
+    let edits = [
+        json!({ "revision": 0, "operation": ["a\n"] }),
+        json!({ "revision": 1, "operation": [2, pasted] }),
+        json!({ "revision": 2, "operation": [43, "b"], "source": "programmatic" }),
+    ];
+    for edit in edits {
+        client.send(&json!({ "Edit": edit })).await;
+        client.recv().await?;
+    }
+
+    let resp = warp::test::request()
+        .path("/api/documents/pasted/blame")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let blame: serde_json::Value = serde_json::from_slice(resp.body())?;
+    let sources: Vec<_> = blame["lines"]
+        .as_array()
+        .expect("lines should be an array")
+        .iter()
+        .map(|line| line["source"].clone())
+        .collect();
+    assert_eq!(
+        sources,
+        [json!("typing"), json!("paste"), json!("programmatic")]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Tests for warm standby replication and promotion.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use common::*;
+use rustpad_server::{server, standby::StandbyConfig, ServerConfig};
+use serde_json::{json, Value};
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Wait until the text of a document on a server matches, as replication
+/// happens in the background.
+async fn wait_for_text(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    text: &str,
+) -> Result<()> {
+    for _ in 0..100 {
+        let resp = warp::test::request()
+            .path(&format!("/api/text/{}", id))
+            .reply(filter)
+            .await;
+        if resp.body() == text {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    bail!("document {} never had text {:?}", id, text)
+}
+
+/// Promote a server, returning the response status.
+async fn promote(filter: &BoxedFilter<(impl Reply + 'static,)>) -> u16 {
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/admin/standby/promote")
+        .reply(filter)
+        .await;
+    resp.status().as_u16()
+}
+
+/// Receive messages until one of the given kind arrives.
+async fn recv_until(client: &mut JsonSocket, kind: &str) -> Result<Value> {
+    for _ in 0..20 {
+        let msg = client.recv().await?;
+        if msg.get(kind).is_some() {
+            return Ok(msg);
+        }
+    }
+    bail!("no {} message was received", kind)
+}
+
+#[tokio::test]
+async fn test_standby_failover() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let primary = server(test_config().await);
+    let (addr, serving) = warp::serve(primary.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serving);
+
+    let mut writer = connect(&primary, "failover").await?;
+    assert_eq!(writer.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(writer.recv().await?, json!({ "AuthenticatedEmail": null }));
+    writer
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    writer.recv().await?;
+
+    let standby = server(ServerConfig {
+        standby: Some(StandbyConfig {
+            primary: format!("http://{}", addr),
+            token: None,
+        }),
+        ..test_config().await
+    });
+    wait_for_text(&standby, "failover", "hello").await?;
+
+    writer
+        .send(&json!({ "Edit": { "revision": 1, "operation": [5, " world"] } }))
+        .await;
+    wait_for_text(&standby, "failover", "hello world").await?;
+
+    let mut reader = connect(&standby, "failover").await?;
+    assert_eq!(
+        recv_until(&mut reader, "ReadOnly").await?,
+        json!({ "ReadOnly": true })
+    );
+    reader
+        .send(&json!({ "Edit": { "revision": 2, "operation": [11, "!"] } }))
+        .await;
+    let resync = recv_until(&mut reader, "Resync").await?;
+    assert_eq!(resync["Resync"]["text"], "hello world");
+
+    let resp = warp::test::request()
+        .path("/api/admin/standby")
+        .reply(&standby)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let status: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(status["promoted"], false);
+    assert_eq!(status["followed"], 1);
+
+    assert_eq!(promote(&standby).await, 204);
+    assert_eq!(promote(&standby).await, 409);
+    assert_eq!(
+        recv_until(&mut reader, "ReadOnly").await?,
+        json!({ "ReadOnly": false })
+    );
+    reader
+        .send(&json!({ "Edit": { "revision": 2, "operation": [11, "!"] } }))
+        .await;
+    wait_for_text(&standby, "failover", "hello world!").await?;
+    expect_text(&primary, "failover", "hello world").await;
+
+    assert_eq!(promote(&primary).await, 409);
+    let resp = warp::test::request()
+        .path("/api/admin/standby")
+        .reply(&primary)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
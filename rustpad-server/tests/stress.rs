@@ -102,6 +102,9 @@ async fn test_large_document() -> Result<()> {
         }
     });
     client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "edit_rejected");
+    assert_eq!(msg["Error"]["params"]["reason"], "too_large");
     client.recv_closed().await?;
 
     Ok(())
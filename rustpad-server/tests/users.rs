@@ -61,6 +61,7 @@ async fn test_invalid_user() -> Result<()> {
 
     let alice = json!({ "name": "Alice" }); // no hue
     client.send(&json!({ "ClientInfo": alice })).await;
+    assert_eq!(client.recv().await?, json!({ "Error": { "code": "invalid_message" } }));
     client.recv_closed().await?;
 
     Ok(())
@@ -89,6 +90,7 @@ async fn test_leave_rejoin() -> Result<()> {
     assert_eq!(client.recv().await?, alice_info);
 
     client.send(&json!({ "Invalid": "please close" })).await;
+    assert_eq!(client.recv().await?, json!({ "Error": { "code": "invalid_message" } }));
     client.recv_closed().await?;
 
     let mut client2 = connect(&filter, "foobar").await?;
@@ -153,6 +155,7 @@ async fn test_cursors() -> Result<()> {
     assert_eq!(client.recv().await?, cursors2_resp);
 
     client.send(&json!({ "Invalid": "please close" })).await;
+    assert_eq!(client.recv().await?, json!({ "Error": { "code": "invalid_message" } }));
     client.recv_closed().await?;
 
     let msg = json!({
@@ -167,6 +167,18 @@ impl OpSeq {
     }
 }
 
+/// Returns the 32-bit FNV-1a hash of the UTF-8 bytes of a string, matching the
+/// checksums sent by the server to detect diverged clients.
+#[wasm_bindgen]
+pub fn checksum(text: &str) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for &byte in text.as_bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
 #[wasm_bindgen]
 impl OpSeqPair {
     /// Returns the first element of the pair.
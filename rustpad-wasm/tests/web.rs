@@ -2,7 +2,7 @@
 
 #![cfg(target_arch = "wasm32")]
 
-use rustpad_wasm::OpSeq;
+use rustpad_wasm::{checksum, OpSeq};
 
 use wasm_bindgen_test::*;
 
@@ -63,3 +63,10 @@ fn transform_index() {
     assert_eq!(o.transform_index(5), 8);
     assert_eq!(o.transform_index(7), 13);
 }
+
+#[wasm_bindgen_test]
+fn checksum_text() {
+    assert_eq!(checksum(""), 0x811c9dc5);
+    assert_eq!(checksum("a"), 0xe40c292c);
+    assert_ne!(checksum("hello"), checksum("hellp"));
+}
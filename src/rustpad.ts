@@ -5,7 +5,7 @@ import type {
   editor,
 } from "monaco-editor/esm/vs/editor/editor.api";
 
-import { OpSeq } from "./wasm";
+import { OpSeq, checksum } from "./wasm";
 
 /** Options passed in to the Rustpad constructor. */
 export type RustpadOptions = {
@@ -44,6 +44,7 @@ class Rustpad {
   private revision: number = 0;
   private outstanding?: OpSeq;
   private buffer?: OpSeq;
+  private catchingUp: boolean = false;
   private users: Record<number, UserInfo> = {};
   private userCursors: Record<number, CursorData> = {};
   private myInfo?: UserInfo;
@@ -220,6 +221,7 @@ class Rustpad {
     ws.onopen = () => {
       this.connecting = false;
       this.ws = ws;
+      this.catchingUp = true;
       this.options.onConnected?.();
       this.users = {};
       this.options.onChangeUsers?.(this.users);
@@ -258,6 +260,7 @@ class Rustpad {
       this.options.onAuthenticatedEmail?.(msg.AuthenticatedEmail);
     } else if (msg.History !== undefined) {
       const { start, operations } = msg.History;
+      this.catchingUp = false;
       if (start > this.revision) {
         console.warn("History message has start greater than last operation.");
         this.ws?.close();
@@ -309,9 +312,59 @@ class Rustpad {
       if (oldHue !== hue) {
         this.updateOwnerHue(email, hue);
       }
+    } else if (msg.Checksum !== undefined) {
+      this.sendChecksum(msg.Checksum.revision);
+    } else if (msg.Resync !== undefined) {
+      const { text, revision } = msg.Resync;
+      const catchingUp = this.catchingUp;
+      this.catchingUp = false;
+      // When the server has trimmed its history, a new connection starts from
+      // the text at the first retained revision. If we reconnected at or past
+      // it, the history that follows brings us up to date without losing our
+      // unacknowledged edits, which were already sent again.
+      if (!catchingUp || revision > this.revision) {
+        this.resync(text, revision);
+      }
     }
   }
 
+  /**
+   * Replace our text with the server's, after our edits no longer applied or
+   * our text diverged. The server has dropped our unacknowledged edits, so
+   * they are discarded too.
+   */
+  private resync(text: string, revision: number) {
+    this.outstanding = undefined;
+    this.buffer = undefined;
+    this.revision = revision;
+
+    this.ignoreChanges = true;
+    this.model.pushEditOperations(
+      this.options.editor.getSelections(),
+      [{ range: this.model.getFullModelRange(), text }],
+      () => null,
+    );
+    this.lastValue = this.model.getValue();
+    this.ignoreChanges = false;
+
+    // Ownership of the replaced lines is no longer known
+    this.lineOwnership.clear();
+    this.updateLineDecorations();
+    this.updateCursors();
+  }
+
+  /**
+   * Reply to a checksum probe with the checksum of our text, so the server
+   * can resync us if it has diverged. Local edits not yet acknowledged make
+   * our text differ from the server's at any revision, so we stay quiet
+   * until they are.
+   */
+  private sendChecksum(revision: number) {
+    if (revision !== this.revision || this.outstanding) return;
+    const hash = checksum(this.model.getValue());
+    this.ws?.send(`{"Checksum":{"revision":${revision},"hash":${hash}}}`);
+  }
+
   private serverAck() {
     if (!this.outstanding) {
       console.warn("Received serverAck with no outstanding operation.");
@@ -781,6 +834,14 @@ type ServerMsg = {
     email: string;
     hue: number;
   };
+  Checksum?: {
+    revision: number;
+    hash: number;
+  };
+  Resync?: {
+    text: string;
+    revision: number;
+  };
 };
 
 /** Returns the number of Unicode codepoints in a string. */
@@ -1,3 +1,3 @@
-// Re-export OpSeq from rustpad-wasm
+// Re-export OpSeq and checksum from rustpad-wasm
 // Vite handles WASM initialization automatically with the bundler target
-export { OpSeq } from "rustpad-wasm";
+export { OpSeq, checksum } from "rustpad-wasm";